@@ -0,0 +1,13 @@
+//! Run with `cargo fuzz run parse_request`.
+
+#![no_main]
+
+use incognita_socket_server::request;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    // any request which parses must be written the same way it reads
+    if let Some(request) = request::parse(line) {
+        assert_eq!(Some(&request), request::parse(&request.to_string()).as_ref(), "parsed from {line:?}");
+    }
+});
@@ -0,0 +1,185 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// A single IP address, or a range of addresses in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Parses an address like `10.0.0.1`, or a range like `10.0.0.0/8`.
+    pub(crate) fn parse(s: &str) -> Option<IpRange> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().ok().filter(|&len| len <= max_len)?,
+            None => max_len,
+        };
+        Some(IpRange {addr: addr.to_canonical(), prefix_len})
+    }
+    
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                prefix_matches(range.to_bits().into(), addr.to_bits().into(), 32, self.prefix_len)
+            },
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                prefix_matches(range.to_bits(), addr.to_bits(), 128, self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(a: u128, b: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = bits - prefix_len;
+    shift == bits || (a >> shift) == (b >> shift)
+}
+
+/// Parses a list of addresses and ranges, one per line. Blank lines and
+/// anything after a `#` are ignored.
+fn parse_list(text: &str) -> Result<Vec<IpRange>, String> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.split('#').next().unwrap_or("").trim();
+            (!line.is_empty()).then_some((i, line))
+        })
+        .map(|(i, line)| IpRange::parse(line).ok_or_else(|| {
+            format!("line {}: invalid address or range '{line}'", i + 1)
+        }))
+        .collect()
+}
+
+/// A list which is loaded from a file, and reloaded whenever the file is
+/// modified.
+#[derive(Debug, Clone)]
+struct WatchedList {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    ranges: Vec<IpRange>,
+}
+
+impl WatchedList {
+    fn new(path: PathBuf) -> WatchedList {
+        WatchedList {path, modified: None, ranges: Vec::new()}
+    }
+    
+    /// Reloads the list if the file has changed. If the file can't be read
+    /// or is invalid, the previous list is kept.
+    fn refresh(&mut self) {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+        
+        let path = self.path.display();
+        match std::fs::read_to_string(&self.path).map_err(|e| e.to_string()).and_then(|text| parse_list(&text)) {
+            Ok(ranges) => {
+                info!(%path, entries = ranges.len(), "Loaded access list");
+                self.ranges = ranges;
+                self.modified = modified;
+            },
+            Err(e) => warn!(%path, error = %e, "Failed to load access list"),
+        }
+    }
+    
+    fn contains(&self, addr: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(addr))
+    }
+}
+
+/// Decides which addresses may connect, according to an optional allowlist
+/// and an optional denylist. An address on the denylist is always refused;
+/// if there is an allowlist, only addresses on it are permitted.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AccessControl {
+    allow: Option<WatchedList>,
+    deny: Option<WatchedList>,
+}
+
+impl AccessControl {
+    pub(crate) fn new(allow: Option<PathBuf>, deny: Option<PathBuf>) -> AccessControl {
+        let mut access = AccessControl {
+            allow: allow.map(WatchedList::new),
+            deny: deny.map(WatchedList::new),
+        };
+        access.refresh();
+        access
+    }
+    
+    fn refresh(&mut self) {
+        self.allow.iter_mut()
+            .chain(self.deny.iter_mut())
+            .for_each(WatchedList::refresh);
+    }
+    
+    /// Whether a connection from this address should be accepted, reloading
+    /// the lists first if their files have changed.
+    pub(crate) fn permits(&mut self, addr: IpAddr) -> bool {
+        self.refresh();
+        self.allow.as_ref().is_none_or(|list| list.contains(addr))
+            && !self.deny.as_ref().is_some_and(|list| list.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    
+    #[test]
+    fn parse_range() {
+        assert_eq!(Some(IpRange {addr: ip("10.0.0.0"), prefix_len: 8}), IpRange::parse("10.0.0.0/8"));
+        assert_eq!(Some(IpRange {addr: ip("10.0.0.1"), prefix_len: 32}), IpRange::parse("10.0.0.1"));
+        assert_eq!(None, IpRange::parse("10.0.0.0/33"));
+        assert_eq!(None, IpRange::parse("localhost"));
+    }
+    
+    #[test]
+    fn range_contains() {
+        let range = IpRange::parse("192.168.0.0/16").unwrap();
+        assert!(range.contains(ip("192.168.4.20")));
+        assert!(range.contains(ip("::ffff:192.168.4.20")));
+        assert!(!range.contains(ip("192.169.0.1")));
+        assert!(!range.contains(ip("::1")));
+        
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(IpRange::parse("2001:db8::/32").unwrap().contains(ip("2001:db8::1")));
+    }
+    
+    #[test]
+    fn parse_list_with_comments() {
+        let list = parse_list("# tournament players\n10.0.0.1\n\n10.1.0.0/16 # office\n").unwrap();
+        assert_eq!(vec![IpRange::parse("10.0.0.1").unwrap(), IpRange::parse("10.1.0.0/16").unwrap()], list);
+        assert_eq!(Err("line 2: invalid address or range 'nope'".to_string()), parse_list("10.0.0.1\nnope"));
+    }
+    
+    #[test]
+    fn deny_overrides_allow() {
+        let mut access = AccessControl {
+            allow: Some(WatchedList {path: PathBuf::new(), modified: None, ranges: vec![IpRange::parse("10.0.0.0/8").unwrap()]}),
+            deny: Some(WatchedList {path: PathBuf::new(), modified: None, ranges: vec![IpRange::parse("10.0.0.66").unwrap()]}),
+        };
+        assert!(access.permits(ip("10.0.0.1")));
+        assert!(!access.permits(ip("10.0.0.66")));
+        assert!(!access.permits(ip("11.0.0.1")));
+        assert!(AccessControl::default().permits(ip("11.0.0.1")));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+use crate::response::Message;
+
+/// The relayed messages a client which asked for acknowledgements hasn't
+/// acknowledged yet, so that they can be sent again after it resumes.
+pub(crate) struct Unacked {
+    /// The number of the next relayed message; the first is 1, so that a
+    /// client which has received none acknowledges 0.
+    next: u64,
+    sent: VecDeque<(u64, Message)>,
+    /// Set when the session resumes, until the client says which messages
+    /// it received; relayed messages are only kept in the meantime.
+    resuming: bool,
+}
+
+impl Unacked {
+    pub(crate) fn new() -> Unacked {
+        Unacked {
+            next: 1,
+            sent: VecDeque::new(),
+            resuming: false,
+        }
+    }
+    
+    /// Numbers a relayed message and keeps it until it is acknowledged.
+    /// Only the most recent `max` messages are kept.
+    pub(crate) fn push(&mut self, msg: Message, max: usize) -> Message {
+        let n = self.next;
+        self.next += 1;
+        let msg = Message::Sequenced(n, Box::new(msg));
+        if self.sent.len() >= max {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((n, msg.clone()));
+        msg
+    }
+    
+    /// Forgets the messages up to and including the `n`th.
+    pub(crate) fn ack(&mut self, n: u64) {
+        while self.sent.front().is_some_and(|&(m, _)| m <= n) {
+            self.sent.pop_front();
+        }
+    }
+    
+    /// The messages which haven't been acknowledged, oldest first.
+    pub(crate) fn pending(&self) -> impl Iterator<Item = Message> + '_ {
+        self.sent.iter().map(|(_, msg)| msg.clone())
+    }
+    
+    pub(crate) fn is_resuming(&self) -> bool {
+        self.resuming
+    }
+    
+    pub(crate) fn set_resuming(&mut self, resuming: bool) {
+        self.resuming = resuming;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn received(payload: &str) -> Message {
+        Message::ReceivedBroadcast(1, payload.into())
+    }
+    
+    fn sequenced(n: u64, payload: &str) -> Message {
+        Message::Sequenced(n, Box::new(received(payload)))
+    }
+    
+    #[test]
+    fn ack() {
+        let mut unacked = Unacked::new();
+        for payload in ["a", "b", "c", "d"] {
+            unacked.push(received(payload), 3);
+        }
+        // the oldest message is forgotten
+        assert_eq!(vec![sequenced(2, "b"), sequenced(3, "c"), sequenced(4, "d")], unacked.pending().collect::<Vec<_>>());
+        
+        unacked.ack(3);
+        assert_eq!(vec![sequenced(4, "d")], unacked.pending().collect::<Vec<_>>());
+        unacked.ack(10);
+        assert_eq!(0, unacked.pending().count());
+        assert_eq!(sequenced(5, "e"), unacked.push(received("e"), 3));
+    }
+}
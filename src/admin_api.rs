@@ -0,0 +1,307 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use futures::SinkExt;
+use futures::channel::oneshot;
+use tracing::{info, warn};
+
+use crate::access::AccessControl;
+use crate::dispatch::{self, Event, Line, Sender};
+use crate::err;
+use crate::ids;
+use crate::limits::Backoff;
+use crate::models::{Room, RoomID, User, UserID};
+use crate::stats::TrafficCounts;
+
+/// Requests are small, so anything longer than this is refused.
+const MAX_HEADER_LENGTH: usize = 8192;
+
+/// Requests with more header lines than this are refused.
+const MAX_HEADER_LINES: usize = 64;
+
+/// Connections which haven't sent a whole request by then are closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something an administrator has asked for through the HTTP API.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AdminQuery {
+    Users,
+    Rooms,
+    Room(RoomID),
+    CloseRoom(RoomID),
+    KickUser(UserID),
+}
+
+/// The HTTP status and JSON body to send back.
+pub(crate) struct AdminReply {
+    pub(crate) status: u16,
+    pub(crate) body: String,
+}
+
+impl AdminReply {
+    pub(crate) fn ok(body: String) -> AdminReply {
+        AdminReply {status: 200, body}
+    }
+    
+    pub(crate) fn error(status: u16, msg: &str) -> AdminReply {
+        AdminReply {status, body: format!("{{\"error\":{}}}", json_string(msg))}
+    }
+}
+
+fn route(method: &str, path: &str) -> Option<AdminQuery> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["users"]) => Some(AdminQuery::Users),
+        ("GET", ["rooms"]) => Some(AdminQuery::Rooms),
+        ("GET", ["rooms", id]) => id.parse().ok().map(AdminQuery::Room),
+        ("DELETE", ["rooms", id]) => id.parse().ok().map(AdminQuery::CloseRoom),
+        ("DELETE", ["users", id]) => id.parse().ok().map(AdminQuery::KickUser),
+        _ => None,
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |v| v.to_string())
+}
+
+fn json_ids<'a>(ids: impl IntoIterator<Item = &'a u32>) -> String {
+    let ids: Vec<String> = ids.into_iter().map(u32::to_string).collect();
+    format!("[{}]", ids.join(","))
+}
+
+/// The rooms a user takes part in, as an object from room IDs to how.
+fn json_memberships(user: &User) -> String {
+    let rooms: Vec<String> = user.rooms.iter()
+        .map(|(room_id, membership)| format!("\"{room_id}\":\"{}\"", membership.name()))
+        .collect();
+    format!("{{{}}}", rooms.join(","))
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+/// Summarises a user; `conn` is how many messages are waiting to be
+/// written to their connection and its traffic so far, if they have one.
+pub(crate) fn user_json(user: &User, conn: Option<(usize, TrafficCounts)>) -> String {
+    let (queued, traffic) = conn.unzip();
+    format!(
+        "{{\"id\":{},\"session\":{},\"name\":{},\"rooms\":{},\"matchmaking\":{},\"client_version\":{},\"admin\":{},\"latency_ms\":{},\"connected\":{},\"queued\":{},\"traffic\":{}}}",
+        user.id,
+        json_string(&user.session_id),
+        json_option(user.name.as_deref().map(json_string)),
+        json_memberships(user),
+        user.queued,
+        json_option(user.client_version.as_deref().map(json_string)),
+        user.is_admin,
+        json_option(user.latency_ms),
+        user.connected,
+        json_option(queued),
+        json_option(traffic.map(traffic_json)),
+    )
+}
+
+fn traffic_json(traffic: TrafficCounts) -> String {
+    format!(
+        "{{\"messages_in\":{},\"bytes_in\":{},\"messages_out\":{},\"bytes_out\":{}}}",
+        traffic.messages_in,
+        traffic.bytes_in,
+        traffic.messages_out,
+        traffic.bytes_out,
+    )
+}
+
+pub(crate) fn users_json<'a>(users: impl Iterator<Item = &'a User>, conn: impl Fn(UserID) -> Option<(usize, TrafficCounts)>) -> String {
+    json_list(users.map(|user| user_json(user, conn(user.id))))
+}
+
+/// Summarises a room; with `detail`, also includes its data and join
+/// requests.
+pub(crate) fn room_json(room: &Room, detail: bool) -> String {
+    let mut json = format!(
+        "{{\"id\":{},\"owner\":{},\"members\":{},\"spectators\":{},\"capacity\":{},\"join_policy\":\"{}\",\"password\":{},\"name\":{}",
+        room.id,
+        room.owner_id,
+        json_ids(&room.members),
+        json_ids(&room.spectators),
+        json_option(room.capacity),
+        room.join_policy,
+        room.password.is_some(),
+        json_option(room.name.as_deref().map(json_string)),
+    );
+    if detail {
+        json += &format!(
+            ",\"join_requests\":{},\"data\":{}",
+            json_ids(&room.join_requests),
+            json_string(&room.data),
+        );
+    }
+    json.push('}');
+    json
+}
+
+pub(crate) fn rooms_json<'a>(rooms: impl Iterator<Item = &'a Room>) -> String {
+    json_list(rooms.map(|room| room_json(room, false)))
+}
+
+/// Serves the HTTP admin API. Every request must carry the admin password as
+/// a bearer token, and addresses which keep getting it wrong are made to
+/// wait longer and longer between attempts.
+pub(crate) async fn serve(admin_addr: String, password: Arc<str>, mut access: AccessControl, dispatcher: Sender<Event>) -> err::Result {
+    let listener = dispatch::bind(&admin_addr).await?;
+    info!(addr = %admin_addr, "Listening for the admin API");
+    
+    let backoff = Arc::new(Mutex::new(Backoff::new()));
+    let mut incoming = listener.incoming();
+    while let Some(conn) = incoming.next().await {
+        let Ok((conn, addr)) = conn
+            .and_then(|s| {
+                s.peer_addr().map(|a| (s, dispatch::canonical_addr(a)))
+            })
+            .map_err(|e| warn!(error = %e, "Failed admin connection"))
+            else { continue; };
+        
+        if !access.permits(addr.ip()) {
+            info!(%addr, "Refused admin connection");
+            continue;
+        }
+        let password = password.clone();
+        let backoff = backoff.clone();
+        let dispatcher = dispatcher.clone();
+        err::spawn_logged_task(async move {
+            // a client which is too slow gets no reply at all
+            io::timeout(READ_TIMEOUT, handle(conn, addr.ip(), &password, &backoff, dispatcher)).await?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+async fn handle(conn: TcpStream, ip: IpAddr, password: &str, backoff: &Mutex<Backoff<IpAddr>>, mut dispatcher: Sender<Event>) -> io::Result<()> {
+    let mut lines = Box::pin(dispatch::bounded_lines(io::BufReader::new(&conn), MAX_HEADER_LENGTH));
+    let Some(Ok(Line::Complete(request_line))) = lines.next().await else {
+        return Ok(());
+    };
+    
+    let mut authorised = false;
+    let mut header_lines = 0;
+    while let Some(line) = lines.next().await {
+        let Line::Complete(line) = line? else {
+            return respond(&conn, AdminReply::error(431, "Header too long")).await;
+        };
+        if line.is_empty() {
+            break;
+        }
+        header_lines += 1;
+        if header_lines > MAX_HEADER_LINES {
+            return respond(&conn, AdminReply::error(431, "Too many headers")).await;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            authorised |= name.trim().eq_ignore_ascii_case("authorization")
+                && value.trim().strip_prefix("Bearer ").is_some_and(|given| ids::secrets_match(given, password));
+        }
+    }
+    
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    info!(method, path, authorised, "Admin API request");
+    
+    let permitted = {
+        let mut backoff = backoff.lock().unwrap();
+        let now = SystemTime::now();
+        let permitted = backoff.permits(&ip, now);
+        if permitted {
+            backoff.record(ip, authorised, now);
+        }
+        permitted
+    };
+    let reply = if !permitted {
+        AdminReply::error(429, "Too many failed attempts")
+    } else if !authorised {
+        AdminReply::error(401, "Admin password required")
+    } else if let Some(query) = route(method, path) {
+        let (reply, replied) = oneshot::channel();
+        if dispatcher.send(Event::Admin(query, reply)).await.is_err() {
+            return Ok(());
+        }
+        replied.await.unwrap_or_else(|_| AdminReply::error(503, "Server is shutting down"))
+    } else {
+        AdminReply::error(404, "Not found")
+    };
+    respond(&conn, reply).await
+}
+
+async fn respond(mut conn: &TcpStream, reply: AdminReply) -> io::Result<()> {
+    let reason = match reply.status {
+        200 => "OK",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.status,
+        reply.body.len(),
+        reply.body,
+    );
+    conn.write_all(response.as_bytes()).await?;
+    conn.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::Membership;
+    use super::*;
+    
+    #[test]
+    fn routes() {
+        assert_eq!(Some(AdminQuery::Users), route("GET", "/users"));
+        assert_eq!(Some(AdminQuery::Rooms), route("GET", "/rooms/"));
+        assert_eq!(Some(AdminQuery::Room(3)), route("GET", "/rooms/3"));
+        assert_eq!(Some(AdminQuery::CloseRoom(3)), route("DELETE", "/rooms/3"));
+        assert_eq!(Some(AdminQuery::KickUser(7)), route("DELETE", "/users/7"));
+        assert_eq!(None, route("DELETE", "/users"));
+        assert_eq!(None, route("GET", "/rooms/x"));
+        assert_eq!(None, route("POST", "/rooms/3"));
+    }
+    
+    #[test]
+    fn user_and_room_json() {
+        let mut user = User::new(2);
+        user.session_id = "1b4e28ba-2fa1-4d2a-883f-0016d3cca427".into();
+        user.client_version = Some("1.\"2\"".into());
+        user.rooms.insert(1, Membership::Member);
+        user.rooms.insert(3, Membership::Spectating);
+        assert_eq!(
+            r#"{"id":2,"session":"1b4e28ba-2fa1-4d2a-883f-0016d3cca427","name":null,"rooms":{"1":"member","3":"spectating"},"matchmaking":false,"client_version":"1.\"2\"","admin":false,"latency_ms":null,"connected":true,"queued":3,"traffic":{"messages_in":5,"bytes_in":60,"messages_out":7,"bytes_out":200}}"#,
+            user_json(&user, Some((3, TrafficCounts {messages_in: 5, bytes_in: 60, messages_out: 7, bytes_out: 200}))),
+        );
+        
+        let mut room = Room::new(1, 1, "hello".into());
+        room.members.insert(2);
+        room.name = Some("Friday \"Draft\"".into());
+        assert_eq!(
+            r#"{"id":1,"owner":1,"members":[2],"spectators":[],"capacity":null,"join_policy":"ASK","password":false,"name":"Friday \"Draft\"","join_requests":[],"data":"hello"}"#,
+            room_json(&room, true),
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::Duration;
+use async_std::io;
+use async_std::net::{SocketAddr, TcpStream};
+use async_std::prelude::*;
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+use crate::dispatch::{self, Line};
+use crate::ids;
+
+/// How long to wait for the account service to answer.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The account service's answer is only a status and perhaps an account
+/// name, so anything longer than this is refused.
+const MAX_RESPONSE_LINE_LENGTH: usize = 8192;
+
+/// Decides which connections may use the server. When authentication is
+/// required, a connection's first request must be `AUTH|credential`, and it
+/// is dropped unless the authenticator accepts the credential.
+pub(crate) trait Authenticator: Send + Sync {
+    /// Whether connections must send `AUTH` before anything else.
+    fn required(&self) -> bool {
+        true
+    }
+    
+    /// Checks a connection's credential, returning the account it belongs
+    /// to if the authenticator knows, or otherwise why it was refused.
+    fn authenticate<'a>(&'a self, addr: SocketAddr, credential: &'a str) -> BoxFuture<'a, Result<Option<String>, String>>;
+}
+
+/// Lets everyone in, without asking for a credential.
+pub(crate) struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn required(&self) -> bool {
+        false
+    }
+    
+    fn authenticate<'a>(&'a self, _addr: SocketAddr, _credential: &'a str) -> BoxFuture<'a, Result<Option<String>, String>> {
+        futures::future::ready(Ok(None)).boxed()
+    }
+}
+
+/// Lets in connections which know a token shared with the game's builds.
+pub(crate) struct StaticToken(pub(crate) String);
+
+impl Authenticator for StaticToken {
+    fn authenticate<'a>(&'a self, _addr: SocketAddr, credential: &'a str) -> BoxFuture<'a, Result<Option<String>, String>> {
+        let verdict = if ids::secrets_match(credential, &self.0) {
+            Ok(None)
+        } else {
+            Err("incorrect token".into())
+        };
+        futures::future::ready(verdict).boxed()
+    }
+}
+
+/// Asks an account service whether to let a connection in, by POSTing its
+/// credential to an `http://` URL. Any 2xx status accepts it, and the first
+/// line of the response body, if there is one, names the account.
+///
+/// Only plain HTTP is spoken, so credentials are sent unencrypted; the
+/// account service should be on the same host or a private network. The
+/// request is made with HTTP/1.0, so that the response isn't chunked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HttpCallback {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl std::str::FromStr for HttpCallback {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<HttpCallback, ()> {
+        let rest = s.strip_prefix("http://").ok_or(())?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // a bare IPv6 address has colons of its own, so must be bracketed
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.ends_with(']') => (host, port.parse().map_err(|_| ())?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(HttpCallback {host: host.to_string(), port, path: path.to_string()})
+    }
+}
+
+impl std::fmt::Display for HttpCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+impl HttpCallback {
+    /// Sends the credential, returning the response's status and the first
+    /// line of its body.
+    async fn call(&self, addr: SocketAddr, credential: &str) -> io::Result<(u16, String)> {
+        let mut conn = TcpStream::connect(format!("{}:{}", self.host, self.port)).await?;
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nX-Forwarded-For: {}\r\nConnection: close\r\n\r\n{credential}",
+            self.path,
+            self.host,
+            credential.len(),
+            addr.ip(),
+        );
+        conn.write_all(request.as_bytes()).await?;
+        conn.flush().await?;
+        
+        let mut lines = Box::pin(dispatch::bounded_lines(io::BufReader::new(&conn), MAX_RESPONSE_LINE_LENGTH));
+        let status_line = next_line(&mut lines).await?.ok_or_else(invalid_response)?;
+        let status = status_line.split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(invalid_response)?;
+        while let Some(header) = next_line(&mut lines).await? {
+            if header.is_empty() {
+                break;
+            }
+            // a chunked body starts with its length, which isn't the account
+            let (name, value) = header.split_once(':').unwrap_or((&header, ""));
+            if name.trim().eq_ignore_ascii_case("transfer-encoding") && !value.trim().eq_ignore_ascii_case("identity") {
+                return Err(invalid_response());
+            }
+        }
+        let body = next_line(&mut lines).await?.unwrap_or_default();
+        Ok((status, body))
+    }
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response")
+}
+
+async fn next_line(lines: &mut (impl Stream<Item = io::Result<Line>> + Unpin)) -> io::Result<Option<String>> {
+    match lines.next().await.transpose()? {
+        Some(Line::Complete(line)) => Ok(Some(line)),
+        Some(Line::TooLong) => Err(invalid_response()),
+        None => Ok(None),
+    }
+}
+
+impl Authenticator for HttpCallback {
+    fn authenticate<'a>(&'a self, addr: SocketAddr, credential: &'a str) -> BoxFuture<'a, Result<Option<String>, String>> {
+        async move {
+            let (status, body) = async_std::future::timeout(CALLBACK_TIMEOUT, self.call(addr, credential))
+                .await
+                .map_err(|_| "account service timed out".to_string())?
+                .map_err(|e| format!("account service failed: {e}"))?;
+            if !(200..300).contains(&status) {
+                return Err(format!("account service answered {status}"));
+            }
+            let account = body.trim();
+            Ok((!account.is_empty()).then(|| account.to_string()))
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use super::*;
+    
+    #[test]
+    fn parse_callback_url() {
+        let callback: HttpCallback = "http://accounts.example:8080/auth/check".parse().unwrap();
+        assert_eq!("accounts.example", callback.host);
+        assert_eq!(8080, callback.port);
+        assert_eq!("/auth/check", callback.path);
+        
+        let callback: HttpCallback = "http://[::1]".parse().unwrap();
+        assert_eq!(("[::1]", 80, "/"), (callback.host.as_str(), callback.port, callback.path.as_str()));
+        
+        assert_eq!(Err(()), "https://accounts.example/".parse::<HttpCallback>());
+        assert_eq!(Err(()), "http://:80/".parse::<HttpCallback>());
+        assert_eq!(Err(()), "http://accounts.example:x/".parse::<HttpCallback>());
+    }
+    
+    #[test]
+    fn static_token() {
+        let auth = StaticToken("sesame".into());
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(Ok(None), task::block_on(auth.authenticate(addr, "sesame")));
+        assert!(task::block_on(auth.authenticate(addr, "open")).is_err());
+    }
+    
+    /// Answers one request with the given response, and returns the request.
+    async fn answer_once(listener: TcpListener, response: &'static str) -> String {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 1024];
+        let n = conn.read(&mut request).await.unwrap();
+        conn.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request[..n]).into_owned()
+    }
+    
+    #[test]
+    fn http_callback() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = task::spawn(answer_once(listener, "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nalice\n"));
+            
+            let auth: HttpCallback = format!("http://127.0.0.1:{port}/check").parse().unwrap();
+            let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+            assert_eq!(Ok(Some("alice".to_string())), auth.authenticate(addr, "s3cret").await);
+            
+            let request = server.await;
+            assert!(request.starts_with("POST /check HTTP/1.0\r\n"));
+            assert!(request.contains("X-Forwarded-For: 10.0.0.1\r\n"));
+            assert!(request.ends_with("\r\n\r\ns3cret"));
+            
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            task::spawn(answer_once(listener, "HTTP/1.1 403 Forbidden\r\n\r\n"));
+            let auth: HttpCallback = format!("http://127.0.0.1:{port}/check").parse().unwrap();
+            assert_eq!(Err("account service answered 403".to_string()), auth.authenticate(addr, "s3cret").await);
+            
+            // a service which answers in chunks anyway isn't misread
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            task::spawn(answer_once(listener, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nalice\n\r\n0\r\n\r\n"));
+            let auth: HttpCallback = format!("http://127.0.0.1:{port}/check").parse().unwrap();
+            assert!(auth.authenticate(addr, "s3cret").await.unwrap_err().contains("invalid HTTP response"));
+        });
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+use async_std::{io, task};
+
+use crate::client::{Client, ClientError, JoinOutcome, Result};
+use crate::models::{JoinPolicy, RoomID};
+use crate::program_args::BenchArgs;
+use crate::request::Request;
+use crate::response::Message;
+
+/// How long an owner waits for the next message before giving up on the
+/// rest, which are then counted as lost.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What happened to the messages sent during a benchmark.
+pub(crate) struct Report {
+    clients: usize,
+    rooms: usize,
+    sent: usize,
+    elapsed: Duration,
+    /// How long each delivered message took to arrive, fastest first.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// The latency which this percentage of delivered messages beat.
+    fn percentile(&self, percent: usize) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[last * percent / 100])
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let delivered = self.latencies.len();
+        let throughput = delivered as f64 / self.elapsed.as_secs_f64();
+        writeln!(f, "{} clients in {} games", self.clients, self.rooms)?;
+        writeln!(f, "Delivered {delivered} of {} messages in {:.1?} ({throughput:.1} per second)", self.sent, self.elapsed)?;
+        let (Some(p50), Some(p90), Some(p99), Some(max)) = (self.percentile(50), self.percentile(90), self.percentile(99), self.latencies.last()) else {
+            return Ok(());
+        };
+        write!(f, "Latency: p50 {p50:.1?}, p90 {p90:.1?}, p99 {p99:.1?}, max {max:.1?}")
+    }
+}
+
+/// Runs the benchmark: each game's owner creates an open game, the other
+/// clients join the games in turn, and then every player sends messages to
+/// their owner, each saying when it was sent.
+pub(crate) async fn run(args: &BenchArgs) -> Result<Report> {
+    let credential = args.auth_token.as_deref();
+    let mut owners = Vec::new();
+    for _ in 0..args.rooms {
+        let mut owner = Client::connect(args.target.as_str(), credential).await?;
+        let room_id = owner.create_room("bench").await?;
+        owner.request(&Request::SetJoinPolicy(room_id, JoinPolicy::Open)).await?;
+        // the policy must be set before anyone joins, so wait until it has been
+        owner.request(&Request::GetRoomInfo(room_id)).await?;
+        loop {
+            match owner.next_event().await? {
+                Some(Message::RoomInfo(..)) => break,
+                Some(Message::Error(e) | Message::Failed(_, e)) => return Err(ClientError::Server(e)),
+                Some(_) => {},
+                None => return Err(ClientError::Closed),
+            }
+        }
+        owners.push((owner, room_id));
+    }
+    
+    let mut players = Vec::new();
+    for i in 0..args.clients - args.rooms {
+        let room_id = owners[i % args.rooms].1;
+        let mut player = Client::connect(args.target.as_str(), credential).await?;
+        if player.join(room_id, "bench", None).await? != JoinOutcome::Joined {
+            return Err(io::Error::other("game is not open").into());
+        }
+        players.push((player, room_id));
+    }
+    
+    let start = Instant::now();
+    let interval = Duration::from_secs_f64(1.0 / args.rate);
+    let messages = args.messages;
+    let senders: Vec<_> = players.into_iter().map(|(mut player, room_id)| task::spawn(async move {
+        for _ in 0..messages {
+            let sent_at = start.elapsed().as_micros();
+            player.send(room_id, &sent_at.to_string()).await?;
+            task::sleep(interval).await;
+        }
+        player.quit().await
+    })).collect();
+    
+    let players_per_room = |i: usize| (args.clients - args.rooms + args.rooms - 1 - i) / args.rooms;
+    let receivers: Vec<_> = owners.into_iter().enumerate().map(|(i, (owner, room_id))| {
+        task::spawn(receive(owner, room_id, start, players_per_room(i) * messages))
+    }).collect();
+    
+    for sender in senders {
+        sender.await?;
+    }
+    let mut latencies = Vec::new();
+    for receiver in receivers {
+        latencies.extend(receiver.await?);
+    }
+    latencies.sort();
+    
+    Ok(Report {
+        clients: args.clients,
+        rooms: args.rooms,
+        sent: (args.clients - args.rooms) * messages,
+        elapsed: start.elapsed(),
+        latencies,
+    })
+}
+
+/// Collects the latencies of messages sent to an owner, until all of them
+/// have arrived or the rest seem to be lost.
+async fn receive(mut owner: Client, room_id: RoomID, start: Instant, expected: usize) -> Result<Vec<Duration>> {
+    let mut latencies = Vec::with_capacity(expected);
+    while latencies.len() < expected {
+        let Ok(event) = async_std::future::timeout(DELIVERY_TIMEOUT, owner.next_event()).await else {
+            break;
+        };
+        match event? {
+            Some(Message::ReceivedFrom(id, _, payload)) if id == room_id => {
+                let sent_at = Duration::from_micros(payload.parse().unwrap_or_default());
+                latencies.push(start.elapsed().saturating_sub(sent_at));
+            },
+            Some(_) => {},
+            None => break,
+        }
+    }
+    owner.quit().await?;
+    Ok(latencies)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn percentiles() {
+        let report = Report {
+            clients: 2,
+            rooms: 1,
+            sent: 101,
+            elapsed: Duration::from_secs(1),
+            latencies: (0..=100).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(Some(Duration::from_millis(50)), report.percentile(50));
+        assert_eq!(Some(Duration::from_millis(99)), report.percentile(99));
+        assert!(report.to_string().contains("Delivered 101 of 101 messages"));
+        
+        let report = Report {latencies: Vec::new(), ..report};
+        assert_eq!(None, report.percentile(50));
+    }
+}
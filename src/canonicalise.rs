@@ -25,6 +25,16 @@ impl Message {
             Message::ListRooms(rooms) => {
                 rooms.sort_by_key(|r| r.0);
             },
+            Message::RoomPings(_, pings) => {
+                pings.sort_by_key(|p| p.0);
+            },
+            Message::ListMembers(_, _, members) => {
+                members.sort();
+            },
+            Message::ListJoinRequests(_, user_ids) |
+            Message::ListSpectators(_, user_ids) => {
+                user_ids.sort();
+            },
             _ => {},
         }
     }
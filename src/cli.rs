@@ -0,0 +1,339 @@
+//! The server's command line, which the `incognita-socket-server` binary
+//! runs.
+
+#[cfg(feature = "sqlite")]
+use crate::sqlite_store;
+use crate::{access, auth, bench, clock, config, dispatch, err, ids, limits, logging, program_args, recording, repl, schedule, server, version};
+
+/// Runs the server, or one of its subcommands, with the program arguments.
+pub fn main() -> err::Result {
+    match std::env::args().nth(1).as_deref() {
+        Some("bench") => bench_main(),
+        Some("client") => client_main(),
+        Some("replay") => replay_main(),
+        _ => {},
+    }
+    let args = program_args::parse();
+    let log_file = args.log_file.as_ref().map(|path| {
+        let max_bytes = args.log_max_size.saturating_mul(1024 * 1024);
+        logging::RotatingFile::open(path.into(), max_bytes, args.log_rotation, args.log_keep)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to open log file {path}: {e}");
+                std::process::exit(1);
+            })
+    });
+    logging::init(args.log_level, args.log_format, log_file);
+    if args.print_version {
+        println!("Incognita Socket server version {}", version::SERVER_VERSION);
+        std::process::exit(0);
+    }
+    
+    if let Some(path) = &args.export_config {
+        if let Err(e) = std::fs::write(path, config::format(&args.settings())) {
+            eprintln!("Failed to write config file {path}: {e}");
+            std::process::exit(1);
+        }
+        println!("Wrote config file {path}");
+        std::process::exit(0);
+    }
+    
+    if !args.instances.is_empty() && args.relay.is_some() {
+        eprintln!("Virtual server instances cannot be used with a relay");
+        std::process::exit(1);
+    }
+    if !args.bind.is_empty() && (args.relay.is_some() || !args.instances.is_empty()) {
+        eprintln!("Bind addresses can only be given for a single server");
+        std::process::exit(1);
+    }
+    if args.mirror_port.is_some() && (args.relay.is_some() || !args.instances.is_empty()) {
+        eprintln!("A mirror listener can only be used with a single server");
+        std::process::exit(1);
+    }
+    if args.udp_port.is_some() && (args.relay.is_some() || !args.instances.is_empty()) {
+        eprintln!("The UDP relay can only be used with a single server");
+        std::process::exit(1);
+    }
+    if args.admin_port.is_some() && (args.relay.is_some() || !args.instances.is_empty()) {
+        eprintln!("The admin API can only be used with a single server");
+        std::process::exit(1);
+    }
+    if args.state_file.is_some() && !args.instances.is_empty() {
+        eprintln!("A state file can only be used with a single server");
+        std::process::exit(1);
+    }
+    if args.record.is_some() && !args.instances.is_empty() {
+        eprintln!("A recording can only be made of a single server");
+        std::process::exit(1);
+    }
+    if args.room_store.is_some() && !args.instances.is_empty() {
+        eprintln!("A room store can only be used with a single server");
+        std::process::exit(1);
+    }
+    if let Err(e) = server::check_match_size(args.match_size, args.max_room_members) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    if args.max_queued_messages == 0 {
+        eprintln!("The maximum number of queued messages must be at least one");
+        std::process::exit(1);
+    }
+    if args.snapshot_interval == 0 {
+        eprintln!("The snapshot interval must be at least one second");
+        std::process::exit(1);
+    }
+    let auth_options = [args.auth_token.is_some(), args.auth_token_file.is_some(), args.auth_url.is_some()];
+    if auth_options.into_iter().filter(|&given| given).count() > 1 {
+        eprintln!("Only one of --auth-token, --auth-token-file and --auth-url may be given");
+        std::process::exit(1);
+    }
+    if args.auth_token.as_deref() == Some("") {
+        eprintln!("The auth token must not be empty");
+        std::process::exit(1);
+    }
+    if args.admin_port.is_some() && args.admin_password.is_none() {
+        eprintln!("The admin API requires an admin password");
+        std::process::exit(1);
+    }
+    
+    let restart_schedule = args.restart_at.as_ref().map(|time| {
+        let drain_timeout = std::time::Duration::from_secs(args.drain_timeout);
+        schedule::RestartSchedule::parse(time, drain_timeout).unwrap_or_else(|| {
+            eprintln!("Invalid restart time: {time}");
+            std::process::exit(1);
+        })
+    });
+    
+    let stopped = async_std::task::block_on(async {
+        if let Some(relay_addr) = &args.relay {
+            let server = server_builder(&args, restart_schedule, None).build();
+            dispatch::start_relay(server, relay_addr).await
+        } else if args.instances.is_empty() {
+            let server = server_builder(&args, restart_schedule, None).build();
+            dispatch::start_server(server, &args.listen_addrs(), args.mirror_port, args.admin_addr()).await
+        } else {
+            let instances = args.instances.iter().map(|instance| {
+                let server = server_builder(&args, restart_schedule, instance.max_connections)
+                    .name(&instance.name)
+                    .build();
+                async move {
+                    dispatch::start_server(server, &[dispatch::ListenAddr::any(instance.port)], None, None).await
+                }
+            });
+            // every instance drains before the process exits
+            let stopped = futures::future::try_join_all(instances).await?;
+            Ok(if stopped.contains(&dispatch::Stopped::Shutdown) {
+                dispatch::Stopped::Shutdown
+            } else {
+                dispatch::Stopped::Restart
+            })
+        }
+    })?;
+    
+    // the supervisor mustn't mistake a shutdown for a scheduled restart
+    if stopped == dispatch::Stopped::Restart && restart_schedule.is_some() {
+        std::process::exit(schedule::RESTART_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// Runs the `bench` subcommand, and then exits.
+fn bench_main() -> ! {
+    let args = program_args::parse_subcommand::<program_args::BenchArgs>();
+    if args.rooms == 0 || args.clients <= args.rooms {
+        eprintln!("There must be at least one game, and more clients than games");
+        std::process::exit(1);
+    }
+    if args.rate <= 0.0 {
+        eprintln!("The message rate must be more than zero");
+        std::process::exit(1);
+    }
+    match async_std::task::block_on(bench::run(&args)) {
+        Ok(report) => {
+            println!("{report}");
+            std::process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("Benchmark failed: {e}");
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Runs the `client` subcommand, and then exits.
+fn client_main() -> ! {
+    let args = program_args::parse_subcommand::<program_args::ClientArgs>();
+    if let Err(e) = async_std::task::block_on(repl::run(&args)) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// Runs the `replay` subcommand, and then exits with status 1 if the server
+/// behaved differently from the recording.
+fn replay_main() -> ! {
+    let args = program_args::parse_subcommand::<program_args::ReplayArgs>();
+    let text = std::fs::read_to_string(&args.recording).unwrap_or_else(|e| {
+        eprintln!("Failed to read recording {}: {e}", args.recording);
+        std::process::exit(1);
+    });
+    let builder = match &args.config {
+        Some(path) => program_args::from_config_file(path).and_then(|server_args| {
+            Ok(configure(&server_args, None, version_policy(&server_args)?, motd(&server_args)?))
+        }),
+        None => Ok(server::ServerBuilder::new()),
+    };
+    match builder.and_then(|builder| recording::replay(&text, builder)) {
+        Ok(replay) => {
+            println!("{replay}");
+            std::process::exit(if replay.divergences.is_empty() { 0 } else { 1 });
+        },
+        Err(e) => {
+            eprintln!("Failed to replay {}: {e}", args.recording);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Configures a server from the program arguments. An instance's own
+/// connection limit, if it has one, overrides `--max-connections`.
+fn server_builder(args: &program_args::ProgramArgs, restart_schedule: Option<schedule::RestartSchedule>, max_connections: Option<usize>) -> server::ServerBuilder {
+    let policy = version_policy(args).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let lines = motd(args).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let builder = configure(args, max_connections, policy, lines)
+        .authenticator(authenticator(args))
+        .codec(args.protocol.codec())
+        .udp_port(args.udp_port)
+        .restart_schedule(restart_schedule)
+        .state_file(
+            args.state_file.as_ref().map(Into::into),
+            std::time::Duration::from_secs(args.snapshot_interval),
+        )
+        .recording(args.record.as_ref().map(Into::into))
+        .handle_signals()
+        .reloader(std::sync::Arc::new(move || {
+            let args = program_args::reparse()?;
+            Ok(configure(&args, max_connections, version_policy(&args)?, motd(&args)?))
+        }));
+    match &args.room_store {
+        Some(path) => with_room_store(builder, path),
+        None => builder,
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn with_room_store(builder: server::ServerBuilder, path: &str) -> server::ServerBuilder {
+    let store = sqlite_store::SqliteStore::open(path.as_ref()).unwrap_or_else(|e| {
+        eprintln!("Failed to open room store {path}: {e}");
+        std::process::exit(1);
+    });
+    builder.room_store(store)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn with_room_store(_builder: server::ServerBuilder, _path: &str) -> server::ServerBuilder {
+    eprintln!("This server was built without SQLite support; rebuild it with `--features sqlite` to use --room-store");
+    std::process::exit(1);
+}
+
+/// The settings which are also re-read when the config is reloaded.
+fn configure(args: &program_args::ProgramArgs, max_connections: Option<usize>, version_policy: version::VersionPolicy, motd: Vec<std::sync::Arc<str>>) -> server::ServerBuilder {
+    let mut builder = server::ServerBuilder::new()
+        .max_connections(max_connections.unwrap_or(args.max_connections))
+        .max_room_members(args.max_room_members)
+        .max_join_requests(Some(args.max_join_requests))
+        .max_room_data_length(args.max_room_data_length)
+        .room_data_pattern(args.room_data_pattern.clone())
+        .version_policy(version_policy)
+        .match_size(args.match_size)
+        .admin_password(args.admin_password.clone())
+        .disconnect_grace(std::time::Duration::from_secs(args.disconnect_grace))
+        .room_idle_timeout(std::time::Duration::from_secs(args.room_idle_timeout))
+        .rejoin_cooldown(std::time::Duration::from_secs(args.rejoin_cooldown))
+        .vote_kick_threshold(args.vote_kick_threshold)
+        .waiting_room(args.waiting_room, std::time::Duration::from_secs(args.waiting_timeout))
+        .max_request_length(args.max_request_length)
+        .max_payload_length(args.max_payload_length)
+        .max_queued_messages(args.max_queued_messages)
+        .compress_threshold(args.compress_threshold)
+        .write_timeout(std::time::Duration::from_secs(args.write_timeout))
+        .undelivered_policy(args.undelivered_policy)
+        .motd(motd)
+        .access_control(access::AccessControl::new(
+            args.allow_list.as_ref().map(Into::into),
+            args.deny_list.as_ref().map(Into::into),
+        ))
+        .rate_limit((args.rate_limit > 0.0).then_some(limits::RateLimit {
+            per_second: args.rate_limit,
+            burst: if args.rate_burst > 0.0 { args.rate_burst } else { 2.0 * args.rate_limit },
+        }));
+    if args.simulated_clock {
+        let clock = clock::SimulatedClock::new(std::time::SystemTime::now());
+        builder = builder.clock(std::sync::Arc::new(clock));
+    }
+    if args.random_ids {
+        builder = builder
+            .user_ids(ids::Random::new())
+            .room_ids(ids::Random::new());
+    }
+    builder
+}
+
+/// Chooses how clients authenticate: with an account service, a shared
+/// token, or not at all.
+fn authenticator(args: &program_args::ProgramArgs) -> std::sync::Arc<dyn auth::Authenticator> {
+    if let Some(callback) = &args.auth_url {
+        return std::sync::Arc::new(callback.clone());
+    }
+    match auth_token(args) {
+        Some(token) => std::sync::Arc::new(auth::StaticToken(token)),
+        None => std::sync::Arc::new(auth::NoAuth),
+    }
+}
+
+/// The token which clients must authenticate with, given directly or read
+/// from a file.
+fn auth_token(args: &program_args::ProgramArgs) -> Option<String> {
+    let Some(path) = &args.auth_token_file else {
+        return args.auth_token.clone();
+    };
+    let token = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read auth token file {path}: {e}");
+        std::process::exit(1);
+    });
+    let token = token.trim();
+    if token.is_empty() {
+        eprintln!("Auth token file {path} is empty");
+        std::process::exit(1);
+    }
+    Some(token.to_string())
+}
+
+/// The message of the day, given directly or read from a file, which is
+/// read again when the config is reloaded. Each line is sent as its own
+/// `MOTD` message.
+fn motd(args: &program_args::ProgramArgs) -> Result<Vec<std::sync::Arc<str>>, String> {
+    let text = match &args.motd_file {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read MOTD file {path}: {e}"))?,
+        None => args.motd.clone().unwrap_or_default(),
+    };
+    Ok(text.trim_end().lines().map(Into::into).collect())
+}
+
+fn version_policy(args: &program_args::ProgramArgs) -> Result<version::VersionPolicy, String> {
+    let parse_version = |v: &String| v.parse()
+        .map_err(|_| format!("Invalid client version: {v}"));
+    
+    Ok(version::VersionPolicy {
+        min_version: args.min_client_version.as_ref().map(parse_version).transpose()?,
+        blocked: args.blocked_client_versions.iter().map(parse_version).collect::<Result<_, _>>()?,
+        upgrade_hint: args.upgrade_url.as_deref().map(Into::into),
+    })
+}
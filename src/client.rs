@@ -0,0 +1,882 @@
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use async_std::io;
+use base64::Engine as _;
+use async_std::net::{TcpStream, ToSocketAddrs};
+use async_std::prelude::*;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+
+use crate::compression::Compression;
+use crate::dispatch::{self, Line};
+use crate::friends::FriendStatus;
+use crate::limits::{RateLimit, Warning};
+use crate::models::{JoinPolicy, RoomID, Signal, UserID};
+use crate::request::{self, Fields, Parts, Request};
+use crate::response::{Error, Message, Named, ServerInfo};
+use crate::stats::TrafficCounts;
+use crate::timeline::{RoomEvent, TimelineEntry};
+use crate::transport::{Conn, Transport, Writer};
+
+/// Messages carry whole game states, so they may be much longer than the
+/// requests which the server accepts.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// The server refused a request.
+    Server(Error),
+    /// The server closed the connection.
+    Closed,
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{e}"),
+            ClientError::Server(e) => write!(f, "{e}"),
+            ClientError::Closed => f.write_str("Connection closed by server"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// How the room's owner answered a request to join.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinOutcome {
+    Joined,
+    /// The owner gave this reason for rejecting the request.
+    Rejected(String),
+}
+
+type Lines = Pin<Box<dyn Stream<Item = io::Result<Line>> + Send>>;
+
+/// A connection to the server, for games written in Rust. Requests which
+/// have a reply wait for it; anything else the server sends meanwhile is
+/// kept, in order, for `next_event`. The server doesn't say which request an
+/// error is about, so an error which arrives while waiting is the answer.
+pub struct Client {
+    user_id: UserID,
+    resume_token: String,
+    lines: Lines,
+    writer: Writer,
+    pending: VecDeque<Message>,
+    /// Messages in rooms this user owns say who sent them, so the client
+    /// must know which rooms it owns in order to read them.
+    owned_rooms: HashSet<RoomID>,
+}
+
+impl Client {
+    /// Connects to a server over TCP. If the server requires authentication,
+    /// a credential must be given.
+    pub async fn connect(addr: impl ToSocketAddrs, credential: Option<&str>) -> Result<Client> {
+        let conn = TcpStream::connect(addr).await?;
+        Client::over(conn, credential).await
+    }
+    
+    /// Starts a session over any transport, and waits to be welcomed; if the
+    /// server is full, this waits until there is room.
+    pub async fn over(transport: impl Transport, credential: Option<&str>) -> Result<Client> {
+        let Conn {reader, writer} = Conn::new(transport);
+        let mut client = Client {
+            user_id: 0,
+            resume_token: String::new(),
+            lines: Box::pin(dispatch::bounded_lines(io::BufReader::new(reader), MAX_MESSAGE_LENGTH)),
+            writer,
+            pending: VecDeque::new(),
+            owned_rooms: HashSet::new(),
+        };
+        if let Some(credential) = credential {
+            client.write_line(&format!("AUTH|{credential}")).await?;
+        }
+        (client.user_id, client.resume_token) = client.reply(|msg| match msg {
+            Message::Welcome(user_id, token, _) => Ok((user_id, token)),
+            msg => Err(msg),
+        }).await?;
+        Ok(client)
+    }
+    
+    pub fn user_id(&self) -> UserID {
+        self.user_id
+    }
+    
+    /// The token for resuming this session on a new connection.
+    pub fn resume_token(&self) -> &str {
+        &self.resume_token
+    }
+    
+    /// Sends any request without waiting for a reply; replies arrive as
+    /// events.
+    pub async fn request(&mut self, request: &Request) -> Result<()> {
+        self.write_line(&request.to_string()).await
+    }
+    
+    pub async fn create_room(&mut self, data: &str) -> Result<RoomID> {
+        self.request(&Request::CreateRoom(data.into(), Vec::new())).await?;
+        self.reply(|msg| match msg {
+            Message::RoomCreated(room_id) => Ok(room_id),
+            msg => Err(msg),
+        }).await
+    }
+    
+    /// Asks to join a room, and waits for the owner to answer, unless the
+    /// room is open to anyone.
+    pub async fn join(&mut self, room_id: RoomID, msg: &str, password: Option<&str>) -> Result<JoinOutcome> {
+        self.request(&Request::AskJoinRoom(room_id, msg.into(), password.map(Into::into))).await?;
+        self.reply(|reply| match reply {
+            Message::RoomJoined(id) if id == room_id => Ok(JoinOutcome::Joined),
+            Message::RoomRejected(id, reason) if id == room_id => Ok(JoinOutcome::Rejected(reason)),
+            reply => Err(reply),
+        }).await
+    }
+    
+    /// Sends a game message: a room's owner sends it to every member, and a
+    /// member sends it to the owner.
+    pub async fn send(&mut self, room_id: RoomID, payload: &str) -> Result<()> {
+        self.request(&Request::Send(room_id, payload.into())).await
+    }
+    
+    pub async fn leave(&mut self, room_id: RoomID) -> Result<()> {
+        self.owned_rooms.remove(&room_id);
+        self.request(&Request::LeaveRoom(room_id)).await
+    }
+    
+    /// Ends the session, leaving any rooms.
+    pub async fn quit(mut self) -> Result<()> {
+        self.request(&Request::Quit).await
+    }
+    
+    /// The next message from the server, or `None` once it has closed the
+    /// connection.
+    pub async fn next_event(&mut self) -> Result<Option<Message>> {
+        match self.pending.pop_front() {
+            Some(msg) => Ok(Some(msg)),
+            None => self.read().await,
+        }
+    }
+    
+    /// Messages from the server, as a stream.
+    pub fn events(&mut self) -> BoxStream<'_, Result<Message>> {
+        futures::stream::unfold(self, |client| async move {
+            let event = client.next_event().await.transpose()?;
+            Some((event, client))
+        }).boxed()
+    }
+    
+    /// Reads messages until `matches` accepts one, keeping the rest as
+    /// events.
+    async fn reply<T>(&mut self, mut matches: impl FnMut(Message) -> std::result::Result<T, Message>) -> Result<T> {
+        loop {
+            match self.read().await? {
+                Some(Message::Error(e) | Message::Failed(_, e)) => return Err(ClientError::Server(e)),
+                Some(msg) => match matches(msg) {
+                    Ok(reply) => return Ok(reply),
+                    Err(msg) => self.pending.push_back(msg),
+                },
+                None => return Err(ClientError::Closed),
+            }
+        }
+    }
+    
+    async fn read(&mut self) -> Result<Option<Message>> {
+        while let Some(line) = self.lines.next().await {
+            let Line::Complete(line) = line? else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long").into());
+            };
+            // a newer server may send messages this client doesn't know
+            let Some(msg) = parse_message(&line, |room_id| self.owned_rooms.contains(&room_id)) else {
+                continue;
+            };
+            match msg {
+                // the server is measuring latency, which the client needn't know about
+                Message::Ping(sequence_number) => {
+                    self.request(&Request::Pong(sequence_number)).await?;
+                    continue;
+                },
+                Message::RoomCreated(room_id) => {
+                    self.owned_rooms.insert(room_id);
+                },
+                Message::MatchFound(room_id, owner_id) |
+                Message::ChangedOwner(room_id, owner_id) => if owner_id == self.user_id {
+                    self.owned_rooms.insert(room_id);
+                } else {
+                    self.owned_rooms.remove(&room_id);
+                },
+                Message::RoomClosed(room_id) => {
+                    self.owned_rooms.remove(&room_id);
+                },
+                _ => {},
+            }
+            return Ok(Some(msg));
+        }
+        Ok(None)
+    }
+    
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(format!("{line}\n").as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Reads a message as the server writes it, or `None` if it isn't one. A
+/// message received in a room can't be told apart from one which says who
+/// sent it, except by whether this user owns the room. Messages sent to one
+/// member are read as broadcasts, since they are written the same way.
+pub fn parse_message(line: &str, owns: impl Fn(RoomID) -> bool) -> Option<Message> {
+    if let (Some(request_id), rest) = request::split_request_id(line) {
+        let msg = parse_message(rest, owns)?;
+        return Some(Message::Reply(request_id, Box::new(msg)));
+    }
+    let mut parts = Parts::of(line);
+    match parts.take_str()? {
+        "WELCOME" => {
+            let user_id = parts.take_int()?;
+            let token = parts.take_string()?;
+            let version = parts.take_string()?;
+            let protocol_version = parts.take_int()?;
+            let max_request_length = parts.take_int()?;
+            let max_room_members = parts.take_int()?;
+            let per_second = parts.take_int()?;
+            let burst = parts.take_int()?;
+            let info = ServerInfo {
+                version,
+                protocol_version,
+                max_request_length,
+                max_room_members: (max_room_members > 0).then_some(max_room_members),
+                rate_limit: (per_second > 0.0).then_some(RateLimit {per_second, burst}),
+            };
+            parts.done(|| Message::Welcome(user_id, token, Box::new(info)))
+        },
+        "WAITING" => {
+            let position = parts.take_int()?;
+            parts.done(|| Message::Waiting(position))
+        },
+        "RESUMED" => {
+            let user_id = parts.take_int()?;
+            let token = parts.take_string()?;
+            parts.done(|| Message::Resumed(user_id, token))
+        },
+        "RESUME_REPLAYED" => {
+            parts.done(|| Message::ResumeReplayed)
+        },
+        "HELLO_OK" => {
+            parts.done(|| Message::HelloOk)
+        },
+        "COMPRESSION" => {
+            let compression = match parts.take_str()? {
+                "none" => None,
+                name => Some(name.parse().ok()?),
+            };
+            parts.done(|| Message::Compression(compression))
+        },
+        "COMPRESSED" => {
+            let compression: Compression = parts.take_str()?.parse().ok()?;
+            let bytes = base64::engine::general_purpose::STANDARD.decode(parts.take_str()?).ok()?;
+            parts.done(|| Message::Compressed(compression, bytes.into()))
+        },
+        "PONG" => {
+            let sequence_number = parts.take_int()?;
+            parts.done(|| Message::Pong(sequence_number))
+        },
+        "PING" => {
+            let sequence_number = parts.take_int()?;
+            parts.done(|| Message::Ping(sequence_number))
+        },
+        "STATS" => {
+            let users = parts.take_int()?;
+            let rooms = parts.take_int()?;
+            let uptime = parts.take_int()?;
+            let traffic = match parts.take_optional_int()? {
+                Some(messages_in) => Some(TrafficCounts {
+                    messages_in,
+                    bytes_in: parts.take_int()?,
+                    messages_out: parts.take_int()?,
+                    bytes_out: parts.take_int()?,
+                }),
+                None => None,
+            };
+            parts.done(|| Message::Stats(users, rooms, uptime, traffic))
+        },
+        "GAME_OPENED" => {
+            let room_id = parts.take_int()?;
+            let players = parts.take_int()?;
+            parts.done(|| Message::MirrorRoomOpened(room_id, players))
+        },
+        "GAME_CLOSED" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::MirrorRoomClosed(room_id))
+        },
+        "GAME_PLAYERS" => {
+            let room_id = parts.take_int()?;
+            let players = parts.take_int()?;
+            parts.done(|| Message::MirrorPlayers(room_id, players))
+        },
+        "GAMES_SUBSCRIBED" => {
+            parts.done(|| Message::RoomsSubscribed)
+        },
+        "GAMES_UNSUBSCRIBED" => {
+            parts.done(|| Message::RoomsUnsubscribed)
+        },
+        keyword @ ("ROOM_ADDED" | "ROOM_UPDATED") => {
+            let room_id = parts.take_int()?;
+            let players = parts.take_int()?;
+            let data = parts.take_rest().into();
+            Some(if keyword == "ROOM_ADDED" {
+                Message::RoomAdded(room_id, players, data, None)
+            } else {
+                Message::RoomUpdated(room_id, players, data, None)
+            })
+        },
+        "ROOM_REMOVED" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::RoomRemoved(room_id))
+        },
+        "SERVER_RESTARTING" => {
+            let deadline_secs = parts.take_int()?;
+            parts.done(|| Message::ServerRestarting(deadline_secs))
+        },
+        "NO_OPEN_GAMES" => {
+            parts.done(|| Message::ListRooms(Vec::new()))
+        },
+        "OPEN_GAMES" => {
+            let mut rooms = Vec::new();
+            while let Some(room_id) = parts.take_string() {
+                let data = parts.take_str()?;
+                rooms.push((room_id.parse().ok()?, data.into(), None));
+            }
+            Some(Message::ListRooms(rooms))
+        },
+        "MEMBERS" => {
+            let room_id = parts.take_int()?;
+            let owner = parse_named(parts.take_str()?)?;
+            let mut members = Vec::new();
+            while let Some(member) = parts.take_str() {
+                members.push(parse_named(member)?);
+            }
+            Some(Message::ListMembers(room_id, owner, members))
+        },
+        "ROOM_PINGS" => {
+            let room_id = parts.take_int()?;
+            let mut pings = Vec::new();
+            while let Some(ping) = parts.take_str() {
+                let (user_id, latency) = ping.split_once(',')?;
+                let latency = match latency {
+                    "?" => None,
+                    ms => Some(ms.parse().ok()?),
+                };
+                pings.push((user_id.parse().ok()?, latency));
+            }
+            Some(Message::RoomPings(room_id, pings))
+        },
+        "JOIN_REQUESTS" => {
+            let room_id = parts.take_int()?;
+            Some(Message::ListJoinRequests(room_id, take_user_ids(parts)?))
+        },
+        "SPECTATORS" => {
+            let room_id = parts.take_int()?;
+            Some(Message::ListSpectators(room_id, take_user_ids(parts)?))
+        },
+        "GAME_INFO" => {
+            let room_id = parts.take_int()?;
+            let owner_id = parts.take_int()?;
+            let member_count = parts.take_int()?;
+            // a capacity of 0 means there is no limit
+            let capacity = parts.take_int()?;
+            let join_policy = match parts.take_str()? {
+                "ASK" => JoinPolicy::AskOwner,
+                "OPEN" => JoinPolicy::Open,
+                _ => return None,
+            };
+            let data = parts.take_rest();
+            Some(Message::RoomInfo(room_id, owner_id, member_count, (capacity > 0).then_some(capacity), join_policy, data.into(), None))
+        },
+        "CREATED_GAME" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::RoomCreated(room_id))
+        },
+        "QUEUED" => {
+            let waiting = parts.take_int()?;
+            parts.done(|| Message::Queued(waiting))
+        },
+        "MATCH_FOUND" => {
+            let room_id = parts.take_int()?;
+            let owner_id = parts.take_int()?;
+            parts.done(|| Message::MatchFound(room_id, owner_id))
+        },
+        "CHANGED_OWNER" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::ChangedOwner(room_id, user_id))
+        },
+        "JOINED" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::RoomJoined(room_id))
+        },
+        "SPECTATING" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::RoomSpectating(room_id))
+        },
+        "SPECTATOR_JOINED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::SpectatorJoined(room_id, user_id))
+        },
+        "GAME_OVER" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::RoomClosed(room_id))
+        },
+        "REJECTED" => {
+            let room_id = parts.take_int()?;
+            let reason = parts.take_rest();
+            Some(Message::RoomRejected(room_id, reason.into()))
+        },
+        "PLAYER_JOINED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let msg = parts.take_string()?;
+            let name = parts.take_string();
+            parts.done(|| Message::JoinRequested(room_id, Named(user_id, name), msg))
+        },
+        "JOIN_REQUESTED" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::JoinRequestSent(room_id))
+        },
+        "MEMBER_JOINED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let msg = parts.take_rest();
+            Some(Message::MemberJoined(room_id, user_id, msg.into()))
+        },
+        "PLAYER_DISCONNECTED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::PlayerDisconnected(room_id, user_id))
+        },
+        "PLAYER_RECONNECTED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::PlayerReconnected(room_id, user_id))
+        },
+        "PLAYER_TIMED_OUT" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::PlayerTimedOut(room_id, user_id))
+        },
+        "KICK_VOTE" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let votes = parts.take_int()?;
+            let needed = parts.take_int()?;
+            parts.done(|| Message::KickVote(room_id, user_id, votes, needed))
+        },
+        "VOTED_OUT" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::VotedOut(room_id, user_id))
+        },
+        "PLAYER_LEFT" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let name = parts.take_string();
+            parts.done(|| Message::PlayerLeft(room_id, Named(user_id, name)))
+        },
+        "INVITED" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let name = parts.take_string();
+            parts.done(|| Message::Invited(room_id, Named(user_id, name)))
+        },
+        "FRIEND_ADDED" => {
+            let account = parts.take_rest();
+            Some(Message::FriendAdded(account.into()))
+        },
+        "FRIEND_REMOVED" => {
+            let account = parts.take_rest();
+            Some(Message::FriendRemoved(account.into()))
+        },
+        "FRIEND_OFFLINE" => {
+            let account = parts.take_rest();
+            Some(Message::FriendOffline(account.into()))
+        },
+        "FRIENDS" => {
+            let mut friends = Vec::new();
+            while let Some(friend) = parts.take_str() {
+                let mut fields = friend.split(',');
+                let account = fields.next()?.to_string();
+                let status = match (fields.next()?, fields.next()) {
+                    ("pending", None) => FriendStatus::Pending,
+                    ("offline", None) => FriendStatus::Offline,
+                    ("online", Some(user_id)) => FriendStatus::Online(user_id.parse().ok()?),
+                    _ => return None,
+                };
+                friends.push((account, status));
+            }
+            Some(Message::Friends(friends))
+        },
+        "FRIEND_ONLINE" => {
+            let user_id = parts.take_int()?;
+            let account = parts.take_rest();
+            Some(Message::FriendOnline(user_id, account.into()))
+        },
+        "FRIEND_JOINED_GAME" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let account = parts.take_rest();
+            Some(Message::FriendJoinedRoom(room_id, user_id, account.into()))
+        },
+        "RECEIVED" => {
+            let room_id = parts.take_int()?;
+            if owns(room_id) {
+                let user_id = parts.take_int()?;
+                Some(Message::ReceivedFrom(room_id, user_id, parts.take_rest().into()))
+            } else {
+                Some(Message::ReceivedBroadcast(room_id, parts.take_rest().into()))
+            }
+        },
+        "RECEIVED_BINARY" => {
+            let room_id = parts.take_int()?;
+            if owns(room_id) {
+                let user_id = parts.take_int()?;
+                let payload = parts.take_shared()?;
+                parts.done(|| Message::ReceivedBinaryFrom(room_id, user_id, payload))
+            } else {
+                let payload = parts.take_shared()?;
+                parts.done(|| Message::ReceivedBinaryBroadcast(room_id, payload))
+            }
+        },
+        "CHAT" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let text = parts.take_rest();
+            Some(Message::Chat(room_id, user_id, text.into()))
+        },
+        "UNDELIVERED" => {
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::Undelivered(user_id))
+        },
+        "WHISPER" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let text = parts.take_rest();
+            Some(Message::Whisper(room_id, user_id, text.into()))
+        },
+        keyword @ ("OFFER" | "ANSWER" | "ICE_CANDIDATE") => {
+            let signal = Signal::from_keyword(keyword)?;
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let data = parts.take_rest();
+            Some(Message::Signal(signal, room_id, user_id, data.into()))
+        },
+        "TURN" => {
+            let room_id = parts.take_int()?;
+            let turn = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Message::Turn(room_id, turn, user_id))
+        },
+        "NO_TURN_ORDER" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Message::NoTurnOrder(room_id))
+        },
+        "RECEIVED_ON_TURN" => {
+            let room_id = parts.take_int()?;
+            let turn = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let payload = parts.take_rest();
+            Some(Message::ReceivedOnTurn(room_id, turn, user_id, payload.into()))
+        },
+        "ACKS_ENABLED" => {
+            parts.done(|| Message::AcksEnabled)
+        },
+        "SEQUENCED" => {
+            let n = parts.take_int()?;
+            let msg = parse_message(parts.take_rest(), owns)?;
+            Some(Message::Sequenced(n, Box::new(msg)))
+        },
+        "REPLAYED" => {
+            let room_id = parts.take_int()?;
+            Some(Message::Replayed(room_id, parts.take_rest().into()))
+        },
+        "ADMIN_OK" => {
+            parts.done(|| Message::AdminOk)
+        },
+        "CONFIG_RELOADED" => {
+            parts.done(|| Message::ConfigReloaded)
+        },
+        "CLOCK" => {
+            let secs = parts.take_int()?;
+            parts.done(|| Message::Clock(secs))
+        },
+        "MOTD" => {
+            Some(Message::Motd(parts.take_rest().into()))
+        },
+        "ANNOUNCEMENT" => {
+            Some(Message::Announcement(parts.take_rest().into()))
+        },
+        "ANNOUNCED" => {
+            let n = parts.take_int()?;
+            parts.done(|| Message::Announced(n))
+        },
+        "UDP_TOKEN" => {
+            let port = parts.take_int()?;
+            let token = parts.take_string()?;
+            parts.done(|| Message::UdpToken(port, token))
+        },
+        "TIMELINE" => {
+            let room_id = parts.take_int()?;
+            let mut entries = Vec::new();
+            while let Some(entry) = parts.take_str() {
+                entries.push(parse_timeline_entry(entry)?);
+            }
+            Some(Message::Timeline(room_id, entries))
+        },
+        "WARNING" => {
+            let warning: fn(usize, usize) -> Warning = match parts.take_str()? {
+                "GAME_NEARLY_FULL" => {
+                    let room_id = parts.take_int()?;
+                    let members = parts.take_int()?;
+                    let capacity = parts.take_int()?;
+                    return parts.done(|| Message::Warning(Warning::RoomNearlyFull(room_id, members, capacity)));
+                },
+                "NEARLY_RATE_LIMITED" => Warning::NearlyRateLimited,
+                "PAYLOAD_NEARLY_TOO_LARGE" => Warning::PayloadNearlyTooLarge,
+                "QUEUE_NEARLY_FULL" => Warning::QueueNearlyFull,
+                _ => return None,
+            };
+            let count = parts.take_int()?;
+            let limit = parts.take_int()?;
+            parts.done(|| Message::Warning(warning(count, limit)))
+        },
+        "ERROR" => {
+            // errors in reply to a request say which kind of request it was
+            let field = parts.take_str()?;
+            let (request, code) = match field.parse() {
+                Ok(code) => (None, code),
+                Err(_) => (Some(field), parts.take_int()?),
+            };
+            let e = parse_error(code, parts.take_rest())?;
+            match request {
+                Some(request) => {
+                    let &keyword = request::KEYWORDS.iter().find(|&&k| k == request)?;
+                    Some(Message::Failed(keyword, e))
+                },
+                None => Some(Message::Error(e)),
+            }
+        },
+        _ => None,
+    }
+}
+
+/// A user ID, followed by a comma and their name if they have one.
+fn parse_named(s: &str) -> Option<Named> {
+    match s.split_once(',') {
+        Some((user_id, name)) => Some(Named(user_id.parse().ok()?, Some(name.to_string()))),
+        None => Some(Named(s.parse().ok()?, None)),
+    }
+}
+
+fn take_user_ids(mut parts: Parts) -> Option<Vec<UserID>> {
+    let mut user_ids = Vec::new();
+    while let Some(user_id) = parts.take_str() {
+        user_ids.push(user_id.parse().ok()?);
+    }
+    Some(user_ids)
+}
+
+fn parse_timeline_entry(s: &str) -> Option<TimelineEntry> {
+    let mut fields = s.split(',');
+    let time = fields.next()?.parse().ok()?;
+    let name = fields.next()?;
+    let user_id = match fields.next() {
+        Some(user_id) => Some(user_id.parse().ok()?),
+        None => None,
+    };
+    let event = RoomEvent::parse(name, user_id)?;
+    fields.next().is_none().then_some(TimelineEntry {time, event})
+}
+
+/// Errors are written only as text, so they are recognised by it.
+/// Reads an error from its code; the description is only needed for errors
+/// which say more than what kind they are.
+fn parse_error(code: u32, text: &str) -> Option<Error> {
+    let error = [
+        Error::ServerFull,
+        Error::InvalidRequest,
+        Error::AlreadyInARoom,
+        Error::AlreadyRequestedJoin,
+        Error::NotRoomOwner,
+        Error::IsRoomOwner,
+        Error::NotInThatRoom,
+        Error::NoSuchUser,
+        Error::NoSuchRoom,
+        Error::NoSuchJoinRequest,
+        Error::RoomFull,
+        Error::UpgradeRequired(None),
+        Error::NoOpenRooms,
+        Error::ServerDraining,
+        Error::AlreadyQueued,
+        Error::NotQueued,
+        Error::InvalidSchema,
+        Error::InvalidPayload,
+        Error::IsSpectator,
+        Error::InvalidToken,
+        Error::ClockNotSimulated,
+        Error::RateLimited,
+        Error::RequestTooLong,
+        Error::NotAdmin,
+        Error::Kicked,
+        Error::ReloadFailed(String::new()),
+        Error::IncorrectPassword,
+        Error::InvalidName,
+        Error::AuthRequired,
+        Error::UdpUnavailable,
+        Error::TooManyRooms,
+        Error::NotYourTurn,
+        Error::NotTurnBased,
+        Error::InvalidTurnOrder,
+        Error::AcksNotEnabled,
+        Error::InvalidTags,
+        Error::NoAccount,
+        Error::TooManyFriends,
+        Error::RoomDataTooLong,
+        Error::InvalidRoomData,
+        Error::PayloadTooLarge,
+        Error::RejoinCooldown,
+        Error::TooManyRequests,
+        Error::RoomNameTaken,
+    ].into_iter().find(|e| e.code() == code)?;
+    
+    Some(match error {
+        Error::ReloadFailed(_) => {
+            let reason = text.strip_prefix("Failed to reload config: ")?;
+            Error::ReloadFailed(reason.to_string())
+        },
+        Error::UpgradeRequired(_) => {
+            let hint = text.strip_prefix("Client upgrade required, download from ");
+            Error::UpgradeRequired(hint.map(Into::into))
+        },
+        error => error,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use async_std::task;
+    use crate::transport::test::Memory;
+    use super::*;
+    
+    #[test]
+    fn parse_round_trip() {
+        let messages = [
+            Message::Welcome(4, "abc".into(), Box::new(ServerInfo {
+                version: "1.2.3".into(),
+                protocol_version: 1,
+                max_request_length: 4096,
+                max_room_members: None,
+                rate_limit: Some(RateLimit {per_second: 2.5, burst: 10.0}),
+            })),
+            Message::Compression(Some(Compression::Zstd)),
+            Message::Compression(None),
+            Message::Compressed(Compression::Deflate, b"\x01\xff".as_slice().into()),
+            Message::ListRooms(vec![(1, "level=1".into(), None), (3, "level=2".into(), None)]),
+            Message::ListRooms(Vec::new()),
+            Message::ListMembers(1, Named(1, Some("alice".into())), vec![Named(2, None), Named(3, Some("bob".into()))]),
+            Message::RoomPings(1, vec![(2, Some(40)), (3, None)]),
+            Message::Ping(7),
+            Message::RoomInfo(1, 1, 2, None, JoinPolicy::Open, "a|b".into(), None),
+            Message::RoomInfo(1, 1, 2, Some(4), JoinPolicy::AskOwner, "".into(), None),
+            Message::JoinRequested(1, Named(2, None), "hi".into()),
+            Message::PlayerLeft(1, Named(2, Some("bob".into()))),
+            Message::ReceivedBroadcast(2, "x|y".into()),
+            Message::ReceivedFrom(1, 2, "x|y".into()),
+            Message::ReceivedBinaryBroadcast(2, "AP8B".into()),
+            Message::ReceivedBinaryFrom(1, 2, "AP8B".into()),
+            Message::Timeline(1, vec![
+                TimelineEntry {time: 60, event: RoomEvent::Created(1)},
+                TimelineEntry {time: 90, event: RoomEvent::Closed},
+            ]),
+            Message::Warning(Warning::RoomNearlyFull(1, 7, 8)),
+            Message::Warning(Warning::NearlyRateLimited(36, 40)),
+            Message::Warning(Warning::PayloadNearlyTooLarge(950, 1000)),
+            Message::Warning(Warning::QueueNearlyFull(230, 256)),
+            Message::UdpToken(4001, "abc".into()),
+            Message::Stats(2, 1, 90, None),
+            Message::Stats(2, 1, 90, Some(TrafficCounts {messages_in: 5, bytes_in: 60, messages_out: 7, bytes_out: 200})),
+            Message::Turn(1, 7, 2),
+            Message::NoTurnOrder(1),
+            Message::ReceivedOnTurn(1, 7, 2, "x|y".into()),
+            Message::Replayed(1, "x|y".into()),
+            Message::AcksEnabled,
+            Message::RoomsSubscribed,
+            Message::RoomsUnsubscribed,
+            Message::RoomAdded(1, 2, "x|y".into(), None),
+            Message::RoomUpdated(1, 3, "x|y".into(), None),
+            Message::RoomRemoved(1),
+            Message::PlayerTimedOut(1, 2),
+            Message::KickVote(1, 2, 1, 2),
+            Message::VotedOut(1, 2),
+            Message::Invited(1, Named(2, Some("alice".into()))),
+            Message::FriendAdded("bob".into()),
+            Message::FriendRemoved("bob".into()),
+            Message::Friends(vec![
+                ("bob".into(), FriendStatus::Online(2)),
+                ("carol".into(), FriendStatus::Offline),
+                ("dave".into(), FriendStatus::Pending),
+            ]),
+            Message::Friends(Vec::new()),
+            Message::FriendOnline(2, "bob|x".into()),
+            Message::FriendOffline("bob".into()),
+            Message::FriendJoinedRoom(1, 2, "bob".into()),
+            Message::Motd("Be nice | have fun".into()),
+            Message::Announcement("Restarting at 12:00".into()),
+            Message::Announced(3),
+            Message::Reply(4, Box::new(Message::Error(Error::NotYourTurn))),
+            Message::Sequenced(3, Box::new(Message::ReceivedFrom(1, 2, "x|y".into()))),
+            Message::Signal(Signal::Offer, 1, 2, "{\"type\":\"offer\",\"sdp\":\"v=0\\r\\n\"}".into()),
+            Message::Error(Error::NoSuchRoom),
+            Message::Failed("JOIN_GAME", Error::RoomFull),
+            Message::Error(Error::UpgradeRequired(Some("https://example.com/".into()))),
+            Message::Error(Error::ReloadFailed("missing file".into())),
+        ];
+        for msg in messages {
+            assert_eq!(Some(&msg), parse_message(&msg.to_string(), |room_id| room_id == 1).as_ref());
+        }
+        assert_eq!(None, parse_message("SOMETHING_NEW|1", |_| false));
+        assert_eq!(None, parse_message("ERROR|Something new", |_| false));
+        assert_eq!(None, parse_message("ERROR|999|Something new", |_| false));
+        // only the code says what kind of error it is
+        assert_eq!(Some(Message::Error(Error::NoSuchRoom)), parse_message("ERROR|9|No such room", |_| false));
+    }
+    
+    #[test]
+    fn client_session() {
+        task::block_on(async {
+            let transport = Memory::new("WELCOME|4|abc|1.0|1|4096|0|0|0\nCREATED_GAME|1\nPLAYER_JOINED|1|5|hi|bob\nPING|7\nRECEIVED|1|5|a|b\nERROR|9|No such game\n");
+            let output = transport.output.clone();
+            
+            let mut client = Client::over(transport, Some("sesame")).await.unwrap();
+            assert_eq!((4, "abc"), (client.user_id(), client.resume_token()));
+            assert_eq!(1, client.create_room("level=1").await.unwrap());
+            client.send(1, "start").await.unwrap();
+            assert!(matches!(client.join(2, "hi", None).await, Err(ClientError::Server(Error::NoSuchRoom))));
+            
+            let events: Vec<_> = client.events().map(Result::unwrap).collect().await;
+            assert_eq!(vec![
+                Message::JoinRequested(1, Named(5, Some("bob".into())), "hi".into()),
+                Message::ReceivedFrom(1, 5, "a|b".into()),
+            ], events);
+            
+            let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+            // pings are answered without being passed on
+            assert_eq!("AUTH|sesame\nCREATE_GAME|level=1\nSEND|1|start\nJOIN_GAME|2|hi\nPONG|7\n", output);
+        });
+    }
+}
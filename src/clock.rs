@@ -0,0 +1,111 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use async_std::task;
+use futures::FutureExt;
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+
+/// The source of time for everything which waits or expires, so that tests
+/// and embedders can control how time passes.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+    
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+    
+    /// Moves the clock forward, waking any sleepers which are due. Returns
+    /// false if the clock cannot be changed.
+    fn advance(&self, _by: Duration) -> bool {
+        false
+    }
+}
+
+/// The real time.
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        task::sleep(duration).boxed()
+    }
+}
+
+/// A clock which only moves when it is told to.
+pub(crate) struct SimulatedClock {
+    state: Mutex<SimulatedState>,
+}
+
+struct SimulatedState {
+    now: SystemTime,
+    sleepers: Vec<(SystemTime, oneshot::Sender<()>)>,
+}
+
+impl SimulatedClock {
+    pub(crate) fn new(start: SystemTime) -> SimulatedClock {
+        SimulatedClock {
+            state: Mutex::new(SimulatedState {
+                now: start,
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().unwrap().now
+    }
+    
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        if duration.is_zero() {
+            return futures::future::ready(()).boxed();
+        }
+        let mut state = self.state.lock().unwrap();
+        let (wake, woken) = oneshot::channel();
+        let deadline = state.now + duration;
+        state.sleepers.push((deadline, wake));
+        woken.map(|_| ()).boxed()
+    }
+    
+    fn advance(&self, by: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.now += by;
+        let now = state.now;
+        let (due, waiting) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = waiting;
+        
+        for (_, wake) in due {
+            wake.send(()).ok();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::UNIX_EPOCH;
+    use super::*;
+    
+    #[test]
+    fn simulated_sleep() {
+        let clock = SimulatedClock::new(UNIX_EPOCH);
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!((&mut sleep).now_or_never().is_none());
+        
+        assert!(clock.advance(Duration::from_secs(5)));
+        assert!((&mut sleep).now_or_never().is_none());
+        
+        assert!(clock.advance(Duration::from_secs(5)));
+        assert!((&mut sleep).now_or_never().is_some());
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(10), clock.now());
+    }
+    
+    #[test]
+    fn system_clock_is_fixed() {
+        assert!(!SystemClock.advance(Duration::from_secs(5)));
+    }
+}
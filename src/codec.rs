@@ -0,0 +1,448 @@
+use std::borrow::Cow;
+use async_std::io;
+use futures::{AsyncBufReadExt, StreamExt};
+use futures::stream::BoxStream;
+use rmp::decode::{LenError, MessageLen};
+
+use crate::dispatch::{self, Line};
+use crate::protobuf::ProtobufCodec;
+use crate::request::{self, Fields, Parts, Request, RequestID};
+use crate::response::{Error, Field, Message};
+use crate::transport::Reader;
+
+/// How requests and messages are written on the wire. The codec decides
+/// where each request ends, as well as what goes in it, so the server can
+/// speak other encodings without changing how it works.
+pub(crate) trait Codec: Send + Sync {
+    /// Splits what a client sends into requests, stopping at the first one
+    /// longer than `max_len` bytes.
+    fn frames<'a>(&self, reader: &'a mut Reader, max_len: usize) -> BoxStream<'a, io::Result<Frame>>;
+    
+    /// Reads a request from one frame, or `None` if it isn't a valid request.
+    fn decode(&self, frame: &[u8]) -> Option<Request>;
+    
+    /// Reads a request from one frame, as the server receives it. Codecs
+    /// which borrow fields from the frame refuse a payload longer than the
+    /// limit before copying it; the server checks the rest.
+    fn decode_within(&self, frame: &[u8], _max_payload_length: usize) -> Result<Request, Error> {
+        self.decode(frame)
+            .ok_or(Error::InvalidRequest)
+    }
+    
+    /// Reads the ID the client gave a request, if any, which is echoed on
+    /// the reply. This works even if the rest of the request is invalid.
+    fn request_id(&self, frame: &[u8]) -> Option<RequestID>;
+    
+    /// Reads the credential from an `AUTH` request, which comes before any
+    /// other request when the server requires it.
+    fn auth_credential<'a>(&self, frame: &'a [u8]) -> Option<Cow<'a, str>>;
+    
+    /// Appends a message to `out`, ready to be written.
+    fn encode(&self, msg: &Message, out: &mut Vec<u8>);
+}
+
+/// One request as it was sent, before it is decoded.
+pub(crate) enum Frame {
+    Complete(Vec<u8>),
+    /// The request was longer than allowed, so the rest of it was not read.
+    TooLong,
+}
+
+/// Which codec clients speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Pipe,
+    MessagePack,
+    Protobuf,
+}
+
+impl Protocol {
+    pub(crate) fn codec(self) -> std::sync::Arc<dyn Codec> {
+        match self {
+            Protocol::Pipe => std::sync::Arc::new(PipeCodec),
+            Protocol::MessagePack => std::sync::Arc::new(MessagePackCodec),
+            Protocol::Protobuf => std::sync::Arc::new(ProtobufCodec),
+        }
+    }
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<Protocol, ()> {
+        match s {
+            "pipe" => Ok(Protocol::Pipe),
+            "msgpack" => Ok(Protocol::MessagePack),
+            "protobuf" => Ok(Protocol::Protobuf),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Pipe => "pipe",
+            Protocol::MessagePack => "msgpack",
+            Protocol::Protobuf => "protobuf",
+        })
+    }
+}
+
+/// The original encoding, one request or message per line, where a keyword
+/// and its fields are separated by `|`, as in `JOIN_GAME|3|hello`.
+pub(crate) struct PipeCodec;
+
+impl Codec for PipeCodec {
+    fn frames<'a>(&self, reader: &'a mut Reader, max_len: usize) -> BoxStream<'a, io::Result<Frame>> {
+        dispatch::bounded_lines(io::BufReader::new(reader), max_len)
+            .map(|line| line.map(|line| match line {
+                Line::Complete(line) => Frame::Complete(line.into_bytes()),
+                Line::TooLong => Frame::TooLong,
+            }))
+            .boxed()
+    }
+    
+    fn decode(&self, frame: &[u8]) -> Option<Request> {
+        std::str::from_utf8(frame).ok()
+            .and_then(request::parse)
+    }
+    
+    fn decode_within(&self, frame: &[u8], max_payload_length: usize) -> Result<Request, Error> {
+        let s = std::str::from_utf8(frame)
+            .map_err(|_| Error::InvalidRequest)?;
+        request::parse_fields_within(Parts::of(request::split_request_id(s).1), max_payload_length)
+    }
+    
+    /// The ID comes first, as in `#42|SEND|1|x`.
+    fn request_id(&self, frame: &[u8]) -> Option<RequestID> {
+        std::str::from_utf8(frame).ok()
+            .and_then(|s| request::split_request_id(s).0)
+    }
+    
+    /// The credential may itself contain `|`.
+    fn auth_credential<'a>(&self, frame: &'a [u8]) -> Option<Cow<'a, str>> {
+        std::str::from_utf8(frame).ok()?
+            .strip_prefix("AUTH|")
+            .map(Cow::Borrowed)
+    }
+    
+    fn encode(&self, msg: &Message, out: &mut Vec<u8>) {
+        out.extend_from_slice(msg.to_string().as_bytes());
+        out.push(b'\n');
+    }
+}
+
+/// Requests and messages are MessagePack arrays, written one after another
+/// with nothing between them. Each array holds the keyword and then the
+/// same fields as the pipe encoding, as in `["JOIN_GAME", 3, "hello"]`;
+/// numbers are integers, and everything else is a string.
+pub(crate) struct MessagePackCodec;
+
+/// Requests are flat arrays, so anything nested deeper than this is not
+/// worth reading.
+const MAX_MESSAGE_PACK_DEPTH: usize = 4;
+
+impl Codec for MessagePackCodec {
+    fn frames<'a>(&self, reader: &'a mut Reader, max_len: usize) -> BoxStream<'a, io::Result<Frame>> {
+        futures::stream::unfold(Some(io::BufReader::new(reader)), move |reader| async move {
+            let mut reader = reader?;
+            let mut frame = Vec::new();
+            let mut len = MessageLen::with_limits(MAX_MESSAGE_PACK_DEPTH, max_len);
+            loop {
+                let available = match reader.fill_buf().await {
+                    Ok(available) => available,
+                    Err(e) => return Some((Err(e), None)),
+                };
+                if available.is_empty() {
+                    // an unfinished request is dropped
+                    return None;
+                }
+                
+                let (end, complete) = match len.incremental_len(available) {
+                    Ok(total) => (total - frame.len(), true),
+                    Err(LenError::Truncated(needed)) if needed.get() <= max_len => (available.len(), false),
+                    Err(LenError::Truncated(_)) => return Some((Ok(Frame::TooLong), None)),
+                    Err(LenError::ParseError) => {
+                        let e = io::Error::new(io::ErrorKind::InvalidData, "invalid MessagePack");
+                        return Some((Err(e), None));
+                    },
+                };
+                frame.extend_from_slice(&available[..end]);
+                reader.consume_unpin(end);
+                
+                if frame.len() > max_len {
+                    return Some((Ok(Frame::TooLong), None));
+                } else if complete {
+                    return Some((Ok(Frame::Complete(frame)), Some(reader)));
+                }
+            }
+        }).boxed()
+    }
+    
+    fn decode(&self, frame: &[u8]) -> Option<Request> {
+        let mut fields = MessagePackFields::of(frame)?;
+        fields.take_request_id();
+        request::parse_fields(fields)
+    }
+    
+    fn decode_within(&self, frame: &[u8], max_payload_length: usize) -> Result<Request, Error> {
+        let mut fields = MessagePackFields::of(frame)
+            .ok_or(Error::InvalidRequest)?;
+        fields.take_request_id();
+        request::parse_fields_within(fields, max_payload_length)
+    }
+    
+    /// The ID is a string before the keyword, as in `["#42", "SEND", 1, "x"]`.
+    fn request_id(&self, frame: &[u8]) -> Option<RequestID> {
+        MessagePackFields::of(frame)?.take_request_id()
+    }
+    
+    fn auth_credential<'a>(&self, frame: &'a [u8]) -> Option<Cow<'a, str>> {
+        let mut fields = MessagePackFields::of(frame)?;
+        if fields.take_str()? != "AUTH" || fields.is_done() {
+            return None;
+        }
+        let credential = fields.take_rest();
+        fields.done(|| Cow::Borrowed(credential))
+    }
+    
+    fn encode(&self, msg: &Message, out: &mut Vec<u8>) {
+        write_message_pack(msg, out)
+            .expect("writing to a Vec can't fail");
+    }
+}
+
+fn write_message_pack(msg: &Message, out: &mut Vec<u8>) -> Result<(), rmp::encode::ValueWriteError> {
+    let fields = msg.fields();
+    if let Message::Reply(request_id, _) = msg {
+        rmp::encode::write_array_len(out, 2 + fields.len() as u32)?;
+        rmp::encode::write_str(out, &format!("#{request_id}"))?;
+    } else {
+        rmp::encode::write_array_len(out, 1 + fields.len() as u32)?;
+    }
+    rmp::encode::write_str(out, msg.keyword())?;
+    for field in fields {
+        match field {
+            Field::Int(n) => rmp::encode::write_uint(out, n).map(|_| ())?,
+            Field::Str(s) => rmp::encode::write_str(out, &s)?,
+            Field::Bytes(bytes) => rmp::encode::write_bin(out, bytes)?,
+        }
+    }
+    Ok(())
+}
+
+/// The elements of a MessagePack array, read as the fields of a request.
+#[derive(Clone)]
+struct MessagePackFields<'a> {
+    rest: &'a [u8],
+    remaining: u32,
+}
+
+impl <'a> MessagePackFields<'a> {
+    fn of(mut frame: &'a [u8]) -> Option<MessagePackFields<'a>> {
+        let remaining = rmp::decode::read_array_len(&mut frame).ok()?;
+        Some(MessagePackFields {rest: frame, remaining})
+    }
+    
+    /// Skips the request's ID and returns it, if it has one.
+    fn take_request_id(&mut self) -> Option<RequestID> {
+        let mut fields = self.clone();
+        let request_id = request::parse_request_id(fields.take_str()?)?;
+        *self = fields;
+        Some(request_id)
+    }
+}
+
+impl <'a> Fields<'a> for MessagePackFields<'a> {
+    fn take_str(&mut self) -> Option<&'a str> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let (s, rest) = rmp::decode::read_str_from_slice(self.rest).ok()?;
+        if !request::is_field(s) {
+            return None;
+        }
+        self.rest = rest;
+        self.remaining -= 1;
+        Some(s)
+    }
+    
+    fn take_int<T: std::str::FromStr>(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let mut rest = self.rest;
+        let n: u64 = rmp::decode::read_int(&mut rest).ok()?;
+        self.rest = rest;
+        self.remaining -= 1;
+        n.to_string().parse().ok()
+    }
+    
+    /// The rest is a single string, which may contain `|`.
+    fn take_rest(&mut self) -> &'a str {
+        if self.remaining != 1 {
+            return "";
+        }
+        match rmp::decode::read_str_from_slice(self.rest) {
+            Ok((s, rest)) if !s.contains('\n') => {
+                self.rest = rest;
+                self.remaining = 0;
+                s
+            },
+            _ => "",
+        }
+    }
+    
+    fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use async_std::task;
+    use crate::response::{Error, Named};
+    use super::*;
+    
+    /// Writes a request as a MessagePack client would.
+    fn pack(fields: &[Field]) -> Vec<u8> {
+        let mut out = Vec::new();
+        rmp::encode::write_array_len(&mut out, fields.len() as u32).unwrap();
+        for field in fields {
+            match field {
+                Field::Int(n) => rmp::encode::write_uint(&mut out, *n).map(|_| ()).unwrap(),
+                Field::Str(s) => rmp::encode::write_str(&mut out, s).unwrap(),
+                Field::Bytes(bytes) => rmp::encode::write_bin(&mut out, bytes).unwrap(),
+            }
+        }
+        out
+    }
+    
+    /// A reader which gives out one byte at a time, so that requests arrive
+    /// in pieces.
+    struct Trickle(Vec<u8>);
+    
+    impl io::Read for Trickle {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            buf[0] = self.0.remove(0);
+            Poll::Ready(Ok(1))
+        }
+    }
+    
+    pub(crate) fn read_frames(codec: &dyn Codec, input: Vec<u8>, max_len: usize) -> Vec<Option<Vec<u8>>> {
+        let mut reader: Reader = Box::new(Trickle(input));
+        let frames = codec.frames(&mut reader, max_len)
+            .map(|frame| match frame.unwrap() {
+                Frame::Complete(frame) => Some(frame),
+                Frame::TooLong => None,
+            });
+        task::block_on(frames.collect())
+    }
+    
+    #[test]
+    fn pipe_codec() {
+        assert_eq!(Some(Request::LeaveRoom(3)), PipeCodec.decode(b"LEAVE_GAME|3"));
+        assert_eq!(None, PipeCodec.decode(b"LEAVE_GAME|three"));
+        assert_eq!(Ok(Request::Send(3, "abc".into())), PipeCodec.decode_within(b"SEND|3|abc", 3));
+        assert_eq!(Err(Error::PayloadTooLarge), PipeCodec.decode_within(b"#42|SEND|3|abcd", 3));
+        assert_eq!(Err(Error::InvalidRequest), PipeCodec.decode_within(b"SEND|three|abcd", 3));
+        let mut out = Vec::new();
+        PipeCodec.encode(&Message::RoomClosed(3), &mut out);
+        assert_eq!(b"GAME_OVER|3\n".to_vec(), out);
+    }
+    
+    #[test]
+    fn pipe_request_id() {
+        assert_eq!(Some(Request::LeaveRoom(3)), PipeCodec.decode(b"#42|LEAVE_GAME|3"));
+        assert_eq!(Some(42), PipeCodec.request_id(b"#42|LEAVE_GAME|three"));
+        assert_eq!(None, PipeCodec.request_id(b"LEAVE_GAME|3"));
+        let mut out = Vec::new();
+        PipeCodec.encode(&Message::Error(Error::NoSuchRoom).replying_to(Some(42)), &mut out);
+        assert_eq!(format!("#42|ERROR|9|{}\n", Error::NoSuchRoom).into_bytes(), out);
+    }
+    
+    #[test]
+    fn pipe_auth() {
+        assert_eq!(Some("s3cr|t"), PipeCodec.auth_credential(b"AUTH|s3cr|t").as_deref());
+        assert_eq!(Some(""), PipeCodec.auth_credential(b"AUTH|").as_deref());
+        assert_eq!(None, PipeCodec.auth_credential(b"HELLO|1.0").as_deref());
+    }
+    
+    #[test]
+    fn message_pack_decode() {
+        let codec = MessagePackCodec;
+        assert_eq!(
+            Some(Request::AskJoinRoom(3, "hello".into(), None)),
+            codec.decode(&pack(&["JOIN_GAME".into(), 3u32.into(), "hello".into()])),
+        );
+        // only the fields which may contain `|` in the pipe format may here
+        assert_eq!(
+            Some(Request::SetSchema(3, "A|B".into())),
+            codec.decode(&pack(&["SET_SCHEMA".into(), 3u32.into(), "A|B".into()])),
+        );
+        assert_eq!(None, codec.decode(&pack(&["CREATE_GAME".into(), "A|B".into()])));
+        assert_eq!(
+            Err(Error::PayloadTooLarge),
+            codec.decode_within(&pack(&["JOIN_GAME".into(), 3u32.into(), "hello".into()]), 4),
+        );
+        assert_eq!(
+            Some(Request::Ping(1, Some(40))),
+            codec.decode(&pack(&["PING".into(), 1u32.into(), 40u32.into()])),
+        );
+        // numbers must be sent as numbers
+        assert_eq!(None, codec.decode(&pack(&["LEAVE_GAME".into(), "3".into()])));
+        assert_eq!(None, codec.decode(&pack(&["LEAVE_GAME".into(), 3u32.into(), 4u32.into()])));
+        assert_eq!(None, codec.decode(b"LEAVE_GAME|3"));
+    }
+    
+    #[test]
+    fn message_pack_request_id() {
+        let codec = MessagePackCodec;
+        let frame = pack(&["#42".into(), "LEAVE_GAME".into(), 3u32.into()]);
+        assert_eq!(Some(Request::LeaveRoom(3)), codec.decode(&frame));
+        assert_eq!(Some(42), codec.request_id(&frame));
+        assert_eq!(None, codec.request_id(&pack(&["LEAVE_GAME".into(), 3u32.into()])));
+        
+        let mut out = Vec::new();
+        codec.encode(&Message::RoomClosed(3).replying_to(Some(42)), &mut out);
+        assert_eq!(pack(&["#42".into(), "GAME_OVER".into(), 3u32.into()]), out);
+    }
+    
+    #[test]
+    fn message_pack_auth() {
+        let codec = MessagePackCodec;
+        assert_eq!(Some("s3cr|t"), codec.auth_credential(&pack(&["AUTH".into(), "s3cr|t".into()])).as_deref());
+        assert_eq!(None, codec.auth_credential(&pack(&["AUTH".into()])).as_deref());
+        assert_eq!(None, codec.auth_credential(&pack(&["HELLO".into(), "1.0".into()])).as_deref());
+    }
+    
+    #[test]
+    fn message_pack_encode() {
+        let mut out = Vec::new();
+        MessagePackCodec.encode(&Message::PlayerLeft(3, Named(4, Some("bob".into()))), &mut out);
+        assert_eq!(pack(&["PLAYER_LEFT".into(), 3u32.into(), 4u32.into(), "bob".into()]), out);
+        
+        out.clear();
+        MessagePackCodec.encode(&Message::ListRooms(Vec::new()), &mut out);
+        assert_eq!(pack(&["NO_OPEN_GAMES".into()]), out);
+    }
+    
+    #[test]
+    fn message_pack_frames() {
+        let ping = pack(&["PING".into(), 1u32.into()]);
+        let long = pack(&["SEND".into(), 1u32.into(), "x".repeat(20).into()]);
+        let input = [ping.clone(), ping.clone(), long, ping.clone()].concat();
+        assert_eq!(vec![Some(ping.clone()), Some(ping), None], read_frames(&MessagePackCodec, input, 16));
+        
+        // an unfinished request at the end is dropped
+        let quit = pack(&["QUIT".into()]);
+        let input = [quit.clone(), quit[..2].to_vec()].concat();
+        assert_eq!(vec![Some(quit)], read_frames(&MessagePackCodec, input, 16));
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::Write;
+
+/// An algorithm which large relayed payloads are compressed with, for
+/// clients which ask for it with `COMPRESS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    /// Raw DEFLATE, without a zlib or gzip header.
+    Deflate,
+}
+
+impl Compression {
+    /// The algorithms the server supports, in the order it prefers them.
+    const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Deflate];
+    
+    /// Chooses the server's preferred algorithm out of those a client offers,
+    /// as a comma-separated list of names. Names the server doesn't know are
+    /// ignored, so that clients can offer newer algorithms.
+    pub(crate) fn negotiate(offered: &str) -> Option<Compression> {
+        let offered: Vec<_> = offered.split(',')
+            .filter_map(|name| name.trim().parse().ok())
+            .collect();
+        Compression::SUPPORTED.into_iter()
+            .find(|c| offered.contains(c))
+    }
+    
+    pub(crate) fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .expect("compressing in memory can't fail"),
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)
+                    .and_then(|_| encoder.finish())
+                    .expect("compressing in memory can't fail")
+            },
+        }
+    }
+    
+    #[cfg(test)]
+    pub(crate) fn decompress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Zstd => zstd::decode_all(bytes).unwrap(),
+            Compression::Deflate => {
+                let mut decoder = flate2::write::DeflateDecoder::new(Vec::new());
+                decoder.write_all(bytes).unwrap();
+                decoder.finish().unwrap()
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<Compression, ()> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::Zstd => "zstd",
+            Compression::Deflate => "deflate",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn negotiate() {
+        assert_eq!(Some(Compression::Zstd), Compression::negotiate("deflate,zstd"));
+        assert_eq!(Some(Compression::Deflate), Compression::negotiate("brotli, deflate"));
+        assert_eq!(None, Compression::negotiate("brotli"));
+        assert_eq!(None, Compression::negotiate(""));
+    }
+    
+    #[test]
+    fn round_trip() {
+        let payload = "state=".repeat(200);
+        for c in Compression::SUPPORTED {
+            let compressed = c.compress(payload.as_bytes());
+            assert!(compressed.len() < payload.len());
+            assert_eq!(payload.as_bytes(), c.decompress(&compressed));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/// Presets and config files bundle command-line options, so that operators
+/// don't have to choose every limit themselves. Options given explicitly on
+/// the command line take precedence over both.
+///
+/// A config file is TOML, with one key per command-line option, named as the
+/// option without the leading dashes. Keys may be grouped into sections such
+/// as `[limits]` or `[logging]`, which are only for readability. Options
+/// which may be given more than once take an array, and boolean flags take
+/// `true` or `false`.
+pub(crate) type Settings = Vec<(String, String)>;
+
+const CASUAL: &[(&str, &str)] = &[
+    ("max-connections", "256"),
+    ("waiting-room", "32"),
+    ("waiting-timeout", "300"),
+    ("rate-limit", "20.0"),
+    ("rate-burst", "40.0"),
+    ("max-request-length", "8192"),
+    ("disconnect-grace", "60"),
+    ("game-idle-timeout", "3600"),
+    ("on-undelivered", "log"),
+];
+
+const TOURNAMENT: &[(&str, &str)] = &[
+    ("max-connections", "64"),
+    ("waiting-room", "0"),
+    ("rate-limit", "10.0"),
+    ("rate-burst", "20.0"),
+    ("max-request-length", "4096"),
+    ("disconnect-grace", "120"),
+    ("on-undelivered", "notify"),
+    ("random-ids", "true"),
+];
+
+const LAN_PARTY: &[(&str, &str)] = &[
+    ("max-connections", "32"),
+    ("waiting-room", "16"),
+    ("waiting-timeout", "600"),
+    ("rate-limit", "0.0"),
+    ("disconnect-grace", "300"),
+    ("on-undelivered", "cleanup"),
+];
+
+pub(crate) const PRESET_NAMES: &[&str] = &["casual", "tournament", "lan-party"];
+
+pub(crate) fn preset(name: &str) -> Option<Settings> {
+    let settings = match name {
+        "casual" => CASUAL,
+        "tournament" => TOURNAMENT,
+        "lan-party" => LAN_PARTY,
+        _ => return None,
+    };
+    Some(settings.iter()
+        .map(|&(option, value)| (option.to_string(), value.to_string()))
+        .collect())
+}
+
+/// The section each option is written in, when exporting a config file.
+const SECTIONS: &[(&str, &[&str])] = &[
+    ("limits", &["max-connections", "waiting-room", "waiting-timeout", "rate-limit", "rate-burst", "max-request-length", "max-payload-length", "max-queued-messages", "write-timeout", "max-game-members", "max-join-requests", "vote-kick-threshold", "max-game-data-length", "game-data-pattern", "match-size"]),
+    ("sessions", &["disconnect-grace", "game-idle-timeout", "rejoin-cooldown", "on-undelivered", "random-ids", "state-file", "snapshot-interval", "room-store"]),
+    ("access", &["allow-list", "deny-list", "auth-token-file", "auth-url"]),
+    ("clients", &["protocol", "compress-threshold", "min-client-version", "block-client-version", "upgrade-url", "motd", "motd-file"]),
+    ("restarts", &["restart-at", "drain-timeout"]),
+    ("logging", &["log-level", "log-format", "log-file", "log-max-size", "log-rotate", "log-keep"]),
+];
+
+/// Parses the contents of a config file.
+pub(crate) fn parse(text: &str) -> Result<Settings, String> {
+    let table: toml::Table = text.parse()
+        .map_err(|e: toml::de::Error| e.message().to_string())?;
+    let mut settings = Vec::new();
+    flatten(&table, &mut settings)?;
+    Ok(settings)
+}
+
+fn flatten(table: &toml::Table, settings: &mut Settings) -> Result<(), String> {
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(section) => flatten(section, settings)?,
+            toml::Value::Array(values) => {
+                for value in values {
+                    settings.push((key.clone(), scalar(key, value)?));
+                }
+            },
+            value => settings.push((key.clone(), scalar(key, value)?)),
+        }
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(x) => Ok(format!("{x:?}")),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("unsupported value for '{key}'")),
+    }
+}
+
+/// Writes a value as a TOML string, unless it reads back the same as a
+/// boolean or number.
+fn toml_value(value: &str) -> String {
+    let is_float = value.parse::<f64>().is_ok_and(|x| format!("{x:?}") == value);
+    if value == "true" || value == "false" || value.parse::<i64>().is_ok() || is_float {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
+
+/// Formats settings as a config file, with options grouped into sections.
+pub(crate) fn format(settings: &Settings) -> String {
+    let mut text = String::from("# incognita-socket-server config\n");
+    let write_option = |text: &mut String, option: &str| {
+        let values: Vec<String> = settings.iter()
+            .filter(|(o, _)| o == option)
+            .map(|(_, value)| toml_value(value))
+            .collect();
+        match values.as_slice() {
+            [] => {},
+            [value] if option != "block-client-version" => *text += &format!("{option} = {value}\n"),
+            values => *text += &format!("{option} = [{}]\n", values.join(", ")),
+        }
+    };
+    
+    // options not in any section go first, as TOML requires
+    let mut written: Vec<&str> = SECTIONS.iter()
+        .flat_map(|(_, options)| options.iter().copied())
+        .collect();
+    for (option, _) in settings {
+        if !written.contains(&option.as_str()) {
+            write_option(&mut text, option);
+            written.push(option);
+        }
+    }
+    for (section, options) in SECTIONS {
+        if settings.iter().any(|(o, _)| options.contains(&o.as_str())) {
+            text += &format!("\n[{section}]\n");
+            for option in *options {
+                write_option(&mut text, option);
+            }
+        }
+    }
+    text
+}
+
+/// Turns settings into command-line arguments, leaving out any options which
+/// `explicit` already gives.
+pub(crate) fn to_args(settings: &Settings, explicit: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    for (option, value) in settings {
+        let flag = format!("--{option}");
+        if explicit.contains(&flag) {
+            continue;
+        }
+        match value.as_str() {
+            "true" => args.push(flag),
+            "false" => {},
+            _ => args.extend([flag, value.clone()]),
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn settings(pairs: &[(&str, &str)]) -> Settings {
+        pairs.iter()
+            .map(|&(option, value)| (option.to_string(), value.to_string()))
+            .collect()
+    }
+    
+    #[test]
+    fn parse_config() {
+        let text = "port = 4000\n\n[limits]\nmax-connections = 16\nrate-limit = 2.5\n\n[clients]\nblock-client-version = [\"1.0\", \"1.10\"]\n\n[logging]\nlog-format = \"json\"\n";
+        // sections and keys come out in alphabetical order
+        let expected = settings(&[
+            ("block-client-version", "1.0"),
+            ("block-client-version", "1.10"),
+            ("max-connections", "16"),
+            ("rate-limit", "2.5"),
+            ("log-format", "json"),
+            ("port", "4000"),
+        ]);
+        assert_eq!(Ok(expected), parse(text));
+        assert!(parse("max-connections 16").is_err());
+    }
+    
+    #[test]
+    fn export_config() {
+        let exported = settings(&[
+            ("port", "4000"),
+            ("max-connections", "16"),
+            ("rate-limit", "2.5"),
+            ("block-client-version", "1.10"),
+            ("random-ids", "true"),
+        ]);
+        let text = format(&exported);
+        assert!(text.contains("port = 4000\n\n[limits]\nmax-connections = 16\nrate-limit = 2.5\n"));
+        assert!(text.contains("block-client-version = [\"1.10\"]\n"));
+        
+        let mut reparsed = parse(&text).unwrap();
+        let mut exported = exported;
+        reparsed.sort();
+        exported.sort();
+        assert_eq!(exported, reparsed);
+    }
+    
+    #[test]
+    fn explicit_args_win() {
+        let settings = preset("tournament").unwrap();
+        let args = to_args(&settings, &["--max-connections".to_string(), "8".to_string()]);
+        assert!(!args.contains(&"--max-connections".to_string()));
+        assert!(args.contains(&"--random-ids".to_string()));
+        assert_eq!(Some("4096"), args.iter()
+            .position(|arg| arg == "--max-request-length")
+            .map(|i| args[i + 1].as_str()));
+    }
+}
@@ -1,202 +1,2290 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use async_std::prelude::*;
-use async_std::io;
-use async_std::net::{TcpListener, TcpStream, SocketAddr};
-use futures::{SinkExt, StreamExt};
-use futures::channel::mpsc;
+use async_std::{io, task};
+use async_std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, UdpSocket};
+use futures::{FutureExt, SinkExt, StreamExt};
+use futures::channel::{mpsc, oneshot};
+use tracing::{debug, info, warn, Instrument};
 
+use crate::access::AccessControl;
+use crate::acks::Unacked;
+use crate::admin_api::{self, AdminQuery, AdminReply};
+use crate::auth::Authenticator;
+use crate::clock::Clock;
+use crate::codec::{Codec, Frame};
+use crate::compression::Compression;
 use crate::err;
+use crate::ids;
+use crate::limits::{self, RateLimiter, RateVerdict, Warning};
+use crate::mirror;
+use crate::recording::{Entry, Recorded, Recorder};
 use crate::models::UserID;
-use crate::request;
+use crate::request::{self, RequestID};
 use crate::response;
+use crate::schedule::RestartSchedule;
 use crate::server::Server;
+use crate::snapshot;
+use crate::stats::{ConnectionStats, Traffic};
+use crate::transport::Conn;
+use crate::udp::{self, UdpRelay};
 
 struct UserIdent {
     id: UserID,
+    /// Logged instead of the user's ID where a session must be traced
+    /// across reconnections, as IDs are reused.
+    session: String,
     addr: SocketAddr,
+    instance: Option<Arc<str>>,
 }
 
-impl std::fmt::Display for UserIdent {
+/// A `host:port` pair which the server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ListenAddr {
+    /// Listens on all interfaces, for both IPv6 and IPv4 clients.
+    pub fn any(port: u16) -> ListenAddr {
+        ListenAddr {host: "[::]".to_string(), port}
+    }
+    
+    fn any_v4(port: u16) -> ListenAddr {
+        ListenAddr {host: "0.0.0.0".to_string(), port}
+    }
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<ListenAddr, ()> {
+        let (host, port) = s.rsplit_once(':').ok_or(())?;
+        if host.is_empty() {
+            return Err(());
+        }
+        let port = port.parse().map_err(|_| ())?;
+        Ok(ListenAddr {host: host.to_string(), port})
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let UserIdent {id, addr} = self;
-        write!(f, "User #{id} @ {addr}")
+        write!(f, "{}:{}", self.host, self.port)
     }
 }
 
-pub(crate) type Sender<T> = mpsc::UnboundedSender<T>;
-pub(crate) type Receiver<T> = mpsc::UnboundedReceiver<T>;
+/// What to do when a message can't be delivered, because the recipient's
+/// connection has gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UndeliveredPolicy {
+    /// Only log it.
+    Log,
+    /// Tell the user whose request caused the message.
+    NotifySender,
+    /// Remove the recipient, as they are evidently no longer connected.
+    Cleanup,
+}
 
-pub(crate) async fn start_server(server: Server, host: &str, port: u16) -> err::Result {
-    let server_addr = format!("{host}:{port}");
-    let listener = TcpListener::bind(&server_addr).await?;
-    println!("Listening on {server_addr}");
+impl std::str::FromStr for UndeliveredPolicy {
+    type Err = ();
     
+    fn from_str(s: &str) -> Result<UndeliveredPolicy, ()> {
+        match s {
+            "log" => Ok(UndeliveredPolicy::Log),
+            "notify" => Ok(UndeliveredPolicy::NotifySender),
+            "cleanup" => Ok(UndeliveredPolicy::Cleanup),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for UndeliveredPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            UndeliveredPolicy::Log => "log",
+            UndeliveredPolicy::NotifySender => "notify",
+            UndeliveredPolicy::Cleanup => "cleanup",
+        })
+    }
+}
+
+pub(crate) type Sender<T> = mpsc::Sender<T>;
+pub(crate) type Receiver<T> = mpsc::Receiver<T>;
+
+/// How many events can wait for the dispatcher. When the queue is full,
+/// connections wait for space before passing on another request, and stop
+/// reading from their sockets meanwhile, so a flood of requests is held back
+/// by TCP instead of growing memory.
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+
+/// How long to wait before trying to reconnect to a relay, after a failed
+/// connection.
+const RELAY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How many idle connections the server keeps open to a relay, so that
+/// several clients can be paired at once.
+const RELAY_CONNECTIONS: usize = 4;
+
+/// The relay's `PAIRED` line is a keyword and a socket address.
+const MAX_PAIRING_LINE_LENGTH: usize = 64;
+
+/// Observers can't make requests, so anything they send is short or a
+/// mistake.
+const MAX_OBSERVER_LINE_LENGTH: usize = 1024;
+
+/// The most messages to a connection which are written at once; the rest wait
+/// for the next write, so that requests are still read in between.
+const MAX_BATCH_MESSAGES: usize = 64;
+
+/// How long a new connection has to send its auth token, when the server
+/// requires one.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a waiting connection is reminded of its position, even if it
+/// hasn't moved, so that the client knows it is still in the queue.
+const WAITING_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often to look for abandoned rooms; rooms may stay open for up to this
+/// much longer than the idle timeout.
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to ping every user, to measure their latency.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How a server stopped, so that the application can choose its exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// The server drained for its scheduled restart.
+    Restart,
+    /// The server was asked to shut down, or stopped accepting connections.
+    Shutdown,
+}
+
+/// Serves clients on each of the given addresses, until the server stops.
+/// Observers, if enabled, are served on the same hosts at their own port;
+/// the admin API is served at its own address.
+pub async fn start_server(server: Server, addrs: &[ListenAddr], mirror_port: Option<u16>, admin_addr: Option<String>) -> err::Result<Stopped> {
+    let mut listeners = Vec::new();
+    let mut hosts = Vec::new();
+    for addr in addrs {
+        let (listener, addr) = match bind(&addr.to_string()).await {
+            // the host may not support IPv6 at all
+            Err(e) if *addr == ListenAddr::any(addr.port) => {
+                warn!(error = %e, "Failed to listen on IPv6; listening on IPv4 only");
+                let addr = ListenAddr::any_v4(addr.port);
+                (bind(&addr.to_string()).await?, addr)
+            },
+            listener => (listener?, addr.clone()),
+        };
+        listeners.push(listener);
+        match server.name() {
+            Some(name) => info!(%addr, instance = %name, "Listening"),
+            None => info!(%addr, "Listening"),
+        }
+        hosts.push(addr.host);
+    }
+    hosts.sort();
+    hosts.dedup();
+    serve_listeners(server, listeners, hosts, mirror_port, admin_addr).await
+}
+
+/// Serves clients who connect to listeners which are already bound; the
+/// mirror and UDP ports, if any, are listened on at each of the hosts.
+pub(crate) async fn serve_listeners(server: Server, listeners: Vec<TcpListener>, hosts: Vec<String>, mirror_port: Option<u16>, admin_addr: Option<String>) -> err::Result<Stopped> {
+    let mut access = server.access_control().clone();
+    let admin_password: Option<Arc<str>> = server.admin_password().map(Into::into);
+    let udp_port = server.udp_port();
     let dispatcher = Dispatcher::new(server);
     let mut dispatcher_send = dispatcher.out.clone();
-    let dispatcher_task = err::spawn_logged_task(dispatcher.run());
+    let mut dispatcher_task = task::spawn(dispatcher.run()).fuse();
+    
+    if let Some((admin_addr, password)) = admin_addr.zip(admin_password) {
+        err::spawn_logged_task(admin_api::serve(admin_addr, password, access.clone(), dispatcher_send.clone()));
+    }
+    for host in hosts {
+        if let Some(udp_port) = udp_port {
+            let udp_addr = format!("{host}:{udp_port}");
+            err::spawn_logged_task(udp::serve(udp_addr, access.clone(), dispatcher_send.clone()));
+        }
+        if let Some(mirror_port) = mirror_port {
+            let mirror_addr = format!("{host}:{mirror_port}");
+            err::spawn_logged_task(accept_observers(mirror_addr, access.clone(), dispatcher_send.clone()));
+        }
+    }
+    
+    let mut incoming = futures::stream::select_all(listeners.iter().map(TcpListener::incoming)).fuse();
+    loop {
+        futures::select! {
+            conn = incoming.next() => {
+                let Some(conn) = conn else { break; };
+                let Ok((conn, addr)) = conn
+                    .and_then(|s| {
+                        peer_addr(&s).map(|a| (s, a))
+                    })
+                    .map_err(|e| warn!(error = %e, "Failed connection"))
+                    else { continue; };
+                
+                if !access.permits(addr.ip()) {
+                    info!(%addr, "Refused connection");
+                    continue;
+                }
+                dispatcher_send.send(Event::Connected(Conn::new(conn), addr)).await?;
+            },
+            // the dispatcher only stops once it has drained or shut down
+            stopped = dispatcher_task => {
+                return stopped;
+            },
+        }
+    }
+    
+    drop(dispatcher_send);
+    dispatcher_task.await?;
+    Ok(Stopped::Shutdown)
+}
+
+/// Writes a file which only the server's own user can read, since the state
+/// file holds the tokens which let users resume their sessions.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+/// Starts listening on an address. An IPv6 wildcard address also accepts
+/// IPv4 clients, even on systems where that isn't the default.
+pub(crate) async fn bind(addr: &str) -> io::Result<TcpListener> {
+    let mut error = None;
+    for addr in addr.to_socket_addrs().await? {
+        match bind_socket(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to listen on")))
+}
+
+fn bind_socket(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
     
-    println!("Waiting for connections...");
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // as std does, so that the server can restart while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(std::net::TcpListener::from(socket).into())
+}
+
+/// The address of a client. IPv4 clients of a dual-stack listener show up
+/// with v4-mapped addresses, which are converted back to plain IPv4.
+fn peer_addr(conn: &TcpStream) -> io::Result<SocketAddr> {
+    conn.peer_addr().map(canonical_addr)
+}
+
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Accepts observer connections, which get a live feed of the lobby but
+/// cannot make any requests.
+async fn accept_observers(mirror_addr: String, mut access: AccessControl, mut dispatcher: Sender<Event>) -> err::Result {
+    let listener = bind(&mirror_addr).await?;
+    info!(addr = %mirror_addr, "Listening for observers");
     
     let mut incoming = listener.incoming();
     while let Some(conn) = incoming.next().await {
         let Ok((conn, addr)) = conn
             .and_then(|s| {
-                s.peer_addr().map(|a| (s, a))
+                peer_addr(&s).map(|a| (s, a))
             })
-            .map_err(|e| println!("Failed connection: {e}"))
+            .map_err(|e| warn!(error = %e, "Failed observer connection"))
             else { continue; };
         
-        dispatcher_send.send(Event::Connected(conn, addr)).await?;
+        if !access.permits(addr.ip()) {
+            info!(%addr, "Refused observer connection");
+            continue;
+        }
+        dispatcher.send(Event::Observer(Conn::new(conn), addr)).await?;
     }
+    Ok(())
+}
+
+/// Writes the lobby feed to an observer until they disconnect. Anything the
+/// observer sends is ignored.
+async fn observe(conn: Conn, addr: SocketAddr, codec: Arc<dyn Codec>, messages: Receiver<response::Message>) -> err::Result {
+    info!(%addr, "Observer connected");
+    let mut messages = messages.fuse();
+    let mut in_ = Box::pin(bounded_lines(io::BufReader::new(conn.reader), MAX_OBSERVER_LINE_LENGTH)).fuse();
+    let mut out = io::BufWriter::new(conn.writer);
     
-    drop(dispatcher_send);
-    dispatcher_task.await;
+    loop {
+        futures::select! {
+            line = in_.next() => {
+                let Some(Ok(Line::Complete(_))) = line else { break; };
+            },
+            msg = messages.next() => {
+                let Some(msg) = msg else { break; };
+                write_message(&mut out, codec.as_ref(), &msg).await?;
+            },
+        }
+    }
+    info!(%addr, "Observer disconnected");
     Ok(())
 }
 
+/// Serves clients through a relay, for hosts which cannot accept inbound
+/// connections. The server keeps `RELAY_CONNECTIONS` idle connections open
+/// to the relay. When the relay pairs one with a client, it sends a line
+/// `PAIRED`, or `PAIRED|<client address>`, before anything from the client;
+/// the connection is then handed to the dispatcher, which speaks first as
+/// usual, and another one is opened in its place.
+pub(crate) async fn start_relay(server: Server, relay_addr: &str) -> err::Result<Stopped> {
+    let dispatcher = Dispatcher::new(server);
+    let dispatcher_send = dispatcher.out.clone();
+    let mut dispatcher_task = task::spawn(dispatcher.run()).fuse();
+    
+    info!(relay = %relay_addr, "Serving through relay");
+    
+    let dialers = (0..RELAY_CONNECTIONS)
+        .map(|_| serve_relay_connections(relay_addr, dispatcher_send.clone()));
+    futures::select! {
+        r = futures::future::try_join_all(dialers).fuse() => r.map(|_| Stopped::Shutdown),
+        // the dispatcher only stops once it has drained or shut down
+        stopped = dispatcher_task => stopped,
+    }
+}
+
+/// Keeps one connection open to the relay, handing it to the dispatcher
+/// each time a client is paired with it.
+async fn serve_relay_connections(relay_addr: &str, mut dispatcher: Sender<Event>) -> err::Result {
+    loop {
+        match dial_relay(relay_addr).await {
+            Ok((conn, addr)) => dispatcher.send(Event::Connected(Conn::new(conn), addr)).await?,
+            Err(e) => {
+                warn!(error = %e, "Failed relay connection");
+                task::sleep(RELAY_RETRY_DELAY).await;
+            },
+        }
+    }
+}
+
+/// Opens a connection to the relay, and waits until a client has been paired
+/// with it. The address is the client's, if the relay says what it is.
+async fn dial_relay(relay_addr: &str) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let mut conn = TcpStream::connect(relay_addr).await?;
+        let relay = conn.peer_addr()?;
+        if let Some(addr) = read_pairing(&mut conn, relay).await? {
+            return Ok((conn, addr));
+        }
+        // the relay closed the connection without pairing it; try again
+        task::sleep(RELAY_RETRY_DELAY).await;
+    }
+}
+
+/// Reads the relay's `PAIRED` line, one byte at a time so that nothing the
+/// client sends after it is taken too. Returns `None` if the relay closed
+/// the connection instead.
+async fn read_pairing(conn: &mut TcpStream, relay: SocketAddr) -> io::Result<Option<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid pairing from relay");
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        if conn.read(&mut byte).await? == 0 {
+            return Ok(None);
+        } else if byte[0] == b'\n' {
+            break;
+        } else if line.len() >= MAX_PAIRING_LINE_LENGTH {
+            return Err(invalid());
+        }
+        line.push(byte[0]);
+    }
+    
+    let line = std::str::from_utf8(&line).map_err(|_| invalid())?.trim_end_matches('\r');
+    match line.split_once('|') {
+        None if line == "PAIRED" => Ok(Some(relay)),
+        Some(("PAIRED", addr)) => addr.parse().map(Some).map_err(|_| invalid()),
+        _ => Err(invalid()),
+    }
+}
+
 pub(crate) enum Event {
-    Connected(TcpStream, SocketAddr),
-    Request(UserID, request::Request),
-    Disconnected(UserID, Receiver<response::Message>),
+    Connected(Conn, SocketAddr),
+    Observer(Conn, SocketAddr),
+    /// A connection in the waiting room has waited too long.
+    WaitExpired(u64),
+    /// A request, with the ID the client gave it, if any.
+    Request(UserID, Option<RequestID>, request::Request),
+    /// The authenticator said which account a user signed in with.
+    Authenticated(UserID, String),
+    Disconnected(Receiver<response::Message>),
+    /// A disconnected user's grace period has ended; the number identifies
+    /// which disconnection it was for, in case they resumed and dropped again.
+    GraceExpired(UserID, u64),
+    /// A request through the HTTP admin API.
+    Admin(AdminQuery, oneshot::Sender<AdminReply>),
+    /// The process received SIGHUP, so the config should be re-read.
+    Reload,
+    /// The process received SIGINT or SIGTERM, so the server should save
+    /// its state and stop.
+    Shutdown,
+    /// Time to save users and rooms to the state file.
+    Snapshot,
+    /// Time to look for abandoned rooms.
+    SweepRooms,
+    /// Time to ping users, to measure their latency.
+    PingUsers,
+    StartDrain,
+    DrainDeadline,
+    /// A datagram for the UDP relay, with the socket it arrived on.
+    Datagram(Arc<UdpSocket>, SocketAddr, Vec<u8>),
+}
+
+/// A connection which is waiting for the server to have a free slot. Its
+/// own task writes to it, so that a client which stops reading can't hold
+/// up the dispatcher; dropping this refuses the connection.
+struct Waiting {
+    ticket: u64,
+    addr: SocketAddr,
+    /// The connection's place in the queue, counting from 1.
+    position: Arc<AtomicUsize>,
+    /// Tells the task that the position has changed.
+    moved: Sender<()>,
+    admitted: oneshot::Sender<Serve>,
+}
+
+/// Starts serving a connection which has been given a user.
+type Serve = Box<dyn FnOnce(Conn) + Send>;
+
+/// A user's queue of messages waiting to be written to their connection.
+/// The queue has a fixed capacity; `queued` counts what is in it, so that
+/// its depth can be reported without asking the connection.
+struct Outbox {
+    sender: Sender<response::Message>,
+    queued: Arc<AtomicUsize>,
+    /// What the connection has received and sent so far.
+    traffic: Arc<Traffic>,
+    /// How large payloads are compressed, if the client asked for it.
+    compression: Option<Compression>,
+    /// Whether the user has been warned that their queue is nearly full,
+    /// since it was last below the threshold.
+    warned: bool,
 }
 
-struct Dispatcher {
+/// A message as it was last compressed.
+struct Compressed {
+    msg: response::Message,
+    compression: Compression,
+    /// `None` if compressing didn't make the message any smaller.
+    bytes: Option<Arc<[u8]>>,
+}
+
+pub(crate) struct Dispatcher {
     server: Server,
-    conns: HashMap<UserID, Sender<response::Message>>,
+    conns: HashMap<UserID, Outbox>,
+    /// Users whose connections have dropped, who will be removed unless
+    /// they resume within the grace period.
+    disconnected: HashMap<UserID, u64>,
+    /// The users whose sessions connections have resumed, by the IDs the
+    /// connections had before; requests already sent by a connection still
+    /// carry its old ID.
+    resumed: HashMap<UserID, UserID>,
+    disconnections: u64,
+    /// Messages for users in their grace period, which are sent if they
+    /// resume.
+    held: HashMap<UserID, Vec<response::Message>>,
+    /// Relayed messages kept for users who asked for acknowledgements.
+    acks: HashMap<UserID, Unacked>,
+    waiting: VecDeque<Waiting>,
+    tickets: u64,
+    /// How many messages couldn't be delivered.
+    undelivered: u64,
+    /// How many of those were dropped because a user's queue was full.
+    overflowed: u64,
+    /// Whether the process was told to stop, rather than stopping by itself
+    /// for a scheduled restart.
+    shutting_down: bool,
+    /// What every connection has received and sent, including those which
+    /// have since closed.
+    traffic: Arc<Traffic>,
+    /// Users whose message queues are full, who are disconnected once the
+    /// current event has been handled.
+    lagging: Vec<UserID>,
+    /// Users who couldn't be sent messages, who are removed once the current
+    /// event has been handled.
+    stale: Vec<UserID>,
+    codec: Arc<dyn Codec>,
+    /// The message compressed last, and how; a broadcast sends the same
+    /// message to each member in turn, so it is only compressed once.
+    compressed: Option<Compressed>,
+    observers: Vec<Sender<response::Message>>,
+    /// The lobby as observers last saw it.
+    lobby: mirror::Lobby,
+    /// Users who asked for changes to the room listing.
+    subscribers: HashSet<UserID>,
+    /// The room listing as subscribers last saw it.
+    listing: mirror::Listing,
+    recorder: Option<Recorder>,
+    /// Present if datagrams are relayed over UDP.
+    udp: Option<UdpRelay>,
     in_: Receiver<Event>,
     out: Sender<Event>,
 }
 
 impl Dispatcher {
-    fn new(server: Server) -> Dispatcher {
-        let (out, in_) = mpsc::unbounded();
+    pub(crate) fn new(server: Server) -> Dispatcher {
+        let (out, in_) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let recorder = server.recording().and_then(|path| {
+            Recorder::create(path)
+                .map_err(|e| warn!(path = %path.display(), error = %e, "Failed to open recording"))
+                .ok()
+        });
         Dispatcher {
+            codec: server.codec().clone(),
+            udp: server.udp_port().map(|_| UdpRelay::new()),
             server,
             conns: HashMap::new(),
+            disconnected: HashMap::new(),
+            resumed: HashMap::new(),
+            disconnections: 0,
+            held: HashMap::new(),
+            acks: HashMap::new(),
+            waiting: VecDeque::new(),
+            tickets: 0,
+            undelivered: 0,
+            overflowed: 0,
+            shutting_down: false,
+            traffic: Arc::default(),
+            lagging: Vec::new(),
+            stale: Vec::new(),
+            compressed: None,
+            observers: Vec::new(),
+            lobby: mirror::Lobby::new(),
+            subscribers: HashSet::new(),
+            listing: mirror::Listing::new(),
+            recorder,
             in_,
             out,
         }
     }
     
-    fn add_user(&mut self) -> Option<(UserID, Receiver<response::Message>)> {
+    fn add_user(&mut self) -> Option<(UserID, Receiver<response::Message>, Arc<AtomicUsize>)> {
         let user_id = self.server.add_user()?;
-        let (sender, receiver) = mpsc::unbounded();
-        self.conns.insert(user_id, sender);
-        Some((user_id, receiver))
+        // the ID may have belonged to a connection which resumed a session
+        self.resumed.remove(&user_id);
+        let token = self.server.resume_token(user_id)?.to_string();
+        let welcome = response::Message::Welcome(user_id, token, Box::new(self.server.info()));
+        self.record(user_id, Recorded::Connected);
+        self.record(user_id, Recorded::message(&welcome));
+        let (mut sender, receiver) = mpsc::channel(self.server.max_queued_messages());
+        sender.try_send(welcome)
+            .ok()?;
+        let mut n = 1;
+        let motd: Vec<_> = self.server.motd().collect();
+        for msg in motd {
+            self.record(user_id, Recorded::message(&msg));
+            // a message of the day too long for the queue is cut short
+            if sender.try_send(msg).is_err() { break; }
+            n += 1;
+        }
+        let queued = Arc::new(AtomicUsize::new(n));
+        let traffic = Arc::new(Traffic::counted_in(&self.traffic));
+        self.conns.insert(user_id, Outbox {sender, queued: queued.clone(), traffic, compression: None, warned: false});
+        Some((user_id, receiver, queued))
+    }
+    
+    /// Finds which user a connection's message queue currently belongs to;
+    /// this changes if the connection resumes another user's session, and
+    /// the user may have since been taken over by another connection.
+    fn owner_of(&self, messages: &Receiver<response::Message>) -> Option<UserID> {
+        self.conns.iter()
+            .find(|(_, out)| out.sender.is_connected_to(messages))
+            .map(|(&user_id, _)| user_id)
     }
     
     async fn remove_user(&mut self, user_id: UserID) -> err::Result {
         let r = self.server.remove_user(user_id)?;
+        self.record(user_id, Recorded::Removed);
+        self.forget_user(user_id, r).await;
+        Ok(())
+    }
+    
+    /// Removes a user whose grace period ended before they resumed.
+    async fn time_out_user(&mut self, user_id: UserID) -> err::Result {
+        let r = self.server.time_out_user(user_id)?;
+        self.record(user_id, Recorded::TimedOut);
+        self.forget_user(user_id, r).await;
+        Ok(())
+    }
+    
+    /// Tells everyone concerned that a user has been removed, and forgets
+    /// everything the dispatcher kept for them.
+    async fn forget_user(&mut self, user_id: UserID, r: response::Response) {
         self.dispatch_response(user_id, r).await;
         self.conns.remove(&user_id);
+        self.held.remove(&user_id);
+        self.acks.remove(&user_id);
+        self.subscribers.remove(&user_id);
+        self.resumed.retain(|_, &mut id| id != user_id);
+        if let Some(udp) = &mut self.udp {
+            udp.remove_user(user_id);
+        }
+    }
+    
+    /// Which user a connection's events are for, if it has resumed another
+    /// user's session since it sent them.
+    fn current_id(&self, user_id: UserID) -> UserID {
+        self.resumed.get(&user_id)
+            .copied()
+            .unwrap_or(user_id)
+    }
+    
+    /// Starts serving a new connection, or gives it back if the server is full.
+    fn connect(&mut self, conn: Conn, addr: SocketAddr) -> Result<(), (Conn, SocketAddr)> {
+        let Some((_, serve)) = self.admit(addr) else {
+            return Err((conn, addr));
+        };
+        serve(conn);
         Ok(())
     }
     
-    async fn send(&mut self, user_id: UserID, msg: response::Message) {
-        if let Some(out) = self.conns.get_mut(&user_id) {
-            if let Err(e) = out.send(msg).await {
-                println!("Error dispatching message to User #{user_id}: {e}");
+    /// Gives a connection from this address a user, if the server isn't
+    /// full. The connection itself is then served by calling the returned
+    /// function, which needn't be done in the dispatcher.
+    fn admit(&mut self, addr: SocketAddr) -> Option<(UserID, Serve)> {
+        let (id, mut user_messages, queued) = self.add_user()?;
+        let session = self.server.session_id(id).unwrap_or_default().to_string();
+        let ident = UserIdent {id, session, addr, instance: self.server.name().cloned()};
+        let traffic = self.conns.get(&id)
+            .map_or_else(Arc::default, |out| Arc::clone(&out.traffic));
+        let clock = self.server.clock();
+        let limiter = self.server.rate_limit()
+            .map(|limit| RateLimiter::new(limit, clock.now()));
+        let max_request_length = self.server.max_request_length();
+        let max_payload_length = self.server.max_payload_length();
+        let write_timeout = self.server.write_timeout();
+        let authenticator = self.server.authenticator().clone();
+        let codec = self.codec.clone();
+        let dispatcher = self.out.clone();
+        Some((id, Box::new(move |conn| {
+            let mut disconnect_handle = dispatcher.clone();
+            let user = UserHandle {ident, conn, dispatcher, limiter, clock, max_request_length, max_payload_length, write_timeout, queued, traffic, authenticator, codec};
+            err::spawn_logged_task(async move {
+                let r = user.run(&mut user_messages).await;
+                disconnect_handle.send(Event::Disconnected(user_messages)).await?;
+                r
+            });
+        })))
+    }
+    
+    /// Puts a connection in the waiting room if there is space, or otherwise
+    /// turns it away.
+    fn wait_or_reject(&mut self, conn: Conn, addr: SocketAddr) {
+        // forget connections which dropped while waiting
+        self.waiting.retain(|waiting| !waiting.admitted.is_canceled());
+        if self.waiting.len() >= self.server.waiting_room_capacity() {
+            info!(%addr, "Refused connection: connection limit reached");
+            let (codec, write_timeout) = (self.codec.clone(), self.server.write_timeout());
+            err::spawn_logged_task(async move {
+                refuse(conn, codec.as_ref(), write_timeout).await;
+                Ok(())
+            });
+            return;
+        }
+        
+        info!(%addr, "Connection is waiting for a free slot");
+        self.tickets += 1;
+        let ticket = self.tickets;
+        let position = Arc::new(AtomicUsize::new(self.waiting.len() + 1));
+        let (moved, moves) = mpsc::channel(1);
+        let (admitted, admission) = oneshot::channel();
+        self.waiting.push_back(Waiting {ticket, addr, position: position.clone(), moved, admitted});
+        
+        let room = WaitingRoom {
+            codec: self.codec.clone(),
+            write_timeout: self.server.write_timeout(),
+            clock: self.server.clock(),
+        };
+        err::spawn_logged_task(async move {
+            room.wait(conn, addr, position, moves, admission).await;
+            Ok(())
+        });
+        
+        let mut out = self.out.clone();
+        let sleep = self.server.clock().sleep(self.server.waiting_timeout());
+        err::spawn_logged_task(async move {
+            sleep.await;
+            out.send(Event::WaitExpired(ticket)).await?;
+            Ok(())
+        });
+    }
+    
+    /// Lets waiting connections in, for as long as there are free slots.
+    async fn admit_waiting(&mut self) {
+        let mut admitted = false;
+        while let Some(addr) = self.waiting.front().map(|waiting| waiting.addr) {
+            if !self.waiting[0].admitted.is_canceled() {
+                let Some((user_id, serve)) = self.admit(addr) else { break; };
+                let Some(waiting) = self.waiting.pop_front() else { break; };
+                if waiting.admitted.send(serve).is_err() {
+                    // the connection dropped in the meantime
+                    err::log_invalid_state(self.remove_user(user_id).await).ok();
+                }
+            } else {
+                self.waiting.pop_front();
             }
+            admitted = true;
+        }
+        if admitted {
+            self.send_positions(0);
+        }
+    }
+    
+    fn expire_waiting(&mut self, ticket: u64) {
+        let Some(index) = self.waiting.iter().position(|w| w.ticket == ticket) else {
+            return;
+        };
+        if let Some(Waiting {addr, ..}) = self.waiting.remove(index) {
+            info!(%addr, "Refused connection: waited too long");
+        }
+        self.send_positions(index);
+    }
+    
+    /// Tells waiting connections their positions in the queue, from the given
+    /// index onwards, since those before it have not moved.
+    fn send_positions(&mut self, from: usize) {
+        for (i, waiting) in self.waiting.iter_mut().enumerate().skip(from) {
+            waiting.position.store(i + 1, Ordering::Relaxed);
+            // if the channel is full, the task hasn't yet seen the last move
+            waiting.moved.try_send(()).ok();
+        }
+    }
+    
+    /// Registers a datagram's endpoint if it holds a token, or else relays it
+    /// from the endpoint's user. Datagrams which can't be relayed are
+    /// dropped without any reply.
+    async fn relay_datagram(&mut self, socket: Arc<UdpSocket>, addr: SocketAddr, datagram: &[u8]) {
+        let Some(udp) = &mut self.udp else { return; };
+        let Some(from_user_id) = udp.user_at(addr) else {
+            if let Some(user_id) = udp.register(socket, addr, datagram) {
+                info!(user_id, %addr, "Registered UDP endpoint");
+                udp.send(user_id, udp::REGISTERED).await;
+            }
+            return;
+        };
+        let Some((room_id, payload)) = udp::parse(datagram) else {
+            // most likely the token again, because the reply was lost
+            udp.send(from_user_id, udp::REGISTERED).await;
+            return;
+        };
+        match self.server.datagram_recipients(from_user_id, room_id) {
+            Ok(recipients) => {
+                let relayed = udp::relayed(room_id, from_user_id, payload);
+                for user_id in recipients {
+                    udp.send(user_id, &relayed).await;
+                }
+            },
+            Err(e) => debug!(user_id = from_user_id, room_id, outcome = %e, "Dropped datagram"),
+        }
+    }
+    
+    /// Keeps a user whose connection has dropped for the grace period, and
+    /// then removes them if they haven't resumed.
+    async fn disconnect_user(&mut self, user_id: UserID) -> err::Result {
+        let grace = self.server.disconnect_grace();
+        if grace.is_zero() {
+            return self.remove_user(user_id).await;
+        }
+        
+        self.conns.remove(&user_id);
+        let r = self.server.disconnect_user(user_id)?;
+        self.record(user_id, Recorded::Disconnected);
+        self.dispatch_response(user_id, r).await;
+        self.start_grace(user_id);
+        Ok(())
+    }
+    
+    /// Removes a disconnected user unless they resume within the grace
+    /// period.
+    fn start_grace(&mut self, user_id: UserID) {
+        let grace = self.server.disconnect_grace();
+        self.disconnections += 1;
+        let n = self.disconnections;
+        self.disconnected.insert(user_id, n);
+        let mut out = self.out.clone();
+        let sleep = self.server.clock().sleep(grace);
+        err::spawn_logged_task(async move {
+            sleep.await;
+            out.send(Event::GraceExpired(user_id, n)).await?;
+            Ok(())
+        });
+    }
+    
+    /// Restores users and rooms from the state file, if there is one. The
+    /// users have the usual grace period to resume their sessions.
+    fn restore_snapshot(&mut self) {
+        let Some(path) = self.server.state_file().map(Path::to_path_buf) else { return; };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Failed to read state file");
+                return;
+            },
+        };
+        match snapshot::parse(&text) {
+            Ok(snapshot) => {
+                self.server.restore(snapshot);
+                info!(path = %path.display(), summary = %self.server.summary(), "Restored state");
+                for user_id in self.server.disconnected_users() {
+                    self.start_grace(user_id);
+                }
+            },
+            Err(e) => warn!(path = %path.display(), error = %e, "Invalid state file"),
+        }
+    }
+    
+    /// Saves users and rooms to the state file, replacing it only once the
+    /// new snapshot has been written in full.
+    fn save_snapshot(&self) {
+        let Some(path) = self.server.state_file() else { return; };
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let saved = write_private(Path::new(&temp), self.server.snapshot().as_bytes())
+            .and_then(|()| std::fs::rename(&temp, path));
+        match saved {
+            Ok(()) => debug!(path = %path.display(), "Saved state"),
+            Err(e) => warn!(path = %path.display(), error = %e, "Failed to save state"),
+        }
+    }
+    
+    async fn sweep_rooms_periodically(clock: Arc<dyn Clock>, mut out: Sender<Event>) -> err::Result {
+        loop {
+            clock.sleep(ROOM_SWEEP_INTERVAL).await;
+            out.send(Event::SweepRooms).await?;
+        }
+    }
+    
+    async fn ping_users_periodically(clock: Arc<dyn Clock>, mut out: Sender<Event>) -> err::Result {
+        loop {
+            clock.sleep(PING_INTERVAL).await;
+            out.send(Event::PingUsers).await?;
+        }
+    }
+    
+    async fn snapshot_periodically(interval: Duration, mut out: Sender<Event>) -> err::Result {
+        loop {
+            task::sleep(interval).await;
+            out.send(Event::Snapshot).await?;
+        }
+    }
+    
+    /// Writes an entry to the recording, if there is one.
+    fn record(&mut self, user_id: UserID, what: Recorded) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&Entry {at: self.server.clock().now(), user_id, what});
+        }
+    }
+    
+    /// Sends a message to a user, returning whether it was delivered. This
+    /// doesn't wait for space in the user's queue; if it is full, the user
+    /// has fallen too far behind, and is disconnected as if their connection
+    /// had dropped rather than holding up everyone else's messages. Observers
+    /// which fall behind are likewise dropped.
+    pub(crate) async fn send(&mut self, user_id: UserID, msg: response::Message) -> bool {
+        if self.recorder.is_some() {
+            self.record(user_id, Recorded::message(&msg));
+        }
+        self.deliver(user_id, msg)
+    }
+    
+    /// Like `send`, but not recorded. Relayed messages are numbered for users
+    /// who asked for acknowledgements.
+    fn deliver(&mut self, user_id: UserID, msg: response::Message) -> bool {
+        let msg = match self.acks.get_mut(&user_id) {
+            Some(unacked) if msg.payload().is_some() => {
+                let msg = unacked.push(msg, self.server.max_queued_messages());
+                // the client will ask for it again once it has resumed
+                if unacked.is_resuming() || self.disconnected.contains_key(&user_id) {
+                    return true;
+                }
+                msg
+            },
+            _ => msg,
+        };
+        self.enqueue(user_id, msg)
+    }
+    
+    /// Puts a message in a user's queue. A message for a user in their grace
+    /// period is held until they resume.
+    fn enqueue(&mut self, user_id: UserID, msg: response::Message) -> bool {
+        let threshold = self.server.compress_threshold();
+        let Some(out) = self.conns.get_mut(&user_id) else {
+            return self.hold(user_id, msg);
+        };
+        let msg = match out.compression {
+            Some(c) if msg.payload().is_some_and(|payload| payload.len() > threshold) => {
+                compress(&mut self.compressed, self.codec.as_ref(), c, msg)
+            },
+            _ => msg,
+        };
+        out.queued.fetch_add(1, Ordering::Relaxed);
+        let r = out.sender.try_send(msg);
+        if r.is_err() {
+            out.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        match r {
+            Ok(()) => {
+                let queued = out.queued.load(Ordering::Relaxed);
+                let capacity = self.server.max_queued_messages();
+                if !limits::is_near_limit(queued, capacity) {
+                    out.warned = false;
+                } else if !out.warned && queued < capacity && out.sender.try_send(response::Message::Warning(Warning::QueueNearlyFull(queued, capacity))).is_ok() {
+                    out.warned = true;
+                    out.queued.fetch_add(1, Ordering::Relaxed);
+                }
+                true
+            },
+            Err(e) if e.is_full() => {
+                self.overflowed += 1;
+                warn!(user_id, total = self.overflowed, "Disconnecting: too many messages waiting");
+                // dropping the sender ends the connection
+                self.conns.remove(&user_id);
+                self.lagging.push(user_id);
+                false
+            },
+            Err(e) => {
+                warn!(user_id, error = %e, "Error dispatching message");
+                false
+            },
         }
     }
     
     async fn dispatch_response(&mut self, user_id: UserID, response: response::Response) {
+        let mut undelivered = Vec::new();
         if let Some(msg) = response.returns {
-            self.send(user_id, msg).await;
+            if !self.send(user_id, msg).await {
+                undelivered.push(user_id);
+            }
         }
         for (other_id, msg) in response.sends.into_iter() {
-            self.send(other_id, msg).await;
+            if !self.send(other_id, msg).await && !undelivered.contains(&other_id) {
+                undelivered.push(other_id);
+            }
+        }
+        
+        for other_id in undelivered {
+            self.handle_undelivered(user_id, other_id).await;
         }
     }
     
-    async fn run(mut self) -> err::Result {
-        while let Some(event) = self.in_.next().await {
-            match event {
-                Event::Connected(conn, addr) => {
-                    if let Some((id, mut user_messages)) = self.add_user() {
-                        let user = UserHandle {
-                            ident: UserIdent {id, addr},
-                            conn,
-                            dispatcher: self.out.clone(),
-                        };
-                        let mut disconnect_handle = self.out.clone();
-                        err::spawn_logged_task(async move {
-                            let r = user.run(&mut user_messages).await;
-                            disconnect_handle.send(Event::Disconnected(id, user_messages)).await?;
-                            r
-                        });
-                    } else {
-                        println!("Failed connection from {addr}: connection limit reached");
-                        let mut writer = io::BufWriter::new(&conn);
-                        write_message(&mut writer, response::SERVER_FULL).await
-                            .ok();
-                    }
-                },
-                Event::Request(user_id, request) => {
-                    let response = self.server.handle_request(user_id, request);
-                    self.dispatch_response(user_id, response).await;
-                },
-                Event::Disconnected(user_id, _) => {
-                    self.remove_user(user_id).await?;
-                },
+    async fn handle_undelivered(&mut self, sender_id: UserID, user_id: UserID) {
+        self.undelivered += 1;
+        let reconnecting = self.disconnected.contains_key(&user_id) || self.lagging.contains(&user_id);
+        warn!(user_id, reconnecting, total = self.undelivered, "Undelivered message");
+        
+        match self.server.undelivered_policy() {
+            UndeliveredPolicy::Log => {},
+            UndeliveredPolicy::NotifySender => {
+                if sender_id != user_id {
+                    self.send(sender_id, response::Message::Undelivered(user_id)).await;
+                }
+            },
+            UndeliveredPolicy::Cleanup => {
+                // users in their grace period are expected to be unreachable
+                if !reconnecting {
+                    self.stale.push(user_id);
+                }
+            },
+        }
+    }
+    
+    /// Keeps a message for a user in their grace period, returning whether it
+    /// was kept. No more messages are held than could wait in the user's
+    /// queue, since the client would have fallen behind anyway.
+    fn hold(&mut self, user_id: UserID, msg: response::Message) -> bool {
+        if !self.disconnected.contains_key(&user_id) {
+            return false;
+        }
+        let held = self.held.entry(user_id).or_default();
+        if held.len() >= self.server.max_queued_messages() {
+            self.overflowed += 1;
+            warn!(user_id, total = self.overflowed, "Dropped message: too many held for reconnection");
+            return false;
+        }
+        held.push(msg);
+        true
+    }
+    
+    /// Gives a user's new connection to the session they resumed, which
+    /// ends the old connection.
+    fn take_over(&mut self, user_id: UserID, old_id: UserID) {
+        if let Some(out) = self.conns.remove(&user_id) {
+            self.conns.insert(old_id, out);
+        }
+        self.resumed.insert(user_id, old_id);
+        self.disconnected.remove(&old_id);
+        // the old session's endpoint is kept, but not this one's
+        if let Some(udp) = &mut self.udp {
+            udp.remove_user(user_id);
+        }
+        self.acks.remove(&user_id);
+        self.subscribers.remove(&user_id);
+        if let Some(unacked) = self.acks.get_mut(&old_id) {
+            unacked.set_resuming(true);
+        }
+    }
+    
+    /// Sends the messages held while a user was disconnected, in order.
+    fn send_held(&mut self, user_id: UserID) {
+        for msg in self.held.remove(&user_id).unwrap_or_default() {
+            if !self.enqueue(user_id, msg) {
+                break;
+            }
+        }
+    }
+    
+    /// Forgets the relayed messages a client has acknowledged. The first
+    /// acknowledgement after resuming also says which messages the client
+    /// missed, and those are sent again.
+    fn ack(&mut self, user_id: UserID, n: u64) -> response::Result<()> {
+        let unacked = self.acks.get_mut(&user_id)
+            .ok_or(response::Error::AcksNotEnabled)?;
+        unacked.ack(n);
+        if unacked.is_resuming() {
+            unacked.set_resuming(false);
+            let missed: Vec<_> = unacked.pending().collect();
+            for msg in missed {
+                if !self.enqueue(user_id, msg) {
+                    break;
+                }
             }
         }
         Ok(())
     }
+    
+    /// Disconnects users whose message queues filled up, and removes users
+    /// who couldn't be sent messages, if the undelivered policy says to.
+    async fn drop_unreachable(&mut self) -> err::Result {
+        loop {
+            if let Some(user_id) = self.lagging.pop() {
+                if self.server.has_user(user_id) && !self.disconnected.contains_key(&user_id) {
+                    err::log_invalid_state(self.disconnect_user(user_id).await)?;
+                }
+            } else if let Some(user_id) = self.stale.pop() {
+                // the user may have been removed since
+                if !self.conns.contains_key(&user_id) && self.server.has_user(user_id) {
+                    info!(user_id, "Removing unreachable user");
+                    self.disconnected.remove(&user_id);
+                    err::log_invalid_state(self.remove_user(user_id).await)?;
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+    
+    /// Tells observers about any changes to the lobby, forgetting observers
+    /// who have disconnected.
+    fn update_observers(&mut self) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let lobby = self.server.lobby();
+        let changes = mirror::changes(&self.lobby, &lobby);
+        self.lobby = lobby;
+        
+        self.observers.retain_mut(|out| {
+            changes.iter()
+                .all(|msg| out.try_send(msg.clone()).is_ok())
+        });
+    }
+    
+    fn add_observer(&mut self, conn: Conn, addr: SocketAddr) {
+        if self.observers.is_empty() {
+            self.lobby = self.server.lobby();
+        }
+        let snapshot = mirror::snapshot(&self.lobby);
+        let (mut out, messages) = mpsc::channel(self.server.max_queued_messages().max(snapshot.len()));
+        for msg in snapshot {
+            out.try_send(msg).ok();
+        }
+        self.observers.push(out);
+        err::spawn_logged_task(observe(conn, addr, self.codec.clone(), messages));
+    }
+    
+    /// Tells subscribers about any changes to the room listing.
+    fn update_subscribers(&mut self) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let listing = self.server.listing();
+        let changes = mirror::listing_changes(&self.listing, &listing);
+        self.listing = listing;
+        
+        let subscribers: Vec<UserID> = self.subscribers.iter().copied().collect();
+        for user_id in subscribers {
+            for msg in &changes {
+                self.deliver(user_id, msg.clone());
+            }
+        }
+    }
+    
+    /// Sends a user every room in the listing, and then its changes until
+    /// they unsubscribe. Subscribing again sends the whole listing again.
+    fn subscribe(&mut self, user_id: UserID) {
+        if self.subscribers.is_empty() {
+            self.listing = self.server.listing();
+        }
+        self.subscribers.insert(user_id);
+        for msg in mirror::listing_snapshot(&self.listing) {
+            self.deliver(user_id, msg);
+        }
+    }
+    
+    async fn admin(&mut self, query: AdminQuery, reply: oneshot::Sender<AdminReply>) -> err::Result {
+        let r = match query {
+            AdminQuery::Users => {
+                let conns = &self.conns;
+                let conn = |id| conns.get(&id).map(|out| (out.queued.load(Ordering::Relaxed), out.traffic.counts()));
+                AdminReply::ok(admin_api::users_json(self.server.users().into_iter(), conn))
+            },
+            AdminQuery::Rooms => {
+                AdminReply::ok(admin_api::rooms_json(self.server.rooms().into_iter()))
+            },
+            AdminQuery::Room(room_id) => match self.server.room(room_id) {
+                Some(room) => AdminReply::ok(admin_api::room_json(room, true)),
+                None => AdminReply::error(404, "No such game"),
+            },
+            AdminQuery::CloseRoom(room_id) => match self.server.force_close_room(room_id) {
+                Ok(response) => {
+                    info!(room_id, "Admin API closed game");
+                    self.record(0, Recorded::ClosedRoom(room_id));
+                    self.dispatch_response(0, response).await;
+                    AdminReply::ok(format!("{{\"closed\":{room_id}}}"))
+                },
+                Err(_) => AdminReply::error(404, "No such game"),
+            },
+            AdminQuery::KickUser(user_id) => {
+                if self.server.has_user(user_id) {
+                    info!(user_id, "Admin API kicked user");
+                    self.record(user_id, Recorded::Kicked);
+                    self.send(user_id, response::Message::Error(response::Error::Kicked)).await;
+                    self.disconnected.remove(&user_id);
+                    err::log_invalid_state(self.remove_user(user_id).await)?;
+                    AdminReply::ok(format!("{{\"kicked\":{user_id}}}"))
+                } else {
+                    AdminReply::error(404, "No such user")
+                }
+            },
+        };
+        reply.send(r).ok();
+        Ok(())
+    }
+    
+    /// Waits until the next scheduled restart, and then tells the dispatcher
+    /// to start draining, and later to give up waiting for games to finish.
+    async fn schedule_restart(schedule: RestartSchedule, clock: Arc<dyn Clock>, mut out: Sender<Event>) -> err::Result {
+        let wait = schedule.until_next(clock.now());
+        info!(secs = wait.as_secs(), "Scheduled restart");
+        clock.sleep(wait).await;
+        out.send(Event::StartDrain).await?;
+        clock.sleep(schedule.drain_timeout).await;
+        out.send(Event::DrainDeadline).await?;
+        Ok(())
+    }
+    
+    /// Tells the dispatcher to reload its config whenever the process
+    /// receives SIGHUP, and to stop when it receives SIGINT or SIGTERM. If
+    /// either of those is received again, the process stops at once.
+    #[cfg(unix)]
+    async fn watch_signals(mut out: Sender<Event>) -> err::Result {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        let stopping = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(signal, 1, stopping.clone())?;
+            signal_hook::flag::register(signal, stopping.clone())?;
+        }
+        let mut signals = signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+        task::spawn_blocking(move || {
+            for signal in signals.forever() {
+                let event = if signal == SIGHUP { Event::Reload } else { Event::Shutdown };
+                if task::block_on(out.send(event)).is_err() {
+                    break;
+                }
+            }
+        }).await;
+        Ok(())
+    }
+    
+    async fn run(mut self) -> err::Result<Stopped> {
+        #[cfg(unix)]
+        if self.server.handles_signals() {
+            err::spawn_logged_task(Dispatcher::watch_signals(self.out.clone()));
+        }
+        err::spawn_logged_task(Dispatcher::sweep_rooms_periodically(self.server.clock(), self.out.clone()));
+        err::spawn_logged_task(Dispatcher::ping_users_periodically(self.server.clock(), self.out.clone()));
+        if self.server.state_file().is_some() {
+            self.restore_snapshot();
+            let interval = self.server.snapshot_interval();
+            err::spawn_logged_task(Dispatcher::snapshot_periodically(interval, self.out.clone()));
+        }
+        if let Some(schedule) = self.server.restart_schedule() {
+            err::spawn_logged_task(Dispatcher::schedule_restart(schedule, self.server.clock(), self.out.clone()));
+        }
+        
+        while let Some(event) = self.in_.next().await {
+            if !self.handle(event).await? {
+                break;
+            }
+        }
+        
+        self.save_snapshot();
+        if self.shutting_down {
+            info!(summary = %self.server.summary(), undelivered = self.undelivered, overflowed = self.overflowed, "Shut down");
+            return Ok(Stopped::Shutdown);
+        }
+        info!(summary = %self.server.summary(), undelivered = self.undelivered, overflowed = self.overflowed, "Restarting");
+        Ok(Stopped::Restart)
+    }
+    
+    /// Handles one event, returning false once the dispatcher should stop.
+    pub(crate) async fn handle(&mut self, event: Event) -> std::result::Result<bool, err::ServerError> {
+        let event = match event {
+            Event::Request(user_id, request_id, request) => Event::Request(self.current_id(user_id), request_id, request),
+            Event::Authenticated(user_id, account) => Event::Authenticated(self.current_id(user_id), account),
+            event => event,
+        };
+        match event {
+            Event::Connected(conn, addr) => {
+                if let Err((conn, addr)) = self.connect(conn, addr) {
+                    self.wait_or_reject(conn, addr);
+                }
+            },
+            Event::WaitExpired(ticket) => {
+                self.expire_waiting(ticket);
+            },
+            Event::Observer(conn, addr) => {
+                self.add_observer(conn, addr);
+            },
+            Event::Request(user_id, request_id, request::Request::Quit) => {
+                self.record(user_id, Recorded::request(request_id, &request::Request::Quit));
+                // quitting deliberately gives up the user's place at once
+                self.disconnected.remove(&user_id);
+                err::log_invalid_state(self.remove_user(user_id).await)?;
+            },
+            Event::Request(user_id, request_id, request @ request::Request::Ack(n)) => {
+                if self.recorder.is_some() {
+                    self.record(user_id, Recorded::request(request_id, &request));
+                }
+                if let Err(e) = self.ack(user_id, n) {
+                    let msg = response::Message::Failed(request.name(), e);
+                    self.send(user_id, msg.replying_to(request_id)).await;
+                }
+            },
+            Event::Request(user_id, request_id, request) => {
+                if self.recorder.is_some() {
+                    self.record(user_id, Recorded::request(request_id, &request));
+                }
+                let request_type = request.name();
+                let mut response = self.server.handle_request(user_id, request);
+                // only the dispatcher knows how much traffic there has been
+                if let Some(response::Message::Stats(.., traffic)) = &mut response.returns {
+                    *traffic = Some(self.traffic.counts());
+                }
+                match &response.returns {
+                    Some(response::Message::Error(e)) => debug!(user_id, request_type, outcome = %e, "Handled request"),
+                    _ => debug!(user_id, request_type, outcome = "ok", "Handled request"),
+                }
+                match &response.returns {
+                    Some(response::Message::Compression(c)) => {
+                        if let Some(out) = self.conns.get_mut(&user_id) {
+                            out.compression = *c;
+                        }
+                    },
+                    Some(response::Message::UdpToken(_, token)) => {
+                        if let Some(udp) = &mut self.udp {
+                            udp.add_token(token.clone(), user_id);
+                        }
+                    },
+                    Some(response::Message::AcksEnabled) => {
+                        self.acks.entry(user_id).or_insert_with(Unacked::new);
+                    },
+                    // the listing is sent before the reply, so the reply
+                    // says that the client is up to date
+                    Some(response::Message::RoomsSubscribed) => {
+                        self.subscribe(user_id);
+                    },
+                    Some(response::Message::RoomsUnsubscribed) => {
+                        self.subscribers.remove(&user_id);
+                    },
+                    _ => {},
+                }
+                let resumed = match response.returns {
+                    Some(response::Message::Resumed(old_id, _)) => {
+                        self.take_over(user_id, old_id);
+                        Some(old_id)
+                    },
+                    _ => None,
+                };
+                let response = response.failing(request_type).replying_to(request_id);
+                self.dispatch_response(resumed.unwrap_or(user_id), response).await;
+                if let Some(old_id) = resumed {
+                    self.send_held(old_id);
+                }
+            },
+            Event::Authenticated(user_id, account) => {
+                self.record(user_id, Recorded::Authenticated(account.clone()));
+                if let Ok(response) = self.server.set_account(user_id, account) {
+                    self.dispatch_response(user_id, response).await;
+                }
+            },
+            Event::Disconnected(messages) => {
+                // the receiver lives until the end of this arm, so messages
+                // sent while the user is disconnected don't fail
+                if let Some(user_id) = self.owner_of(&messages) {
+                    err::log_invalid_state(self.disconnect_user(user_id).await)?;
+                }
+            },
+            Event::Admin(query, reply) => {
+                err::log_invalid_state(self.admin(query, reply).await)?;
+            },
+            Event::Shutdown => {
+                info!("Shutting down");
+                self.shutting_down = true;
+                return Ok(false);
+            },
+            Event::Reload => match self.server.reload() {
+                Ok(()) => info!("Reloaded config"),
+                Err(e) => warn!("{e}"),
+            },
+            Event::Snapshot => {
+                self.save_snapshot();
+            },
+            Event::SweepRooms => {
+                let (closed, response) = self.server.close_idle_rooms();
+                if !closed.is_empty() {
+                    self.record(0, Recorded::Swept);
+                }
+                for room_id in closed {
+                    info!(room_id, "Closed abandoned game");
+                }
+                self.dispatch_response(0, response).await;
+            },
+            Event::PingUsers => {
+                let response = self.server.ping_users();
+                self.record(0, Recorded::Pinged);
+                self.dispatch_response(0, response).await;
+            },
+            Event::GraceExpired(user_id, n) => {
+                if self.disconnected.get(&user_id) == Some(&n) {
+                    info!(user_id, "User did not resume in time");
+                    self.disconnected.remove(&user_id);
+                    err::log_invalid_state(self.time_out_user(user_id).await)?;
+                }
+            },
+            Event::StartDrain => {
+                let Some(schedule) = self.server.restart_schedule() else { return Ok(true); };
+                info!("Draining for scheduled restart");
+                let response = self.server.start_draining(schedule.drain_timeout.as_secs());
+                self.record(0, Recorded::Draining(schedule.drain_timeout.as_secs()));
+                self.dispatch_response(0, response).await;
+            },
+            Event::DrainDeadline => {
+                warn!("Drain deadline reached");
+                return Ok(false);
+            },
+            Event::Datagram(socket, addr, datagram) => {
+                self.relay_datagram(socket, addr, &datagram).await;
+            },
+        }
+        
+        err::log_invalid_state(self.drop_unreachable().await)?;
+        self.admit_waiting().await;
+        self.update_observers();
+        self.update_subscribers();
+        
+        if self.server.is_drained() {
+            info!("All games finished");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+impl Dispatcher {
+    /// The next event, for tests which handle events one at a time instead
+    /// of running the dispatcher.
+    pub(crate) async fn next_event(&mut self) -> Option<Event> {
+        self.in_.next().await
+    }
+    
+    pub(crate) fn server(&self) -> &Server {
+        &self.server
+    }
 }
 
 struct UserHandle {
     ident: UserIdent,
-    conn: TcpStream,
+    conn: Conn,
     dispatcher: Sender<Event>,
+    limiter: Option<RateLimiter>,
+    /// The server's clock, which the rate limit is measured by.
+    clock: Arc<dyn Clock>,
+    max_request_length: usize,
+    /// Checked as requests are decoded, so that an oversized payload isn't
+    /// copied; the server checks again, in case the limit has been lowered.
+    max_payload_length: usize,
+    /// How long to wait for the client to accept a message before giving up
+    /// on it, or zero to wait indefinitely.
+    write_timeout: Duration,
+    /// How many messages are waiting in this connection's queue; shared
+    /// with the dispatcher, which counts them in.
+    queued: Arc<AtomicUsize>,
+    /// Shared with the dispatcher, so that it can report this connection's
+    /// traffic while it is open.
+    traffic: Arc<Traffic>,
+    authenticator: Arc<dyn Authenticator>,
+    codec: Arc<dyn Codec>,
 }
 
 impl UserHandle {
     pub(crate) async fn run(mut self, messages: &mut Receiver<response::Message>) -> err::Result {
-        let ident = self.ident;
-        println!("Connected {ident}");
+        let UserIdent {id, session, addr, instance} = &self.ident;
+        let span = tracing::info_span!("connection", user_id = id, %session, %addr, instance = instance.as_deref());
+        async move {
+            info!("Connected");
+            
+            let mut stats = ConnectionStats::new(Arc::clone(&self.traffic));
+            let r = match self.serve(messages, &mut stats).await {
+                Err(err::ServerError::IO(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    warn!("Disconnecting: client stopped reading");
+                    Ok(())
+                },
+                r => r,
+            };
+            
+            info!(%stats, "Disconnected");
+            r
+        }.instrument(span).await
+    }
+    
+    async fn serve(&mut self, messages: &mut Receiver<response::Message>, stats: &mut ConnectionStats) -> err::Result {
+        let ident = &mut self.ident;
         
         let mut messages = messages.fuse();
-        let mut in_ = io::BufReader::new(&self.conn).lines().fuse();
-        let mut out = io::BufWriter::new(&self.conn);
+        let mut in_ = self.codec.frames(&mut self.conn.reader, self.max_request_length).fuse();
+        let mut out = io::BufWriter::new(&mut self.conn.writer);
         
-        write_message(&mut out, response::Message::Welcome(ident.id)).await?;
+        if self.authenticator.required() {
+            // nothing is sent, not even the welcome, until the client proves
+            // it is one of ours
+            let line = async_std::future::timeout(AUTH_TIMEOUT, in_.next()).await;
+            let verdict = match line {
+                Ok(Some(Ok(Frame::Complete(frame)))) => match self.codec.auth_credential(&frame) {
+                    Some(credential) => self.authenticator.authenticate(ident.addr, &credential).await,
+                    None => Err("first request was not AUTH".into()),
+                },
+                _ => Err("no AUTH request".into()),
+            };
+            if let Ok(account) = &verdict {
+                info!(account, "Authenticated");
+            }
+            if let Ok(Some(account)) = &verdict {
+                self.dispatcher.send(Event::Authenticated(ident.id, account.clone())).await?;
+            }
+            if let Err(reason) = verdict {
+                warn!(reason, "Disconnecting: not authenticated");
+                let msg = response::AUTH_REQUIRED;
+                let bytes = within(self.write_timeout, write_message(&mut out, self.codec.as_ref(), &msg)).await?;
+                stats.record_message(&msg, bytes);
+                return Ok(());
+            }
+        }
         
         loop {
             futures::select! {
-                line = in_.next() => {
-                    let Ok(Some(line)) = line.transpose()
-                        .map_err(|e| println!("Read error from {ident}: {e}"))
+                frame = in_.next() => {
+                    let Ok(Some(frame)) = frame.transpose()
+                        .map_err(|e| warn!(error = %e, "Read error"))
                         else { break; };
+                    let Frame::Complete(frame) = frame else {
+                        warn!("Disconnecting: request too long");
+                        let msg = response::REQUEST_TOO_LONG;
+                        let bytes = within(self.write_timeout, write_message(&mut out, self.codec.as_ref(), &msg)).await?;
+                        stats.record_message(&msg, bytes);
+                        break;
+                    };
+                    
+                    let request = self.codec.decode_within(&frame, self.max_payload_length);
+                    let request_id = self.codec.request_id(&frame);
+                    let request_type = request.as_ref().map_or("invalid", request::Request::name);
+                    debug!(request = %String::from_utf8_lossy(&frame), request_type, "Received");
+                    stats.record_request(frame.len(), request.as_ref().ok());
                     
-                    println!("Received from {ident}: {line}");
-                    match request::parse(&line) {
-                        Some(request) => if request.is_quit() {
+                    let verdict = self.limiter.as_mut()
+                        .map_or(RateVerdict::Allowed, |limiter| limiter.check(self.clock.now()));
+                    if verdict != RateVerdict::Allowed {
+                        let msg = match &request {
+                            Ok(request) => response::RATE_LIMITED.failing(request.name()),
+                            Err(_) => response::RATE_LIMITED,
+                        };
+                        let msg = msg.replying_to(request_id);
+                        let bytes = within(self.write_timeout, write_message(&mut out, self.codec.as_ref(), &msg)).await?;
+                        stats.record_message(&msg, bytes);
+                        if verdict == RateVerdict::Disconnect {
+                            warn!("Disconnecting: too many requests");
                             break;
-                        } else {
-                            self.dispatcher.send(Event::Request(ident.id, request)).await?;
+                        }
+                        continue;
+                    }
+                    if let Some(warning) = self.limiter.as_mut().and_then(RateLimiter::warning) {
+                        let msg = response::Message::Warning(warning);
+                        let bytes = within(self.write_timeout, write_message(&mut out, self.codec.as_ref(), &msg)).await?;
+                        stats.record_message(&msg, bytes);
+                    }
+                    
+                    match request {
+                        Ok(request) => {
+                            let quit = request.is_quit();
+                            self.dispatcher.send(Event::Request(ident.id, request_id, request)).await?;
+                            if quit { break; }
                         },
-                        None => {
-                            write_message(&mut out, response::INVALID_REQUEST).await?;
+                        Err(e) => {
+                            let msg = response::Message::Error(e).replying_to(request_id);
+                            let bytes = within(self.write_timeout, write_message(&mut out, self.codec.as_ref(), &msg)).await?;
+                            stats.record_message(&msg, bytes);
                         },
                     }
                 },
                 msg = messages.next() => {
                     let Some(msg) = msg else { break; };
-                    println!("Sending to {ident}: {msg}");
-                    write_message(&mut out, msg).await?;
+                    // whatever else is already waiting, such as the rest of a
+                    // broadcast, goes in the same write
+                    let mut batch = vec![msg];
+                    while batch.len() < MAX_BATCH_MESSAGES {
+                        // if the queue has closed, the next select finds out
+                        let Ok(Some(msg)) = messages.get_mut().try_next() else { break; };
+                        batch.push(msg);
+                    }
+                    let depth = self.queued.fetch_sub(batch.len(), Ordering::Relaxed);
+                    stats.record_queue_depth(depth);
+                    
+                    let mut bytes = Vec::new();
+                    let mut lengths = Vec::with_capacity(batch.len());
+                    for msg in &batch {
+                        debug!(msg = %msg, "Sending");
+                        if let response::Message::Resumed(user_id, token) = msg {
+                            let session = ids::token_session(token).unwrap_or_default();
+                            info!(user_id, session, "Resumed");
+                            tracing::Span::current()
+                                .record("user_id", user_id)
+                                .record("session", session);
+                            ident.id = *user_id;
+                            ident.session = session.to_string();
+                        }
+                        let start = bytes.len();
+                        self.codec.encode(msg, &mut bytes);
+                        lengths.push(bytes.len() - start);
+                    }
+                    within(self.write_timeout, write_bytes(&mut out, &bytes)).await?;
+                    for (msg, bytes) in batch.iter().zip(lengths) {
+                        stats.record_message(msg, bytes);
+                    }
                 },
             }
         }
-        
-        println!("Disconnected {ident}");
         Ok(())
     }
 }
 
-async fn write_message(writer: &mut io::BufWriter<&TcpStream>, msg: response::Message) -> err::Result {
-    let msg = format!("{msg}\n");
-    writer.write_all(msg.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
+pub(crate) enum Line {
+    Complete(String),
+    /// The line was longer than allowed, so the rest of it was not read.
+    TooLong,
+}
+
+/// Like `BufRead::lines`, but stops reading once a line is longer than
+/// `max_len` bytes, rather than buffering it all.
+pub(crate) fn bounded_lines<R: io::BufRead + Unpin>(reader: R, max_len: usize) -> impl Stream<Item = io::Result<Line>> {
+    futures::stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        let mut buf = Vec::new();
+        loop {
+            let available = match futures::AsyncBufReadExt::fill_buf(&mut reader).await {
+                Ok(available) => available,
+                Err(e) => return Some((Err(e), None)),
+            };
+            if available.is_empty() {
+                // a final line without a newline still counts
+                return (!buf.is_empty()).then(|| (to_line(buf), None));
+            }
+            
+            let newline = available.iter().position(|&b| b == b'\n');
+            let end = newline.unwrap_or(available.len());
+            buf.extend_from_slice(&available[..end]);
+            futures::AsyncBufReadExt::consume_unpin(&mut reader, newline.map_or(end, |i| i + 1));
+            
+            if buf.len() > max_len {
+                return Some((Ok(Line::TooLong), None));
+            } else if newline.is_some() {
+                return Some((to_line(buf), Some(reader)));
+            }
+        }
+    })
+}
+
+fn to_line(mut buf: Vec<u8>) -> io::Result<Line> {
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    String::from_utf8(buf)
+        .map(Line::Complete)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Compresses a message as the codec would have written it, unless that
+/// doesn't make it any smaller. The last message compressed is kept, and
+/// reused if the same message is sent again.
+fn compress(last: &mut Option<Compressed>, codec: &dyn Codec, c: Compression, msg: response::Message) -> response::Message {
+    let compressed = match last {
+        Some(last) if last.compression == c && last.msg == msg => last.bytes.clone(),
+        _ => {
+            let mut bytes = Vec::new();
+            codec.encode(&msg, &mut bytes);
+            let compressed = Some(c.compress(&bytes))
+                .filter(|compressed| compressed.len() < bytes.len())
+                .map(Arc::from);
+            *last = Some(Compressed {msg: msg.clone(), compression: c, bytes: compressed.clone()});
+            compressed
+        },
+    };
+    match compressed {
+        Some(bytes) => response::Message::Compressed(c, bytes),
+        None => msg,
+    }
+}
+
+/// Writes a message, returning the number of bytes written.
+async fn write_message(writer: &mut (impl io::Write + Unpin), codec: &dyn Codec, msg: &response::Message) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    codec.encode(msg, &mut bytes);
+    write_bytes(writer, &bytes).await?;
+    Ok(bytes.len())
+}
+
+/// Writes one or more encoded messages, and flushes them.
+async fn write_bytes(writer: &mut (impl io::Write + Unpin), bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+/// What a task needs to keep a waiting connection informed.
+struct WaitingRoom {
+    codec: Arc<dyn Codec>,
+    write_timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl WaitingRoom {
+    /// Tells a waiting client its position whenever it changes, and every so
+    /// often anyway, until the dispatcher either admits the connection or
+    /// drops its side of the channels to refuse it.
+    async fn wait(&self, mut conn: Conn, addr: SocketAddr, position: Arc<AtomicUsize>, mut moves: Receiver<()>, mut admission: oneshot::Receiver<Serve>) {
+        loop {
+            let msg = response::Message::Waiting(position.load(Ordering::Relaxed));
+            let mut writer = io::BufWriter::new(&mut conn.writer);
+            if within(self.write_timeout, write_message(&mut writer, self.codec.as_ref(), &msg)).await.is_err() {
+                info!(%addr, "Waiting connection dropped");
+                // the connection may have been admitted while it was being
+                // written to, in which case its user must still be served,
+                // so that they are removed once the connection fails
+                admission.close();
+                if let Ok(Some(serve)) = admission.try_recv() {
+                    serve(conn);
+                }
+                return;
+            }
+            
+            let mut tick = self.clock.sleep(WAITING_UPDATE_INTERVAL).fuse();
+            futures::select_biased! {
+                serve = admission => {
+                    match serve {
+                        Ok(serve) => serve(conn),
+                        Err(_) => refuse(conn, self.codec.as_ref(), self.write_timeout).await,
+                    }
+                    return;
+                },
+                _ = moves.next() => {},
+                _ = tick => {},
+            }
+        }
+    }
+}
+
+/// Tells a connection that the server is full, and closes it.
+async fn refuse(mut conn: Conn, codec: &dyn Codec, write_timeout: Duration) {
+    let mut writer = io::BufWriter::new(&mut conn.writer);
+    within(write_timeout, write_message(&mut writer, codec, &response::SERVER_FULL)).await
+        .ok();
+}
+
+/// Waits for a write, failing with `TimedOut` if the client doesn't accept it
+/// in time, so that a client which has stopped reading can't keep its
+/// connection.
+async fn within<T>(timeout: Duration, write: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    if timeout.is_zero() {
+        write.await
+    } else {
+        io::timeout(timeout, write).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+    use crate::auth::NoAuth;
+    use crate::clock::{SimulatedClock, SystemClock};
+    use crate::limits::RateLimit;
+    use crate::request::Request;
+    use crate::server::ServerBuilder;
+    use crate::transport::test::{Memory, Stalled};
+    use super::*;
+    
+    fn user_handle(conn: Conn, dispatcher: Sender<Event>, write_timeout: Duration) -> UserHandle {
+        UserHandle {
+            ident: UserIdent {id: 1, session: String::new(), addr: "127.0.0.1:4000".parse().unwrap(), instance: None},
+            conn,
+            dispatcher,
+            limiter: None,
+            clock: Arc::new(SystemClock),
+            max_request_length: 1024,
+            max_payload_length: usize::MAX,
+            write_timeout,
+            queued: Arc::default(),
+            traffic: Arc::default(),
+            authenticator: Arc::new(NoAuth),
+            codec: Arc::new(crate::codec::PipeCodec),
+        }
+    }
+    
+    /// Keeps each write separately, to count them.
+    struct Writes(Arc<std::sync::Mutex<Vec<Vec<u8>>>>);
+    
+    impl io::Write for Writes {
+        fn poll_write(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+        
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        
+        fn poll_close(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    
+    #[test]
+    fn serve_memory_transport() {
+        let transport = Memory::new("PING|1\nNONSENSE\nQUIT\nPING|2\n");
+        let output = transport.output.clone();
+        let (dispatcher, mut events) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let user = user_handle(Conn::new(transport), dispatcher, Duration::ZERO);
+        let (_messages, mut receiver) = mpsc::channel(1);
+        task::block_on(user.run(&mut receiver)).unwrap();
+        
+        // nothing after QUIT is read
+        let requests: Vec<_> = std::iter::from_fn(|| events.try_next().ok().flatten())
+            .map(|event| match event {
+                Event::Request(user_id, _, request) => (user_id, request),
+                _ => panic!("unexpected event"),
+            })
+            .collect();
+        assert_eq!(vec![(1, Request::Ping(1, None)), (1, Request::Quit)], requests);
+        assert_eq!(b"ERROR|2|Invalid request\n", output.lock().unwrap().as_slice());
+    }
+    
+    #[test]
+    fn count_traffic() {
+        let transport = Memory::new("PING|1\nNONSENSE\nQUIT\n");
+        let total = Arc::default();
+        let traffic = Arc::new(Traffic::counted_in(&total));
+        let (dispatcher, _events) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let user = UserHandle {
+            traffic: Arc::clone(&traffic),
+            ..user_handle(Conn::new(transport), dispatcher, Duration::ZERO)
+        };
+        let (_messages, mut receiver) = mpsc::channel(1);
+        task::block_on(user.run(&mut receiver)).unwrap();
+        
+        let counts = traffic.counts();
+        assert_eq!(3, counts.messages_in);
+        assert_eq!(6 + 8 + 4, counts.bytes_in);
+        assert_eq!(1, counts.messages_out);
+        assert_eq!(counts, total.counts());
+    }
+    
+    #[test]
+    fn rate_limit_by_server_clock() {
+        let transport = Memory::new("PING|1\nPING|2\nQUIT\n");
+        let clock = Arc::new(SimulatedClock::new(SystemTime::UNIX_EPOCH));
+        // the limit refills almost at once, but only as the server's clock
+        // says, and it doesn't move
+        let limit = RateLimit {per_second: 1e9, burst: 1.0};
+        let (dispatcher, mut events) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let user = UserHandle {
+            limiter: Some(RateLimiter::new(limit, clock.now())),
+            clock,
+            ..user_handle(Conn::new(transport), dispatcher, Duration::ZERO)
+        };
+        let (_messages, mut receiver) = mpsc::channel(1);
+        task::block_on(user.run(&mut receiver)).unwrap();
+        
+        let requests: Vec<_> = std::iter::from_fn(|| events.try_next().ok().flatten())
+            .filter_map(|event| match event {
+                Event::Request(_, _, request) => Some(request),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![Request::Ping(1, None)], requests);
+    }
+    
+    #[test]
+    fn serve_message_pack() {
+        let mut input = Vec::new();
+        rmp::encode::write_array_len(&mut input, 2).unwrap();
+        rmp::encode::write_str(&mut input, "PING").unwrap();
+        rmp::encode::write_uint(&mut input, 1).unwrap();
+        rmp::encode::write_array_len(&mut input, 1).unwrap();
+        rmp::encode::write_str(&mut input, "QUIT").unwrap();
+        let transport = Memory::from_bytes(input);
+        let (dispatcher, mut events) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let user = UserHandle {
+            codec: Arc::new(crate::codec::MessagePackCodec),
+            ..user_handle(Conn::new(transport), dispatcher, Duration::ZERO)
+        };
+        let (_messages, mut receiver) = mpsc::channel(1);
+        task::block_on(user.run(&mut receiver)).unwrap();
+        
+        let requests: Vec<_> = std::iter::from_fn(|| events.try_next().ok().flatten())
+            .map(|event| match event {
+                Event::Request(_, _, request) => request,
+                _ => panic!("unexpected event"),
+            })
+            .collect();
+        assert_eq!(vec![Request::Ping(1, None), Request::Quit], requests);
+    }
+    
+    #[test]
+    fn disconnect_stalled_client() {
+        let user = user_handle(Conn::new(Stalled), mpsc::channel(1).0, Duration::from_millis(50));
+        let (mut messages, mut receiver) = mpsc::channel(1);
+        messages.try_send(response::Message::Pong(1)).unwrap();
+        let r = task::block_on(io::timeout(Duration::from_secs(5), async {
+            user.run(&mut receiver).await
+                .map_err(|e| io::Error::other(e.to_string()))
+        }));
+        assert!(r.is_ok(), "stalled client wasn't disconnected: {r:?}");
+    }
+    
+    #[test]
+    fn serve_through_relay() {
+        let r = task::block_on(io::timeout(Duration::from_secs(5), async {
+            let relay = TcpListener::bind("127.0.0.1:0").await?;
+            let relay_addr = relay.local_addr()?.to_string();
+            task::spawn(async move {
+                start_relay(ServerBuilder::new().build(), &relay_addr).await
+            });
+            
+            // every idle connection is open before any client is paired
+            let mut conns = Vec::new();
+            for _ in 0..RELAY_CONNECTIONS {
+                conns.push(relay.accept().await?.0);
+            }
+            // the client doesn't speak first, so pairing must not wait for it
+            let mut conn = conns.pop().unwrap();
+            conn.write_all(b"PAIRED|10.0.0.5:4000\n").await?;
+            let mut lines = io::BufReader::new(conn).lines();
+            lines.next().await.transpose()
+        }));
+        assert!(r.unwrap().is_some_and(|line| line.starts_with("WELCOME|")));
+    }
+    
+    #[test]
+    fn read_relay_pairing() {
+        task::block_on(async {
+            let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let relay_addr = relay.local_addr().unwrap();
+            task::spawn(async move {
+                for pairing in ["PAIRED\r\nPING|1\n", "PAIRED|10.0.0.5:4000\n", "HELLO\n"] {
+                    let (mut conn, _) = relay.accept().await.unwrap();
+                    conn.write_all(pairing.as_bytes()).await.unwrap();
+                }
+            });
+            
+            let mut conn = TcpStream::connect(relay_addr).await.unwrap();
+            assert_eq!(Some(relay_addr), read_pairing(&mut conn, relay_addr).await.unwrap());
+            // what the client sends is left for the dispatcher
+            let mut rest = String::new();
+            conn.read_to_string(&mut rest).await.unwrap();
+            assert_eq!("PING|1\n", rest);
+            
+            let mut conn = TcpStream::connect(relay_addr).await.unwrap();
+            let client: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+            assert_eq!(Some(client), read_pairing(&mut conn, relay_addr).await.unwrap());
+            
+            let mut conn = TcpStream::connect(relay_addr).await.unwrap();
+            assert!(read_pairing(&mut conn, relay_addr).await.is_err());
+        });
+    }
+    
+    #[test]
+    fn stalled_waiting_connection() {
+        let r = task::block_on(io::timeout(Duration::from_secs(5), async {
+            let server = ServerBuilder::new()
+                .max_connections(1)
+                .waiting_room(2, Duration::from_secs(60))
+                .write_timeout(Duration::from_millis(50))
+                .build();
+            let mut dispatcher = Dispatcher::new(server);
+            dispatcher.add_user().unwrap();
+            
+            let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+            dispatcher.wait_or_reject(Conn::new(Stalled), addr);
+            let transport = Memory::new("");
+            let output = Arc::clone(&transport.output);
+            dispatcher.wait_or_reject(Conn::new(transport), addr);
+            
+            while output.lock().unwrap().is_empty() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let written = output.lock().unwrap().clone();
+            Ok(written)
+        }));
+        assert_eq!(b"WAITING|2\n".to_vec(), r.unwrap());
+    }
+    
+    #[test]
+    fn coalesce_queued_messages() {
+        let writes = Arc::default();
+        let conn = Conn {reader: Box::new(Stalled), writer: Box::new(Writes(Arc::clone(&writes)))};
+        let queued = Arc::new(AtomicUsize::new(3));
+        let user = UserHandle {queued: Arc::clone(&queued), ..user_handle(conn, mpsc::channel(1).0, Duration::ZERO)};
+        let (mut messages, mut receiver) = mpsc::channel(4);
+        for i in 1..=3 {
+            messages.try_send(response::Message::Pong(i)).unwrap();
+        }
+        drop(messages);
+        task::block_on(user.run(&mut receiver)).unwrap();
+        
+        assert_eq!(vec![b"PONG|1\nPONG|2\nPONG|3\n".to_vec()], *writes.lock().unwrap());
+        assert_eq!(0, queued.load(Ordering::Relaxed));
+    }
+    
+    #[test]
+    fn disconnect_lagging_user() {
+        task::block_on(async {
+            let mut dispatcher = Dispatcher::new(ServerBuilder::new().max_queued_messages(8).build());
+            let (user_id, messages, queued) = dispatcher.add_user().unwrap();
+            
+            // the welcome is already waiting
+            let mut sent = 1;
+            while sent < 20 && dispatcher.send(user_id, response::Message::Pong(0)).await {
+                sent += 1;
+            }
+            assert!((8..20).contains(&sent));
+            assert_eq!(sent, queued.load(Ordering::Relaxed));
+            assert_eq!(1, dispatcher.overflowed);
+            
+            dispatcher.drop_unreachable().await.unwrap();
+            assert!(!dispatcher.server.has_user(user_id));
+            // what was already queued is still written, and then the connection ends
+            assert_eq!(sent, messages.count().await);
+        });
+    }
+    
+    #[test]
+    fn warn_before_queue_full() {
+        task::block_on(async {
+            let mut dispatcher = Dispatcher::new(ServerBuilder::new().max_queued_messages(20).build());
+            let (user_id, messages, queued) = dispatcher.add_user().unwrap();
+            
+            // the welcome is already waiting
+            for _ in 1..18 {
+                assert!(dispatcher.send(user_id, response::Message::Pong(0)).await);
+            }
+            assert_eq!(19, queued.load(Ordering::Relaxed));
+            assert!(dispatcher.send(user_id, response::Message::Pong(0)).await);
+            
+            drop(dispatcher);
+            let messages: Vec<_> = messages.collect().await;
+            let warnings: Vec<_> = messages.iter()
+                .filter(|msg| matches!(msg, response::Message::Warning(_)))
+                .collect();
+            assert_eq!([&response::Message::Warning(Warning::QueueNearlyFull(18, 20))], warnings[..]);
+            assert_eq!(20, messages.len());
+        });
+    }
+    
+    #[test]
+    fn shut_down() {
+        task::block_on(async {
+            let mut dispatcher = Dispatcher::new(ServerBuilder::new().build());
+            assert!(dispatcher.handle(Event::SweepRooms).await.unwrap());
+            assert!(!dispatcher.handle(Event::Shutdown).await.unwrap());
+            assert!(dispatcher.shutting_down);
+            
+            let dispatcher = Dispatcher::new(ServerBuilder::new().build());
+            dispatcher.out.clone().send(Event::Shutdown).await.unwrap();
+            assert_eq!(Stopped::Shutdown, dispatcher.run().await.unwrap());
+        });
+    }
+    
+    #[test]
+    fn motd_after_welcome() {
+        task::block_on(async {
+            let server = ServerBuilder::new()
+                .motd(vec!["Be nice".into(), "Tournament at 8pm".into()])
+                .build();
+            let mut dispatcher = Dispatcher::new(server);
+            let (user_id, messages, queued) = dispatcher.add_user().unwrap();
+            assert_eq!(3, queued.load(Ordering::Relaxed));
+            
+            drop(dispatcher);
+            let messages: Vec<_> = messages.collect().await;
+            assert!(matches!(messages[0], response::Message::Welcome(id, ..) if id == user_id));
+            assert_eq!(
+                [response::Message::Motd("Be nice".into()), response::Message::Motd("Tournament at 8pm".into())],
+                messages[1..],
+            );
+        });
+    }
+    
+    #[test]
+    fn hold_messages_until_resumed() {
+        task::block_on(async {
+            let server = ServerBuilder::new()
+                .disconnect_grace(Duration::from_secs(60))
+                .max_queued_messages(2)
+                .build();
+            let mut dispatcher = Dispatcher::new(server);
+            let (alice, _, _) = dispatcher.add_user().unwrap();
+            let (new_alice, messages, _) = dispatcher.add_user().unwrap();
+            dispatcher.disconnect_user(alice).await.unwrap();
+            
+            assert!(dispatcher.send(alice, response::Message::Pong(1)).await);
+            assert!(dispatcher.send(alice, response::Message::Pong(2)).await);
+            assert!(!dispatcher.send(alice, response::Message::Pong(3)).await);
+            
+            dispatcher.take_over(new_alice, alice);
+            dispatcher.send_held(alice);
+            dispatcher.conns.clear();
+            let messages: Vec<_> = messages.skip(1).collect().await;
+            assert_eq!(vec![response::Message::Pong(1), response::Message::Pong(2)], messages);
+        });
+    }
+    
+    #[test]
+    fn resend_unacknowledged_messages() {
+        task::block_on(async {
+            let server = ServerBuilder::new()
+                .disconnect_grace(Duration::from_secs(60))
+                .build();
+            let mut dispatcher = Dispatcher::new(server);
+            let (alice, old_messages, _) = dispatcher.add_user().unwrap();
+            let (new_alice, new_messages, _) = dispatcher.add_user().unwrap();
+            dispatcher.acks.insert(alice, Unacked::new());
+            let received = |payload: &str| response::Message::ReceivedBroadcast(1, payload.into());
+            let sequenced = |n, payload| response::Message::Sequenced(n, Box::new(received(payload)));
+            
+            dispatcher.send(alice, received("a")).await;
+            dispatcher.send(alice, response::Message::Pong(1)).await;
+            dispatcher.disconnect_user(alice).await.unwrap();
+            assert!(dispatcher.send(alice, received("b")).await);
+            
+            // nothing relayed is sent until the client says what it missed
+            dispatcher.take_over(new_alice, alice);
+            dispatcher.send(alice, received("c")).await;
+            dispatcher.send(alice, response::Message::Pong(2)).await;
+            dispatcher.ack(alice, 1).unwrap();
+            dispatcher.send(alice, received("d")).await;
+            dispatcher.conns.clear();
+            
+            let old_messages: Vec<_> = old_messages.skip(1).collect().await;
+            assert_eq!(vec![sequenced(1, "a"), response::Message::Pong(1)], old_messages);
+            let new_messages: Vec<_> = new_messages.skip(1).collect().await;
+            assert_eq!(vec![response::Message::Pong(2), sequenced(2, "b"), sequenced(3, "c"), sequenced(4, "d")], new_messages);
+        });
+    }
+    
+    #[test]
+    fn quit_after_resume() {
+        task::block_on(async {
+            let server = ServerBuilder::new()
+                .disconnect_grace(Duration::from_secs(60))
+                .build();
+            let mut dispatcher = Dispatcher::new(server);
+            let mut out = dispatcher.out.clone();
+            let (alice, mut old_messages, _) = dispatcher.add_user().unwrap();
+            let Some(response::Message::Welcome(_, token, _)) = old_messages.next().await else {
+                panic!("expected WELCOME");
+            };
+            let (new_alice, new_messages, _) = dispatcher.add_user().unwrap();
+            
+            // the connection sends QUIT before it learns that it resumed
+            out.send(Event::Disconnected(old_messages)).await.unwrap();
+            out.send(Event::Request(new_alice, None, Request::Resume(token))).await.unwrap();
+            out.send(Event::Request(new_alice, None, Request::Quit)).await.unwrap();
+            let (reply, users) = oneshot::channel();
+            out.send(Event::Admin(AdminQuery::Users, reply)).await.unwrap();
+            out.send(Event::DrainDeadline).await.unwrap();
+            dispatcher.run().await.unwrap();
+            
+            assert_eq!("[]", users.await.unwrap().body);
+            let new_messages: Vec<_> = new_messages.skip(1).collect().await;
+            assert!(matches!(new_messages[0], response::Message::Resumed(id, _) if id == alice));
+        });
+    }
+    
+    #[test]
+    fn compress_large_payloads() {
+        task::block_on(async {
+            let mut dispatcher = Dispatcher::new(ServerBuilder::new().compress_threshold(64).build());
+            let (alice, alice_messages, _) = dispatcher.add_user().unwrap();
+            let (bob, bob_messages, _) = dispatcher.add_user().unwrap();
+            dispatcher.conns.get_mut(&alice).unwrap().compression = Some(Compression::Zstd);
+            
+            let small = response::Message::ReceivedBroadcast(1, "state=1".into());
+            let large = response::Message::ReceivedBroadcast(1, "state=1;".repeat(20).into());
+            let response = response::Response::to_all([alice, bob]).msg(small.clone())
+                .and_to_all([alice, bob]).msg(large.clone());
+            dispatcher.dispatch_response(0, response).await;
+            dispatcher.conns.clear();
+            
+            let alice_messages: Vec<_> = alice_messages.skip(1).collect().await;
+            let bob_messages: Vec<_> = bob_messages.skip(1).collect().await;
+            assert_eq!(vec![small.clone(), large.clone()], bob_messages);
+            assert_eq!(small, alice_messages[0]);
+            let response::Message::Compressed(Compression::Zstd, bytes) = &alice_messages[1] else {
+                panic!("not compressed: {:?}", alice_messages[1]);
+            };
+            assert_eq!(format!("{large}\n").as_bytes(), Compression::Zstd.decompress(bytes));
+        });
+    }
+    
+    #[test]
+    fn push_room_changes() {
+        task::block_on(async {
+            let mut dispatcher = Dispatcher::new(ServerBuilder::new().build());
+            let (alice, messages, _) = dispatcher.add_user().unwrap();
+            let (bob, _, _) = dispatcher.add_user().unwrap();
+            dispatcher.server.handle_request(bob, Request::CreateRoom("a".into(), Vec::new()));
+            
+            dispatcher.subscribe(alice);
+            dispatcher.server.handle_request(alice, Request::CreateRoom("b".into(), Vec::new()));
+            dispatcher.update_subscribers();
+            dispatcher.server.handle_request(bob, Request::LeaveRoom(1));
+            dispatcher.update_subscribers();
+            dispatcher.subscribers.remove(&alice);
+            dispatcher.server.handle_request(alice, Request::LeaveRoom(2));
+            dispatcher.update_subscribers();
+            dispatcher.conns.clear();
+            
+            let messages: Vec<_> = messages.skip(1).collect().await;
+            assert_eq!(vec![
+                response::Message::RoomAdded(1, 1, "a".into(), None),
+                response::Message::RoomAdded(2, 1, "b".into(), None),
+                response::Message::RoomRemoved(1),
+            ], messages);
+        });
+    }
+    
+    #[test]
+    fn parse_undelivered_policy() {
+        assert_eq!(Ok(UndeliveredPolicy::Log), "log".parse());
+        assert_eq!(Ok(UndeliveredPolicy::NotifySender), "notify".parse());
+        assert_eq!(Ok(UndeliveredPolicy::Cleanup), "cleanup".parse());
+        assert_eq!(Err(()), "ignore".parse::<UndeliveredPolicy>());
+    }
+    
+    #[test]
+    fn parse_listen_addr() {
+        let addr: ListenAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(ListenAddr {host: "127.0.0.1".into(), port: 4000}, addr);
+        assert_eq!("127.0.0.1:4000", addr.to_string());
+        assert_eq!(Ok(ListenAddr {host: "[::1]".into(), port: 4000}), "[::1]:4000".parse());
+        assert_eq!(Err(()), "localhost".parse::<ListenAddr>());
+        assert_eq!(Err(()), ":4000".parse::<ListenAddr>());
+        assert_eq!(Err(()), "localhost:http".parse::<ListenAddr>());
+    }
+    
+    #[test]
+    fn canonical_peer_addr() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:4000".parse().unwrap();
+        assert_eq!("10.0.0.1:4000", canonical_addr(mapped).to_string());
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        assert_eq!(v6, canonical_addr(v6));
+    }
+    
+    fn read_lines(input: &[u8], max_len: usize) -> Vec<Option<String>> {
+        let lines = bounded_lines(io::BufReader::with_capacity(4, input), max_len)
+            .map(|line| match line.unwrap() {
+                Line::Complete(line) => Some(line),
+                Line::TooLong => None,
+            })
+            .collect();
+        task::block_on(lines)
+    }
+    
+    #[test]
+    fn read_bounded_lines() {
+        let expected = vec![Some("PING|1".to_string()), Some("".to_string()), Some("QUIT".to_string())];
+        assert_eq!(expected, read_lines(b"PING|1\r\n\nQUIT", 10));
+    }
+    
+    #[test]
+    fn fuzz_bounded_lines() {
+        use crate::ids::{self, IdGenerator, Random};
+        
+        let mut rng = Random::seeded(ids::test_seed());
+        for _ in 0..2_000 {
+            let input: Vec<u8> = (0..rng.generate() % 64)
+                .map(|_| match rng.generate() % 4 {
+                    0 => b'\n',
+                    1 => b'\r',
+                    _ => rng.generate() as u8,
+                })
+                .collect();
+            let lines: Vec<_> = task::block_on(bounded_lines(io::BufReader::with_capacity(4, &input[..]), 10).collect());
+            // reading stops after a line which is too long
+            let before_long = lines.iter().take_while(|line| !matches!(line, Ok(Line::TooLong))).count();
+            assert!(before_long + 1 >= lines.len(), "read past a long line in {input:?}");
+            for line in &lines {
+                if let Ok(Line::Complete(line)) = line {
+                    assert!(line.len() <= 10 && !line.contains('\n'), "bad line {line:?} in {input:?}");
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn read_line_too_long() {
+        let expected = vec![Some("PING|1".to_string()), None];
+        assert_eq!(expected, read_lines(b"PING|1\nCHAT|1|hello world\nQUIT\n", 10));
+    }
 }
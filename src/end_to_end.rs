@@ -0,0 +1,123 @@
+use std::time::Duration;
+use async_std::io::{self, BufReader};
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+
+use crate::dispatch;
+use crate::server::ServerBuilder;
+
+/// How long to wait for a message which should arrive, before failing.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server on an ephemeral port, and returns its address.
+async fn boot(builder: ServerBuilder) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(dispatch::serve_listeners(builder.build(), vec![listener], Vec::new(), None, None));
+    addr
+}
+
+/// A client connected over a real socket, which sends and expects lines of
+/// the wire protocol.
+struct TestClient {
+    lines: io::Lines<BufReader<TcpStream>>,
+    conn: TcpStream,
+}
+
+impl TestClient {
+    async fn connect(addr: SocketAddr) -> TestClient {
+        let conn = TcpStream::connect(addr).await.unwrap();
+        TestClient {lines: BufReader::new(conn.clone()).lines(), conn}
+    }
+    
+    /// Connects and checks that the server welcomes this user.
+    async fn welcomed(addr: SocketAddr, user_id: u32) -> TestClient {
+        let mut client = TestClient::connect(addr).await;
+        let welcome = client.next().await.unwrap();
+        assert!(welcome.starts_with(&format!("WELCOME|{user_id}|")), "unexpected {welcome}");
+        client
+    }
+    
+    async fn send(&mut self, line: &str) {
+        self.conn.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+    }
+    
+    /// The next line from the server, or `None` if it has closed the
+    /// connection.
+    async fn next(&mut self) -> Option<String> {
+        io::timeout(TIMEOUT, async { self.lines.next().await.transpose() })
+            .await
+            .expect("no message from server")
+    }
+    
+    async fn expect(&mut self, line: &str) {
+        assert_eq!(Some(line), self.next().await.as_deref());
+    }
+}
+
+#[test]
+fn create_join_and_send() {
+    task::block_on(async {
+        let addr = boot(ServerBuilder::new()).await;
+        let mut owner = TestClient::welcomed(addr, 1).await;
+        let mut player = TestClient::welcomed(addr, 2).await;
+        
+        owner.send("CREATE_GAME|level=1").await;
+        owner.expect("CREATED_GAME|1").await;
+        player.send("LIST_OPEN_GAMES").await;
+        player.expect("OPEN_GAMES|1|level=1").await;
+        
+        player.send("JOIN_GAME|1|hi").await;
+        owner.expect("PLAYER_JOINED|1|2|hi").await;
+        owner.send("ACCEPT_JOIN|1|2").await;
+        player.expect("JOINED|1").await;
+        
+        owner.send("SEND|1|start").await;
+        player.expect("RECEIVED|1|start").await;
+        player.send("SEND|1|move:a1").await;
+        owner.expect("RECEIVED|1|2|move:a1").await;
+        
+        player.send("LEAVE_GAME|1").await;
+        owner.expect("PLAYER_LEFT|1|2").await;
+        owner.send("QUIT").await;
+        assert_eq!(None, owner.next().await);
+    });
+}
+
+#[test]
+fn disconnect_hands_over_room() {
+    task::block_on(async {
+        let addr = boot(ServerBuilder::new()).await;
+        let mut owner = TestClient::welcomed(addr, 1).await;
+        let mut player = TestClient::welcomed(addr, 2).await;
+        
+        owner.send("CREATE_GAME|x").await;
+        owner.expect("CREATED_GAME|1").await;
+        owner.send("SET_JOIN_POLICY|1|OPEN").await;
+        player.send("JOIN_GAME|1|hi").await;
+        player.expect("JOINED|1").await;
+        
+        // without a grace period, a lost connection leaves straight away
+        drop(owner);
+        player.expect("PLAYER_LEFT|1|1").await;
+        player.expect("CHANGED_OWNER|1|2").await;
+        player.send("LEAVE_GAME|1").await;
+        player.send("LIST_OPEN_GAMES").await;
+        player.expect("NO_OPEN_GAMES").await;
+    });
+}
+
+#[test]
+fn invalid_and_refused_connections() {
+    task::block_on(async {
+        let addr = boot(ServerBuilder::new().max_connections(1)).await;
+        let mut first = TestClient::welcomed(addr, 1).await;
+        first.send("NONSENSE").await;
+        first.expect("ERROR|2|Invalid request").await;
+        
+        let mut second = TestClient::connect(addr).await;
+        second.expect("ERROR|1|Server is full").await;
+        assert_eq!(None, second.next().await);
+    });
+}
@@ -1,17 +1,28 @@
 use async_std::{io, task};
 use futures::channel::mpsc;
+use tracing::{error, warn};
 
 use crate::response;
 
-pub(crate) type Result = std::result::Result<(), ServerError>;
+pub type Result<T = ()> = std::result::Result<T, ServerError>;
 
 #[derive(Debug)]
-pub(crate) enum ServerError {
+pub enum ServerError {
     IO(io::Error),
     InvalidState(response::Error),
     DispatcherFailed(mpsc::SendError),
 }
 
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::IO(e) => write!(f, "IO error: {e}"),
+            ServerError::InvalidState(e) => write!(f, "Invalid state: {e}"),
+            ServerError::DispatcherFailed(e) => write!(f, "Dispatcher failed: {e}"),
+        }
+    }
+}
+
 impl From<io::Error> for ServerError {
     fn from(e: io::Error) -> ServerError {
         ServerError::IO(e)
@@ -28,10 +39,23 @@ impl From<mpsc::SendError> for ServerError {
     }
 }
 
+/// Logs an error which only means that the state changed before an event
+/// was handled, such as a user having already been removed, so that it
+/// doesn't stop the dispatcher.
+pub(crate) fn log_invalid_state(r: Result) -> Result {
+    match r {
+        Err(ServerError::InvalidState(e)) => {
+            warn!(error = %e, "Ignoring event for stale state");
+            Ok(())
+        },
+        r => r,
+    }
+}
+
 pub(crate) fn spawn_logged_task<F>(fut: F) -> task::JoinHandle<()> where F: futures::Future<Output = Result> + Send + 'static {
     task::spawn(async move {
         if let Err(e) = fut.await {
-            eprintln!("SERVER ERROR: {e:?}")
+            error!(error = %e, "Server error")
         }
     })
 }
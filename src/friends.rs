@@ -0,0 +1,139 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::models::UserID;
+use crate::response::{Error, Result};
+
+/// The most friends an account can list.
+const MAX_FRIENDS: usize = 200;
+
+/// The maximum length of an account name which can be listed as a friend,
+/// in bytes.
+const MAX_ACCOUNT_LENGTH: usize = 64;
+
+/// Each account's friends, by account name. A friendship only counts once
+/// both accounts have listed each other, so that nobody can follow when
+/// someone comes and goes without their say-so.
+#[derive(Debug, Default)]
+pub(crate) struct Friends {
+    lists: HashMap<String, BTreeSet<String>>,
+}
+
+/// How a friend appears in a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FriendStatus {
+    /// They haven't listed this account back.
+    Pending,
+    Offline,
+    Online(UserID),
+}
+
+impl Friends {
+    pub(crate) fn new() -> Friends {
+        Friends::default()
+    }
+    
+    /// Lists a friend for an account, returning whether they weren't listed
+    /// already.
+    pub(crate) fn add(&mut self, account: &str, friend: &str) -> Result<bool> {
+        // friends are listed in fields of their own, with commas in them
+        let is_valid = !friend.is_empty()
+            && friend.len() <= MAX_ACCOUNT_LENGTH
+            && friend != account
+            && !friend.contains([',', '|'])
+            && !friend.chars().any(char::is_control);
+        if !is_valid {
+            return Err(Error::InvalidName);
+        }
+        let list = self.lists.entry(account.to_string()).or_default();
+        if list.contains(friend) {
+            return Ok(false);
+        } else if list.len() >= MAX_FRIENDS {
+            return Err(Error::TooManyFriends);
+        }
+        list.insert(friend.to_string());
+        Ok(true)
+    }
+    
+    /// Takes a friend off an account's list, returning whether they were on
+    /// it.
+    pub(crate) fn remove(&mut self, account: &str, friend: &str) -> bool {
+        let Some(list) = self.lists.get_mut(account) else {
+            return false;
+        };
+        let removed = list.remove(friend);
+        if list.is_empty() {
+            self.lists.remove(account);
+        }
+        removed
+    }
+    
+    /// The friends an account has listed, in order of name.
+    pub(crate) fn list(&self, account: &str) -> impl Iterator<Item = &str> {
+        self.lists.get(account)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+    
+    pub(crate) fn are_mutual(&self, a: &str, b: &str) -> bool {
+        let lists = |x: &str, y: &str| self.lists.get(x).is_some_and(|list| list.contains(y));
+        lists(a, b) && lists(b, a)
+    }
+    
+    /// The accounts which this account has listed, and which have listed it
+    /// back.
+    pub(crate) fn mutual<'a>(&'a self, account: &'a str) -> impl Iterator<Item = &'a str> {
+        self.list(account)
+            .filter(move |friend| self.are_mutual(account, friend))
+    }
+    
+    /// Every account's list, for saving.
+    pub(crate) fn lists(&self) -> impl Iterator<Item = (&str, &BTreeSet<String>)> {
+        self.lists.iter().map(|(account, list)| (account.as_str(), list))
+    }
+    
+    /// Replaces an account's list, when restoring them.
+    pub(crate) fn set_list(&mut self, account: String, list: BTreeSet<String>) {
+        if !list.is_empty() {
+            self.lists.insert(account, list);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn mutual_friends() {
+        let mut friends = Friends::new();
+        assert_eq!(Ok(true), friends.add("alice", "bob"));
+        assert_eq!(Ok(false), friends.add("alice", "bob"));
+        assert_eq!(Ok(true), friends.add("alice", "carol"));
+        assert!(!friends.are_mutual("alice", "bob"));
+        assert_eq!(0, friends.mutual("alice").count());
+        
+        friends.add("bob", "alice").unwrap();
+        assert!(friends.are_mutual("bob", "alice"));
+        assert_eq!(vec!["bob"], friends.mutual("alice").collect::<Vec<_>>());
+        assert_eq!(vec!["bob", "carol"], friends.list("alice").collect::<Vec<_>>());
+        
+        assert!(friends.remove("alice", "bob"));
+        assert!(!friends.remove("alice", "bob"));
+        assert!(!friends.are_mutual("bob", "alice"));
+    }
+    
+    #[test]
+    fn invalid_friends() {
+        let mut friends = Friends::new();
+        assert_eq!(Err(Error::InvalidName), friends.add("alice", "alice"));
+        assert_eq!(Err(Error::InvalidName), friends.add("alice", ""));
+        assert_eq!(Err(Error::InvalidName), friends.add("alice", "bob,carol"));
+        assert_eq!(Err(Error::InvalidName), friends.add("alice", "bob|carol"));
+        assert_eq!(Err(Error::InvalidName), friends.add("alice", &"b".repeat(65)));
+        for i in 0..MAX_FRIENDS {
+            friends.add("alice", &format!("friend{i}")).unwrap();
+        }
+        assert_eq!(Err(Error::TooManyFriends), friends.add("alice", "bob"));
+    }
+}
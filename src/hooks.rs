@@ -0,0 +1,32 @@
+use crate::models::{RoomID, UserID};
+
+/// Callbacks for an application which embeds the server, so that it can
+/// follow what happens in the lobby, for example to keep its own records.
+/// Every callback does nothing by default, so only the interesting ones need
+/// to be implemented.
+pub trait Hooks: Send {
+    fn room_created(&self, _room_id: RoomID, _owner_id: UserID) {}
+    
+    fn room_closed(&self, _room_id: RoomID) {}
+    
+    fn user_joined(&self, _room_id: RoomID, _user_id: UserID) {}
+    
+    /// Also called for an owner who leaves, if their room is handed over to
+    /// someone else rather than closed.
+    fn user_left(&self, _room_id: RoomID, _user_id: UserID) {}
+    
+    /// A game message was sent on by the server, from the user who made the
+    /// request.
+    fn message_relayed(&self, _room_id: RoomID, _from_user_id: UserID, _payload: &str) {}
+    
+    /// Decides whether a user may create a game with this data, after the
+    /// server's own length limit and pattern have allowed it.
+    fn accept_room_data(&self, _owner_id: UserID, _data: &str) -> bool {
+        true
+    }
+}
+
+/// The hooks used unless the embedding application gives its own.
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
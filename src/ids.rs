@@ -0,0 +1,227 @@
+/// A source of candidate IDs for users or rooms. The server asks for IDs
+/// until it gets a non-zero one which is not already in use; if a generator
+/// gives a thousand in a row which are taken, the server counts as full.
+pub trait IdGenerator: Send {
+    fn generate(&mut self) -> u32;
+}
+
+/// Externally-supplied IDs, e.g. from an embedder's own account system.
+impl <F: FnMut() -> u32 + Send> IdGenerator for F {
+    fn generate(&mut self) -> u32 {
+        self()
+    }
+}
+
+/// Generates IDs 1, 2, 3, ..., wrapping around to 1 on overflow.
+#[derive(Default)]
+pub struct Sequential {
+    last_id: u32,
+}
+
+impl IdGenerator for Sequential {
+    fn generate(&mut self) -> u32 {
+        self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+        self.last_id
+    }
+}
+
+/// Generates unpredictable non-zero IDs, using the SplitMix64 algorithm with
+/// a randomly-chosen seed.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random {state: u64::from_le_bytes(random_bytes())}
+    }
+    
+    /// The same IDs every time, for reproducible tests.
+    #[cfg(test)]
+    pub(crate) fn seeded(seed: u64) -> Random {
+        Random {state: seed}
+    }
+}
+
+/// A seed for a randomised test: the `TEST_SEED` environment variable if it
+/// is set, so that a failure can be reproduced, or otherwise a new one each
+/// run. The seed is printed, which the test harness shows if the test fails.
+#[cfg(test)]
+pub(crate) fn test_seed() -> u64 {
+    let seed = std::env::var("TEST_SEED").ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| u64::from_le_bytes(random_bytes()));
+    println!("TEST_SEED={seed}");
+    seed
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+impl IdGenerator for Random {
+    fn generate(&mut self) -> u32 {
+        loop {
+            self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            
+            let id = (z >> 32) as u32;
+            if id != 0 { return id; }
+        }
+    }
+}
+
+/// Generates IDs from random (version 4) UUIDs, taking their first 32 bits,
+/// which are written as the UUID's first group of hex digits. The UUIDs
+/// come from the operating system's random number generator, so unlike
+/// `Random`, the IDs can't be predicted from each other.
+#[derive(Default)]
+pub struct Uuid;
+
+impl IdGenerator for Uuid {
+    fn generate(&mut self) -> u32 {
+        loop {
+            let uuid = uuid_v4();
+            let id = (uuid >> 96) as u32;
+            if id != 0 { return id; }
+        }
+    }
+}
+
+/// Bytes from the operating system's cryptographically secure random
+/// number generator.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes)
+        .expect("OS random number generator failed");
+    bytes
+}
+
+/// Generates an unguessable 128-bit secret, as 32 hex digits.
+pub(crate) fn secret_token() -> String {
+    random_bytes::<16>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Compares a secret given by a client with the real one, taking the same
+/// time however much of it matches, so that the timing of failed attempts
+/// gives nothing away.
+pub(crate) fn secrets_match(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && given.bytes()
+        .zip(secret.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Generates a random (version 4) UUID, which identifies one session from
+/// connection to disconnection, across any resumes.
+pub(crate) fn session_id() -> String {
+    let uuid = uuid_v4();
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        uuid >> 96,
+        (uuid >> 80) & 0xffff,
+        (uuid >> 64) & 0xffff,
+        (uuid >> 48) & 0xffff,
+        uuid & 0xffff_ffff_ffff,
+    )
+}
+
+/// A random (version 4) UUID, as a number.
+fn uuid_v4() -> u128 {
+    let uuid = u128::from_be_bytes(random_bytes());
+    let uuid = (uuid & !(0xf << 76)) | (0x4 << 76);
+    (uuid & !(0b11 << 62)) | (0b10 << 62)
+}
+
+/// Generates a resume token for a session. The session ID comes first so
+/// that attempts to resume it can be traced in the logs; the rest is the
+/// secret.
+pub(crate) fn resume_token(session_id: &str) -> String {
+    format!("{session_id}.{}", secret_token())
+}
+
+/// The session which a resume token was issued for, if it is well-formed.
+pub(crate) fn token_session(token: &str) -> Option<&str> {
+    token.split_once('.')
+        .map(|(session_id, _)| session_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn sequential() {
+        let mut ids = Sequential::default();
+        assert_eq!(1, ids.generate());
+        assert_eq!(2, ids.generate());
+        assert_eq!(3, ids.generate());
+    }
+    
+    #[test]
+    fn sequential_wraps() {
+        let mut ids = Sequential {last_id: u32::MAX - 1};
+        assert_eq!(u32::MAX, ids.generate());
+        assert_eq!(1, ids.generate());
+    }
+    
+    #[test]
+    fn random_is_nonzero() {
+        let mut ids = Random::new();
+        for _ in 0..1000 {
+            assert_ne!(0, ids.generate());
+        }
+    }
+    
+    #[test]
+    fn uuid() {
+        let mut ids = Uuid;
+        let (a, b) = (ids.generate(), ids.generate());
+        assert_ne!(0, a);
+        assert_ne!(a, b);
+    }
+    
+    #[test]
+    fn secret_tokens() {
+        let a = secret_token();
+        let b = secret_token();
+        assert_eq!(32, a.len());
+        assert_ne!(a, b);
+    }
+    
+    #[test]
+    fn compare_secrets() {
+        assert!(secrets_match("abc", "abc"));
+        assert!(!secrets_match("abd", "abc"));
+        assert!(!secrets_match("ab", "abc"));
+        assert!(!secrets_match("", "abc"));
+    }
+    
+    #[test]
+    fn session_ids() {
+        let a = session_id();
+        assert_eq!(36, a.len());
+        assert_eq!(Some('4'), a.chars().nth(14));
+        assert!(matches!(a.chars().nth(19), Some('8' | '9' | 'a' | 'b')));
+        assert_ne!(a, session_id());
+        
+        let token = resume_token(&a);
+        assert_eq!(Some(a.as_str()), token_session(&token));
+        assert_eq!(None, token_session("abc"));
+    }
+    
+    #[test]
+    fn external() {
+        let mut next = 100;
+        let mut ids = move || { next += 10; next };
+        assert_eq!(110, ids.generate());
+        assert_eq!(120, ids.generate());
+    }
+}
@@ -0,0 +1,52 @@
+//! A server for the Incognita Socket mod, which lets games connect their
+//! players to each other. Besides the `incognita-socket-server` binary, the
+//! server can be embedded in another application with `server::ServerBuilder`
+//! and `dispatch::start_server`, following what happens with `hooks::Hooks`,
+//! and games written in Rust can connect to it with `client::Client`.
+
+#![deny(unsafe_code)]
+
+mod access;
+mod acks;
+mod admin_api;
+mod auth;
+mod bench;
+mod canonicalise;
+#[doc(hidden)]
+pub mod cli;
+pub mod client;
+mod clock;
+mod codec;
+pub mod compression;
+mod config;
+pub mod dispatch;
+#[cfg(test)]
+mod end_to_end;
+pub mod err;
+pub mod friends;
+pub mod hooks;
+pub mod ids;
+pub mod limits;
+mod logging;
+mod matchmaking;
+mod mirror;
+pub mod models;
+mod program_args;
+mod protobuf;
+mod recording;
+mod repl;
+pub mod request;
+mod schedule;
+pub mod response;
+pub mod server;
+#[cfg(test)]
+mod simulation;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+pub mod stats;
+pub mod timeline;
+pub mod transport;
+mod turns;
+mod udp;
+mod version;
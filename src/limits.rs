@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use crate::models::RoomID;
+
+/// Clients are warned once usage reaches this percentage of a limit, so that
+/// they can back off before the limit is enforced.
+pub(crate) const WARNING_THRESHOLD_PERCENT: usize = 90;
+
+/// Whether `count` is close enough to `limit` that the client should be
+/// warned.
+pub(crate) fn is_near_limit(count: usize, limit: usize) -> bool {
+    count.saturating_mul(100) >= limit.saturating_mul(WARNING_THRESHOLD_PERCENT)
+}
+
+/// A client whose requests have been refused this many more times than they
+/// have been allowed is disconnected.
+const MAX_RATE_LIMIT_STRIKES: u32 = 50;
+
+/// How many requests a single connection may make.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    /// How many requests may be made at once, after a quiet period.
+    pub burst: f64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RateVerdict {
+    Allowed,
+    Limited,
+    Disconnect,
+}
+
+/// A token bucket, which refills at a constant rate and allows bursts up to
+/// its capacity.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: SystemTime,
+    strikes: u32,
+    /// Whether the client has been warned since it was last well within
+    /// the limit.
+    warned: bool,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit, now: SystemTime) -> RateLimiter {
+        RateLimiter {
+            limit,
+            tokens: limit.burst,
+            last_refill: now,
+            strikes: 0,
+            warned: false,
+        }
+    }
+    
+    /// Decides whether a request received at the given time may be handled.
+    pub(crate) fn check(&mut self, now: SystemTime) -> RateVerdict {
+        let elapsed = now.duration_since(self.last_refill).unwrap_or_default().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.last_refill = now;
+        
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.strikes = self.strikes.saturating_sub(1);
+            RateVerdict::Allowed
+        } else if self.strikes < MAX_RATE_LIMIT_STRIKES {
+            self.strikes += 1;
+            RateVerdict::Limited
+        } else {
+            RateVerdict::Disconnect
+        }
+    }
+    
+    /// A warning for a client which has used up most of its burst, once
+    /// until it slows down again.
+    pub(crate) fn warning(&mut self) -> Option<Warning> {
+        let burst = self.limit.burst as usize;
+        let used = (self.limit.burst - self.tokens) as usize;
+        let near = is_near_limit(used, burst);
+        let warn = near && !self.warned;
+        self.warned = near;
+        warn.then_some(Warning::NearlyRateLimited(used, burst))
+    }
+}
+
+/// How long further attempts are refused after a failed one; each failure
+/// after that doubles it, up to `MAX_FAILURE_BACKOFF`.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Slows down password guessing by refusing further attempts for a while
+/// after each failure, for longer each time, until an attempt succeeds.
+pub(crate) struct Backoff<K> {
+    /// How many times in a row each has failed, and until when to refuse it.
+    failures: HashMap<K, (u32, SystemTime)>,
+}
+
+impl <K: Eq + Hash> Backoff<K> {
+    pub(crate) fn new() -> Backoff<K> {
+        Backoff {failures: HashMap::new()}
+    }
+    
+    /// Whether an attempt may be made at the given time.
+    pub(crate) fn permits(&self, key: &K, now: SystemTime) -> bool {
+        self.failures.get(key)
+            .is_none_or(|&(_, until)| now >= until)
+    }
+    
+    pub(crate) fn record(&mut self, key: K, succeeded: bool, now: SystemTime) {
+        // whoever hasn't tried again for a while starts afresh
+        self.failures.retain(|_, (_, until)| now < *until + MAX_FAILURE_BACKOFF);
+        if succeeded {
+            self.failures.remove(&key);
+            return;
+        }
+        let (failures, until) = self.failures.entry(key).or_insert((0, now));
+        let delay = FAILURE_BACKOFF.saturating_mul(1 << (*failures).min(16));
+        *failures += 1;
+        *until = now + delay.min(MAX_FAILURE_BACKOFF);
+    }
+}
+
+/// A limit which a client is approaching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A room has this many members, out of this capacity.
+    RoomNearlyFull(RoomID, usize, usize),
+    /// The client has made this many of the requests it may make at once.
+    NearlyRateLimited(usize, usize),
+    /// The client sent a payload of this length, out of the most allowed.
+    PayloadNearlyTooLarge(usize, usize),
+    /// This many messages are waiting to be sent to the client, out of how
+    /// many may wait before it is disconnected.
+    QueueNearlyFull(usize, usize),
+}
+
+impl Warning {
+    /// How the warning is written, after `WARNING`.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Warning::RoomNearlyFull(..) => "GAME_NEARLY_FULL",
+            Warning::NearlyRateLimited(..) => "NEARLY_RATE_LIMITED",
+            Warning::PayloadNearlyTooLarge(..) => "PAYLOAD_NEARLY_TOO_LARGE",
+            Warning::QueueNearlyFull(..) => "QUEUE_NEARLY_FULL",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+    
+    const LIMIT: RateLimit = RateLimit {per_second: 2.0, burst: 3.0};
+    
+    #[test]
+    fn rate_limit_burst() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut limiter = RateLimiter::new(LIMIT, now);
+        assert_eq!(RateVerdict::Allowed, limiter.check(now));
+        assert_eq!(RateVerdict::Allowed, limiter.check(now));
+        assert_eq!(RateVerdict::Allowed, limiter.check(now));
+        assert_eq!(RateVerdict::Limited, limiter.check(now));
+        
+        let later = now + Duration::from_millis(500);
+        assert_eq!(RateVerdict::Allowed, limiter.check(later));
+        assert_eq!(RateVerdict::Limited, limiter.check(later));
+    }
+    
+    #[test]
+    fn rate_limit_warning() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut limiter = RateLimiter::new(RateLimit {per_second: 1.0, burst: 10.0}, now);
+        for _ in 0..8 {
+            limiter.check(now);
+            assert_eq!(None, limiter.warning());
+        }
+        limiter.check(now);
+        assert_eq!(Some(Warning::NearlyRateLimited(9, 10)), limiter.warning());
+        limiter.check(now);
+        assert_eq!(None, limiter.warning());
+        
+        // once the client slows down, it can be warned again
+        let later = now + Duration::from_secs(5);
+        limiter.check(later);
+        assert_eq!(None, limiter.warning());
+        for _ in 0..4 {
+            limiter.check(later);
+        }
+        assert_eq!(Some(Warning::NearlyRateLimited(10, 10)), limiter.warning());
+    }
+    
+    #[test]
+    fn rate_limit_disconnects() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut limiter = RateLimiter::new(LIMIT, now);
+        for _ in 0..3 {
+            limiter.check(now);
+        }
+        for _ in 0..MAX_RATE_LIMIT_STRIKES {
+            assert_eq!(RateVerdict::Limited, limiter.check(now));
+        }
+        assert_eq!(RateVerdict::Disconnect, limiter.check(now));
+    }
+    
+    #[test]
+    fn backoff() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut backoff = Backoff::new();
+        assert!(backoff.permits(&1, now));
+        backoff.record(1, false, now);
+        assert!(!backoff.permits(&1, now));
+        assert!(backoff.permits(&2, now));
+        
+        // each failure doubles the wait
+        let now = now + FAILURE_BACKOFF;
+        assert!(backoff.permits(&1, now));
+        backoff.record(1, false, now);
+        assert!(!backoff.permits(&1, now + FAILURE_BACKOFF));
+        assert!(backoff.permits(&1, now + 2 * FAILURE_BACKOFF));
+        
+        backoff.record(1, true, now + 2 * FAILURE_BACKOFF);
+        assert!(backoff.permits(&1, now + 2 * FAILURE_BACKOFF));
+    }
+    
+    #[test]
+    fn near_limit() {
+        assert!(!is_near_limit(8, 10));
+        assert!(is_near_limit(9, 10));
+        assert!(is_near_limit(10, 10));
+        assert!(!is_near_limit(0, 1));
+        assert!(is_near_limit(1, 1));
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// How often log files are rotated, regardless of their size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn period_secs(self) -> Option<u64> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(60 * 60),
+            Rotation::Daily => Some(24 * 60 * 60),
+        }
+    }
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<Rotation, ()> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl std::fmt::Display for Rotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Rotation::Never => "never",
+            Rotation::Hourly => "hourly",
+            Rotation::Daily => "daily",
+        })
+    }
+}
+
+/// A log file which is rotated once it reaches a maximum size, or when a new
+/// hour or day begins. Rotated files are renamed with suffixes `.1`, `.2`
+/// and so on, newest first, and only the most recent few are kept.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    rotation: Rotation,
+    keep: usize,
+    file: File,
+    written: u64,
+    /// Which hour or day the current file was started in.
+    period: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: PathBuf, max_bytes: u64, rotation: Rotation, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let written = file.metadata()?.len();
+        let period = period_of(rotation, SystemTime::now());
+        Ok(RotatingFile {path, max_bytes, rotation, keep, file, written, period})
+    }
+    
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+    
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {},
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        self.period = period_of(self.rotation, now);
+        Ok(())
+    }
+    
+    fn write_at(&mut self, buf: &[u8], now: SystemTime) -> io::Result<usize> {
+        let too_big = self.written + buf.len() as u64 > self.max_bytes;
+        let new_period = period_of(self.rotation, now) != self.period;
+        if self.written > 0 && (too_big || new_period) {
+            self.rotate(now)?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+}
+
+fn period_of(rotation: Rotation, now: SystemTime) -> u64 {
+    let secs = now.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    rotation.period_secs().map_or(0, |period| secs / period)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, SystemTime::now())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Starts writing log events at `level` and above, to the given file or
+/// otherwise to stdout.
+pub(crate) fn init(level: Level, format: LogFormat, file: Option<RotatingFile>) {
+    let ansi = file.is_none() && format == LogFormat::Text;
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(Mutex::new(file)),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => builder.init(),
+        // connection fields like the user ID and address come from the span
+        LogFormat::Json => builder.json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+    
+    fn temp_log(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("incognita-log-test-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("server.log")
+    }
+    
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+    
+    #[test]
+    fn rotate_by_size() {
+        let path = temp_log("size");
+        let mut log = RotatingFile::open(path.clone(), 10, Rotation::Never, 2).unwrap();
+        let now = SystemTime::now();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            log.write_at(line.as_bytes(), now).unwrap();
+        }
+        
+        // the oldest file, with "one" and "two", has been dropped
+        assert_eq!("six\n", read(path.clone()));
+        assert_eq!("four\nfive\n", read(log.rotated_path(1)));
+        assert_eq!("three\n", read(log.rotated_path(2)));
+        assert!(!log.rotated_path(3).exists());
+    }
+    
+    #[test]
+    fn rotate_by_time() {
+        let path = temp_log("time");
+        let mut log = RotatingFile::open(path.clone(), u64::MAX, Rotation::Hourly, 1).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(log.period * 60 * 60);
+        log.write_at(b"one\n", now).unwrap();
+        log.write_at(b"two\n", now + Duration::from_secs(59 * 60)).unwrap();
+        log.write_at(b"three\n", now + Duration::from_secs(60 * 60)).unwrap();
+        
+        assert_eq!("three\n", read(path));
+        assert_eq!("one\ntwo\n", read(log.rotated_path(1)));
+    }
+    
+    #[test]
+    fn parse_rotation() {
+        assert_eq!(Ok(Rotation::Daily), "daily".parse());
+        assert_eq!(Err(()), "weekly".parse::<Rotation>());
+    }
+    
+    #[test]
+    fn parse_log_format() {
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert_eq!(Err(()), "xml".parse::<LogFormat>());
+    }
+}
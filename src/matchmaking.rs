@@ -0,0 +1,91 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::models::UserID;
+
+/// The outcome of adding a user to the matchmaking queue.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Enqueued {
+    /// The user is waiting, along with this many others with the same criteria.
+    Waiting(usize),
+    /// A full group was found; the first user is the longest-waiting.
+    Matched(Vec<UserID>),
+}
+
+/// Groups together users who queued with the same criteria, so that a game
+/// can be created for them.
+pub(crate) struct Matchmaker {
+    group_size: usize,
+    queues: HashMap<String, VecDeque<UserID>>,
+}
+
+impl Matchmaker {
+    pub(crate) fn new(group_size: usize) -> Matchmaker {
+        Matchmaker {
+            group_size: group_size.max(1),
+            queues: HashMap::new(),
+        }
+    }
+    
+    pub(crate) fn group_size(&self) -> usize {
+        self.group_size
+    }
+    
+    pub(crate) fn enqueue(&mut self, user_id: UserID, criteria: &str) -> Enqueued {
+        let queue = self.queues.entry(criteria.to_string())
+            .or_default();
+        queue.push_back(user_id);
+        
+        if queue.len() < self.group_size {
+            return Enqueued::Waiting(queue.len() - 1);
+        }
+        
+        let group = queue.drain(..self.group_size).collect();
+        if queue.is_empty() {
+            self.queues.remove(criteria);
+        }
+        Enqueued::Matched(group)
+    }
+    
+    /// Removes a user from whichever queue they are in, returning whether
+    /// they were queued.
+    pub(crate) fn remove(&mut self, user_id: UserID) -> bool {
+        let mut removed = false;
+        self.queues.retain(|_, queue| {
+            if let Some(index) = queue.iter().position(|&u_id| u_id == user_id) {
+                queue.remove(index);
+                removed = true;
+            }
+            !queue.is_empty()
+        });
+        removed
+    }
+    
+    #[cfg(test)]
+    pub(crate) fn queued_users(&self) -> Vec<UserID> {
+        self.queues.values().flatten().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn match_group() {
+        let mut mm = Matchmaker::new(3);
+        assert_eq!(Enqueued::Waiting(0), mm.enqueue(1, "coop"));
+        assert_eq!(Enqueued::Waiting(0), mm.enqueue(2, "versus"));
+        assert_eq!(Enqueued::Waiting(1), mm.enqueue(3, "coop"));
+        assert_eq!(Enqueued::Matched(vec![1, 3, 4]), mm.enqueue(4, "coop"));
+        assert_eq!(Enqueued::Waiting(0), mm.enqueue(5, "coop"));
+    }
+    
+    #[test]
+    fn remove() {
+        let mut mm = Matchmaker::new(2);
+        mm.enqueue(1, "coop");
+        assert!(mm.remove(1));
+        assert!(!mm.remove(1));
+        assert_eq!(Enqueued::Waiting(0), mm.enqueue(2, "coop"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::models::RoomID;
+use crate::response::Message;
+
+/// The number of players in each open room; this is all that observers get
+/// to see, so nothing identifies the players or reveals the game's data.
+pub(crate) type Lobby = HashMap<RoomID, usize>;
+
+/// The number of players in each open room, its data and its name, if it has
+/// one; this is what clients which subscribe to the room listing get to see.
+pub(crate) type Listing = HashMap<RoomID, (usize, Arc<str>, Option<Arc<str>>)>;
+
+/// The messages which bring a new observer up to date.
+pub(crate) fn snapshot(lobby: &Lobby) -> Vec<Message> {
+    changes(&Lobby::new(), lobby)
+}
+
+/// The messages which tell observers how the lobby has changed.
+pub(crate) fn changes(old: &Lobby, new: &Lobby) -> Vec<Message> {
+    diff(old, new, Message::MirrorRoomOpened, Message::MirrorPlayers, Message::MirrorRoomClosed)
+}
+
+/// The messages which bring a new subscriber up to date.
+pub(crate) fn listing_snapshot(listing: &Listing) -> Vec<Message> {
+    listing_changes(&Listing::new(), listing)
+}
+
+/// The messages which tell subscribers how the room listing has changed.
+pub(crate) fn listing_changes(old: &Listing, new: &Listing) -> Vec<Message> {
+    diff(
+        old,
+        new,
+        |room_id, (players, data, name)| Message::RoomAdded(room_id, players, data, name),
+        |room_id, (players, data, name)| Message::RoomUpdated(room_id, players, data, name),
+        Message::RoomRemoved,
+    )
+}
+
+fn diff<K: Copy + Eq + Hash, V: Clone + PartialEq>(
+    old: &HashMap<K, V>,
+    new: &HashMap<K, V>,
+    added: impl Fn(K, V) -> Message,
+    updated: impl Fn(K, V) -> Message,
+    removed: impl Fn(K) -> Message,
+) -> Vec<Message> {
+    let removed = old.keys()
+        .filter(|k| !new.contains_key(k))
+        .map(|&k| removed(k));
+    let changed = new.iter()
+        .filter_map(|(&k, v)| match old.get(&k) {
+            None => Some(added(k, v.clone())),
+            Some(old_v) if old_v != v => Some(updated(k, v.clone())),
+            Some(_) => None,
+        });
+    removed.chain(changed).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn snapshot_opens_every_room() {
+        let lobby = Lobby::from([(1, 2), (3, 1)]);
+        let mut messages = snapshot(&lobby);
+        messages.sort_by_key(|m| m.to_string());
+        assert_eq!(vec![
+            Message::MirrorRoomOpened(1, 2),
+            Message::MirrorRoomOpened(3, 1),
+        ], messages);
+    }
+    
+    #[test]
+    fn changes() {
+        let old = Lobby::from([(1, 2), (2, 1), (3, 4)]);
+        let new = Lobby::from([(1, 3), (3, 4), (4, 1)]);
+        let mut messages = super::changes(&old, &new);
+        messages.sort_by_key(|m| m.to_string());
+        assert_eq!(vec![
+            Message::MirrorRoomClosed(2),
+            Message::MirrorRoomOpened(4, 1),
+            Message::MirrorPlayers(1, 3),
+        ], messages);
+    }
+    
+    #[test]
+    fn listing_changes() {
+        let old = Listing::from([(1, (1, "a".into(), None)), (2, (1, "b".into(), None))]);
+        let new = Listing::from([(1, (2, "a".into(), None)), (3, (1, "c".into(), Some("Draft".into())))]);
+        let mut messages = super::listing_changes(&old, &new);
+        messages.sort_by_key(|m| m.to_string());
+        assert_eq!(vec![
+            Message::RoomAdded(3, 1, "c".into(), Some("Draft".into())),
+            Message::RoomRemoved(2),
+            Message::RoomUpdated(1, 2, "a".into(), None),
+        ], messages);
+        assert_eq!(2, listing_snapshot(&new).len());
+    }
+}
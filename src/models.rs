@@ -1,27 +1,188 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use indexmap::IndexSet;
+use regex::{Regex, RegexBuilder};
 
-use crate::response::{Error, Result};
+use crate::ids;
+use crate::limits;
+use crate::response::{Error, Named, Result};
+use crate::turns::Turns;
 
-pub(crate) type UserID = u32;
-pub(crate) type RoomID = u32;
+pub type UserID = u32;
+pub type RoomID = u32;
+
+/// The maximum compiled size of a room's payload schema, in bytes.
+const MAX_SCHEMA_SIZE: usize = 1 << 16;
+
+/// The maximum length of a user's display name, in characters.
+const MAX_NAME_LENGTH: usize = 32;
+
+/// The most rooms a user can own, be in, spectate or ask to join at once.
+const MAX_ROOMS_PER_USER: usize = 16;
+
+/// How many of the owner's broadcasts a room keeps, to replay to players
+/// who join later.
+pub(crate) const MAX_HISTORY_LENGTH: usize = 32;
+
+/// How many tags a room can be created with.
+const MAX_ROOM_TAGS: usize = 8;
+
+pub(crate) fn expect_valid_tags(tags: &[String]) -> Result<()> {
+    // tags are sent separated by commas
+    let is_valid = |tag: &String| !tag.is_empty() && !tag.contains(',') && !tag.chars().any(char::is_control);
+    if tags.len() <= MAX_ROOM_TAGS && tags.iter().all(is_valid) {
+        Ok(())
+    } else {
+        Err(Error::InvalidTags)
+    }
+}
+
+/// Checks the name a room is created with. Names are compared ignoring
+/// case, and can't start or end with spaces, so that rooms can't easily be
+/// named to look like others.
+pub(crate) fn expect_valid_room_name(name: &str) -> Result<()> {
+    let is_valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LENGTH
+        && name.trim() == name
+        && !name.chars().any(char::is_control);
+    if is_valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidName)
+    }
+}
+
+/// A regular expression which the whole of a string must match, such as a
+/// room's payload schema.
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern(Regex);
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Result<Pattern> {
+        // check the pattern by itself first, so that it can't escape from
+        // the anchors, e.g. `a)|(b`
+        let build = |pattern: &str| RegexBuilder::new(pattern)
+            .size_limit(MAX_SCHEMA_SIZE)
+            .build()
+            .map_err(|_| Error::InvalidSchema);
+        build(pattern)?;
+        Ok(Pattern(build(&format!("^(?:{pattern})$"))?))
+    }
+    
+    pub(crate) fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+    
+    /// The pattern as it was given, without the anchors.
+    pub(crate) fn as_str(&self) -> &str {
+        let anchored = self.0.as_str();
+        &anchored[4..anchored.len() - 2]
+    }
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = ();
+    
+    fn from_str(s: &str) -> std::result::Result<Pattern, ()> {
+        Pattern::new(s).map_err(|_| ())
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct User {
     pub(crate) id: UserID,
-    pub(crate) state: UserState,
+    /// The rooms the user owns, is in, spectates or has asked to join.
+    pub(crate) rooms: BTreeMap<RoomID, Membership>,
+    /// Whether the user is waiting in the matchmaking queue.
+    pub(crate) queued: bool,
+    pub(crate) client_version: Option<String>,
+    pub(crate) is_admin: bool,
+    /// The display name the user has chosen, shown to others alongside
+    /// their ID.
+    pub(crate) name: Option<String>,
+    /// The account the user signed in with, if the authenticator named one.
+    pub(crate) account: Option<String>,
+    /// A smoothed estimate of the round-trip time to this user's client, in
+    /// milliseconds, if their client has answered any pings.
+    pub(crate) latency_ms: Option<u32>,
+    /// The sequence number of the ping the user was last sent, and when it
+    /// was sent, until they answer it.
+    pub(crate) ping: Option<(u32, SystemTime)>,
+    /// Identifies this session in logs and resume tokens. Unlike the user's
+    /// ID it is never reused, and it stays the same when the session is
+    /// resumed from a new connection.
+    pub(crate) session_id: String,
+    /// A secret which lets the user take over this session from a new
+    /// connection, if their old one drops.
+    pub(crate) resume_token: String,
+    /// The token this user resumed with last, so that replays of it can be
+    /// recognised.
+    pub(crate) previous_resume_token: Option<String>,
+    /// False while the user's connection has dropped but they may resume.
+    pub(crate) connected: bool,
 }
 
+/// How a user takes part in one room.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum UserState {
-    RoomOwner(RoomID),
-    InRoom(RoomID),
-    RequestedJoin(RoomID),
-    Nowhere,
+pub(crate) enum Membership {
+    Owner,
+    Member,
+    RequestedJoin,
+    Spectating,
 }
 
-impl Default for UserState {
-    fn default() -> UserState {
-        UserState::Nowhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinPolicy {
+    /// Users must ask to join, and be accepted by the room owner.
+    AskOwner,
+    /// Users join immediately, without asking the room owner.
+    Open,
+}
+
+/// Who is told when a player's connection drops, comes back, or times out.
+/// Everyone in the room is always told about the owner's connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Owner,
+    /// Everyone in the room, including spectators.
+    All,
+}
+
+/// A WebRTC signaling message, which the server relays between two players
+/// in a room so that they can connect to each other directly. The server
+/// doesn't read the session descriptions or candidates it relays; since
+/// they are sent in one line, clients must escape any line breaks in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Offer,
+    Answer,
+    IceCandidate,
+}
+
+impl Signal {
+    /// The keyword which both the request and the relayed message use.
+    pub(crate) fn keyword(self) -> &'static str {
+        match self {
+            Signal::Offer => "OFFER",
+            Signal::Answer => "ANSWER",
+            Signal::IceCandidate => "ICE_CANDIDATE",
+        }
+    }
+    
+    pub(crate) fn from_keyword(keyword: &str) -> Option<Signal> {
+        match keyword {
+            "OFFER" => Some(Signal::Offer),
+            "ANSWER" => Some(Signal::Answer),
+            "ICE_CANDIDATE" => Some(Signal::IceCandidate),
+            _ => None,
+        }
     }
 }
 
@@ -30,62 +191,176 @@ pub(crate) struct Room {
     pub(crate) id: RoomID,
     pub(crate) owner_id: UserID,
     pub(crate) data: Arc<str>,
-    pub(crate) members: Vec<UserID>,
-    pub(crate) join_requests: Vec<UserID>,
+    /// Members in order of how long they have been in the room.
+    pub(crate) members: IndexSet<UserID>,
+    pub(crate) join_requests: IndexSet<UserID>,
+    /// Spectators receive the owner's broadcasts, but cannot send messages.
+    pub(crate) spectators: IndexSet<UserID>,
+    /// Users the owner has invited, who can join without asking and without
+    /// the password.
+    pub(crate) invited: IndexSet<UserID>,
+    /// The maximum number of members, not counting the owner.
+    pub(crate) capacity: Option<usize>,
+    /// The maximum number of join requests waiting for the owner.
+    pub(crate) max_join_requests: Option<usize>,
+    pub(crate) join_policy: JoinPolicy,
+    pub(crate) presence: Presence,
+    /// If set, payloads sent by members must match this pattern.
+    pub(crate) schema: Option<Pattern>,
+    /// If set, users must give this password to join or spectate.
+    pub(crate) password: Option<String>,
+    /// If set, players can only send payloads on their turn.
+    pub(crate) turns: Option<Turns>,
+    /// When the owner last rejected each user's join request.
+    pub(crate) rejected: HashMap<UserID, SystemTime>,
+    /// If set by the owner, how long a rejected user must wait before
+    /// asking again, instead of the server's default.
+    pub(crate) rejoin_cooldown: Option<Duration>,
+    /// The players who have voted to remove each member.
+    pub(crate) kick_votes: HashMap<UserID, IndexSet<UserID>>,
+    /// Set by the owner when the room is created, so that users can join it
+    /// by name; no two rooms have the same name.
+    pub(crate) name: Option<String>,
+    /// Set by the owner when the room is created, so that clients can list
+    /// only the rooms they are interested in.
+    pub(crate) tags: Vec<String>,
+    /// The owner's most recent broadcasts, oldest first.
+    pub(crate) history: VecDeque<Arc<str>>,
+    /// When the room was created, or `None` if it was restored from a
+    /// snapshot which didn't say.
+    pub(crate) created_at: Option<SystemTime>,
+    /// When anyone in the room last did anything in it, or `None` if the
+    /// server hasn't seen any activity since it started.
+    pub(crate) last_active: Option<SystemTime>,
+}
+
+impl Membership {
+    /// How the membership is named in the admin API.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Membership::Owner => "owner",
+            Membership::Member => "member",
+            Membership::RequestedJoin => "requested_join",
+            Membership::Spectating => "spectating",
+        }
+    }
 }
 
 impl User {
     pub(crate) fn new(id: UserID) -> User {
+        let session_id = ids::session_id();
         User {
             id,
-            state: UserState::Nowhere,
+            rooms: BTreeMap::new(),
+            queued: false,
+            client_version: None,
+            is_admin: false,
+            name: None,
+            account: None,
+            latency_ms: None,
+            ping: None,
+            resume_token: ids::resume_token(&session_id),
+            session_id,
+            previous_resume_token: None,
+            connected: true,
+        }
+    }
+    
+    /// Sets the user's display name, or clears it if the name is empty.
+    pub(crate) fn set_name(&mut self, name: String) -> Result<()> {
+        // names are shown in comma-separated listings
+        if name.chars().count() > MAX_NAME_LENGTH || name.contains(',') || name.chars().any(char::is_control) {
+            return Err(Error::InvalidName);
+        }
+        self.name = (!name.is_empty()).then_some(name);
+        Ok(())
+    }
+    
+    pub(crate) fn named(&self) -> Named {
+        Named(self.id, self.name.clone())
+    }
+    
+    /// Updates the latency estimate with a round-trip time measured by the
+    /// server, weighting older samples more heavily to smooth out jitter.
+    pub(crate) fn record_latency(&mut self, sample_ms: u32) {
+        self.latency_ms = Some(match self.latency_ms {
+            Some(old) => ((3 * old as u64 + sample_ms as u64) / 4) as u32,
+            None => sample_ms,
+        });
+    }
+    
+    pub(crate) fn membership(&self, room_id: RoomID) -> Option<Membership> {
+        self.rooms.get(&room_id).copied()
+    }
+    
+    /// Checks that the user isn't doing anything yet, as a new connection
+    /// which resumes another session must not be.
+    pub(crate) fn expect_nowhere(&self) -> Result<()> {
+        if self.queued {
+            Err(Error::AlreadyQueued)
+        } else if !self.rooms.is_empty() {
+            Err(Error::AlreadyInARoom)
+        } else {
+            Ok(())
+        }
+    }
+    
+    /// Checks that the user can take part in another room.
+    pub(crate) fn expect_room_slot(&self) -> Result<()> {
+        if self.rooms.len() >= MAX_ROOMS_PER_USER {
+            Err(Error::TooManyRooms)
+        } else {
+            Ok(())
         }
     }
     
-    fn expect_nowhere(&self) -> Result<()> {
-        match self.state {
-            UserState::RoomOwner(_) |
-            UserState::InRoom(_) => Err(Error::AlreadyInARoom),
-            UserState::RequestedJoin(_) => Err(Error::AlreadyRequestedJoin),
-            UserState::Nowhere => Ok(()),
+    /// Checks that the user can join or spectate this room.
+    pub(crate) fn expect_not_in(&self, room_id: RoomID) -> Result<()> {
+        match self.membership(room_id) {
+            Some(Membership::RequestedJoin) => Err(Error::AlreadyRequestedJoin),
+            Some(_) => Err(Error::AlreadyInARoom),
+            None => self.expect_room_slot(),
         }
     }
     
     pub(crate) fn try_create_room(&mut self, room_id: RoomID, data: String) -> Result<Room> {
-        self.expect_nowhere()?;
+        self.expect_room_slot()?;
         let room = Room::new(room_id, self.id, data);
-        self.state = UserState::RoomOwner(room_id);
+        self.rooms.insert(room_id, Membership::Owner);
         Ok(room)
     }
     
     pub(crate) fn try_join_room(&mut self, room: &mut Room) -> Result<()> {
-        self.expect_nowhere()?;
-        self.state = UserState::RequestedJoin(room.id);
-        room.join_requests.push(self.id);
+        self.expect_not_in(room.id)?;
+        self.rooms.insert(room.id, Membership::RequestedJoin);
+        room.join_requests.insert(self.id);
+        Ok(())
+    }
+    
+    pub(crate) fn try_spectate_room(&mut self, room: &mut Room) -> Result<()> {
+        self.expect_not_in(room.id)?;
+        self.rooms.insert(room.id, Membership::Spectating);
+        room.spectators.insert(self.id);
         Ok(())
     }
     
     pub(crate) fn leave_room(&mut self, room: &mut Room) -> Result<()> {
-        match self.state {
-            UserState::RoomOwner(_) => {
+        match self.membership(room.id) {
+            Some(Membership::Owner) => {
                 Err(Error::IsRoomOwner)
             },
-            UserState::InRoom(room_id) => {
-                if room_id == room.id {
-                    self.state = UserState::Nowhere;
-                    room.remove_user(self.id)
-                } else {
-                    Err(Error::NotInThatRoom)
-                }
+            Some(Membership::Member) => {
+                self.rooms.remove(&room.id);
+                room.remove_user(self.id)
+            },
+            Some(Membership::RequestedJoin) => {
+                room.cancel_join_request(self)
             },
-            UserState::RequestedJoin(room_id) => {
-                if room_id == room.id {
-                    room.cancel_join_request(self)
-                } else {
-                    Err(Error::NotInThatRoom)
-                }
+            Some(Membership::Spectating) => {
+                self.rooms.remove(&room.id);
+                room.remove_spectator(self.id)
             },
-            UserState::Nowhere => {
+            None => {
                 Err(Error::NotInThatRoom)
             },
         }
@@ -98,8 +373,135 @@ impl Room {
             id,
             owner_id,
             data: Arc::from(data),
-            members: Vec::new(),
-            join_requests: Vec::new(),
+            members: IndexSet::new(),
+            join_requests: IndexSet::new(),
+            spectators: IndexSet::new(),
+            invited: IndexSet::new(),
+            capacity: None,
+            max_join_requests: None,
+            join_policy: JoinPolicy::AskOwner,
+            presence: Presence::Owner,
+            schema: None,
+            password: None,
+            turns: None,
+            rejected: HashMap::new(),
+            rejoin_cooldown: None,
+            kick_votes: HashMap::new(),
+            name: None,
+            tags: Vec::new(),
+            history: VecDeque::new(),
+            created_at: None,
+            last_active: None,
+        }
+    }
+    
+    pub(crate) fn is_full(&self) -> bool {
+        self.capacity.is_some_and(|c| self.members.len() >= c)
+    }
+    
+    pub(crate) fn has_too_many_join_requests(&self) -> bool {
+        self.max_join_requests.is_some_and(|n| self.join_requests.len() >= n)
+    }
+    
+    pub(crate) fn is_nearly_full(&self) -> bool {
+        self.capacity.is_some_and(|c| limits::is_near_limit(self.members.len(), c))
+    }
+    
+    /// Sets or changes the password needed to join, or removes it if the
+    /// password is empty. Existing members and spectators are unaffected.
+    pub(crate) fn set_password(&mut self, password: String) {
+        self.password = (!password.is_empty()).then_some(password);
+    }
+    
+    /// Checks the password given to join or spectate; invited users needn't
+    /// give one.
+    pub(crate) fn expect_password(&self, user_id: UserID, given: Option<&str>) -> Result<()> {
+        match &self.password {
+            Some(_) if self.invited.contains(&user_id) => Ok(()),
+            Some(password) if !given.is_some_and(|given| ids::secrets_match(given, password)) => Err(Error::IncorrectPassword),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Checks that the user wasn't rejected too recently to ask to join
+    /// again; invited users needn't wait.
+    pub(crate) fn expect_cooled_down(&self, user_id: UserID, now: SystemTime, default: Duration) -> Result<()> {
+        let cooldown = self.rejoin_cooldown.unwrap_or(default);
+        match self.rejected.get(&user_id) {
+            Some(_) if self.invited.contains(&user_id) => Ok(()),
+            Some(&at) if now.duration_since(at).unwrap_or_default() < cooldown => Err(Error::RejoinCooldown),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Remembers that the user's join request was rejected, forgetting any
+    /// rejections which no longer matter.
+    pub(crate) fn reject(&mut self, user_id: UserID, now: SystemTime, default: Duration) {
+        let cooldown = self.rejoin_cooldown.unwrap_or(default);
+        self.rejected.retain(|_, at| now.duration_since(*at).unwrap_or_default() < cooldown);
+        self.rejected.insert(user_id, now);
+    }
+    
+    /// Counts a player's vote to remove a member, returning how many of the
+    /// room's players have voted to remove them.
+    pub(crate) fn vote_kick(&mut self, voter_id: UserID, target_id: UserID) -> usize {
+        let Room {owner_id, members, kick_votes, ..} = self;
+        let voters = kick_votes.entry(target_id).or_default();
+        voters.insert(voter_id);
+        // players who have left since voting don't count
+        voters.retain(|u_id| u_id == owner_id || members.contains(u_id));
+        voters.len()
+    }
+    
+    /// Whether the room has all of the given tags.
+    pub(crate) fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.contains(tag))
+    }
+    
+    /// What the room listing searches and sorts by: the room's name, or its
+    /// data if it has none, which games usually start with a name.
+    pub(crate) fn listed_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.data)
+    }
+    
+    /// Sets the pattern which member payloads must match, or clears it if the
+    /// pattern is empty.
+    pub(crate) fn set_schema(&mut self, pattern: &str) -> Result<()> {
+        self.schema = if pattern.is_empty() {
+            None
+        } else {
+            Some(Pattern::new(pattern)?)
+        };
+        Ok(())
+    }
+    
+    /// The pattern given to `set_schema`, if any.
+    pub(crate) fn schema_pattern(&self) -> Option<&str> {
+        self.schema.as_ref()
+            .map(Pattern::as_str)
+    }
+    
+    /// Keeps a broadcast from the owner, forgetting the oldest one if the
+    /// history is full.
+    pub(crate) fn remember(&mut self, payload: Arc<str>) {
+        if self.history.len() >= MAX_HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(payload);
+    }
+    
+    /// Checks that it is this user's turn, if the game is turn-based.
+    pub(crate) fn expect_turn(&self, user_id: UserID) -> Result<()> {
+        match &self.turns {
+            Some(turns) if turns.current() != user_id => Err(Error::NotYourTurn),
+            _ => Ok(()),
+        }
+    }
+    
+    pub(crate) fn expect_valid_payload(&self, payload: &str) -> Result<()> {
+        match &self.schema {
+            Some(schema) if !schema.is_match(payload) => Err(Error::InvalidPayload),
+            _ => Ok(()),
         }
     }
     
@@ -119,25 +521,63 @@ impl Room {
         }
     }
     
+    pub(crate) fn owner_and_members(&self) -> impl Iterator<Item = UserID> + '_ {
+        std::iter::once(self.owner_id)
+            .chain(self.members.iter().copied())
+    }
+    
+    /// The owner, members and spectators.
+    pub(crate) fn everyone(&self) -> impl Iterator<Item = UserID> + '_ {
+        self.owner_and_members()
+            .chain(self.spectators.iter().copied())
+    }
+    
+    /// Everyone who receives the owner's broadcasts.
+    pub(crate) fn audience(&self) -> impl Iterator<Item = UserID> + '_ {
+        self.members.iter()
+            .chain(self.spectators.iter())
+            .copied()
+    }
+    
     pub(crate) fn set_owner(&mut self, user: &mut User) -> Result<()> {
-        let index = index_of(&self.members, user.id, Error::NoSuchUser)?;
-        std::mem::swap(&mut self.members[index], &mut self.owner_id);
-        user.state = UserState::RoomOwner(self.id);
+        if !self.members.shift_remove(&user.id) {
+            return Err(Error::NoSuchUser);
+        }
+        // the old owner has been here longer than anyone
+        self.members.shift_insert(0, self.owner_id);
+        self.owner_id = user.id;
+        user.rooms.insert(self.id, Membership::Owner);
+        Ok(())
+    }
+    
+    /// Makes the longest-standing member the owner, after the old owner has
+    /// gone.
+    pub(crate) fn promote_member(&mut self, user: &mut User) -> Result<()> {
+        if !self.members.shift_remove(&user.id) {
+            return Err(Error::NoSuchUser);
+        }
+        self.owner_id = user.id;
+        user.rooms.insert(self.id, Membership::Owner);
         Ok(())
     }
     
     pub(crate) fn cancel_join_request(&mut self, user: &mut User) -> Result<()> {
-        let index = index_of(&self.join_requests, user.id, Error::NoSuchJoinRequest)?;
-        self.join_requests.swap_remove(index);
-        user.state = UserState::Nowhere;
+        if !self.join_requests.swap_remove(&user.id) {
+            return Err(Error::NoSuchJoinRequest);
+        }
+        user.rooms.remove(&self.id);
         Ok(())
     }
     
     pub(crate) fn accept_join_request(&mut self, user: &mut User) -> Result<()> {
+        if self.is_full() {
+            return Err(Error::RoomFull);
+        }
         self.cancel_join_request(user)?;
         
-        self.members.push(user.id);
-        user.state = UserState::InRoom(self.id);
+        self.members.insert(user.id);
+        self.invited.swap_remove(&user.id);
+        user.rooms.insert(self.id, Membership::Member);
         Ok(())
     }
     
@@ -146,14 +586,17 @@ impl Room {
             return Err(Error::IsRoomOwner);
         }
         
-        let index = index_of(&self.members, user_id, Error::NoSuchUser)?;
-        self.members.swap_remove(index);
+        if !self.members.shift_remove(&user_id) {
+            return Err(Error::NoSuchUser);
+        }
+        self.kick_votes.remove(&user_id);
+        Ok(())
+    }
+    
+    pub(crate) fn remove_spectator(&mut self, user_id: UserID) -> Result<()> {
+        if !self.spectators.swap_remove(&user_id) {
+            return Err(Error::NoSuchUser);
+        }
         Ok(())
     }
-}
-
-fn index_of<T: Eq>(arr: &[T], v: T, e: Error) -> Result<usize> {
-    arr.iter()
-        .position(|t| *t == v)
-        .ok_or(e)
 }
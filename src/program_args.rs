@@ -1,5 +1,13 @@
 use arg::Args;
 
+use crate::auth::HttpCallback;
+use crate::codec::Protocol;
+use crate::config;
+
+use crate::dispatch::{ListenAddr, UndeliveredPolicy};
+use crate::logging::{LogFormat, Rotation};
+use crate::models::Pattern;
+
 #[derive(Args)]
 ///incognita-socket-server
 ///Runs a server for the Incognita Socket protocol.
@@ -8,14 +16,514 @@ pub(crate) struct ProgramArgs {
     ///Print version number and then exit
     pub(crate) print_version: bool,
     
+    #[arg(long = "preset")]
+    ///Start from a bundle of limits and timeouts: casual, tournament or lan-party
+    pub(crate) preset: Option<String>,
+    
+    #[arg(long = "config")]
+    ///Read options from this TOML file; overrides the preset, and is overridden by options given here
+    pub(crate) config: Option<String>,
+    
+    #[arg(long = "export-config")]
+    ///Write the limits and timeouts which would be used to this file, and then exit
+    pub(crate) export_config: Option<String>,
+    
     #[arg(short, long, default_value = "31337")]
-    ///Listen on this port
+    ///Listen on this port, on all interfaces
     pub(crate) port: u16,
     
+    #[arg(long = "bind")]
+    ///Listen on this host:port instead; may be given more than once
+    pub(crate) bind: Vec<ListenAddr>,
+    
+    #[arg(long = "relay")]
+    ///Serve clients through the relay at this address, instead of accepting connections; the relay sends PAIRED when it pairs a connection with a client
+    pub(crate) relay: Option<String>,
+    
+    #[arg(long = "protocol", default_value = "Protocol::Pipe")]
+    ///How clients encode requests and messages: pipe (one line each, with |-separated fields), msgpack (MessagePack arrays) or protobuf (see proto/incognita.proto)
+    pub(crate) protocol: Protocol,
+    
+    #[arg(long = "compress-threshold", default_value = "1024")]
+    ///Compress relayed payloads longer than this many bytes, for clients which ask for it with COMPRESS
+    pub(crate) compress_threshold: usize,
+    
+    #[arg(long = "mirror-port")]
+    ///Also listen on this port for observers, who get a read-only feed of open games and player counts
+    pub(crate) mirror_port: Option<u16>,
+    
+    #[arg(long = "udp-port")]
+    ///Also relay game datagrams over UDP on this port, for clients which ask with REGISTER_UDP
+    pub(crate) udp_port: Option<u16>,
+    
     #[arg(long = "max-connections", default_value = "256")]
     pub(crate) max_connections: usize,
+    
+    #[arg(long = "waiting-room", default_value = "0")]
+    ///When the server is full, let up to this many connections wait for a free slot
+    pub(crate) waiting_room: usize,
+    
+    #[arg(long = "waiting-timeout", default_value = "120")]
+    ///Maximum number of seconds a connection may wait for a free slot
+    pub(crate) waiting_timeout: u64,
+    
+    #[arg(long = "rate-limit", default_value = "0.0")]
+    ///Maximum number of requests per second from each connection, or 0 for no limit
+    pub(crate) rate_limit: f64,
+    
+    #[arg(long = "rate-burst", default_value = "0.0")]
+    ///Number of requests a connection may make at once, above the rate limit, or 0 for twice the rate limit
+    pub(crate) rate_burst: f64,
+    
+    #[arg(long = "max-request-length", default_value = "8192")]
+    ///Maximum length of a request in bytes; clients sending longer requests are disconnected
+    pub(crate) max_request_length: usize,
+    
+    #[arg(long = "max-payload-length", default_value = "4096")]
+    ///Maximum length in bytes of a game message or join message to relay
+    pub(crate) max_payload_length: usize,
+    
+    #[arg(long = "max-queued-messages", default_value = "256")]
+    ///Maximum number of messages waiting to be sent to a client; clients which fall further behind are disconnected
+    pub(crate) max_queued_messages: usize,
+    
+    #[arg(long = "write-timeout", default_value = "30")]
+    ///Disconnect clients which don't accept a message within this many seconds, or 0 to wait indefinitely
+    pub(crate) write_timeout: u64,
+    
+    #[arg(long = "game-idle-timeout", default_value = "0")]
+    ///Close games where nobody has done anything for this many seconds, or 0 to keep them open
+    pub(crate) room_idle_timeout: u64,
+    
+    #[arg(long = "rejoin-cooldown", default_value = "60")]
+    ///Number of seconds a player whose join request was rejected must wait before asking to join the same game again
+    pub(crate) rejoin_cooldown: u64,
+    
+    #[arg(long = "max-game-members")]
+    ///Maximum number of players who may join each game, not counting the owner
+    pub(crate) max_room_members: Option<usize>,
+    
+    #[arg(long = "max-join-requests", default_value = "32")]
+    ///Maximum number of players who may be waiting for the owner to accept them into each game
+    pub(crate) max_join_requests: usize,
+    
+    #[arg(long = "vote-kick-threshold", default_value = "0.5")]
+    ///Fraction of a game's other players who must vote to remove a player before they are removed, or 1 to disable vote kicks
+    pub(crate) vote_kick_threshold: f64,
+    
+    #[arg(long = "max-game-data-length", default_value = "1024")]
+    ///Maximum length in bytes of the data which games are created with
+    pub(crate) max_room_data_length: usize,
+    
+    #[arg(long = "game-data-pattern")]
+    ///Only let games be created with data which wholly matches this regular expression
+    pub(crate) room_data_pattern: Option<Pattern>,
+    
+    #[arg(long = "instance")]
+    ///Run a virtual server instance, as name:port[:max_connections]; may be given more than once
+    pub(crate) instances: Vec<InstanceSpec>,
+    
+    #[arg(long = "allow-list")]
+    ///Only accept connections from the addresses and CIDR ranges in this file, which is reloaded when it changes
+    pub(crate) allow_list: Option<String>,
+    
+    #[arg(long = "deny-list")]
+    ///Refuse connections from the addresses and CIDR ranges in this file, which is reloaded when it changes
+    pub(crate) deny_list: Option<String>,
+    
+    #[arg(long = "admin-password")]
+    ///Users who send ADMIN_LOGIN with this password become administrators
+    pub(crate) admin_password: Option<String>,
+    
+    #[arg(long = "auth-token")]
+    ///Only serve clients whose first request is AUTH with this token
+    pub(crate) auth_token: Option<String>,
+    
+    #[arg(long = "auth-token-file")]
+    ///Like --auth-token, but read the token from this file, so that it isn't visible in the process list
+    pub(crate) auth_token_file: Option<String>,
+    
+    #[arg(long = "auth-url")]
+    ///Only serve clients whose first request is AUTH with a credential which this http:// URL accepts, when POSTed to it; credentials are sent unencrypted, so the URL should be on a trusted network
+    pub(crate) auth_url: Option<HttpCallback>,
+    
+    #[arg(long = "admin-port")]
+    ///Also serve an HTTP admin API on this port, authenticated with the admin password as a bearer token
+    pub(crate) admin_port: Option<u16>,
+    
+    #[arg(long = "admin-host", default_value = "\"127.0.0.1\".into()")]
+    ///Serve the admin API on this host only; by default, it only accepts connections from this machine
+    pub(crate) admin_host: String,
+    
+    #[arg(long = "match-size", default_value = "2")]
+    ///Number of players grouped into each game by the matchmaking queue, including its owner
+    pub(crate) match_size: usize,
+    
+    #[arg(long = "min-client-version")]
+    ///Reject clients older than this version
+    pub(crate) min_client_version: Option<String>,
+    
+    #[arg(long = "block-client-version")]
+    ///Reject clients with this exact version; may be given more than once
+    pub(crate) blocked_client_versions: Vec<String>,
+    
+    #[arg(long = "upgrade-url")]
+    ///Where rejected clients can download a newer version
+    pub(crate) upgrade_url: Option<String>,
+    
+    #[arg(long = "motd")]
+    ///Send this message of the day to each client right after WELCOME
+    pub(crate) motd: Option<String>,
+    
+    #[arg(long = "motd-file")]
+    ///Like --motd, but read the message from this file, one MOTD message per line; the file is read again when the config is reloaded
+    pub(crate) motd_file: Option<String>,
+    
+    #[arg(long = "restart-at")]
+    ///Restart daily at this time (HH:MM, UTC), exiting with status 75 once games have finished
+    pub(crate) restart_at: Option<String>,
+    
+    #[arg(long = "drain-timeout", default_value = "600")]
+    ///Maximum number of seconds to wait for games to finish before a scheduled restart
+    pub(crate) drain_timeout: u64,
+    
+    #[arg(long = "disconnect-grace", default_value = "30")]
+    ///Number of seconds to keep a disconnected player's place, so they can resume their session
+    pub(crate) disconnect_grace: u64,
+    
+    #[arg(long = "state-file")]
+    ///Save users and games to this file, and restore them from it at startup so that games survive a restart
+    pub(crate) state_file: Option<String>,
+    
+    #[arg(long = "room-store")]
+    ///Record every game and its history in this SQLite database, if the server was built with the sqlite feature
+    pub(crate) room_store: Option<String>,
+    
+    #[arg(long = "record")]
+    ///Record every request and message, with the time and user, to this file so the session can be replayed; passwords and tokens are left out
+    pub(crate) record: Option<String>,
+    
+    #[arg(long = "snapshot-interval", default_value = "30")]
+    ///Number of seconds between saves to the state file
+    pub(crate) snapshot_interval: u64,
+    
+    #[arg(long = "on-undelivered", default_value = "UndeliveredPolicy::Log")]
+    ///What to do when a message can't be delivered: log, notify (the sender) or cleanup (remove the recipient)
+    pub(crate) undelivered_policy: UndeliveredPolicy,
+    
+    #[arg(long = "log-level", default_value = "tracing::Level::INFO")]
+    ///Most detailed level of log events to show: error, warn, info, debug or trace
+    pub(crate) log_level: tracing::Level,
+    
+    #[arg(long = "log-format", default_value = "LogFormat::Text")]
+    ///Write log events as text, or as one JSON object per line: text or json
+    pub(crate) log_format: LogFormat,
+    
+    #[arg(long = "log-file")]
+    ///Write the log to this file instead of stdout
+    pub(crate) log_file: Option<String>,
+    
+    #[arg(long = "log-max-size", default_value = "64")]
+    ///Start a new log file once the current one reaches this many megabytes
+    pub(crate) log_max_size: u64,
+    
+    #[arg(long = "log-rotate", default_value = "Rotation::Daily")]
+    ///Also start a new log file every hour or day: hourly, daily or never
+    pub(crate) log_rotation: Rotation,
+    
+    #[arg(long = "log-keep", default_value = "7")]
+    ///Number of old log files to keep
+    pub(crate) log_keep: usize,
+    
+    #[arg(long = "simulated-clock")]
+    ///Only let time pass when an administrator sends ADVANCE_CLOCK, for testing
+    pub(crate) simulated_clock: bool,
+    
+    #[arg(long = "random-ids")]
+    ///Hand out random user and game IDs instead of sequential ones
+    pub(crate) random_ids: bool,
+}
+
+#[derive(Args)]
+///incognita-socket-server bench
+///Simulates clients against a running server, and reports how quickly their messages are delivered.
+pub(crate) struct BenchArgs {
+    #[arg(long = "target", default_value = "\"127.0.0.1:31337\".into()")]
+    ///Address of the server to test, as host:port
+    pub(crate) target: String,
+    
+    #[arg(long = "clients", default_value = "16")]
+    ///Number of simulated clients, including the game owners
+    pub(crate) clients: usize,
+    
+    #[arg(long = "rooms", default_value = "4")]
+    ///Number of games; the other clients are shared between them
+    pub(crate) rooms: usize,
+    
+    #[arg(long = "messages", default_value = "100")]
+    ///Number of messages each player sends to their game's owner
+    pub(crate) messages: usize,
+    
+    #[arg(long = "rate", default_value = "10.0")]
+    ///Messages per second sent by each player, which should be within the server's rate limit
+    pub(crate) rate: f64,
+    
+    #[arg(long = "auth-token")]
+    ///Authenticate with this token, if the server requires one
+    pub(crate) auth_token: Option<String>,
+}
+
+#[derive(Args)]
+///incognita-socket-server client
+///Connects to a server and sends the requests typed at the prompt, showing messages as they arrive.
+pub(crate) struct ClientArgs {
+    #[arg(long = "target", default_value = "\"127.0.0.1:31337\".into()")]
+    ///Address of the server, as host:port
+    pub(crate) target: String,
+    
+    #[arg(long = "auth-token")]
+    ///Authenticate with this token, if the server requires one
+    pub(crate) auth_token: Option<String>,
+}
+
+#[derive(Args)]
+///incognita-socket-server replay
+///Feeds a recording made with --record into a fresh server, and reports any messages which the server now sends differently.
+pub(crate) struct ReplayArgs {
+    #[arg(required)]
+    ///The recording to replay
+    pub(crate) recording: String,
+    
+    #[arg(long = "config")]
+    ///Configure the server from this TOML file, as written by --export-config, to match the one which made the recording
+    pub(crate) config: Option<String>,
+}
+
+/// A virtual server instance, which has its own port, limits, users and
+/// games, but shares the process with other instances.
+pub(crate) struct InstanceSpec {
+    pub(crate) name: String,
+    pub(crate) port: u16,
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl std::str::FromStr for InstanceSpec {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<InstanceSpec, ()> {
+        let mut parts = s.split(':');
+        let name = parts.next()
+            .filter(|name| !name.is_empty())
+            .ok_or(())?
+            .to_string();
+        let port = parts.next()
+            .ok_or(())?
+            .parse()
+            .map_err(|_| ())?;
+        let max_connections = parts.next()
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| ())?;
+        
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(InstanceSpec {name, port, max_connections})
+    }
+}
+
+impl ProgramArgs {
+    /// The addresses to listen on, which are given by `--bind` or otherwise
+    /// `--port`.
+    pub(crate) fn listen_addrs(&self) -> Vec<ListenAddr> {
+        if self.bind.is_empty() {
+            vec![ListenAddr::any(self.port)]
+        } else {
+            self.bind.clone()
+        }
+    }
+    
+    /// Where to serve the admin API, if anywhere.
+    pub(crate) fn admin_addr(&self) -> Option<String> {
+        self.admin_port
+            .map(|port| ListenAddr {host: self.admin_host.clone(), port}.to_string())
+    }
+    
+    /// The rules, limits and logging options which a preset or config file
+    /// can set, as they would be written in a config file.
+    pub(crate) fn settings(&self) -> config::Settings {
+        let mut settings = vec![
+            ("protocol", self.protocol.to_string()),
+            ("compress-threshold", self.compress_threshold.to_string()),
+            ("max-connections", self.max_connections.to_string()),
+            ("waiting-room", self.waiting_room.to_string()),
+            ("waiting-timeout", self.waiting_timeout.to_string()),
+            ("rate-limit", format!("{:?}", self.rate_limit)),
+            ("rate-burst", format!("{:?}", self.rate_burst)),
+            ("max-request-length", self.max_request_length.to_string()),
+            ("max-payload-length", self.max_payload_length.to_string()),
+            ("max-queued-messages", self.max_queued_messages.to_string()),
+            ("max-game-data-length", self.max_room_data_length.to_string()),
+            ("max-join-requests", self.max_join_requests.to_string()),
+            ("vote-kick-threshold", format!("{:?}", self.vote_kick_threshold)),
+            ("write-timeout", self.write_timeout.to_string()),
+            ("match-size", self.match_size.to_string()),
+            ("drain-timeout", self.drain_timeout.to_string()),
+            ("disconnect-grace", self.disconnect_grace.to_string()),
+            ("game-idle-timeout", self.room_idle_timeout.to_string()),
+            ("rejoin-cooldown", self.rejoin_cooldown.to_string()),
+            ("snapshot-interval", self.snapshot_interval.to_string()),
+            ("on-undelivered", self.undelivered_policy.to_string()),
+            ("random-ids", self.random_ids.to_string()),
+            ("log-level", self.log_level.to_string().to_lowercase()),
+            ("log-format", self.log_format.to_string()),
+            ("log-max-size", self.log_max_size.to_string()),
+            ("log-rotate", self.log_rotation.to_string()),
+            ("log-keep", self.log_keep.to_string()),
+        ];
+        let optional = [
+            ("max-game-members", self.max_room_members.map(|n| n.to_string())),
+            ("game-data-pattern", self.room_data_pattern.as_ref().map(Pattern::to_string)),
+            ("allow-list", self.allow_list.clone()),
+            ("deny-list", self.deny_list.clone()),
+            ("auth-token-file", self.auth_token_file.clone()),
+            ("auth-url", self.auth_url.as_ref().map(HttpCallback::to_string)),
+            ("min-client-version", self.min_client_version.clone()),
+            ("upgrade-url", self.upgrade_url.clone()),
+            ("motd", self.motd.clone()),
+            ("motd-file", self.motd_file.clone()),
+            ("restart-at", self.restart_at.clone()),
+            ("state-file", self.state_file.clone()),
+            ("room-store", self.room_store.clone()),
+            ("record", self.record.clone()),
+            ("log-file", self.log_file.clone()),
+        ];
+        settings.extend(optional.into_iter()
+            .filter_map(|(option, value)| value.map(|value| (option, value))));
+        settings.extend(self.blocked_client_versions.iter()
+            .map(|version| ("block-client-version", version.clone())));
+        
+        settings.into_iter()
+            .map(|(option, value)| (option.to_string(), value))
+            .collect()
+    }
 }
 
 pub(crate) fn parse() -> ProgramArgs {
-    arg::parse_args()
+    let args: ProgramArgs = arg::parse_args();
+    with_config(args).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    })
+}
+
+/// Parses the command line after a subcommand such as `bench`.
+pub(crate) fn parse_subcommand<T: Args>() -> T {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    match T::from_args(args.iter().map(String::as_str)) {
+        Ok(args) => args,
+        Err(arg::ParseError::HelpRequested(help)) => {
+            println!("{help}");
+            std::process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Parses the command line again, re-reading the config file, so that a
+/// running server can pick up changes to it.
+pub(crate) fn reparse() -> Result<ProgramArgs, String> {
+    let explicit: Vec<String> = std::env::args().skip(1).collect();
+    let args = ProgramArgs::from_args(explicit.iter().map(String::as_str))
+        .map_err(|e| e.to_string())?;
+    with_config(args)
+}
+
+/// The options in a config file alone, such as for the server which a
+/// recording is replayed into.
+pub(crate) fn from_config_file(path: &str) -> Result<ProgramArgs, String> {
+    let args = config::to_args(&read_config(path)?, &[]);
+    ProgramArgs::from_args(args.iter().map(String::as_str))
+        .map_err(|e| e.to_string())
+}
+
+fn read_config(path: &str) -> Result<config::Settings, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| config::parse(&text))
+        .map_err(|e| format!("Failed to read config file {path}: {e}"))
+}
+
+fn with_config(args: ProgramArgs) -> Result<ProgramArgs, String> {
+    if args.preset.is_none() && args.config.is_none() {
+        return Ok(args);
+    }
+    
+    let mut settings = Vec::new();
+    if let Some(name) = &args.preset {
+        settings = config::preset(name).ok_or_else(|| {
+            format!("Unknown preset '{name}'; expected one of {}", config::PRESET_NAMES.join(", "))
+        })?;
+    }
+    if let Some(path) = &args.config {
+        let file_settings = read_config(path)?;
+        // options in the config file replace the preset's, rather than adding to them
+        settings.retain(|(option, _)| !file_settings.iter().any(|(o, _)| o == option));
+        settings.extend(file_settings);
+    }
+    
+    let explicit: Vec<String> = std::env::args().skip(1).collect();
+    let mut combined = config::to_args(&settings, &explicit);
+    combined.extend(explicit);
+    ProgramArgs::from_args(combined.iter().map(String::as_str))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn instance_spec() {
+        let spec: InstanceSpec = "coop:31338".parse().unwrap();
+        assert_eq!("coop", spec.name);
+        assert_eq!(31338, spec.port);
+        assert_eq!(None, spec.max_connections);
+        
+        let spec: InstanceSpec = "versus:31339:16".parse().unwrap();
+        assert_eq!(Some(16), spec.max_connections);
+    }
+    
+    #[test]
+    fn presets_are_valid() {
+        for name in config::PRESET_NAMES {
+            let args = config::to_args(&config::preset(name).unwrap(), &[]);
+            let parsed = ProgramArgs::from_args(args.iter().map(String::as_str));
+            assert!(parsed.is_ok(), "invalid preset {name}");
+        }
+    }
+    
+    #[test]
+    fn export_round_trip() {
+        let args = config::to_args(&config::preset("tournament").unwrap(), &[]);
+        let parsed = ProgramArgs::from_args(args.iter().map(String::as_str)).unwrap();
+        let exported = parsed.settings();
+        
+        let args = config::to_args(&exported, &[]);
+        let reparsed = ProgramArgs::from_args(args.iter().map(String::as_str)).unwrap();
+        assert_eq!(exported, reparsed.settings());
+        assert_eq!(64, reparsed.max_connections);
+        assert!(reparsed.random_ids);
+    }
+    
+    #[test]
+    fn invalid_instance_spec() {
+        assert!("coop".parse::<InstanceSpec>().is_err());
+        assert!(":31338".parse::<InstanceSpec>().is_err());
+        assert!("coop:x".parse::<InstanceSpec>().is_err());
+        assert!("coop:31338:16:1".parse::<InstanceSpec>().is_err());
+    }
 }
@@ -0,0 +1,1129 @@
+use std::borrow::Cow;
+use async_std::io;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::{AsyncReadExt, StreamExt};
+use futures::stream::BoxStream;
+use prost::Message as _;
+
+use crate::codec::{Codec, Frame};
+use crate::friends::FriendStatus;
+use crate::limits::Warning;
+use crate::models::{JoinPolicy, Presence, Signal};
+use crate::request::{self, Audience, Request, RequestID, RoomOrder, RoomQuery};
+use crate::response::{Message, Named};
+use crate::transport::Reader;
+
+/// Requests and messages are the protobuf messages defined in
+/// `proto/incognita.proto`, each sent after its length as a varint.
+pub(crate) struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn frames<'a>(&self, reader: &'a mut Reader, max_len: usize) -> BoxStream<'a, io::Result<Frame>> {
+        futures::stream::unfold(Some(io::BufReader::new(reader)), move |reader| async move {
+            let mut reader = reader?;
+            let len = match read_length(&mut reader).await {
+                Ok(Some(len)) => len,
+                Ok(None) => return None,
+                Err(e) => return Some((Err(e), None)),
+            };
+            if len > max_len {
+                return Some((Ok(Frame::TooLong), None));
+            }
+            let mut frame = vec![0; len];
+            match reader.read_exact(&mut frame).await {
+                Ok(()) => Some((Ok(Frame::Complete(frame)), Some(reader))),
+                // an unfinished request at the end is dropped
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        }).boxed()
+    }
+    
+    fn decode(&self, frame: &[u8]) -> Option<Request> {
+        let request = wire::Request::decode(frame).ok()?;
+        from_wire(request.kind?)
+    }
+    
+    fn auth_credential<'a>(&self, frame: &'a [u8]) -> Option<Cow<'a, str>> {
+        let request = wire::Request::decode(frame).ok()?;
+        let Some(wire::RequestKind::Auth(auth)) = request.kind else {
+            return None;
+        };
+        Some(Cow::Owned(auth.text))
+    }
+    
+    fn request_id(&self, frame: &[u8]) -> Option<RequestID> {
+        wire::Request::decode(frame).ok()?.request_id
+    }
+    
+    fn encode(&self, msg: &Message, out: &mut Vec<u8>) {
+        to_wire_message(msg).encode_length_delimited(out)
+            .expect("writing to a Vec can't fail");
+    }
+}
+
+/// Reads a varint length prefix, or `None` if the connection closed first.
+async fn read_length(reader: &mut (impl io::Read + Unpin)) -> io::Result<Option<usize>> {
+    let mut len = 0;
+    for i in 0..10 {
+        let mut byte = [0];
+        if reader.read(&mut byte).await? == 0 {
+            return Ok(None);
+        }
+        len |= usize::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "invalid length prefix"))
+}
+
+/// A string field of a request, held to the same rules as in the pipe format.
+fn field(s: String) -> Option<String> {
+    request::is_field(&s).then_some(s)
+}
+
+fn fields(v: Vec<String>) -> Option<Vec<String>> {
+    v.into_iter().map(field).collect()
+}
+
+fn optional_field(s: Option<String>) -> Option<Option<String>> {
+    match s {
+        Some(s) => field(s).map(Some),
+        None => Some(None),
+    }
+}
+
+fn from_wire(kind: wire::RequestKind) -> Option<Request> {
+    use wire::RequestKind as K;
+    let request = match kind {
+        K::Hello(t) => Request::Hello(field(t.text)?),
+        K::SetName(t) => Request::SetName(field(t.text)?),
+        K::Resume(r) => Request::Resume(field(r.token)?),
+        K::ListOpenGames(l) => {
+            let sort = match wire::RoomOrder::try_from(l.sort).ok()? {
+                wire::RoomOrder::Unsorted => None,
+                wire::RoomOrder::Newest => Some(RoomOrder::Newest),
+                wire::RoomOrder::FewestPlayers => Some(RoomOrder::FewestPlayers),
+                wire::RoomOrder::Name => Some(RoomOrder::Name),
+            };
+            Request::ListRooms(RoomQuery {tags: fields(l.tags)?, search: optional_field(l.search)?, sort})
+        },
+        K::Stats(_) => Request::Stats,
+        K::ListMembers(r) => Request::ListMembers(r.room_id),
+        K::GetGameInfo(r) => Request::GetRoomInfo(r.room_id),
+        K::RoomPings(r) => Request::RoomPings(r.room_id),
+        K::Ping(p) => Request::Ping(p.sequence_number, p.latency_ms),
+        K::CreateGame(c) => match optional_field(c.name)? {
+            Some(name) => Request::CreateNamedRoom(name, field(c.text)?, fields(c.tags)?),
+            None => Request::CreateRoom(field(c.text)?, fields(c.tags)?),
+        },
+        K::SetOwner(r) => Request::SetOwner(r.room_id, r.user_id),
+        K::SetJoinPolicy(p) => {
+            let policy = match wire::JoinPolicy::try_from(p.policy).ok()? {
+                wire::JoinPolicy::Ask => JoinPolicy::AskOwner,
+                wire::JoinPolicy::Open => JoinPolicy::Open,
+            };
+            Request::SetJoinPolicy(p.room_id, policy)
+        },
+        // the pattern may contain `|`, as it is the last field
+        K::SetSchema(r) => Request::SetSchema(r.room_id, (!r.text.contains('\n')).then_some(r.text)?),
+        K::SetPassword(r) => Request::SetPassword(r.room_id, field(r.text)?),
+        K::SetRejoinCooldown(r) => Request::SetRejoinCooldown(r.room_id, r.count),
+        K::JoinGame(j) => {
+            let password = optional_field(j.password)?;
+            Request::AskJoinRoom(j.room_id, field(j.message)?, password)
+        },
+        K::JoinNamedGame(j) => {
+            let password = optional_field(j.password)?;
+            Request::JoinNamedRoom(field(j.name)?, field(j.message)?, password)
+        },
+        K::JoinAny(j) => Request::JoinAnyRoom(field(j.filter)?, field(j.message)?),
+        K::Spectate(s) => Request::Spectate(s.room_id, optional_field(s.password)?),
+        K::Queue(t) => Request::Queue(field(t.text)?),
+        K::Unqueue(_) => Request::Unqueue,
+        K::AcceptJoin(r) => Request::AcceptJoinRoom(r.room_id, r.user_id),
+        K::Invite(r) => Request::Invite(r.room_id, r.user_id),
+        K::VoteKick(r) => Request::VoteKick(r.room_id, r.user_id),
+        K::RejectJoin(r) => Request::RejectJoinRoom(r.room_id, r.user_id, field(r.text)?),
+        K::LeaveGame(r) => Request::LeaveRoom(r.room_id),
+        K::CloseGame(r) => Request::CloseRoom(r.room_id),
+        K::Send(r) => Request::Send(r.room_id, field(r.text)?.into()),
+        // binary payloads are relayed in base64, as in the pipe format
+        K::SendBinary(r) => Request::SendBinary(r.room_id, BASE64.encode(r.data).into()),
+        K::SendTo(r) => Request::SendTo(r.room_id, r.user_id, field(r.text)?.into()),
+        K::Chat(r) => Request::Chat(r.room_id, field(r.text)?.into()),
+        K::Whisper(r) => Request::Whisper(r.room_id, r.user_id, field(r.text)?.into()),
+        K::EchoFrom(r) => Request::EchoFrom(r.room_id, r.user_id, field(r.text)?.into()),
+        K::AdminLogin(t) => Request::AdminLogin(field(t.text)?),
+        K::ReloadConfig(_) => Request::ReloadConfig,
+        K::GetTimeline(r) => Request::GetTimeline(r.room_id),
+        K::AdvanceClock(a) => Request::AdvanceClock(a.seconds),
+        K::Announce(a) => {
+            let audience = match wire::Audience::try_from(a.audience).ok()? {
+                wire::Audience::All => Audience::Everyone,
+                wire::Audience::InGames => Audience::InRooms,
+            };
+            Request::Announce(audience, field(a.text)?.into())
+        },
+        K::Quit(_) => Request::Quit,
+        K::Compress(t) => Request::Compress(field(t.text)?),
+        K::RegisterUdp(_) => Request::RegisterUdp,
+        K::Offer(r) => Request::Signal(Signal::Offer, r.room_id, r.user_id, field(r.text)?.into()),
+        K::Answer(r) => Request::Signal(Signal::Answer, r.room_id, r.user_id, field(r.text)?.into()),
+        K::IceCandidate(r) => Request::Signal(Signal::IceCandidate, r.room_id, r.user_id, field(r.text)?.into()),
+        K::SetTurnOrder(r) => Request::SetTurnOrder(r.room_id, r.user_ids),
+        K::EndTurn(r) => Request::EndTurn(r.room_id),
+        K::EnableAcks(_) => Request::EnableAcks,
+        K::Ack(c) => Request::Ack(c.value),
+        K::Pong(c) => Request::Pong(u32::try_from(c.value).ok()?),
+        K::SubscribeGames(_) => Request::SubscribeRooms,
+        K::UnsubscribeGames(_) => Request::UnsubscribeRooms,
+        K::AddFriend(t) => Request::AddFriend(field(t.text)?),
+        K::RemoveFriend(t) => Request::RemoveFriend(field(t.text)?),
+        K::ListFriends(_) => Request::ListFriends,
+        K::SetPresence(p) => {
+            let presence = match wire::Presence::try_from(p.presence).ok()? {
+                wire::Presence::Owner => Presence::Owner,
+                wire::Presence::All => Presence::All,
+            };
+            Request::SetPresence(p.room_id, presence)
+        },
+        // only valid as the first request, where it is read separately
+        K::Auth(_) => return None,
+    };
+    Some(request)
+}
+
+fn to_wire_message(msg: &Message) -> wire::Message {
+    match msg {
+        Message::Reply(request_id, msg) => wire::Message {request_id: Some(*request_id), ..to_wire_message(msg)},
+        _ => wire::Message {kind: Some(to_wire(msg)), request_id: None},
+    }
+}
+
+fn to_wire(msg: &Message) -> wire::MessageKind {
+    use wire::MessageKind as K;
+    let text = |text: &str| wire::Text {text: text.to_string()};
+    let count = |value: u64| wire::Count {value};
+    let room = |room_id| wire::Room {room_id};
+    let room_user = |room_id, user_id| wire::RoomUser {room_id, user_id};
+    let room_text = |room_id, text: &str| wire::RoomText {room_id, text: text.to_string()};
+    let room_user_text = |room_id, user_id, text: &str| wire::RoomUserText {room_id, user_id, text: text.to_string()};
+    let room_count = |room_id, count: usize| wire::RoomCount {room_id, count: count as u64};
+    let listed_game = |room_id, players: usize, data: &str, name: Option<&str>| wire::ListedGame {room_id, players: players as u64, data: data.to_string(), name: name.map(Into::into)};
+    let user = |Named(user_id, name): &Named| wire::User {user_id: *user_id, name: name.clone()};
+    
+    match msg {
+        Message::Welcome(user_id, token, info) => K::Welcome(wire::Welcome {
+            user_id: *user_id,
+            token: token.clone(),
+            server_version: info.version.clone(),
+            protocol_version: info.protocol_version,
+            max_request_length: info.max_request_length as u64,
+            max_room_members: info.max_room_members.map(|n| n as u64),
+            rate_limit: info.rate_limit.map(|limit| wire::RateLimit {per_second: limit.per_second, burst: limit.burst}),
+        }),
+        &Message::Waiting(position) => K::Waiting(count(position as u64)),
+        Message::Resumed(user_id, token) => K::Resumed(wire::Session {user_id: *user_id, token: token.clone()}),
+        Message::ResumeReplayed => K::ResumeReplayed(wire::Empty {}),
+        Message::HelloOk => K::HelloOk(wire::Empty {}),
+        &Message::Pong(sequence_number) => K::Pong(count(sequence_number.into())),
+        &Message::Ping(sequence_number) => K::Ping(count(sequence_number.into())),
+        &Message::Stats(users, games, uptime_secs, traffic) => K::Stats(wire::Stats {
+            users: users as u64,
+            games: games as u64,
+            uptime_secs,
+            traffic: traffic.map(|traffic| wire::Traffic {
+                messages_in: traffic.messages_in,
+                bytes_in: traffic.bytes_in,
+                messages_out: traffic.messages_out,
+                bytes_out: traffic.bytes_out,
+            }),
+        }),
+        &Message::MirrorRoomOpened(room_id, players) => K::GameOpened(room_count(room_id, players)),
+        &Message::MirrorRoomClosed(room_id) => K::GameClosed(room(room_id)),
+        &Message::MirrorPlayers(room_id, players) => K::GamePlayers(room_count(room_id, players)),
+        Message::RoomsSubscribed => K::GamesSubscribed(wire::Empty {}),
+        Message::RoomsUnsubscribed => K::GamesUnsubscribed(wire::Empty {}),
+        Message::RoomAdded(room_id, players, data, name) => K::RoomAdded(listed_game(*room_id, *players, data, name.as_deref())),
+        Message::RoomUpdated(room_id, players, data, name) => K::RoomUpdated(listed_game(*room_id, *players, data, name.as_deref())),
+        &Message::RoomRemoved(room_id) => K::RoomRemoved(room(room_id)),
+        &Message::ServerRestarting(secs) => K::ServerRestarting(count(secs)),
+        Message::ListRooms(rooms) => K::OpenGames(wire::OpenGames {
+            games: rooms.iter()
+                .map(|(room_id, data, name)| wire::OpenGame {room_id: *room_id, data: data.to_string(), name: name.as_deref().map(Into::into)})
+                .collect(),
+        }),
+        Message::ListMembers(room_id, owner, members) => K::Members(wire::Members {
+            room_id: *room_id,
+            owner: Some(user(owner)),
+            members: members.iter().map(user).collect(),
+        }),
+        Message::RoomPings(room_id, pings) => K::RoomPings(wire::RoomPings {
+            room_id: *room_id,
+            pings: pings.iter()
+                .map(|&(user_id, latency_ms)| wire::Latency {user_id, latency_ms})
+                .collect(),
+        }),
+        Message::ListJoinRequests(room_id, user_ids) => K::JoinRequests(wire::RoomUsers {room_id: *room_id, user_ids: user_ids.clone()}),
+        Message::ListSpectators(room_id, user_ids) => K::Spectators(wire::RoomUsers {room_id: *room_id, user_ids: user_ids.clone()}),
+        Message::RoomInfo(room_id, owner_id, member_count, capacity, join_policy, data, name) => K::GameInfo(wire::GameInfo {
+            room_id: *room_id,
+            owner_id: *owner_id,
+            member_count: *member_count as u64,
+            capacity: capacity.map(|n| n as u64),
+            join_policy: match join_policy {
+                JoinPolicy::AskOwner => wire::JoinPolicy::Ask,
+                JoinPolicy::Open => wire::JoinPolicy::Open,
+            }.into(),
+            data: data.to_string(),
+            name: name.as_deref().map(Into::into),
+        }),
+        &Message::RoomCreated(room_id) => K::CreatedGame(room(room_id)),
+        &Message::Queued(waiting) => K::Queued(count(waiting as u64)),
+        &Message::MatchFound(room_id, owner_id) => K::MatchFound(room_user(room_id, owner_id)),
+        &Message::RoomJoined(room_id) => K::Joined(room(room_id)),
+        &Message::RoomSpectating(room_id) => K::Spectating(room(room_id)),
+        &Message::SpectatorJoined(room_id, user_id) => K::SpectatorJoined(room_user(room_id, user_id)),
+        &Message::RoomClosed(room_id) => K::GameOver(room(room_id)),
+        &Message::ChangedOwner(room_id, user_id) => K::ChangedOwner(room_user(room_id, user_id)),
+        Message::RoomRejected(room_id, reason) => K::Rejected(room_text(*room_id, reason)),
+        Message::JoinRequested(room_id, named, msg) => K::PlayerJoined(wire::PlayerJoined {
+            room_id: *room_id,
+            user: Some(user(named)),
+            message: msg.clone(),
+        }),
+        &Message::JoinRequestSent(room_id) => K::JoinRequested(room(room_id)),
+        Message::MemberJoined(room_id, user_id, msg) => K::MemberJoined(room_user_text(*room_id, *user_id, msg)),
+        &Message::PlayerDisconnected(room_id, user_id) => K::PlayerDisconnected(room_user(room_id, user_id)),
+        &Message::PlayerReconnected(room_id, user_id) => K::PlayerReconnected(room_user(room_id, user_id)),
+        &Message::PlayerTimedOut(room_id, user_id) => K::PlayerTimedOut(room_user(room_id, user_id)),
+        &Message::KickVote(room_id, user_id, votes, needed) => K::KickVote(wire::KickVote {room_id, user_id, votes: votes as u64, needed: needed as u64}),
+        &Message::VotedOut(room_id, user_id) => K::VotedOut(room_user(room_id, user_id)),
+        Message::PlayerLeft(room_id, named) => K::PlayerLeft(wire::PlayerLeft {room_id: *room_id, user: Some(user(named))}),
+        Message::Invited(room_id, named) => K::Invited(wire::Invited {room_id: *room_id, owner: Some(user(named))}),
+        Message::FriendAdded(account) => K::FriendAdded(text(account)),
+        Message::FriendRemoved(account) => K::FriendRemoved(text(account)),
+        Message::Friends(friends) => K::Friends(wire::Friends {
+            friends: friends.iter()
+                .map(|(account, status)| {
+                    let (status, user_id) = match *status {
+                        FriendStatus::Pending => (wire::FriendStatus::Pending, None),
+                        FriendStatus::Offline => (wire::FriendStatus::Offline, None),
+                        FriendStatus::Online(user_id) => (wire::FriendStatus::Online, Some(user_id)),
+                    };
+                    wire::Friend {account: account.clone(), status: status.into(), user_id}
+                })
+                .collect(),
+        }),
+        Message::FriendOnline(user_id, account) => K::FriendOnline(wire::FriendOnline {user_id: *user_id, account: account.clone()}),
+        Message::FriendOffline(account) => K::FriendOffline(text(account)),
+        Message::FriendJoinedRoom(room_id, user_id, account) => K::FriendJoinedGame(wire::FriendJoinedGame {
+            room_id: *room_id,
+            user_id: *user_id,
+            account: account.clone(),
+        }),
+        Message::ReceivedBroadcast(room_id, payload) |
+        Message::ReceivedIndividual(room_id, payload) => K::Received(room_text(*room_id, payload)),
+        Message::ReceivedFrom(room_id, user_id, payload) => K::ReceivedFrom(room_user_text(*room_id, *user_id, payload)),
+        Message::ReceivedBinaryBroadcast(room_id, payload) => K::ReceivedBinary(wire::ReceivedBinary {
+            room_id: *room_id,
+            user_id: None,
+            data: BASE64.decode(&**payload).unwrap_or_default(),
+        }),
+        Message::ReceivedBinaryFrom(room_id, user_id, payload) => K::ReceivedBinary(wire::ReceivedBinary {
+            room_id: *room_id,
+            user_id: Some(*user_id),
+            data: BASE64.decode(&**payload).unwrap_or_default(),
+        }),
+        Message::Chat(room_id, user_id, text) => K::Chat(room_user_text(*room_id, *user_id, text)),
+        &Message::Undelivered(user_id) => K::Undelivered(wire::UserRef {user_id}),
+        Message::Whisper(room_id, user_id, text) => K::Whisper(room_user_text(*room_id, *user_id, text)),
+        Message::Signal(signal, room_id, user_id, data) => {
+            let r = room_user_text(*room_id, *user_id, data);
+            match signal {
+                Signal::Offer => K::Offer(r),
+                Signal::Answer => K::Answer(r),
+                Signal::IceCandidate => K::IceCandidate(r),
+            }
+        },
+        &Message::Turn(room_id, turn, user_id) => K::Turn(wire::Turn {room_id, turn, user_id}),
+        &Message::NoTurnOrder(room_id) => K::NoTurnOrder(room(room_id)),
+        Message::ReceivedOnTurn(room_id, turn, user_id, payload) => K::ReceivedOnTurn(wire::ReceivedOnTurn {
+            room_id: *room_id,
+            turn: *turn,
+            user_id: *user_id,
+            text: payload.to_string(),
+        }),
+        Message::Replayed(room_id, payload) => K::Replayed(room_text(*room_id, payload)),
+        Message::AdminOk => K::AdminOk(wire::Empty {}),
+        Message::ConfigReloaded => K::ConfigReloaded(wire::Empty {}),
+        &Message::Clock(secs) => K::Clock(count(secs)),
+        Message::Motd(t) => K::Motd(text(t)),
+        Message::Announcement(t) => K::Announcement(text(t)),
+        &Message::Announced(n) => K::Announced(count(n as u64)),
+        Message::Timeline(room_id, entries) => K::Timeline(wire::Timeline {
+            room_id: *room_id,
+            entries: entries.iter()
+                .map(|entry| wire::TimelineEntry {
+                    time: entry.time,
+                    event: entry.event.name().to_string(),
+                    user_id: entry.event.user_id(),
+                })
+                .collect(),
+        }),
+        &Message::Warning(Warning::RoomNearlyFull(room_id, members, capacity)) => K::GameNearlyFull(wire::GameNearlyFull {
+            room_id,
+            members: members as u64,
+            capacity: capacity as u64,
+        }),
+        Message::Warning(warning @ (
+            Warning::NearlyRateLimited(count, limit) |
+            Warning::PayloadNearlyTooLarge(count, limit) |
+            Warning::QueueNearlyFull(count, limit)
+        )) => K::LimitWarning(wire::LimitWarning {
+            kind: warning.name().to_string(),
+            count: *count as u64,
+            limit: *limit as u64,
+        }),
+        Message::Error(e) => K::Error(wire::Error {text: e.to_string(), code: e.code(), request: None}),
+        Message::Failed(request, e) => K::Error(wire::Error {text: e.to_string(), code: e.code(), request: Some(request.to_string())}),
+        Message::UdpToken(port, token) => K::UdpToken(wire::UdpToken {port: (*port).into(), token: token.clone()}),
+        Message::Compression(c) => K::Compression(text(&c.map_or("none".to_string(), |c| c.to_string()))),
+        Message::Compressed(c, bytes) => K::Compressed(wire::Compressed {algorithm: c.to_string(), data: bytes.to_vec()}),
+        Message::AcksEnabled => K::AcksEnabled(wire::Empty {}),
+        // the ID is written on the enclosing message, by `to_wire_message`
+        Message::Reply(_, msg) => to_wire(msg),
+        Message::Sequenced(n, msg) => K::Sequenced(wire::Sequenced {
+            sequence_number: *n,
+            message: Some(Box::new(to_wire_message(msg))),
+        }),
+    }
+}
+
+/// The types declared in `proto/incognita.proto`.
+mod wire {
+    use crate::models::{RoomID, UserID};
+    use crate::request::RequestID;
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Request {
+        #[prost(oneof = "RequestKind", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56")]
+        pub(crate) kind: Option<RequestKind>,
+        #[prost(uint32, optional, tag = "100")] pub(crate) request_id: Option<RequestID>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum RequestKind {
+        #[prost(message, tag = "1")] Hello(Text),
+        #[prost(message, tag = "2")] SetName(Text),
+        #[prost(message, tag = "3")] Resume(Resume),
+        #[prost(message, tag = "4")] ListOpenGames(ListOpenGames),
+        #[prost(message, tag = "5")] Stats(Empty),
+        #[prost(message, tag = "6")] ListMembers(Room),
+        #[prost(message, tag = "7")] GetGameInfo(Room),
+        #[prost(message, tag = "8")] RoomPings(Room),
+        #[prost(message, tag = "9")] Ping(Ping),
+        #[prost(message, tag = "10")] CreateGame(CreateGame),
+        #[prost(message, tag = "11")] SetOwner(RoomUser),
+        #[prost(message, tag = "12")] SetJoinPolicy(SetJoinPolicy),
+        #[prost(message, tag = "13")] SetSchema(RoomText),
+        #[prost(message, tag = "14")] SetPassword(RoomText),
+        #[prost(message, tag = "15")] JoinGame(JoinGame),
+        #[prost(message, tag = "16")] JoinAny(JoinAny),
+        #[prost(message, tag = "17")] Spectate(Spectate),
+        #[prost(message, tag = "18")] Queue(Text),
+        #[prost(message, tag = "19")] Unqueue(Empty),
+        #[prost(message, tag = "20")] AcceptJoin(RoomUser),
+        #[prost(message, tag = "21")] RejectJoin(RoomUserText),
+        #[prost(message, tag = "22")] LeaveGame(Room),
+        #[prost(message, tag = "23")] Send(RoomText),
+        #[prost(message, tag = "24")] SendTo(RoomUserText),
+        #[prost(message, tag = "25")] Chat(RoomText),
+        #[prost(message, tag = "26")] Whisper(RoomUserText),
+        #[prost(message, tag = "27")] EchoFrom(RoomUserText),
+        #[prost(message, tag = "28")] AdminLogin(Text),
+        #[prost(message, tag = "29")] ReloadConfig(Empty),
+        #[prost(message, tag = "30")] GetTimeline(Room),
+        #[prost(message, tag = "31")] AdvanceClock(AdvanceClock),
+        #[prost(message, tag = "32")] Quit(Empty),
+        #[prost(message, tag = "33")] Auth(Text),
+        #[prost(message, tag = "34")] Compress(Text),
+        #[prost(message, tag = "35")] SendBinary(RoomBytes),
+        #[prost(message, tag = "36")] RegisterUdp(Empty),
+        #[prost(message, tag = "37")] Offer(RoomUserText),
+        #[prost(message, tag = "38")] Answer(RoomUserText),
+        #[prost(message, tag = "39")] IceCandidate(RoomUserText),
+        #[prost(message, tag = "40")] SetTurnOrder(RoomUsers),
+        #[prost(message, tag = "41")] EndTurn(Room),
+        #[prost(message, tag = "42")] EnableAcks(Empty),
+        #[prost(message, tag = "43")] Ack(Count),
+        #[prost(message, tag = "44")] SubscribeGames(Empty),
+        #[prost(message, tag = "45")] UnsubscribeGames(Empty),
+        #[prost(message, tag = "46")] SetPresence(SetPresence),
+        #[prost(message, tag = "47")] Invite(RoomUser),
+        #[prost(message, tag = "48")] AddFriend(Text),
+        #[prost(message, tag = "49")] RemoveFriend(Text),
+        #[prost(message, tag = "50")] ListFriends(Empty),
+        #[prost(message, tag = "51")] Announce(Announce),
+        #[prost(message, tag = "52")] SetRejoinCooldown(RoomCount),
+        #[prost(message, tag = "53")] CloseGame(Room),
+        #[prost(message, tag = "54")] VoteKick(RoomUser),
+        #[prost(message, tag = "55")] JoinNamedGame(JoinNamedGame),
+        #[prost(message, tag = "56")] Pong(Count),
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Message {
+        #[prost(oneof = "MessageKind", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76")]
+        pub(crate) kind: Option<MessageKind>,
+        #[prost(uint32, optional, tag = "100")] pub(crate) request_id: Option<RequestID>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum MessageKind {
+        #[prost(message, tag = "1")] Welcome(Welcome),
+        #[prost(message, tag = "2")] Waiting(Count),
+        #[prost(message, tag = "3")] Resumed(Session),
+        #[prost(message, tag = "4")] ResumeReplayed(Empty),
+        #[prost(message, tag = "5")] HelloOk(Empty),
+        #[prost(message, tag = "6")] Pong(Count),
+        #[prost(message, tag = "7")] Stats(Stats),
+        #[prost(message, tag = "8")] GameOpened(RoomCount),
+        #[prost(message, tag = "9")] GameClosed(Room),
+        #[prost(message, tag = "10")] GamePlayers(RoomCount),
+        #[prost(message, tag = "11")] ServerRestarting(Count),
+        #[prost(message, tag = "12")] OpenGames(OpenGames),
+        #[prost(message, tag = "13")] Members(Members),
+        #[prost(message, tag = "14")] RoomPings(RoomPings),
+        #[prost(message, tag = "15")] JoinRequests(RoomUsers),
+        #[prost(message, tag = "16")] Spectators(RoomUsers),
+        #[prost(message, tag = "17")] GameInfo(GameInfo),
+        #[prost(message, tag = "18")] CreatedGame(Room),
+        #[prost(message, tag = "19")] Queued(Count),
+        #[prost(message, tag = "20")] MatchFound(RoomUser),
+        #[prost(message, tag = "21")] Joined(Room),
+        #[prost(message, tag = "22")] Spectating(Room),
+        #[prost(message, tag = "23")] SpectatorJoined(RoomUser),
+        #[prost(message, tag = "24")] GameOver(Room),
+        #[prost(message, tag = "25")] ChangedOwner(RoomUser),
+        #[prost(message, tag = "26")] Rejected(RoomText),
+        #[prost(message, tag = "27")] PlayerJoined(PlayerJoined),
+        #[prost(message, tag = "28")] JoinRequested(Room),
+        #[prost(message, tag = "29")] MemberJoined(RoomUserText),
+        #[prost(message, tag = "30")] PlayerDisconnected(RoomUser),
+        #[prost(message, tag = "31")] PlayerReconnected(RoomUser),
+        #[prost(message, tag = "32")] PlayerLeft(PlayerLeft),
+        #[prost(message, tag = "33")] Received(RoomText),
+        #[prost(message, tag = "34")] ReceivedFrom(RoomUserText),
+        #[prost(message, tag = "35")] Chat(RoomUserText),
+        #[prost(message, tag = "36")] Undelivered(UserRef),
+        #[prost(message, tag = "37")] Whisper(RoomUserText),
+        #[prost(message, tag = "38")] AdminOk(Empty),
+        #[prost(message, tag = "39")] ConfigReloaded(Empty),
+        #[prost(message, tag = "40")] Clock(Count),
+        #[prost(message, tag = "41")] Timeline(Timeline),
+        #[prost(message, tag = "42")] GameNearlyFull(GameNearlyFull),
+        #[prost(message, tag = "43")] Error(Error),
+        #[prost(message, tag = "44")] Compression(Text),
+        #[prost(message, tag = "45")] Compressed(Compressed),
+        #[prost(message, tag = "46")] ReceivedBinary(ReceivedBinary),
+        #[prost(message, tag = "47")] UdpToken(UdpToken),
+        #[prost(message, tag = "48")] Offer(RoomUserText),
+        #[prost(message, tag = "49")] Answer(RoomUserText),
+        #[prost(message, tag = "50")] IceCandidate(RoomUserText),
+        #[prost(message, tag = "51")] Turn(Turn),
+        #[prost(message, tag = "52")] NoTurnOrder(Room),
+        #[prost(message, tag = "53")] ReceivedOnTurn(ReceivedOnTurn),
+        #[prost(message, tag = "54")] Replayed(RoomText),
+        #[prost(message, tag = "55")] AcksEnabled(Empty),
+        #[prost(message, tag = "56")] Sequenced(Sequenced),
+        #[prost(message, tag = "57")] GamesSubscribed(Empty),
+        #[prost(message, tag = "58")] GamesUnsubscribed(Empty),
+        #[prost(message, tag = "59")] RoomAdded(ListedGame),
+        #[prost(message, tag = "60")] RoomUpdated(ListedGame),
+        #[prost(message, tag = "61")] RoomRemoved(Room),
+        #[prost(message, tag = "62")] PlayerTimedOut(RoomUser),
+        #[prost(message, tag = "63")] Invited(Invited),
+        #[prost(message, tag = "64")] FriendAdded(Text),
+        #[prost(message, tag = "65")] FriendRemoved(Text),
+        #[prost(message, tag = "66")] Friends(Friends),
+        #[prost(message, tag = "67")] FriendOnline(FriendOnline),
+        #[prost(message, tag = "68")] FriendOffline(Text),
+        #[prost(message, tag = "69")] FriendJoinedGame(FriendJoinedGame),
+        #[prost(message, tag = "70")] Announcement(Text),
+        #[prost(message, tag = "71")] Announced(Count),
+        #[prost(message, tag = "72")] Motd(Text),
+        #[prost(message, tag = "73")] KickVote(KickVote),
+        #[prost(message, tag = "74")] VotedOut(RoomUser),
+        #[prost(message, tag = "75")] LimitWarning(LimitWarning),
+        #[prost(message, tag = "76")] Ping(Count),
+    }
+    
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub(crate) enum JoinPolicy {
+        Ask = 0,
+        Open = 1,
+    }
+    
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub(crate) enum Presence {
+        Owner = 0,
+        All = 1,
+    }
+    
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub(crate) enum RoomOrder {
+        Unsorted = 0,
+        Newest = 1,
+        FewestPlayers = 2,
+        Name = 3,
+    }
+    
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub(crate) enum Audience {
+        All = 0,
+        InGames = 1,
+    }
+    
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub(crate) enum FriendStatus {
+        Pending = 0,
+        Offline = 1,
+        Online = 2,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Empty {}
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Text {
+        #[prost(string, tag = "1")] pub(crate) text: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Count {
+        #[prost(uint64, tag = "1")] pub(crate) value: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Room {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct UserRef {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomText {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(string, tag = "2")] pub(crate) text: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomUser {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, tag = "2")] pub(crate) user_id: UserID,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomUserText {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, tag = "2")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "3")] pub(crate) text: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomBytes {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(bytes = "vec", tag = "2")] pub(crate) data: Vec<u8>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ReceivedBinary {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, optional, tag = "2")] pub(crate) user_id: Option<UserID>,
+        #[prost(bytes = "vec", tag = "3")] pub(crate) data: Vec<u8>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomCount {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint64, tag = "2")] pub(crate) count: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomUsers {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, repeated, tag = "2")] pub(crate) user_ids: Vec<UserID>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Resume {
+        #[prost(string, tag = "1")] pub(crate) token: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Ping {
+        #[prost(uint32, tag = "1")] pub(crate) sequence_number: u32,
+        #[prost(uint32, optional, tag = "2")] pub(crate) latency_ms: Option<u32>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct SetJoinPolicy {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(enumeration = "JoinPolicy", tag = "2")] pub(crate) policy: i32,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct SetPresence {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(enumeration = "Presence", tag = "2")] pub(crate) presence: i32,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct JoinGame {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(string, tag = "2")] pub(crate) message: String,
+        #[prost(string, optional, tag = "3")] pub(crate) password: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct JoinNamedGame {
+        #[prost(string, tag = "1")] pub(crate) name: String,
+        #[prost(string, tag = "2")] pub(crate) message: String,
+        #[prost(string, optional, tag = "3")] pub(crate) password: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ListOpenGames {
+        #[prost(string, repeated, tag = "1")] pub(crate) tags: Vec<String>,
+        #[prost(string, optional, tag = "2")] pub(crate) search: Option<String>,
+        #[prost(enumeration = "RoomOrder", tag = "3")] pub(crate) sort: i32,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct CreateGame {
+        #[prost(string, tag = "1")] pub(crate) text: String,
+        #[prost(string, repeated, tag = "2")] pub(crate) tags: Vec<String>,
+        #[prost(string, optional, tag = "3")] pub(crate) name: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct JoinAny {
+        #[prost(string, tag = "1")] pub(crate) filter: String,
+        #[prost(string, tag = "2")] pub(crate) message: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Spectate {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(string, optional, tag = "2")] pub(crate) password: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct AdvanceClock {
+        #[prost(uint64, tag = "1")] pub(crate) seconds: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Announce {
+        #[prost(enumeration = "Audience", tag = "1")] pub(crate) audience: i32,
+        #[prost(string, tag = "2")] pub(crate) text: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Welcome {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "2")] pub(crate) token: String,
+        #[prost(string, tag = "3")] pub(crate) server_version: String,
+        #[prost(uint32, tag = "4")] pub(crate) protocol_version: u32,
+        #[prost(uint64, tag = "5")] pub(crate) max_request_length: u64,
+        #[prost(uint64, optional, tag = "6")] pub(crate) max_room_members: Option<u64>,
+        #[prost(message, optional, tag = "7")] pub(crate) rate_limit: Option<RateLimit>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Error {
+        #[prost(string, tag = "1")] pub(crate) text: String,
+        #[prost(uint32, tag = "2")] pub(crate) code: u32,
+        #[prost(string, optional, tag = "3")] pub(crate) request: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RateLimit {
+        #[prost(double, tag = "1")] pub(crate) per_second: f64,
+        #[prost(double, tag = "2")] pub(crate) burst: f64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Session {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "2")] pub(crate) token: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Stats {
+        #[prost(uint64, tag = "1")] pub(crate) users: u64,
+        #[prost(uint64, tag = "2")] pub(crate) games: u64,
+        #[prost(uint64, tag = "3")] pub(crate) uptime_secs: u64,
+        #[prost(message, optional, tag = "4")] pub(crate) traffic: Option<Traffic>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Traffic {
+        #[prost(uint64, tag = "1")] pub(crate) messages_in: u64,
+        #[prost(uint64, tag = "2")] pub(crate) bytes_in: u64,
+        #[prost(uint64, tag = "3")] pub(crate) messages_out: u64,
+        #[prost(uint64, tag = "4")] pub(crate) bytes_out: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct User {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+        #[prost(string, optional, tag = "2")] pub(crate) name: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct OpenGame {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(string, tag = "2")] pub(crate) data: String,
+        #[prost(string, optional, tag = "3")] pub(crate) name: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct OpenGames {
+        #[prost(message, repeated, tag = "1")] pub(crate) games: Vec<OpenGame>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ListedGame {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint64, tag = "2")] pub(crate) players: u64,
+        #[prost(string, tag = "3")] pub(crate) data: String,
+        #[prost(string, optional, tag = "4")] pub(crate) name: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Members {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, optional, tag = "2")] pub(crate) owner: Option<User>,
+        #[prost(message, repeated, tag = "3")] pub(crate) members: Vec<User>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Latency {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+        #[prost(uint32, optional, tag = "2")] pub(crate) latency_ms: Option<u32>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct RoomPings {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, repeated, tag = "2")] pub(crate) pings: Vec<Latency>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct GameInfo {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, tag = "2")] pub(crate) owner_id: UserID,
+        #[prost(uint64, tag = "3")] pub(crate) member_count: u64,
+        #[prost(uint64, optional, tag = "4")] pub(crate) capacity: Option<u64>,
+        #[prost(enumeration = "JoinPolicy", tag = "5")] pub(crate) join_policy: i32,
+        #[prost(string, tag = "6")] pub(crate) data: String,
+        #[prost(string, optional, tag = "7")] pub(crate) name: Option<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct PlayerJoined {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, optional, tag = "2")] pub(crate) user: Option<User>,
+        #[prost(string, tag = "3")] pub(crate) message: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct PlayerLeft {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, optional, tag = "2")] pub(crate) user: Option<User>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Invited {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, optional, tag = "2")] pub(crate) owner: Option<User>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Friend {
+        #[prost(string, tag = "1")] pub(crate) account: String,
+        #[prost(enumeration = "FriendStatus", tag = "2")] pub(crate) status: i32,
+        #[prost(uint32, optional, tag = "3")] pub(crate) user_id: Option<UserID>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Friends {
+        #[prost(message, repeated, tag = "1")] pub(crate) friends: Vec<Friend>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct FriendOnline {
+        #[prost(uint32, tag = "1")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "2")] pub(crate) account: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct FriendJoinedGame {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, tag = "2")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "3")] pub(crate) account: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct TimelineEntry {
+        #[prost(uint64, tag = "1")] pub(crate) time: u64,
+        #[prost(string, tag = "2")] pub(crate) event: String,
+        #[prost(uint32, optional, tag = "3")] pub(crate) user_id: Option<UserID>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Timeline {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(message, repeated, tag = "2")] pub(crate) entries: Vec<TimelineEntry>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct GameNearlyFull {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint64, tag = "2")] pub(crate) members: u64,
+        #[prost(uint64, tag = "3")] pub(crate) capacity: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct LimitWarning {
+        #[prost(string, tag = "1")] pub(crate) kind: String,
+        #[prost(uint64, tag = "2")] pub(crate) count: u64,
+        #[prost(uint64, tag = "3")] pub(crate) limit: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct UdpToken {
+        #[prost(uint32, tag = "1")] pub(crate) port: u32,
+        #[prost(string, tag = "2")] pub(crate) token: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct KickVote {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint32, tag = "2")] pub(crate) user_id: UserID,
+        #[prost(uint64, tag = "3")] pub(crate) votes: u64,
+        #[prost(uint64, tag = "4")] pub(crate) needed: u64,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Turn {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint64, tag = "2")] pub(crate) turn: u64,
+        #[prost(uint32, tag = "3")] pub(crate) user_id: UserID,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ReceivedOnTurn {
+        #[prost(uint32, tag = "1")] pub(crate) room_id: RoomID,
+        #[prost(uint64, tag = "2")] pub(crate) turn: u64,
+        #[prost(uint32, tag = "3")] pub(crate) user_id: UserID,
+        #[prost(string, tag = "4")] pub(crate) text: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Sequenced {
+        #[prost(uint64, tag = "1")] pub(crate) sequence_number: u64,
+        #[prost(message, optional, boxed, tag = "2")] pub(crate) message: Option<Box<Message>>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Compressed {
+        #[prost(string, tag = "1")] pub(crate) algorithm: String,
+        #[prost(bytes = "vec", tag = "2")] pub(crate) data: Vec<u8>,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::test::read_frames;
+    use crate::response::{Error, ServerInfo};
+    use super::*;
+    use wire::RequestKind as K;
+    
+    /// Writes a request as a protobuf client would, without its length.
+    fn request(kind: wire::RequestKind) -> Vec<u8> {
+        wire::Request {kind: Some(kind), request_id: None}.encode_to_vec()
+    }
+    
+    fn delimited(kind: wire::RequestKind) -> Vec<u8> {
+        wire::Request {kind: Some(kind), request_id: None}.encode_length_delimited_to_vec()
+    }
+    
+    fn encode(msg: &Message) -> wire::MessageKind {
+        let mut out = Vec::new();
+        ProtobufCodec.encode(msg, &mut out);
+        wire::Message::decode_length_delimited(out.as_slice()).unwrap().kind.unwrap()
+    }
+    
+    #[test]
+    fn decode() {
+        let codec = ProtobufCodec;
+        assert_eq!(
+            Some(Request::AskJoinRoom(3, "hello".into(), Some("pw".into()))),
+            codec.decode(&request(K::JoinGame(wire::JoinGame {room_id: 3, message: "hello".into(), password: Some("pw".into())}))),
+        );
+        assert_eq!(
+            Some(Request::SetJoinPolicy(3, JoinPolicy::Open)),
+            codec.decode(&request(K::SetJoinPolicy(wire::SetJoinPolicy {room_id: 3, policy: wire::JoinPolicy::Open.into()}))),
+        );
+        assert_eq!(
+            Some(Request::SetPresence(3, Presence::All)),
+            codec.decode(&request(K::SetPresence(wire::SetPresence {room_id: 3, presence: wire::Presence::All.into()}))),
+        );
+        assert_eq!(
+            Some(Request::SetRejoinCooldown(3, 60)),
+            codec.decode(&request(K::SetRejoinCooldown(wire::RoomCount {room_id: 3, count: 60}))),
+        );
+        assert_eq!(Some(Request::ListRooms(RoomQuery::default())), codec.decode(&request(K::ListOpenGames(wire::ListOpenGames {tags: Vec::new(), search: None, sort: 0}))));
+        assert_eq!(
+            Some(Request::SendBinary(3, "AP8B".into())),
+            codec.decode(&request(K::SendBinary(wire::RoomBytes {room_id: 3, data: vec![0, 255, 1]}))),
+        );
+        assert_eq!(None, codec.decode(b""));
+        assert_eq!(None, codec.decode(b"LEAVE_GAME|3"));
+    }
+    
+    #[test]
+    fn decode_pipe_rules() {
+        let codec = ProtobufCodec;
+        assert_eq!(
+            Some(Request::SetSchema(3, "A|B".into())),
+            codec.decode(&request(K::SetSchema(wire::RoomText {room_id: 3, text: "A|B".into()}))),
+        );
+        assert_eq!(None, codec.decode(&request(K::CreateGame(wire::CreateGame {text: "A|B".into(), tags: Vec::new(), name: None}))));
+        assert_eq!(
+            Some(Request::CreateNamedRoom("Draft".into(), "x".into(), Vec::new())),
+            codec.decode(&request(K::CreateGame(wire::CreateGame {text: "x".into(), tags: Vec::new(), name: Some("Draft".into())}))),
+        );
+        assert_eq!(
+            Some(Request::JoinNamedRoom("Draft".into(), "hi".into(), None)),
+            codec.decode(&request(K::JoinNamedGame(wire::JoinNamedGame {name: "Draft".into(), message: "hi".into(), password: None}))),
+        );
+        assert_eq!(None, codec.decode(&request(K::Send(wire::RoomText {room_id: 3, text: "a\nb".into()}))));
+        assert_eq!(None, codec.decode(&request(K::Spectate(wire::Spectate {room_id: 3, password: Some("a|b".into())}))));
+    }
+    
+    #[test]
+    fn auth() {
+        let codec = ProtobufCodec;
+        assert_eq!(Some("s3cr|t"), codec.auth_credential(&request(K::Auth(wire::Text {text: "s3cr|t".into()}))).as_deref());
+        assert_eq!(Some(""), codec.auth_credential(&request(K::Auth(wire::Text {text: String::new()}))).as_deref());
+        assert_eq!(None, codec.auth_credential(&request(K::Hello(wire::Text {text: "1.0".into()}))).as_deref());
+        // only valid as the first request
+        assert_eq!(None, codec.decode(&request(K::Auth(wire::Text {text: "s3cr|t".into()}))));
+        
+        // the request ID is encoded after the credential
+        let frame = wire::Request {kind: Some(K::Auth(wire::Text {text: "s3cr|t".into()})), request_id: Some(42)}.encode_to_vec();
+        assert_eq!(Some("s3cr|t"), codec.auth_credential(&frame).as_deref());
+    }
+    
+    #[test]
+    fn encode_messages() {
+        assert_eq!(
+            wire::MessageKind::PlayerLeft(wire::PlayerLeft {
+                room_id: 3,
+                user: Some(wire::User {user_id: 4, name: Some("bob".into())}),
+            }),
+            encode(&Message::PlayerLeft(3, Named(4, Some("bob".into())))),
+        );
+        assert_eq!(
+            wire::MessageKind::OpenGames(wire::OpenGames {games: Vec::new()}),
+            encode(&Message::ListRooms(Vec::new())),
+        );
+        assert_eq!(
+            wire::MessageKind::RoomAdded(wire::ListedGame {room_id: 3, players: 2, data: "x".into(), name: Some("Draft".into())}),
+            encode(&Message::RoomAdded(3, 2, "x".into(), Some("Draft".into()))),
+        );
+        assert_eq!(
+            wire::MessageKind::ReceivedBinary(wire::ReceivedBinary {room_id: 3, user_id: Some(4), data: vec![0, 255, 1]}),
+            encode(&Message::ReceivedBinaryFrom(3, 4, "AP8B".into())),
+        );
+        assert_eq!(
+            wire::MessageKind::KickVote(wire::KickVote {room_id: 3, user_id: 4, votes: 1, needed: 2}),
+            encode(&Message::KickVote(3, 4, 1, 2)),
+        );
+        assert_eq!(
+            wire::MessageKind::Stats(wire::Stats {users: 2, games: 1, uptime_secs: 90, traffic: None}),
+            encode(&Message::Stats(2, 1, 90, None)),
+        );
+        assert_eq!(
+            wire::MessageKind::Error(wire::Error {text: Error::RoomFull.to_string(), code: 11, request: None}),
+            encode(&Message::Error(Error::RoomFull)),
+        );
+    }
+    
+    #[test]
+    fn welcome() {
+        let info = ServerInfo {
+            version: "1.2.3".into(),
+            protocol_version: 1,
+            max_request_length: 4096,
+            max_room_members: Some(8),
+            rate_limit: None,
+        };
+        let wire::MessageKind::Welcome(welcome) = encode(&Message::Welcome(4, "abc".into(), Box::new(info))) else {
+            panic!("expected a welcome");
+        };
+        assert_eq!((Some(8), None), (welcome.max_room_members, welcome.rate_limit.as_ref()));
+        
+        // clients which expect a session can still read it
+        let session = wire::Session::decode(welcome.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(wire::Session {user_id: 4, token: "abc".into()}, session);
+    }
+    
+    #[test]
+    fn request_id() {
+        let frame = wire::Request {kind: Some(K::LeaveGame(wire::Room {room_id: 3})), request_id: Some(42)}.encode_to_vec();
+        assert_eq!(Some(Request::LeaveRoom(3)), ProtobufCodec.decode(&frame));
+        assert_eq!(Some(42), ProtobufCodec.request_id(&frame));
+        assert_eq!(None, ProtobufCodec.request_id(&request(K::LeaveGame(wire::Room {room_id: 3}))));
+        
+        let mut out = Vec::new();
+        ProtobufCodec.encode(&Message::RoomClosed(3).replying_to(Some(42)), &mut out);
+        let msg = wire::Message::decode_length_delimited(out.as_slice()).unwrap();
+        assert_eq!(Some(42), msg.request_id);
+        assert_eq!(Some(wire::MessageKind::GameOver(wire::Room {room_id: 3})), msg.kind);
+    }
+    
+    #[test]
+    fn frames() {
+        let ping = request(K::Ping(wire::Ping {sequence_number: 1, latency_ms: None}));
+        let long = request(K::Send(wire::RoomText {room_id: 1, text: "x".repeat(20)}));
+        let input = [
+            delimited(K::Ping(wire::Ping {sequence_number: 1, latency_ms: None})),
+            delimited(K::Ping(wire::Ping {sequence_number: 1, latency_ms: None})),
+            delimited(K::Send(wire::RoomText {room_id: 1, text: "x".repeat(20)})),
+            delimited(K::Quit(wire::Empty {})),
+        ].concat();
+        assert!(long.len() > 16);
+        assert_eq!(vec![Some(ping.clone()), Some(ping), None], read_frames(&ProtobufCodec, input, 16));
+        
+        // an unfinished request at the end is dropped
+        let quit = request(K::Quit(wire::Empty {}));
+        let input = [delimited(K::Quit(wire::Empty {})), vec![5, 1]].concat();
+        assert_eq!(vec![Some(quit)], read_frames(&ProtobufCodec, input, 16));
+    }
+}
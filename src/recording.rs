@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+use crate::clock::{Clock, SimulatedClock};
+use crate::ids;
+use crate::models::{RoomID, UserID};
+use crate::request::{self, Request, RequestID};
+use crate::response::{self, Error, Message, Response};
+use crate::server::{Server, ServerBuilder};
+
+/// Something which changed the server's state, or a message which the server
+/// sent as a result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Recorded {
+    Connected,
+    /// The user signed in to this account.
+    Authenticated(String),
+    Request(String),
+    Message(String),
+    /// The user's connection dropped, but they may still resume.
+    Disconnected,
+    Removed,
+    /// The user didn't resume their session in time.
+    TimedOut,
+    /// An administrator removed the user; they are told so before being
+    /// removed.
+    Kicked,
+    /// Abandoned rooms were closed.
+    Swept,
+    /// Every connected user was pinged.
+    Pinged,
+    /// An administrator closed this room.
+    ClosedRoom(RoomID),
+    /// The server started draining for a restart, with this many seconds to
+    /// go.
+    Draining(u64),
+}
+
+/// Written in place of passwords and secret tokens, so that a recording
+/// can be shared without giving them away.
+const REDACTED: &str = "*";
+
+impl Recorded {
+    /// A request as the client sent it, with its ID if it had one, since
+    /// the ID is echoed on the reply. Passwords and tokens are redacted.
+    pub(crate) fn request(request_id: Option<RequestID>, request: &Request) -> Recorded {
+        let redacted = redact_request(request);
+        let request = redacted.as_ref().unwrap_or(request);
+        Recorded::Request(match request_id {
+            Some(request_id) => format!("#{request_id}|{request}"),
+            None => request.to_string(),
+        })
+    }
+    
+    /// A message as it was sent, with any tokens redacted.
+    pub(crate) fn message(msg: &Message) -> Recorded {
+        let redacted = redact_message(msg);
+        Recorded::Message(redacted.as_ref().unwrap_or(msg).to_string())
+    }
+}
+
+/// A copy of a request with its password or token redacted, if it has one.
+/// Every password is redacted the same way, so a replayed user who gave the
+/// right password still gets in; AUTH credentials never reach the
+/// dispatcher, so they are never recorded.
+fn redact_request(request: &Request) -> Option<Request> {
+    let redacted = || REDACTED.to_string();
+    Some(match request {
+        Request::Resume(token) => Request::Resume(redact_token(token)),
+        Request::AdminLogin(_) => Request::AdminLogin(redacted()),
+        // an empty password removes it, which is no secret
+        Request::SetPassword(room_id, password) if !password.is_empty() => Request::SetPassword(*room_id, redacted()),
+        Request::AskJoinRoom(room_id, msg, Some(_)) => Request::AskJoinRoom(*room_id, msg.clone(), Some(redacted())),
+        Request::JoinNamedRoom(name, msg, Some(_)) => Request::JoinNamedRoom(name.clone(), msg.clone(), Some(redacted())),
+        Request::Spectate(room_id, Some(_)) => Request::Spectate(*room_id, Some(redacted())),
+        _ => return None,
+    })
+}
+
+fn redact_message(msg: &Message) -> Option<Message> {
+    Some(match msg {
+        Message::Welcome(user_id, token, info) => Message::Welcome(*user_id, redact_token(token), info.clone()),
+        Message::Resumed(user_id, token) => Message::Resumed(*user_id, redact_token(token)),
+        Message::UdpToken(port, _) => Message::UdpToken(*port, REDACTED.to_string()),
+        Message::Reply(request_id, msg) => Message::Reply(*request_id, Box::new(redact_message(msg)?)),
+        _ => return None,
+    })
+}
+
+/// Keeps the session ID which a resume token starts with, so that replays
+/// can still tell sessions apart.
+fn redact_token(token: &str) -> String {
+    match ids::token_session(token) {
+        Some(session_id) => format!("{session_id}.{REDACTED}"),
+        None => REDACTED.to_string(),
+    }
+}
+
+/// One line of a recording: the time in milliseconds since the Unix epoch,
+/// the user, what happened, and the request or message if there is one, all
+/// separated by tabs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) at: SystemTime,
+    pub(crate) user_id: UserID,
+    pub(crate) what: Recorded,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let millis = self.at.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        write!(f, "{millis}\t{}\t", self.user_id)?;
+        match &self.what {
+            Recorded::Connected => f.write_str("CONNECT"),
+            Recorded::Authenticated(account) => write!(f, "AUTH\t{account}"),
+            Recorded::Request(line) => write!(f, "REQUEST\t{line}"),
+            Recorded::Message(line) => write!(f, "MESSAGE\t{line}"),
+            Recorded::Disconnected => f.write_str("DISCONNECT"),
+            Recorded::Removed => f.write_str("REMOVE"),
+            Recorded::TimedOut => f.write_str("TIMEOUT"),
+            Recorded::Kicked => f.write_str("KICK"),
+            Recorded::Swept => f.write_str("SWEEP"),
+            Recorded::Pinged => f.write_str("PING"),
+            Recorded::ClosedRoom(room_id) => write!(f, "CLOSE\t{room_id}"),
+            Recorded::Draining(secs) => write!(f, "DRAIN\t{secs}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Entry {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Entry, String> {
+        let mut fields = s.splitn(4, '\t');
+        let (Some(millis), Some(user_id), Some(kind)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err("expected a time, a user ID and an event".to_string());
+        };
+        let millis: u64 = millis.parse().map_err(|_| format!("invalid time '{millis}'"))?;
+        let user_id = user_id.parse().map_err(|_| format!("invalid user ID '{user_id}'"))?;
+        let arg = fields.next();
+        let what = match (kind, arg) {
+            ("CONNECT", None) => Recorded::Connected,
+            ("AUTH", Some(account)) => Recorded::Authenticated(account.to_string()),
+            ("REQUEST", Some(line)) => Recorded::Request(line.to_string()),
+            ("MESSAGE", Some(line)) => Recorded::Message(line.to_string()),
+            ("DISCONNECT", None) => Recorded::Disconnected,
+            ("REMOVE", None) => Recorded::Removed,
+            ("TIMEOUT", None) => Recorded::TimedOut,
+            ("KICK", None) => Recorded::Kicked,
+            ("SWEEP", None) => Recorded::Swept,
+            ("PING", None) => Recorded::Pinged,
+            ("CLOSE", arg) => Recorded::ClosedRoom(number(kind, arg)?),
+            ("DRAIN", arg) => Recorded::Draining(number(kind, arg)?),
+            _ => return Err(format!("invalid event '{kind}'")),
+        };
+        Ok(Entry {at: SystemTime::UNIX_EPOCH + Duration::from_millis(millis), user_id, what})
+    }
+}
+
+fn number<T: std::str::FromStr>(kind: &str, arg: Option<&str>) -> Result<T, String> {
+    arg.and_then(|n| n.parse().ok())
+        .ok_or_else(|| format!("expected a number after {kind}"))
+}
+
+/// Writes a recording as the server runs. Each entry is written as soon as
+/// it is recorded, so the recording is complete even if the server crashes.
+pub(crate) struct Recorder {
+    out: LineWriter<File>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> io::Result<Recorder> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Recorder {out: LineWriter::new(file)})
+    }
+    
+    pub(crate) fn record(&mut self, entry: &Entry) {
+        if let Err(e) = writeln!(self.out, "{entry}") {
+            warn!(error = %e, "Failed to write recording");
+        }
+    }
+}
+
+/// A message which was sent differently when a recording was replayed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Divergence {
+    /// The line of the recording where this happened.
+    pub(crate) line: usize,
+    pub(crate) user_id: UserID,
+    pub(crate) expected: Option<String>,
+    pub(crate) got: Option<String>,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Divergence {line, user_id, ..} = self;
+        match (&self.expected, &self.got) {
+            (Some(expected), Some(got)) => write!(f, "line {line}: user {user_id} was sent {expected} but now gets {got}"),
+            (Some(expected), None) => write!(f, "line {line}: user {user_id} was sent {expected} but now gets nothing"),
+            (None, Some(got)) => write!(f, "line {line}: user {user_id} now also gets {got}"),
+            (None, None) => write!(f, "line {line}: user {user_id}"),
+        }
+    }
+}
+
+/// The outcome of replaying a recording.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    pub(crate) events: usize,
+    pub(crate) messages: usize,
+    pub(crate) divergences: Vec<Divergence>,
+}
+
+impl std::fmt::Display for Replay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for divergence in &self.divergences {
+            writeln!(f, "{divergence}")?;
+        }
+        write!(f, "Replayed {} events; {} of {} recorded messages differed", self.events, self.divergences.len(), self.messages)
+    }
+}
+
+/// Feeds a recording back into a fresh server, in virtual time, and compares
+/// the messages it sends with the recorded ones. Users are given new IDs as
+/// they connect, so messages only match if the server which made the
+/// recording handed out IDs in the same order.
+pub(crate) fn replay(text: &str, builder: ServerBuilder) -> Result<Replay, String> {
+    let entries = text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            line.parse::<Entry>()
+                .map(|entry| (i + 1, entry))
+                .map_err(|e| format!("line {}: {e}", i + 1))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    let start = entries.first().map_or(SystemTime::UNIX_EPOCH, |(_, entry)| entry.at);
+    let clock = Arc::new(SimulatedClock::new(start));
+    let mut replayer = Replayer {
+        server: builder.clock(clock.clone()).build(),
+        clock,
+        users: HashMap::new(),
+        tokens: HashMap::new(),
+        replay: Replay::default(),
+    };
+    
+    let mut entries = entries.into_iter().peekable();
+    while let Some((line, entry)) = entries.next() {
+        let mut expected = Vec::new();
+        let got = match entry.what {
+            Recorded::Message(msg) => {
+                expected.push((line, entry.user_id, msg));
+                Vec::new()
+            },
+            what => {
+                replayer.replay.events += 1;
+                if let Ok(by) = entry.at.duration_since(replayer.clock.now()) {
+                    replayer.clock.advance(by);
+                }
+                replayer.apply(entry.user_id, what)
+            },
+        };
+        while let Some((line, Entry {user_id, what: Recorded::Message(msg), ..})) = entries.next_if(|(_, entry)| matches!(entry.what, Recorded::Message(_))) {
+            expected.push((line, user_id, msg));
+        }
+        replayer.compare(line, expected, got);
+    }
+    Ok(replayer.replay)
+}
+
+struct Replayer {
+    server: Server,
+    clock: Arc<SimulatedClock>,
+    /// The replayed ID of each recorded user.
+    users: HashMap<UserID, UserID>,
+    /// The replayed resume token for each recorded one.
+    tokens: HashMap<String, String>,
+    replay: Replay,
+}
+
+impl Replayer {
+    fn user(&self, recorded_id: UserID) -> UserID {
+        self.users.get(&recorded_id).copied().unwrap_or(recorded_id)
+    }
+    
+    /// Makes the same change to the server as was recorded, and returns the
+    /// messages it sends in the order the dispatcher would send them.
+    fn apply(&mut self, recorded_id: UserID, what: Recorded) -> Vec<(UserID, Message)> {
+        let user_id = self.user(recorded_id);
+        let (user_id, r) = match what {
+            Recorded::Connected => {
+                let Some(new_id) = self.server.add_user() else {
+                    return vec![(user_id, response::SERVER_FULL)];
+                };
+                self.users.insert(recorded_id, new_id);
+                let token = self.server.resume_token(new_id).unwrap_or_default().to_string();
+                let welcome = Message::Welcome(new_id, token, Box::new(self.server.info()));
+                return std::iter::once(welcome)
+                    .chain(self.server.motd())
+                    .map(|msg| (new_id, msg))
+                    .collect();
+            },
+            Recorded::Request(line) => {
+                let (request_id, line) = request::split_request_id(&line);
+                let Some(request) = request::parse(line) else {
+                    return Vec::new();
+                };
+                let request_type = request.name();
+                let response = match request {
+                    // the user is then removed, which is recorded separately
+                    Request::Quit => return Vec::new(),
+                    Request::Resume(token) => {
+                        let token = self.tokens.get(&token).cloned().unwrap_or(token);
+                        self.server.handle_request(user_id, Request::Resume(token))
+                    },
+                    request => self.server.handle_request(user_id, request),
+                };
+                // a resumed user gets their reply on their old connection
+                let user_id = match response.returns {
+                    Some(Message::Resumed(old_id, _)) => old_id,
+                    _ => user_id,
+                };
+                (user_id, Ok(response.failing(request_type).replying_to(request_id)))
+            },
+            Recorded::Authenticated(account) => (user_id, self.server.set_account(user_id, account)),
+            Recorded::Message(_) => return Vec::new(),
+            Recorded::Disconnected => (user_id, self.server.disconnect_user(user_id)),
+            Recorded::Removed => (user_id, self.server.remove_user(user_id)),
+            Recorded::TimedOut => (user_id, self.server.time_out_user(user_id)),
+            Recorded::Kicked => return vec![(user_id, Message::Error(Error::Kicked))],
+            Recorded::Swept => (0, Ok(self.server.close_idle_rooms().1)),
+            Recorded::Pinged => (0, Ok(self.server.ping_users())),
+            Recorded::ClosedRoom(room_id) => (0, self.server.force_close_room(room_id)),
+            Recorded::Draining(secs) => (0, Ok(self.server.start_draining(secs))),
+        };
+        let response = r.unwrap_or_else(Response::error);
+        response.returns
+            .map(|msg| (user_id, msg))
+            .into_iter()
+            .chain(response.sends)
+            .collect()
+    }
+    
+    fn compare(&mut self, line: usize, expected: Vec<(usize, UserID, String)>, got: Vec<(UserID, Message)>) {
+        self.replay.messages += expected.len();
+        let mut got = got.into_iter();
+        for (line, recorded_id, expected) in expected {
+            let user_id = self.user(recorded_id);
+            match got.next() {
+                Some((id, msg)) if id == user_id && self.same_message(&expected, &msg.to_string()) => {},
+                Some((_, msg)) => self.diverged(line, recorded_id, Some(expected), Some(msg.to_string())),
+                None => self.diverged(line, recorded_id, Some(expected), None),
+            }
+        }
+        for (user_id, msg) in got {
+            let recorded_id = self.users.iter()
+                .find(|&(_, &id)| id == user_id)
+                .map_or(user_id, |(&recorded_id, _)| recorded_id);
+            self.diverged(line, recorded_id, None, Some(msg.to_string()));
+        }
+    }
+    
+    fn diverged(&mut self, line: usize, user_id: UserID, expected: Option<String>, got: Option<String>) {
+        self.replay.divergences.push(Divergence {line, user_id, expected, got});
+    }
+    
+    /// Whether a replayed message is the recorded one. Resume and UDP tokens
+    /// are random, so they needn't match, but the replayed token is remembered so
+    /// that it can be used when the recorded one is. What `WELCOME` says about
+    /// the server depends on its version and config, so that isn't compared.
+    fn same_message(&mut self, recorded: &str, replayed: &str) -> bool {
+        if recorded == replayed {
+            return true;
+        }
+        // the token is the third field of each of these
+        let has_token = |keyword: &str| matches!(keyword, "WELCOME" | "RESUMED" | "UDP_TOKEN");
+        let recorded: Vec<&str> = recorded.splitn(4, '|').collect();
+        let replayed: Vec<&str> = replayed.splitn(4, '|').collect();
+        match (recorded.as_slice(), replayed.as_slice()) {
+            ([keyword, a, old, a_rest @ ..], [other, b, new, b_rest @ ..])
+                if keyword == other && a == b && has_token(keyword)
+                    && (a_rest == b_rest || *keyword == "WELCOME") => {
+                self.tokens.insert(old.to_string(), new.to_string());
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn entry(millis: u64, user_id: UserID, what: Recorded) -> Entry {
+        Entry {at: SystemTime::UNIX_EPOCH + Duration::from_millis(millis), user_id, what}
+    }
+    
+    #[test]
+    fn redact_secrets() {
+        let request = |request| match Recorded::request(None, &request) {
+            Recorded::Request(line) => line,
+            _ => unreachable!(),
+        };
+        assert_eq!("ADMIN_LOGIN|*", request(Request::AdminLogin("hunter2".into())));
+        assert_eq!("SET_PASSWORD|1|*", request(Request::SetPassword(1, "hunter2".into())));
+        assert_eq!("SET_PASSWORD|1|", request(Request::SetPassword(1, String::new())));
+        assert_eq!("JOIN_GAME|1|hi|*", request(Request::AskJoinRoom(1, "hi".into(), Some("hunter2".into()))));
+        assert_eq!("JOIN_GAME|1|hi", request(Request::AskJoinRoom(1, "hi".into(), None)));
+        assert_eq!("JOIN_NAMED_GAME|lobby|hi|*", request(Request::JoinNamedRoom("lobby".into(), "hi".into(), Some("hunter2".into()))));
+        assert_eq!("SPECTATE|1|*", request(Request::Spectate(1, Some("hunter2".into()))));
+        assert_eq!("RESUME|abc.*", request(Request::Resume("abc.0123".into())));
+        assert_eq!(
+            Recorded::Request("#4|ADMIN_LOGIN|*".into()),
+            Recorded::request(Some(4), &Request::AdminLogin("hunter2".into())),
+        );
+        
+        let resumed = Message::Resumed(2, "abc.0123".into()).replying_to(Some(4));
+        assert_eq!(Recorded::Message("#4|RESUMED|2|abc.*".into()), Recorded::message(&resumed));
+        assert_eq!(Recorded::Message("UDP_TOKEN|4001|*".into()), Recorded::message(&Message::UdpToken(4001, "0123".into())));
+        assert_eq!(Recorded::Message("PONG|1".into()), Recorded::message(&Message::Pong(1)));
+    }
+    
+    #[test]
+    fn entry_round_trip() {
+        let entries = [
+            entry(1_700_000_000_123, 1, Recorded::Connected),
+            entry(2, 1, Recorded::Authenticated("alice".into())),
+            entry(5, 2, Recorded::Request("SEND|1|a\tb".into())),
+            entry(5, 1, Recorded::Message("RECEIVED|1|2|a\tb".into())),
+            entry(6, 2, Recorded::Disconnected),
+            entry(6, 2, Recorded::TimedOut),
+            entry(7, 0, Recorded::ClosedRoom(3)),
+            entry(8, 0, Recorded::Draining(600)),
+            entry(9, 0, Recorded::Pinged),
+        ];
+        for entry in entries {
+            assert_eq!(Ok(entry.clone()), entry.to_string().parse());
+        }
+        assert!("5\t1\tREQUEST".parse::<Entry>().is_err());
+        assert!("5\tx\tCONNECT".parse::<Entry>().is_err());
+        assert!("5\t1\tCLOSE\tx".parse::<Entry>().is_err());
+    }
+    
+    /// Records a session the way the dispatcher does, by hand.
+    fn record(steps: &[(UserID, Recorded)]) -> String {
+        let clock = Arc::new(SimulatedClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000)));
+        let mut replayer = Replayer {
+            server: ServerBuilder::new().clock(clock.clone()).build(),
+            clock,
+            users: HashMap::new(),
+            tokens: HashMap::new(),
+            replay: Replay::default(),
+        };
+        let mut text = String::new();
+        for (user_id, what) in steps {
+            replayer.clock.advance(Duration::from_secs(1));
+            let at = replayer.clock.now();
+            text += &format!("{}\n", Entry {at, user_id: *user_id, what: what.clone()});
+            for (user_id, msg) in replayer.apply(*user_id, what.clone()) {
+                text += &format!("{}\n", Entry {at, user_id, what: Recorded::Message(msg.to_string())});
+            }
+        }
+        text
+    }
+    
+    #[test]
+    fn replay_matches() {
+        let text = record(&[
+            (1, Recorded::Connected),
+            (2, Recorded::Connected),
+            (1, Recorded::Request("CREATE_GAME|x".into())),
+            (1, Recorded::Request("SET_JOIN_POLICY|1|OPEN".into())),
+            (2, Recorded::Request("JOIN_GAME|1|hi".into())),
+            (2, Recorded::Request("SEND|1|move".into())),
+            (1, Recorded::Removed),
+        ]);
+        let replay = replay(&text, ServerBuilder::new()).unwrap();
+        assert_eq!(Vec::<Divergence>::new(), replay.divergences);
+        assert_eq!(7, replay.events);
+        assert!(replay.messages >= 7);
+    }
+    
+    #[test]
+    fn replay_resumes_with_new_token() {
+        let builder = || ServerBuilder::new().disconnect_grace(Duration::from_secs(30));
+        // the recorded tokens are not the ones the replayed server hands out
+        let text = [
+            "1000\t1\tCONNECT\n1000\t1\tMESSAGE\tWELCOME|1|abcd",
+            "2000\t1\tDISCONNECT",
+            "3000\t2\tCONNECT\n3000\t2\tMESSAGE\tWELCOME|2|0000",
+            "4000\t2\tREQUEST\tRESUME|abcd\n4000\t1\tMESSAGE\tRESUMED|1|1111",
+        ].join("\n");
+        
+        let replay = replay(&text, builder()).unwrap();
+        assert_eq!(Vec::<Divergence>::new(), replay.divergences);
+    }
+    
+    #[test]
+    fn replay_finds_divergence() {
+        let text = "1000\t1\tCONNECT\n1000\t1\tMESSAGE\tWELCOME|1|abc\n2000\t1\tREQUEST\tCREATE_GAME|x\n2000\t1\tMESSAGE\tCREATED_GAME|2";
+        let replay = replay(text, ServerBuilder::new()).unwrap();
+        let expected = Divergence {
+            line: 4,
+            user_id: 1,
+            expected: Some("CREATED_GAME|2".into()),
+            got: Some("CREATED_GAME|1".into()),
+        };
+        assert_eq!(vec![expected], replay.divergences);
+        
+        assert!(super::replay("1000\t1\tNONSENSE", ServerBuilder::new()).is_err());
+    }
+}
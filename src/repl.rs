@@ -0,0 +1,295 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use futures::{FutureExt, StreamExt};
+use futures::channel::mpsc;
+
+use crate::client::{self, Client};
+use crate::program_args::ClientArgs;
+use crate::request::{self, Request, KEYWORDS};
+use crate::response::Message;
+
+const PROMPT: &str = "> ";
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// Reads keys one at a time, without echoing them, until dropped. This uses
+/// `stty` so that the server needn't depend on a terminal library.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    /// Returns `None` if stdin isn't a terminal, or its mode can't be set.
+    fn enable() -> Option<RawTerminal> {
+        let output = stty(&["-g"]).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let saved = String::from_utf8(output.stdout).ok()?.trim().to_string();
+        // Ctrl+C is read as a key, so that the terminal is restored on quitting
+        let status = stty(&["-icanon", "-echo", "-isig"]).status().ok()?;
+        status.success().then_some(RawTerminal {saved})
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]).status();
+    }
+}
+
+fn stty(args: &[&str]) -> Command {
+    let mut command = Command::new("stty");
+    command.args(args).stdin(Stdio::inherit()).stderr(Stdio::null());
+    command
+}
+
+/// What the user did by pressing a key.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    None,
+    Submit(String),
+    Quit,
+}
+
+/// The line being typed. When the terminal is in raw mode this echoes keys
+/// and completes keywords; otherwise the terminal does the editing, and
+/// whole lines arrive.
+struct Editor {
+    raw: bool,
+    line: Vec<u8>,
+    /// The number of bytes left in an escape sequence, such as an arrow key,
+    /// which are ignored.
+    escape: u8,
+}
+
+impl Editor {
+    fn new(raw: bool) -> Editor {
+        Editor {raw, line: Vec::new(), escape: 0}
+    }
+    
+    fn key(&mut self, key: u8) -> Action {
+        if self.escape > 0 {
+            self.escape -= 1;
+            return Action::None;
+        }
+        match key {
+            b'\r' | b'\n' => {
+                let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                self.line.clear();
+                if self.raw {
+                    println!();
+                }
+                return Action::Submit(line);
+            },
+            // Ctrl+C, or Ctrl+D on an empty line
+            3 => return Action::Quit,
+            4 if self.line.is_empty() => return Action::Quit,
+            // backspace, which removes a whole character even if it isn't ASCII
+            8 | 127 => while let Some(byte) = self.line.pop() {
+                if byte & 0xc0 != 0x80 {
+                    break;
+                }
+            },
+            b'\t' if self.raw => self.complete(),
+            0x1b => self.escape = 2,
+            key if key >= b' ' || !self.raw => self.line.push(key),
+            _ => {},
+        }
+        self.redraw();
+        Action::None
+    }
+    
+    /// Completes the keyword at the start of the line, or lists the keywords
+    /// it could be.
+    fn complete(&mut self) {
+        let Ok(prefix) = std::str::from_utf8(&self.line) else {
+            return;
+        };
+        if prefix.contains('|') {
+            return;
+        }
+        let prefix = prefix.to_ascii_uppercase();
+        let candidates: Vec<&str> = KEYWORDS.into_iter()
+            .filter(|keyword| keyword.starts_with(&prefix))
+            .collect();
+        match candidates[..] {
+            [] => {},
+            [keyword] => self.line = format!("{keyword}|").into_bytes(),
+            _ => {
+                let common = common_prefix(&candidates);
+                if common.len() > prefix.len() {
+                    self.line = common.as_bytes().to_vec();
+                } else {
+                    self.print(&candidates.join("  "));
+                }
+            },
+        }
+    }
+    
+    fn redraw(&self) {
+        if self.raw {
+            print!("\r\x1b[K{PROMPT}{}", String::from_utf8_lossy(&self.line));
+            let _ = std::io::stdout().flush();
+        }
+    }
+    
+    /// Prints a line above the one being typed.
+    fn print(&self, text: &str) {
+        if self.raw {
+            print!("\r\x1b[K");
+        }
+        println!("{text}");
+        self.redraw();
+    }
+}
+
+fn common_prefix<'a>(words: &[&'a str]) -> &'a str {
+    let first = words[0];
+    let len = words.iter()
+        .map(|word| first.bytes().zip(word.bytes()).take_while(|(a, b)| a == b).count())
+        .min()
+        .unwrap_or(0);
+    &first[..len]
+}
+
+/// Shows a message with its keyword highlighted and its fields spaced out.
+fn pretty(msg: &Message, colour: bool) -> String {
+    let line = msg.to_string();
+    if !colour {
+        return line;
+    }
+    let (keyword, fields) = line.split_once('|').unwrap_or((&line, ""));
+    let keyword_colour = match msg {
+        Message::Error(_) |
+        Message::Failed(..) => RED,
+        Message::Warning(_) => YELLOW,
+        _ => CYAN,
+    };
+    let mut out = format!("{BOLD}{keyword_colour}{keyword}{RESET}");
+    if !fields.is_empty() {
+        for field in fields.split('|') {
+            out += &format!(" {DIM}|{RESET} {field}");
+        }
+    }
+    out
+}
+
+/// Reads stdin on its own thread, since reading it can't be cancelled.
+fn read_keys() -> mpsc::UnboundedReceiver<u8> {
+    let (sender, receiver) = mpsc::unbounded();
+    std::thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            let Ok(byte) = byte else {
+                break;
+            };
+            if sender.unbounded_send(byte).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+enum Next {
+    Event(Option<Message>),
+    Key(Option<u8>),
+}
+
+/// Runs the interactive client until the user quits, or the server closes
+/// the connection.
+pub(crate) async fn run(args: &ClientArgs) -> client::Result<()> {
+    let mut client = Client::connect(args.target.as_str(), args.auth_token.as_deref()).await?;
+    let terminal = RawTerminal::enable();
+    let mut editor = Editor::new(terminal.is_some());
+    editor.print(&format!("Connected as user {}; press Tab to complete a request, or Ctrl+C to quit", client.user_id()));
+    
+    let mut keys = read_keys();
+    loop {
+        let next = futures::select! {
+            event = client.next_event().fuse() => Next::Event(event?),
+            key = keys.next() => Next::Key(key),
+        };
+        let line = match next {
+            Next::Event(Some(msg)) => {
+                editor.print(&pretty(&msg, terminal.is_some()));
+                continue;
+            },
+            Next::Event(None) => {
+                editor.print("Connection closed by server");
+                break;
+            },
+            // the end of the input is the same as quitting
+            Next::Key(None) => break,
+            Next::Key(Some(key)) => match editor.key(key) {
+                Action::None => continue,
+                Action::Submit(line) => line,
+                Action::Quit => {
+                    client.request(&Request::Quit).await?;
+                    break;
+                },
+            },
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let Some(request) = request::parse(&line) else {
+            editor.print(&format!("Not a valid request: {line}"));
+            continue;
+        };
+        client.request(&request).await?;
+        if request.is_quit() {
+            break;
+        }
+    }
+    if terminal.is_some() {
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn type_keys(editor: &mut Editor, keys: &str) -> Vec<Action> {
+        keys.bytes()
+            .map(|key| editor.key(key))
+            .filter(|action| *action != Action::None)
+            .collect()
+    }
+    
+    #[test]
+    fn complete_keywords() {
+        let mut editor = Editor::new(true);
+        type_keys(&mut editor, "cre\t");
+        assert_eq!(b"CREATE_", &editor.line[..]);
+        type_keys(&mut editor, "G\t");
+        assert_eq!(b"CREATE_GAME|", &editor.line[..]);
+        
+        let mut editor = Editor::new(true);
+        type_keys(&mut editor, "SET_\t");
+        assert_eq!(b"SET_", &editor.line[..]);
+        type_keys(&mut editor, "J\t");
+        assert_eq!(b"SET_JOIN_POLICY|", &editor.line[..]);
+        
+        let mut editor = Editor::new(true);
+        type_keys(&mut editor, "LEAVE_GAME|1\t");
+        assert_eq!(b"LEAVE_GAME|1", &editor.line[..]);
+    }
+    
+    #[test]
+    fn edit_line() {
+        let mut editor = Editor::new(true);
+        assert_eq!(vec![Action::Submit("CHAT|1|hé".into())], type_keys(&mut editor, "CHAT|1|héy\x7f\x1b[D\r"));
+        assert_eq!(vec![Action::Quit], type_keys(&mut editor, "\x04"));
+        
+        let mut editor = Editor::new(false);
+        assert_eq!(vec![Action::Submit("STATS".into())], type_keys(&mut editor, "STATS\t\n"));
+    }
+}
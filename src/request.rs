@@ -1,85 +1,749 @@
-use crate::models::{UserID, RoomID};
+use std::sync::Arc;
+use base64::Engine as _;
+use crate::models::{UserID, RoomID, JoinPolicy, Presence, Signal};
+use crate::response::Error;
 
 #[derive(Debug, PartialEq, Eq)]
-pub(crate) enum Request {
-    ListRooms,
-    Ping(u32),
-    CreateRoom(String),
+pub enum Request {
+    Hello(String),
+    /// The compression algorithms the client supports, separated by commas.
+    Compress(String),
+    /// A display name, or an empty string to clear it.
+    SetName(String),
+    Resume(String),
+    ListRooms(RoomQuery),
+    Stats,
+    ListMembers(RoomID),
+    GetRoomInfo(RoomID),
+    RoomPings(RoomID),
+    /// A sequence number, and optionally the round-trip time in milliseconds
+    /// which the client measured for its previous ping. The server doesn't
+    /// trust this time, since the client could say anything.
+    Ping(u32, Option<u32>),
+    /// Answers the server's `PING` with its sequence number, so that the
+    /// server can measure the round-trip time itself.
+    Pong(u32),
+    /// The room's data, and tags which listings can be filtered by.
+    CreateRoom(String, Vec<String>),
+    /// Like `CreateRoom`, but the room also has a name, which no other room
+    /// may have, so that users can join it by name.
+    CreateNamedRoom(String, String, Vec<String>),
     SetOwner(RoomID, UserID),
-    AskJoinRoom(RoomID, String),
+    SetJoinPolicy(RoomID, JoinPolicy),
+    SetPresence(RoomID, Presence),
+    SetSchema(RoomID, String),
+    SetPassword(RoomID, String),
+    /// How many seconds a rejected user must wait before asking to join the
+    /// room again, instead of the server's default.
+    SetRejoinCooldown(RoomID, u64),
+    /// A room, a message for its owner, and the room's password if it has one.
+    AskJoinRoom(RoomID, String, Option<String>),
+    /// Like `AskJoinRoom`, but the room is given by name.
+    JoinNamedRoom(String, String, Option<String>),
+    JoinAnyRoom(String, String),
+    Spectate(RoomID, Option<String>),
+    Queue(String),
+    Unqueue,
     AcceptJoinRoom(RoomID, UserID),
+    /// Lets a user join without asking the owner, or giving the password.
+    Invite(RoomID, UserID),
     RejectJoinRoom(RoomID, UserID, String),
     LeaveRoom(RoomID),
-    Send(RoomID, String),
-    SendTo(RoomID, UserID, String),
-    EchoFrom(RoomID, UserID, String),
+    /// Ends the game for everyone in it, without the owner leaving.
+    CloseRoom(RoomID),
+    /// Votes to remove another member from the room, which happens once
+    /// enough of its players agree.
+    VoteKick(RoomID, UserID),
+    /// Payloads and chat text are relayed as they are, so they are shared
+    /// with the messages which carry them rather than copied.
+    Send(RoomID, Arc<str>),
+    /// A payload of binary data, in base64.
+    SendBinary(RoomID, Arc<str>),
+    SendTo(RoomID, UserID, Arc<str>),
+    Chat(RoomID, Arc<str>),
+    Whisper(RoomID, UserID, Arc<str>),
+    EchoFrom(RoomID, UserID, Arc<str>),
+    /// Relays a WebRTC offer, answer or ICE candidate to another player.
+    Signal(Signal, RoomID, UserID, Arc<str>),
+    /// The players in the order they take turns, or none to stop taking
+    /// turns.
+    SetTurnOrder(RoomID, Vec<UserID>),
+    EndTurn(RoomID),
+    AdminLogin(String),
+    GetTimeline(RoomID),
+    AdvanceClock(u64),
+    ReloadConfig,
+    /// Sends a notice from the administrators, such as a maintenance
+    /// warning, to every connected user or only those in games.
+    Announce(Audience, Arc<str>),
+    /// Asks for a token to register a UDP endpoint with.
+    RegisterUdp,
+    /// Asks for relayed messages to be numbered, and kept until the client
+    /// acknowledges them.
+    EnableAcks,
+    /// Acknowledges every relayed message up to the given number.
+    Ack(u64),
+    /// Asks for every change to the list of open games to be pushed to the
+    /// client, until it unsubscribes.
+    SubscribeRooms,
+    UnsubscribeRooms,
+    /// Friends are named by their accounts, so these need the user to have
+    /// signed in to one.
+    AddFriend(String),
+    RemoveFriend(String),
+    ListFriends,
     Quit,
 }
 
+/// The keyword of every kind of request, as returned by `Request::name`.
+pub(crate) const KEYWORDS: [&str; 56] = [
+    "HELLO", "COMPRESS", "SET_NAME", "RESUME", "LIST_OPEN_GAMES", "STATS",
+    "LIST_MEMBERS", "GET_GAME_INFO", "ROOM_PINGS", "PING", "PONG",
+    "CREATE_GAME", "CREATE_NAMED_GAME", "SET_OWNER", "SET_JOIN_POLICY",
+    "SET_PRESENCE", "SET_SCHEMA", "SET_PASSWORD", "SET_REJOIN_COOLDOWN",
+    "JOIN_GAME", "JOIN_NAMED_GAME", "JOIN_ANY", "SPECTATE", "QUEUE", "UNQUEUE",
+    "ACCEPT_JOIN", "INVITE", "REJECT_JOIN", "LEAVE_GAME", "CLOSE_GAME",
+    "VOTE_KICK", "SEND", "SEND_BINARY", "SEND_TO", "CHAT", "WHISPER",
+    "ECHO_FROM", "OFFER", "ANSWER", "ICE_CANDIDATE", "SET_TURN_ORDER",
+    "END_TURN", "ADMIN_LOGIN", "RELOAD_CONFIG", "GET_TIMELINE", "ADVANCE_CLOCK",
+    "ANNOUNCE", "REGISTER_UDP", "ENABLE_ACKS", "ACK", "SUBSCRIBE_GAMES",
+    "UNSUBSCRIBE_GAMES", "ADD_FRIEND", "REMOVE_FRIEND", "LIST_FRIENDS", "QUIT",
+];
+
 impl Request {
     pub(crate) fn is_quit(&self) -> bool {
         matches!(self, Request::Quit)
     }
+    
+    /// The keyword which this request is sent with.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Request::Hello(..) => "HELLO",
+            Request::Compress(..) => "COMPRESS",
+            Request::SetName(..) => "SET_NAME",
+            Request::Resume(..) => "RESUME",
+            Request::ListRooms(_) => "LIST_OPEN_GAMES",
+            Request::Stats => "STATS",
+            Request::ListMembers(..) => "LIST_MEMBERS",
+            Request::GetRoomInfo(..) => "GET_GAME_INFO",
+            Request::RoomPings(..) => "ROOM_PINGS",
+            Request::Ping(..) => "PING",
+            Request::Pong(..) => "PONG",
+            Request::CreateRoom(..) => "CREATE_GAME",
+            Request::CreateNamedRoom(..) => "CREATE_NAMED_GAME",
+            Request::SetOwner(..) => "SET_OWNER",
+            Request::SetJoinPolicy(..) => "SET_JOIN_POLICY",
+            Request::SetPresence(..) => "SET_PRESENCE",
+            Request::SetSchema(..) => "SET_SCHEMA",
+            Request::SetPassword(..) => "SET_PASSWORD",
+            Request::SetRejoinCooldown(..) => "SET_REJOIN_COOLDOWN",
+            Request::AskJoinRoom(..) => "JOIN_GAME",
+            Request::JoinNamedRoom(..) => "JOIN_NAMED_GAME",
+            Request::JoinAnyRoom(..) => "JOIN_ANY",
+            Request::Spectate(..) => "SPECTATE",
+            Request::Queue(..) => "QUEUE",
+            Request::Unqueue => "UNQUEUE",
+            Request::AcceptJoinRoom(..) => "ACCEPT_JOIN",
+            Request::Invite(..) => "INVITE",
+            Request::RejectJoinRoom(..) => "REJECT_JOIN",
+            Request::LeaveRoom(..) => "LEAVE_GAME",
+            Request::CloseRoom(..) => "CLOSE_GAME",
+            Request::VoteKick(..) => "VOTE_KICK",
+            Request::Send(..) => "SEND",
+            Request::SendBinary(..) => "SEND_BINARY",
+            Request::SendTo(..) => "SEND_TO",
+            Request::Chat(..) => "CHAT",
+            Request::Whisper(..) => "WHISPER",
+            Request::EchoFrom(..) => "ECHO_FROM",
+            Request::Signal(signal, ..) => signal.keyword(),
+            Request::SetTurnOrder(..) => "SET_TURN_ORDER",
+            Request::EndTurn(..) => "END_TURN",
+            Request::AdminLogin(..) => "ADMIN_LOGIN",
+            Request::ReloadConfig => "RELOAD_CONFIG",
+            Request::Announce(..) => "ANNOUNCE",
+            Request::GetTimeline(..) => "GET_TIMELINE",
+            Request::AdvanceClock(..) => "ADVANCE_CLOCK",
+            Request::RegisterUdp => "REGISTER_UDP",
+            Request::EnableAcks => "ENABLE_ACKS",
+            Request::Ack(..) => "ACK",
+            Request::SubscribeRooms => "SUBSCRIBE_GAMES",
+            Request::UnsubscribeRooms => "UNSUBSCRIBE_GAMES",
+            Request::AddFriend(..) => "ADD_FRIEND",
+            Request::RemoveFriend(..) => "REMOVE_FRIEND",
+            Request::ListFriends => "LIST_FRIENDS",
+            Request::Quit => "QUIT",
+        }
+    }
+    
+    /// The text which this request asks to relay to other users, if any,
+    /// whose size is limited separately from the request's.
+    pub(crate) fn payload(&self) -> Option<&str> {
+        match self {
+            Request::Send(_, payload) |
+            Request::SendBinary(_, payload) |
+            Request::SendTo(_, _, payload) |
+            Request::EchoFrom(_, _, payload) => Some(payload),
+            Request::AskJoinRoom(_, msg, _) |
+            Request::JoinNamedRoom(_, msg, _) |
+            Request::JoinAnyRoom(_, msg) => Some(msg),
+            _ => None,
+        }
+    }
+    
+    /// The room which this request refers to, if any.
+    pub(crate) fn room_id(&self) -> Option<RoomID> {
+        match *self {
+            Request::SetTurnOrder(room_id, _) |
+            Request::EndTurn(room_id) |
+            Request::ListMembers(room_id) |
+            Request::GetRoomInfo(room_id) |
+            Request::SetOwner(room_id, _) |
+            Request::SetJoinPolicy(room_id, _) |
+            Request::SetPresence(room_id, _) |
+            Request::SetSchema(room_id, _) |
+            Request::SetPassword(room_id, _) |
+            Request::SetRejoinCooldown(room_id, _) |
+            Request::AskJoinRoom(room_id, ..) |
+            Request::Spectate(room_id, _) |
+            Request::AcceptJoinRoom(room_id, _) |
+            Request::Invite(room_id, _) |
+            Request::RejectJoinRoom(room_id, ..) |
+            Request::LeaveRoom(room_id) |
+            Request::CloseRoom(room_id) |
+            Request::VoteKick(room_id, _) |
+            Request::Send(room_id, _) |
+            Request::SendBinary(room_id, _) |
+            Request::SendTo(room_id, ..) |
+            Request::Chat(room_id, _) |
+            Request::Whisper(room_id, ..) |
+            Request::EchoFrom(room_id, ..) |
+            Request::Signal(_, room_id, ..) |
+            Request::GetTimeline(room_id) |
+            Request::RoomPings(room_id) => Some(room_id),
+            
+            Request::Hello(_) |
+            Request::Compress(_) |
+            Request::SetName(_) |
+            Request::Resume(..) |
+            Request::ListRooms(_) |
+            Request::Stats |
+            Request::Ping(..) |
+            Request::Pong(_) |
+            Request::CreateRoom(..) |
+            Request::CreateNamedRoom(..) |
+            Request::JoinNamedRoom(..) |
+            Request::JoinAnyRoom(..) |
+            Request::Queue(_) |
+            Request::Unqueue |
+            Request::AdminLogin(_) |
+            Request::ReloadConfig |
+            Request::AdvanceClock(_) |
+            Request::Announce(..) |
+            Request::RegisterUdp |
+            Request::EnableAcks |
+            Request::Ack(_) |
+            Request::SubscribeRooms |
+            Request::UnsubscribeRooms |
+            Request::AddFriend(_) |
+            Request::RemoveFriend(_) |
+            Request::ListFriends |
+            Request::Quit => None,
+        }
+    }
 }
 
-struct Parts<'a> (std::str::Split<'a, char>);
-impl <'a> Parts<'a> {
-    fn of(s: &'a str) -> Parts<'a> {
-        Parts(s.split('|'))
+/// Which rooms `LIST_OPEN_GAMES` lists, and in what order.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RoomQuery {
+    /// Only rooms with all of these tags are listed.
+    pub tags: Vec<String>,
+    /// Only rooms whose name contains this are listed; rooms without a name
+    /// are searched by their data instead.
+    pub search: Option<String>,
+    /// If not set, rooms are listed in no particular order.
+    pub sort: Option<RoomOrder>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomOrder {
+    Newest,
+    FewestPlayers,
+    /// By the rooms' names, or by their data if they have none.
+    Name,
+}
+
+impl std::str::FromStr for RoomOrder {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<RoomOrder, ()> {
+        match s {
+            "newest" => Ok(RoomOrder::Newest),
+            "fewest_players" => Ok(RoomOrder::FewestPlayers),
+            "name" => Ok(RoomOrder::Name),
+            _ => Err(()),
+        }
     }
+}
+
+impl std::fmt::Display for RoomOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RoomOrder::Newest => "newest",
+            RoomOrder::FewestPlayers => "fewest_players",
+            RoomOrder::Name => "name",
+        })
+    }
+}
+
+/// Who an announcement is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Audience {
+    Everyone,
+    /// Users who own, play in or are spectating a game.
+    InRooms,
+}
+
+impl std::str::FromStr for Audience {
+    type Err = ();
     
-    fn take_str(&mut self) -> Option<&str> {
-        self.0.next()
+    fn from_str(s: &str) -> Result<Audience, ()> {
+        match s {
+            "ALL" => Ok(Audience::Everyone),
+            "IN_GAMES" => Ok(Audience::InRooms),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Audience {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Audience::Everyone => "ALL",
+            Audience::InRooms => "IN_GAMES",
+        })
+    }
+}
+
+/// Writes a request as a client would send it, so that `parse` reads it back.
+impl std::fmt::Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())?;
+        match self {
+            Request::Stats |
+            Request::Unqueue |
+            Request::ReloadConfig |
+            Request::RegisterUdp |
+            Request::EnableAcks |
+            Request::SubscribeRooms |
+            Request::UnsubscribeRooms |
+            Request::ListFriends |
+            Request::Quit => Ok(()),
+            
+            Request::Hello(s) |
+            Request::Compress(s) |
+            Request::SetName(s) |
+            Request::Queue(s) |
+            Request::AdminLogin(s) |
+            Request::AddFriend(s) |
+            Request::RemoveFriend(s) => write!(f, "|{s}"),
+            
+            Request::ListMembers(room_id) |
+            Request::GetRoomInfo(room_id) |
+            Request::RoomPings(room_id) |
+            Request::LeaveRoom(room_id) |
+            Request::CloseRoom(room_id) |
+            Request::EndTurn(room_id) |
+            Request::GetTimeline(room_id) => write!(f, "|{room_id}"),
+            
+            Request::SetSchema(room_id, s) |
+            Request::SetPassword(room_id, s) => write!(f, "|{room_id}|{s}"),
+            Request::Send(room_id, s) |
+            Request::SendBinary(room_id, s) |
+            Request::Chat(room_id, s) => write!(f, "|{room_id}|{s}"),
+            
+            Request::SetOwner(room_id, user_id) |
+            Request::AcceptJoinRoom(room_id, user_id) |
+            Request::Invite(room_id, user_id) |
+            Request::VoteKick(room_id, user_id) => write!(f, "|{room_id}|{user_id}"),
+            
+            Request::RejectJoinRoom(room_id, user_id, s) => write!(f, "|{room_id}|{user_id}|{s}"),
+            Request::SendTo(room_id, user_id, s) |
+            Request::Whisper(room_id, user_id, s) |
+            Request::EchoFrom(room_id, user_id, s) |
+            Request::Signal(_, room_id, user_id, s) => write!(f, "|{room_id}|{user_id}|{s}"),
+            
+            Request::ListRooms(query) => {
+                query.tags.iter().try_for_each(|tag| write!(f, "|tag={tag}"))?;
+                if let Some(search) = &query.search {
+                    write!(f, "|search={search}")?;
+                }
+                if let Some(sort) = query.sort {
+                    write!(f, "|sort={sort}")?;
+                }
+                Ok(())
+            },
+            Request::CreateRoom(data, tags) if tags.is_empty() => write!(f, "|{data}"),
+            Request::CreateRoom(data, tags) => write!(f, "|{data}|{}", tags.join(",")),
+            Request::CreateNamedRoom(name, data, tags) if tags.is_empty() => write!(f, "|{name}|{data}"),
+            Request::CreateNamedRoom(name, data, tags) => write!(f, "|{name}|{data}|{}", tags.join(",")),
+            Request::Resume(token) => write!(f, "|{token}"),
+            Request::Ping(sequence_number, None) => write!(f, "|{sequence_number}"),
+            Request::Ping(sequence_number, Some(latency)) => write!(f, "|{sequence_number}|{latency}"),
+            Request::Pong(sequence_number) => write!(f, "|{sequence_number}"),
+            Request::SetJoinPolicy(room_id, policy) => write!(f, "|{room_id}|{policy}"),
+            Request::SetPresence(room_id, presence) => write!(f, "|{room_id}|{presence}"),
+            Request::Announce(audience, text) => write!(f, "|{audience}|{text}"),
+            Request::AskJoinRoom(room_id, msg, None) => write!(f, "|{room_id}|{msg}"),
+            Request::AskJoinRoom(room_id, msg, Some(password)) => write!(f, "|{room_id}|{msg}|{password}"),
+            Request::JoinNamedRoom(name, msg, None) => write!(f, "|{name}|{msg}"),
+            Request::JoinNamedRoom(name, msg, Some(password)) => write!(f, "|{name}|{msg}|{password}"),
+            Request::JoinAnyRoom(filter, msg) => write!(f, "|{filter}|{msg}"),
+            Request::Spectate(room_id, None) => write!(f, "|{room_id}"),
+            Request::Spectate(room_id, Some(password)) => write!(f, "|{room_id}|{password}"),
+            Request::AdvanceClock(secs) => write!(f, "|{secs}"),
+            Request::SetRejoinCooldown(room_id, secs) => write!(f, "|{room_id}|{secs}"),
+            Request::Ack(n) => write!(f, "|{n}"),
+            Request::SetTurnOrder(room_id, order) => {
+                write!(f, "|{room_id}")?;
+                order.iter().try_for_each(|user_id| write!(f, "|{user_id}"))
+            },
+        }
     }
+}
+
+/// The fields of a request or message after its keyword, however they
+/// were encoded. Fields are borrowed from the input, so nothing is copied
+/// until a request keeps one.
+pub(crate) trait Fields<'a> {
+    fn take_str(&mut self) -> Option<&'a str>;
+    
+    fn take_int<T: std::str::FromStr>(&mut self) -> Option<T>;
+    
+    /// Takes the rest of the input as one field, which may contain `|`.
+    fn take_rest(&mut self) -> &'a str;
+    
+    fn is_done(&self) -> bool;
     
     fn take_string(&mut self) -> Option<String> {
-        self.0.next()
+        self.take_str()
             .map(str::to_string)
     }
     
+    /// Takes a field which will be relayed to other users, so it is shared
+    /// rather than copied again for each of them.
+    fn take_shared(&mut self) -> Option<Arc<str>> {
+        self.take_str()
+            .map(Arc::from)
+    }
+    
+    /// Takes a field whose length is limited by the server's maximum
+    /// payload length, as in `Request::payload`, so that it can be checked
+    /// before it is copied.
+    fn take_payload(&mut self) -> Option<&'a str> {
+        self.take_str()
+    }
+    
+    /// Takes an integer if there is another field; returns `None` only if
+    /// the field is present but not a valid integer.
+    fn take_optional_int<T: std::str::FromStr>(&mut self) -> Option<Option<T>> {
+        if self.is_done() {
+            Some(None)
+        } else {
+            self.take_int().map(Some)
+        }
+    }
+    
+    fn done<T>(self, then: impl FnOnce() -> T) -> Option<T> where Self: Sized {
+        self.is_done().then(then)
+    }
+}
+
+/// Fields whose payload is refused if it is longer than the limit.
+struct Limited<'f, F> {
+    fields: F,
+    max_payload_length: usize,
+    too_large: &'f mut bool,
+}
+
+impl <'a, F: Fields<'a>> Fields<'a> for Limited<'_, F> {
+    fn take_str(&mut self) -> Option<&'a str> {
+        self.fields.take_str()
+    }
+    
     fn take_int<T: std::str::FromStr>(&mut self) -> Option<T> {
-        self.0.next()
+        self.fields.take_int()
+    }
+    
+    fn take_rest(&mut self) -> &'a str {
+        self.fields.take_rest()
+    }
+    
+    fn is_done(&self) -> bool {
+        self.fields.is_done()
+    }
+    
+    fn take_payload(&mut self) -> Option<&'a str> {
+        let payload = self.fields.take_payload()?;
+        if payload.len() > self.max_payload_length {
+            *self.too_large = true;
+            return None;
+        }
+        Some(payload)
+    }
+}
+
+/// The `|`-separated fields of a request or message.
+pub(crate) struct Parts<'a> (Option<&'a str>);
+impl <'a> Parts<'a> {
+    pub(crate) fn of(s: &'a str) -> Parts<'a> {
+        Parts(Some(s))
+    }
+}
+
+impl <'a> Fields<'a> for Parts<'a> {
+    fn take_str(&mut self) -> Option<&'a str> {
+        let rest = self.0.take()?;
+        match rest.split_once('|') {
+            Some((part, rest)) => {
+                self.0 = Some(rest);
+                Some(part)
+            },
+            None => Some(rest),
+        }
+    }
+    
+    fn take_int<T: std::str::FromStr>(&mut self) -> Option<T> {
+        self.take_str()
             .and_then(|s| s.parse::<T>().ok())
     }
     
-    fn done(self, then: impl FnOnce() -> Request) -> Option<Request> {
-        (self.0.count() == 0).then(then)
+    /// Takes all of the remaining parts, including any `|` separators.
+    fn take_rest(&mut self) -> &'a str {
+        self.0.take()
+            .unwrap_or("")
+    }
+    
+    fn is_done(&self) -> bool {
+        self.0.is_none()
     }
 }
 
-pub(crate) fn parse(s: &str) -> Option<Request> {
-    let mut parts = Parts::of(s);
+/// Whether a string could be sent as one field of a pipe-format request.
+/// Other encodings are held to the same rule, so that every request can
+/// still be written in the pipe format, as it is in recordings.
+pub(crate) fn is_field(s: &str) -> bool {
+    !s.contains(['|', '\n'])
+}
+
+/// Whether a string is binary data written in base64, with padding.
+pub(crate) fn is_base64(s: &str) -> bool {
+    base64::engine::general_purpose::STANDARD.decode(s).is_ok()
+}
+
+/// An ID which a client may put before a request, as in `#42|SEND|1|x`, so
+/// that it can tell which request a reply or error is for.
+pub(crate) type RequestID = u32;
+
+/// Reads a request ID from the field it is sent in, as in `#42`.
+pub(crate) fn parse_request_id(field: &str) -> Option<RequestID> {
+    field.strip_prefix('#')?.parse().ok()
+}
+
+/// Splits a request in the pipe format into its ID, if it has one, and the
+/// rest of the request.
+pub(crate) fn split_request_id(s: &str) -> (Option<RequestID>, &str) {
+    match s.split_once('|') {
+        Some((first, rest)) => match parse_request_id(first) {
+            Some(request_id) => (Some(request_id), rest),
+            None => (None, s),
+        },
+        None => (None, s),
+    }
+}
+
+/// Tags are optional, and separated by commas.
+fn tags(field: Option<&str>) -> Vec<String> {
+    match field {
+        Some("") | None => Vec::new(),
+        Some(tags) => tags.split(',').map(str::to_string).collect(),
+    }
+}
+
+/// Reads a request in the pipe format, ignoring its ID if it has one.
+pub fn parse(s: &str) -> Option<Request> {
+    parse_fields(Parts::of(split_request_id(s).1))
+}
+
+/// Reads a request from its keyword and fields, refusing its payload if it
+/// is longer than the limit, before the payload is copied.
+pub(crate) fn parse_fields_within<'a>(fields: impl Fields<'a>, max_payload_length: usize) -> Result<Request, Error> {
+    let mut too_large = false;
+    let fields = Limited {fields, max_payload_length, too_large: &mut too_large};
+    match parse_fields(fields) {
+        Some(request) => Ok(request),
+        None if too_large => Err(Error::PayloadTooLarge),
+        None => Err(Error::InvalidRequest),
+    }
+}
+
+/// Reads a request from its keyword and fields.
+pub(crate) fn parse_fields<'a>(mut parts: impl Fields<'a>) -> Option<Request> {
     match parts.take_str()? {
+        "HELLO" => {
+            let version = parts.take_string()?;
+            parts.done(|| Request::Hello(version))
+        },
+        "COMPRESS" => {
+            let algorithms = parts.take_string()?;
+            parts.done(|| Request::Compress(algorithms))
+        },
+        "SET_NAME" => {
+            let name = parts.take_string()?;
+            parts.done(|| Request::SetName(name))
+        },
         "LIST_OPEN_GAMES" => {
-            parts.done(|| Request::ListRooms)
+            // filters and the order are given as `key=value` fields
+            let mut query = RoomQuery::default();
+            while !parts.is_done() {
+                match parts.take_str()?.split_once('=')? {
+                    ("tag", tag) => query.tags.push(tag.to_string()),
+                    ("search", text) if query.search.is_none() => query.search = Some(text.to_string()),
+                    ("sort", order) if query.sort.is_none() => query.sort = Some(order.parse().ok()?),
+                    _ => return None,
+                }
+            }
+            parts.done(|| Request::ListRooms(query))
+        },
+        "STATS" => {
+            parts.done(|| Request::Stats)
+        },
+        "LIST_MEMBERS" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::ListMembers(room_id))
+        },
+        "GET_GAME_INFO" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::GetRoomInfo(room_id))
+        },
+        "RESUME" => {
+            let token = parts.take_string()?;
+            parts.done(|| Request::Resume(token))
+        },
+        "ROOM_PINGS" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::RoomPings(room_id))
         },
         "PING" => {
             let sequence_number = parts.take_int()?;
-            parts.done(|| Request::Ping(sequence_number))
+            let latency = parts.take_optional_int()?;
+            parts.done(|| Request::Ping(sequence_number, latency))
+        },
+        "PONG" => {
+            let sequence_number = parts.take_int()?;
+            parts.done(|| Request::Pong(sequence_number))
         },
         "CREATE_GAME" => {
             let data = parts.take_string()?;
-            parts.done(|| Request::CreateRoom(data))
+            let tags = tags(parts.take_str());
+            parts.done(|| Request::CreateRoom(data, tags))
+        },
+        "CREATE_NAMED_GAME" => {
+            let name = parts.take_string()?;
+            let data = parts.take_string()?;
+            let tags = tags(parts.take_str());
+            parts.done(|| Request::CreateNamedRoom(name, data, tags))
         },
         "SET_OWNER" => {
             let room_id = parts.take_int()?;
             let other_id = parts.take_int()?;
             parts.done(|| Request::SetOwner(room_id, other_id))
         },
+        "SET_JOIN_POLICY" => {
+            let room_id = parts.take_int()?;
+            let policy = match parts.take_str()? {
+                "ASK" => JoinPolicy::AskOwner,
+                "OPEN" => JoinPolicy::Open,
+                _ => return None,
+            };
+            parts.done(|| Request::SetJoinPolicy(room_id, policy))
+        },
+        "SET_PRESENCE" => {
+            let room_id = parts.take_int()?;
+            let presence = match parts.take_str()? {
+                "OWNER" => Presence::Owner,
+                "ALL" => Presence::All,
+                _ => return None,
+            };
+            parts.done(|| Request::SetPresence(room_id, presence))
+        },
+        "SET_SCHEMA" => {
+            let room_id = parts.take_int()?;
+            // the pattern may itself contain `|`
+            let pattern = parts.take_rest().to_string();
+            parts.done(|| Request::SetSchema(room_id, pattern))
+        },
+        "SET_PASSWORD" => {
+            let room_id = parts.take_int()?;
+            let password = parts.take_string()?;
+            parts.done(|| Request::SetPassword(room_id, password))
+        },
+        "SET_REJOIN_COOLDOWN" => {
+            let room_id = parts.take_int()?;
+            let secs = parts.take_int()?;
+            parts.done(|| Request::SetRejoinCooldown(room_id, secs))
+        },
         "JOIN_GAME" => {
             let room_id = parts.take_int()?;
-            let msg = parts.take_string()?;
-            parts.done(|| Request::AskJoinRoom(room_id, msg))
+            let msg = parts.take_payload()?.to_string();
+            let password = parts.take_string();
+            parts.done(|| Request::AskJoinRoom(room_id, msg, password))
+        },
+        "JOIN_NAMED_GAME" => {
+            let name = parts.take_string()?;
+            let msg = parts.take_payload()?.to_string();
+            let password = parts.take_string();
+            parts.done(|| Request::JoinNamedRoom(name, msg, password))
+        },
+        "JOIN_ANY" => {
+            let filter = parts.take_string()?;
+            let msg = parts.take_payload()?.to_string();
+            parts.done(|| Request::JoinAnyRoom(filter, msg))
+        },
+        "SPECTATE" => {
+            let room_id = parts.take_int()?;
+            let password = parts.take_string();
+            parts.done(|| Request::Spectate(room_id, password))
+        },
+        "QUEUE" => {
+            let criteria = parts.take_string()?;
+            parts.done(|| Request::Queue(criteria))
+        },
+        "UNQUEUE" => {
+            parts.done(|| Request::Unqueue)
         },
         "LEAVE_GAME" => {
             let room_id = parts.take_int()?;
             parts.done(|| Request::LeaveRoom(room_id))
         },
+        "CLOSE_GAME" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::CloseRoom(room_id))
+        },
+        "VOTE_KICK" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Request::VoteKick(room_id, user_id))
+        },
         "ACCEPT_JOIN" => {
             let room_id = parts.take_int()?;
             let user_id = parts.take_int()?;
             parts.done(|| Request::AcceptJoinRoom(room_id, user_id))
         },
+        "INVITE" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            parts.done(|| Request::Invite(room_id, user_id))
+        },
         "REJECT_JOIN" => {
             let room_id = parts.take_int()?;
             let user_id = parts.take_int()?;
@@ -88,21 +752,105 @@ pub(crate) fn parse(s: &str) -> Option<Request> {
         },
         "SEND" => {
             let room_id = parts.take_int()?;
-            let payload = parts.take_string()?;
+            let payload = parts.take_payload()?.into();
             parts.done(|| Request::Send(room_id, payload))
         },
+        "SEND_BINARY" => {
+            let room_id = parts.take_int()?;
+            let payload = parts.take_payload()
+                .filter(|payload| is_base64(payload))?
+                .into();
+            parts.done(|| Request::SendBinary(room_id, payload))
+        },
         "SEND_TO" => {
             let room_id = parts.take_int()?;
             let user_id = parts.take_int()?;
-            let payload = parts.take_string()?;
+            let payload = parts.take_payload()?.into();
             parts.done(|| Request::SendTo(room_id, user_id, payload))
         },
+        "CHAT" => {
+            let room_id = parts.take_int()?;
+            let text = parts.take_shared()?;
+            parts.done(|| Request::Chat(room_id, text))
+        },
+        "WHISPER" => {
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let text = parts.take_shared()?;
+            parts.done(|| Request::Whisper(room_id, user_id, text))
+        },
         "ECHO_FROM" => {
             let room_id = parts.take_int()?;
             let user_id = parts.take_int()?;
-            let payload = parts.take_string()?;
+            let payload = parts.take_payload()?.into();
             parts.done(|| Request::EchoFrom(room_id, user_id, payload))
         },
+        keyword @ ("OFFER" | "ANSWER" | "ICE_CANDIDATE") => {
+            let signal = Signal::from_keyword(keyword)?;
+            let room_id = parts.take_int()?;
+            let user_id = parts.take_int()?;
+            let data = parts.take_shared()?;
+            parts.done(|| Request::Signal(signal, room_id, user_id, data))
+        },
+        "SET_TURN_ORDER" => {
+            let room_id = parts.take_int()?;
+            let mut order = Vec::new();
+            while !parts.is_done() {
+                order.push(parts.take_int()?);
+            }
+            parts.done(|| Request::SetTurnOrder(room_id, order))
+        },
+        "END_TURN" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::EndTurn(room_id))
+        },
+        "ADMIN_LOGIN" => {
+            let password = parts.take_string()?;
+            parts.done(|| Request::AdminLogin(password))
+        },
+        "RELOAD_CONFIG" => {
+            parts.done(|| Request::ReloadConfig)
+        },
+        "GET_TIMELINE" => {
+            let room_id = parts.take_int()?;
+            parts.done(|| Request::GetTimeline(room_id))
+        },
+        "ADVANCE_CLOCK" => {
+            let secs = parts.take_int()?;
+            parts.done(|| Request::AdvanceClock(secs))
+        },
+        "ANNOUNCE" => {
+            let audience = parts.take_str()?.parse().ok()?;
+            let text = parts.take_shared()?;
+            parts.done(|| Request::Announce(audience, text))
+        },
+        "REGISTER_UDP" => {
+            parts.done(|| Request::RegisterUdp)
+        },
+        "ENABLE_ACKS" => {
+            parts.done(|| Request::EnableAcks)
+        },
+        "ACK" => {
+            let n = parts.take_int()?;
+            parts.done(|| Request::Ack(n))
+        },
+        "SUBSCRIBE_GAMES" => {
+            parts.done(|| Request::SubscribeRooms)
+        },
+        "UNSUBSCRIBE_GAMES" => {
+            parts.done(|| Request::UnsubscribeRooms)
+        },
+        "ADD_FRIEND" => {
+            let account = parts.take_string()?;
+            parts.done(|| Request::AddFriend(account))
+        },
+        "REMOVE_FRIEND" => {
+            let account = parts.take_string()?;
+            parts.done(|| Request::RemoveFriend(account))
+        },
+        "LIST_FRIENDS" => {
+            parts.done(|| Request::ListFriends)
+        },
         "QUIT" => {
             parts.done(|| Request::Quit)
         },
@@ -112,24 +860,189 @@ pub(crate) fn parse(s: &str) -> Option<Request> {
 
 #[cfg(test)]
 mod test {
+    use crate::ids::{self, IdGenerator, Random};
     use super::*;
     
+    /// A line made of random keywords, numbers and bytes, separated by `|`,
+    /// so that parsing gets past the keyword more often than not.
+    fn random_line(rng: &mut Random) -> String {
+        let mut line = String::new();
+        for i in 0..rng.generate() % 6 {
+            if i > 0 {
+                line.push('|');
+            }
+            match rng.generate() % 5 {
+                0 | 1 if i == 0 => line.push_str(KEYWORDS[rng.generate() as usize % KEYWORDS.len()]),
+                0 => line.push_str(&rng.generate().to_string()),
+                1 => line.push_str(&(rng.generate() % 4).to_string()),
+                2 => line.push_str(["", "OPEN", "ASK", "-1", "99999999999", ",", "\u{e9}"][rng.generate() as usize % 7]),
+                _ => {
+                    let bytes: Vec<u8> = (0..rng.generate() % 8).map(|_| rng.generate() as u8).collect();
+                    line.push_str(&String::from_utf8_lossy(&bytes));
+                },
+            }
+        }
+        line
+    }
+    
+    #[test]
+    fn fuzz_parse() {
+        let mut rng = Random::seeded(ids::test_seed());
+        for _ in 0..20_000 {
+            let line = random_line(&mut rng);
+            // any request which parses must be written the same way it reads
+            if let Some(request) = parse(&line) {
+                assert_eq!(Some(&request), parse(&request.to_string()).as_ref(), "parsed from {line:?}");
+            }
+        }
+    }
+    
+    #[test]
+    fn hello() {
+        let r = parse("HELLO|1.2.3").unwrap();
+        assert_eq!(Request::Hello("1.2.3".into()), r);
+    }
+    
+    #[test]
+    fn keywords() {
+        let requests = [
+            "HELLO|1", "COMPRESS|zstd", "SET_NAME|a", "RESUME|t", "LIST_OPEN_GAMES", "STATS",
+            "LIST_MEMBERS|1", "GET_GAME_INFO|1", "ROOM_PINGS|1", "PING|1", "PONG|1",
+            "CREATE_GAME|x", "CREATE_NAMED_GAME|x|y", "SET_OWNER|1|2", "SET_JOIN_POLICY|1|OPEN", "SET_PRESENCE|1|ALL",
+            "SET_SCHEMA|1|x", "SET_PASSWORD|1|x", "SET_REJOIN_COOLDOWN|1|60", "JOIN_GAME|1|hi", "JOIN_NAMED_GAME|x|hi", "JOIN_ANY|x|hi",
+            "SPECTATE|1", "QUEUE|x", "UNQUEUE", "ACCEPT_JOIN|1|2", "INVITE|1|2", "REJECT_JOIN|1|2|x",
+            "LEAVE_GAME|1", "CLOSE_GAME|1", "VOTE_KICK|1|2", "SEND|1|x", "SEND_BINARY|1|AAE=", "SEND_TO|1|2|x",
+            "CHAT|1|x", "WHISPER|1|2|x", "ECHO_FROM|1|2|x", "OFFER|1|2|x",
+            "ANSWER|1|2|x", "ICE_CANDIDATE|1|2|x", "SET_TURN_ORDER|1|2|3", "END_TURN|1",
+            "ADMIN_LOGIN|x", "RELOAD_CONFIG", "GET_TIMELINE|1", "ADVANCE_CLOCK|1", "ANNOUNCE|ALL|x",
+            "REGISTER_UDP",
+            "ENABLE_ACKS", "ACK|1", "SUBSCRIBE_GAMES", "UNSUBSCRIBE_GAMES",
+            "ADD_FRIEND|bob", "REMOVE_FRIEND|bob", "LIST_FRIENDS", "QUIT",
+        ];
+        for (keyword, request) in KEYWORDS.iter().zip(requests) {
+            assert_eq!(Some(*keyword), parse(request).as_ref().map(Request::name));
+        }
+    }
+    
+    #[test]
+    fn display_round_trip() {
+        let requests = [
+            Request::Stats,
+            Request::Hello("1.2.3".into()),
+            Request::Compress("zstd,deflate".into()),
+            Request::Resume("abc".into()),
+            Request::Ping(23, Some(150)),
+            Request::Pong(23),
+            Request::SetJoinPolicy(3, JoinPolicy::Open),
+            Request::SetSchema(3, "a|b".into()),
+            Request::SetRejoinCooldown(3, 60),
+            Request::AskJoinRoom(3, "hi".into(), Some("hunter2".into())),
+            Request::Spectate(3, None),
+            Request::RejectJoinRoom(3, 4, "ur banned".into()),
+            Request::JoinAnyRoom("level=3".into(), "hi".into()),
+            Request::AdvanceClock(60),
+            Request::Announce(Audience::InRooms, "back in 5".into()),
+            Request::Ack(12),
+            Request::SubscribeRooms,
+            Request::AddFriend("bob".into()),
+            Request::ListRooms(RoomQuery {
+                tags: vec!["coop".into()],
+                search: Some("x".into()),
+                sort: Some(RoomOrder::FewestPlayers),
+            }),
+            Request::CreateRoom("level=3".into(), vec!["coop".into(), "eu".into()]),
+            Request::CreateNamedRoom("Friday Night Draft".into(), "level=3".into(), Vec::new()),
+            Request::JoinNamedRoom("Friday Night Draft".into(), "hi".into(), Some("hunter2".into())),
+        ];
+        for request in requests {
+            assert_eq!(Some(&request), parse(&request.to_string()).as_ref());
+        }
+    }
+    
+    #[test]
+    fn set_name() {
+        let r = parse("SET_NAME|alice").unwrap();
+        assert_eq!(Request::SetName("alice".into()), r);
+        
+        let r = parse("SET_NAME|").unwrap();
+        assert_eq!(Request::SetName(String::new()), r);
+    }
+    
     #[test]
     fn list_rooms() {
         let r = parse("LIST_OPEN_GAMES").unwrap();
-        assert_eq!(Request::ListRooms, r);
+        assert_eq!(Request::ListRooms(RoomQuery::default()), r);
+        let r = parse("LIST_OPEN_GAMES|tag=coop|sort=newest|tag=eu|search=a=b").unwrap();
+        let query = RoomQuery {
+            tags: vec!["coop".into(), "eu".into()],
+            search: Some("a=b".into()),
+            sort: Some(RoomOrder::Newest),
+        };
+        assert_eq!(Request::ListRooms(query), r);
+        assert_eq!(None, parse("LIST_OPEN_GAMES|coop"));
+        assert_eq!(None, parse("LIST_OPEN_GAMES|sort=oldest"));
+        assert_eq!(None, parse("LIST_OPEN_GAMES|sort=name|sort=newest"));
+    }
+    
+    #[test]
+    fn stats() {
+        let r = parse("STATS").unwrap();
+        assert_eq!(Request::Stats, r);
+        assert_eq!(None, parse("STATS|1"));
+    }
+    
+    #[test]
+    fn list_members() {
+        let r = parse("LIST_MEMBERS|3").unwrap();
+        assert_eq!(Request::ListMembers(3), r);
+    }
+    
+    #[test]
+    fn get_room_info() {
+        let r = parse("GET_GAME_INFO|3").unwrap();
+        assert_eq!(Request::GetRoomInfo(3), r);
     }
     
     #[test]
     fn ping() {
         let r = parse("PING|23").unwrap();
-        assert_eq!(Request::Ping(23), r);
+        assert_eq!(Request::Ping(23, None), r);
+    }
+    
+    #[test]
+    fn ping_with_latency() {
+        let r = parse("PING|23|150").unwrap();
+        assert_eq!(Request::Ping(23, Some(150)), r);
+        assert_eq!(None, parse("PING|23|fast"));
+    }
+    
+    #[test]
+    fn pong() {
+        let r = parse("PONG|23").unwrap();
+        assert_eq!(Request::Pong(23), r);
+        assert_eq!(None, parse("PONG"));
+    }
+    
+    #[test]
+    fn room_pings() {
+        let r = parse("ROOM_PINGS|3").unwrap();
+        assert_eq!(Request::RoomPings(3), r);
     }
     
     #[test]
     fn create_room() {
         let r = parse("CREATE_GAME|hello").unwrap();
-        assert_eq!(Request::CreateRoom("hello".into()), r);
+        assert_eq!(Request::CreateRoom("hello".into(), Vec::new()), r);
+        let r = parse("CREATE_GAME|hello|coop,eu").unwrap();
+        assert_eq!(Request::CreateRoom("hello".into(), vec!["coop".into(), "eu".into()]), r);
+        assert_eq!(None, parse("CREATE_GAME|hello|coop|eu"));
+    }
+    
+    #[test]
+    fn create_named_room() {
+        let r = parse("CREATE_NAMED_GAME|Friday Night Draft|hello|coop").unwrap();
+        assert_eq!(Request::CreateNamedRoom("Friday Night Draft".into(), "hello".into(), vec!["coop".into()]), r);
+        assert_eq!(None, parse("CREATE_NAMED_GAME|Friday Night Draft"));
     }
     
     #[test]
@@ -141,7 +1054,69 @@ mod test {
     #[test]
     fn ask_join() {
         let r = parse("JOIN_GAME|3|hello").unwrap();
-        assert_eq!(Request::AskJoinRoom(3, "hello".into()), r);
+        assert_eq!(Request::AskJoinRoom(3, "hello".into(), None), r);
+        let r = parse("JOIN_GAME|3|hello|secret").unwrap();
+        assert_eq!(Request::AskJoinRoom(3, "hello".into(), Some("secret".into())), r);
+    }
+    
+    #[test]
+    fn join_named_room() {
+        let r = parse("JOIN_NAMED_GAME|Friday Night Draft|hello").unwrap();
+        assert_eq!(Request::JoinNamedRoom("Friday Night Draft".into(), "hello".into(), None), r);
+    }
+    
+    #[test]
+    fn set_password() {
+        let r = parse("SET_PASSWORD|3|secret").unwrap();
+        assert_eq!(Request::SetPassword(3, "secret".into()), r);
+        let r = parse("SET_PASSWORD|3|").unwrap();
+        assert_eq!(Request::SetPassword(3, "".into()), r);
+    }
+    
+    #[test]
+    fn set_join_policy() {
+        let r = parse("SET_JOIN_POLICY|3|OPEN").unwrap();
+        assert_eq!(Request::SetJoinPolicy(3, JoinPolicy::Open), r);
+        assert_eq!(None, parse("SET_JOIN_POLICY|3|CLOSED"));
+    }
+    
+    #[test]
+    fn set_presence() {
+        let r = parse("SET_PRESENCE|3|ALL").unwrap();
+        assert_eq!(Request::SetPresence(3, Presence::All), r);
+        assert_eq!(None, parse("SET_PRESENCE|3|NOBODY"));
+    }
+    
+    #[test]
+    fn set_schema() {
+        let r = parse("SET_SCHEMA|3|MOVE|[0-9]+|(A|B)").unwrap();
+        assert_eq!(Request::SetSchema(3, "MOVE|[0-9]+|(A|B)".into()), r);
+        let r = parse("SET_SCHEMA|3|").unwrap();
+        assert_eq!(Request::SetSchema(3, "".into()), r);
+    }
+    
+    #[test]
+    fn join_any() {
+        let r = parse("JOIN_ANY||hello").unwrap();
+        assert_eq!(Request::JoinAnyRoom("".into(), "hello".into()), r);
+        let r = parse("JOIN_ANY|coop|hello").unwrap();
+        assert_eq!(Request::JoinAnyRoom("coop".into(), "hello".into()), r);
+    }
+    
+    #[test]
+    fn spectate() {
+        let r = parse("SPECTATE|3").unwrap();
+        assert_eq!(Request::Spectate(3, None), r);
+        let r = parse("SPECTATE|3|secret").unwrap();
+        assert_eq!(Request::Spectate(3, Some("secret".into())), r);
+    }
+    
+    #[test]
+    fn queue() {
+        let r = parse("QUEUE|coop").unwrap();
+        assert_eq!(Request::Queue("coop".into()), r);
+        let r = parse("UNQUEUE").unwrap();
+        assert_eq!(Request::Unqueue, r);
     }
     
     #[test]
@@ -150,6 +1125,13 @@ mod test {
         assert_eq!(Request::AcceptJoinRoom(3, 4), r);
     }
     
+    #[test]
+    fn invite() {
+        let r = parse("INVITE|3|4").unwrap();
+        assert_eq!(Request::Invite(3, 4), r);
+        assert_eq!(None, parse("INVITE|3"));
+    }
+    
     #[test]
     fn reject_join() {
         let r = parse("REJECT_JOIN|3|4|ur banned").unwrap();
@@ -162,21 +1144,143 @@ mod test {
         assert_eq!(Request::LeaveRoom(3), r);
     }
     
+    #[test]
+    fn close_room() {
+        let r = parse("CLOSE_GAME|3").unwrap();
+        assert_eq!(Request::CloseRoom(3), r);
+        assert_eq!(None, parse("CLOSE_GAME"));
+    }
+    
+    #[test]
+    fn vote_kick() {
+        let r = parse("VOTE_KICK|3|4").unwrap();
+        assert_eq!(Request::VoteKick(3, 4), r);
+        assert_eq!(None, parse("VOTE_KICK|3"));
+    }
+    
+    #[test]
+    fn parts() {
+        let line = "A||B|C";
+        let (first, rest) = {
+            let mut parts = Parts::of(line);
+            (parts.take_str(), parts.take_rest())
+        };
+        // the fields outlive the parts they were taken from
+        assert_eq!(Some("A"), first);
+        assert_eq!("|B|C", rest);
+        
+        let mut parts = Parts::of("");
+        assert_eq!(Some(""), parts.take_str());
+        assert_eq!(None, parts.take_str());
+        assert_eq!("", parts.take_rest());
+    }
+    
     #[test]
     fn send() {
         let r = parse("SEND|3|hello").unwrap();
         assert_eq!(Request::Send(3, "hello".into()), r);
     }
     
+    #[test]
+    fn send_binary() {
+        let r = parse("SEND_BINARY|3|AP8B").unwrap();
+        assert_eq!(Request::SendBinary(3, "AP8B".into()), r);
+        
+        assert_eq!(None, parse("SEND_BINARY|3|hello"));
+        assert_eq!(None, parse("SEND_BINARY|3|AP8B|AP8B"));
+    }
+    
     #[test]
     fn send_to() {
         let r = parse("SEND_TO|3|4|hello").unwrap();
         assert_eq!(Request::SendTo(3, 4, "hello".into()), r);
     }
     
+    #[test]
+    fn chat() {
+        let r = parse("CHAT|3|hello").unwrap();
+        assert_eq!(Request::Chat(3, "hello".into()), r);
+    }
+    
+    #[test]
+    fn whisper() {
+        let r = parse("WHISPER|3|4|hello").unwrap();
+        assert_eq!(Request::Whisper(3, 4, "hello".into()), r);
+    }
+    
     #[test]
     fn echo_from() {
         let r = parse("ECHO_FROM|3|4|hello").unwrap();
         assert_eq!(Request::EchoFrom(3, 4, "hello".into()), r);
     }
+    
+    #[test]
+    fn signal() {
+        let r = parse("ICE_CANDIDATE|3|4|candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host").unwrap();
+        let expected = Request::Signal(Signal::IceCandidate, 3, 4, "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host".into());
+        assert_eq!(expected, r);
+        assert_eq!(None, parse("OFFER|3|sdp"));
+    }
+    
+    #[test]
+    fn set_turn_order() {
+        assert_eq!(Some(Request::SetTurnOrder(3, vec![4, 1])), parse("SET_TURN_ORDER|3|4|1"));
+        assert_eq!(Some(Request::SetTurnOrder(3, Vec::new())), parse("SET_TURN_ORDER|3"));
+        assert_eq!(None, parse("SET_TURN_ORDER|3|"));
+        assert_eq!(None, parse("SET_TURN_ORDER|3|x"));
+    }
+    
+    #[test]
+    fn resume() {
+        let r = parse("RESUME|0123abcd").unwrap();
+        assert_eq!(Request::Resume("0123abcd".into()), r);
+        assert_eq!(None, parse("RESUME|0123abcd|2"));
+    }
+    
+    #[test]
+    fn admin_login() {
+        let r = parse("ADMIN_LOGIN|hunter2").unwrap();
+        assert_eq!(Request::AdminLogin("hunter2".into()), r);
+    }
+    
+    #[test]
+    fn reload_config() {
+        let r = parse("RELOAD_CONFIG").unwrap();
+        assert_eq!(Request::ReloadConfig, r);
+    }
+    
+    #[test]
+    fn advance_clock() {
+        let r = parse("ADVANCE_CLOCK|60").unwrap();
+        assert_eq!(Request::AdvanceClock(60), r);
+    }
+    
+    #[test]
+    fn announce() {
+        let r = parse("ANNOUNCE|IN_GAMES|Restarting soon").unwrap();
+        assert_eq!(Request::Announce(Audience::InRooms, "Restarting soon".into()), r);
+        assert_eq!(None, parse("ANNOUNCE|SOME|x"));
+        assert_eq!(None, parse("ANNOUNCE|ALL"));
+    }
+    
+    #[test]
+    fn get_timeline() {
+        let r = parse("GET_TIMELINE|3").unwrap();
+        assert_eq!(Request::GetTimeline(3), r);
+    }
+    
+    #[test]
+    fn request_id() {
+        assert_eq!((Some(42), "SEND|1|x"), split_request_id("#42|SEND|1|x"));
+        assert_eq!((None, "#x|SEND|1|x"), split_request_id("#x|SEND|1|x"));
+        assert_eq!((None, "#42"), split_request_id("#42"));
+        assert_eq!(Some(Request::Send(1, "x".into())), parse("#42|SEND|1|x"));
+    }
+    
+    #[test]
+    fn ack() {
+        let r = parse("ACK|12").unwrap();
+        assert_eq!(Request::Ack(12), r);
+        assert_eq!(None, parse("ACK"));
+    }
 }
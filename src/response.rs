@@ -1,9 +1,19 @@
+use std::borrow::Cow;
 use std::sync::Arc;
+use base64::Engine as _;
 
-use crate::models::{UserID, RoomID};
+use crate::compression::Compression;
+use crate::limits::{RateLimit, Warning};
+use crate::friends::FriendStatus;
+use crate::models::{UserID, RoomID, JoinPolicy, Presence, Room, Signal};
+use crate::request::RequestID;
+use crate::stats::TrafficCounts;
+use crate::timeline::TimelineEntry;
 
 pub(crate) const SERVER_FULL: Message = Message::Error(Error::ServerFull);
-pub(crate) const INVALID_REQUEST: Message = Message::Error(Error::InvalidRequest);
+pub(crate) const RATE_LIMITED: Message = Message::Error(Error::RateLimited);
+pub(crate) const REQUEST_TOO_LONG: Message = Message::Error(Error::RequestTooLong);
+pub(crate) const AUTH_REQUIRED: Message = Message::Error(Error::AuthRequired);
 
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct Response {
@@ -30,6 +40,7 @@ impl Response {
         Response::returns(Message::Error(e))
     }
     
+    #[cfg(test)]
     pub(crate) fn sends(user_id: UserID, message: Message) -> Response {
         Response::sends_all([
             (user_id, message)
@@ -44,6 +55,87 @@ impl Response {
     }
 }
 
+/// Builds a response one message at a time, e.g.
+/// `Response::to(user_id).msg(a).broadcast(room, b)`.
+impl Response {
+    /// Starts a response whose first message goes to a single user.
+    pub(crate) fn to(user_id: UserID) -> Recipients {
+        Response::empty().and_to(user_id)
+    }
+    
+    /// Starts a response whose first message goes to each of these users.
+    pub(crate) fn to_all(user_ids: impl IntoIterator<Item = UserID>) -> Recipients {
+        Response::empty().and_to_all(user_ids)
+    }
+    
+    pub(crate) fn and_to(self, user_id: UserID) -> Recipients {
+        self.and_to_all([user_id])
+    }
+    
+    pub(crate) fn and_to_all(self, user_ids: impl IntoIterator<Item = UserID>) -> Recipients {
+        Recipients {
+            response: self,
+            user_ids: user_ids.into_iter().collect(),
+        }
+    }
+    
+    /// Sends a message to the room's owner, members and spectators.
+    pub(crate) fn broadcast(self, room: &Room, message: Message) -> Response {
+        self.and_to_all(room.everyone())
+            .msg(message)
+    }
+    
+    /// Adds the messages another response sends after this one's.
+    pub(crate) fn and(mut self, other: Response) -> Response {
+        self.sends.extend(other.sends);
+        self.returns = self.returns.or(other.returns);
+        self
+    }
+    
+    /// Sets the message which goes back to the user who made the request.
+    pub(crate) fn returning(mut self, message: Message) -> Response {
+        self.returns = Some(message);
+        self
+    }
+    
+    /// Marks an error which goes back to the user with the keyword of the
+    /// request which failed.
+    pub(crate) fn failing(mut self, request: &'static str) -> Response {
+        self.returns = self.returns.map(|msg| msg.failing(request));
+        self
+    }
+    
+    /// Marks the message which goes back to the user with the ID they gave
+    /// the request, if any.
+    pub(crate) fn replying_to(mut self, request_id: Option<RequestID>) -> Response {
+        self.returns = self.returns.map(|msg| msg.replying_to(request_id));
+        self
+    }
+}
+
+/// The users who will receive the next message added to a response.
+pub(crate) struct Recipients {
+    response: Response,
+    user_ids: Vec<UserID>,
+}
+
+impl Recipients {
+    pub(crate) fn except(mut self, user_id: UserID) -> Recipients {
+        self.user_ids.retain(|&u_id| u_id != user_id);
+        self
+    }
+    
+    pub(crate) fn msg(mut self, message: Message) -> Response {
+        if let Some((&last, rest)) = self.user_ids.split_last() {
+            for &u_id in rest {
+                self.response.sends.push((u_id, message.clone()));
+            }
+            self.response.sends.push((last, message));
+        }
+        self.response
+    }
+}
+
 pub(crate) type Result<T = Response> = std::result::Result<T, Error>;
 
 impl From<Result> for Response {
@@ -66,26 +158,170 @@ impl FromIterator<(UserID, Message)> for Response {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) enum Message {
-    Welcome(UserID),
+/// A user as others see them: their ID, and their display name if they have
+/// chosen one.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Named(pub UserID, pub Option<String>);
+
+impl From<UserID> for Named {
+    fn from(user_id: UserID) -> Named {
+        Named(user_id, None)
+    }
+}
+
+/// Written as `id,name` in listings, like the entries of `ROOM_PINGS`.
+impl std::fmt::Display for Named {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.1 {
+            Some(name) => write!(f, "{},{name}", self.0),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// What a client is told about the server when it connects, so that it can
+/// keep within the server's limits instead of finding them out by error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub version: String,
+    pub protocol_version: u32,
+    /// The longest request the server accepts, in bytes.
+    pub max_request_length: usize,
+    pub max_room_members: Option<usize>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+// rate limits are never NaN
+impl Eq for ServerInfo {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// The user's ID and resume token, then what the client may need to
+    /// know about the server; older clients only read the first two fields.
+    Welcome(UserID, String, Box<ServerInfo>),
+    /// The server is full; this connection is waiting at the given position.
+    Waiting(usize),
+    /// The connection now belongs to this user, who has a new resume token.
+    Resumed(UserID, String),
+    /// Someone tried to resume this user's session with a token which had
+    /// already been used.
+    ResumeReplayed,
+    HelloOk,
+    /// The algorithm relayed payloads will be compressed with, if any.
+    Compression(Option<Compression>),
+    /// Another message, compressed; the bytes are exactly what the codec
+    /// would otherwise have sent.
+    Compressed(Compression, Arc<[u8]>),
+    AcksEnabled,
+    /// A relayed message, numbered so that the client can acknowledge it.
+    Sequenced(u64, Box<Message>),
+    /// The reply to a request which the client gave an ID, written with the
+    /// ID first, as in `#42|ERROR|...`.
+    Reply(RequestID, Box<Message>),
     Pong(u32),
-    ListRooms(Vec<(RoomID, Arc<str>)>),
+    /// Asks the client to answer with `PONG` and this sequence number, so
+    /// that the server can measure its latency.
+    Ping(u32),
+    /// Connected users, open games, the server's uptime in seconds, and the
+    /// traffic over all connections, which only the dispatcher can fill in.
+    Stats(usize, usize, u64, Option<TrafficCounts>),
+    ServerRestarting(u64),
+    MirrorRoomOpened(RoomID, usize),
+    MirrorRoomClosed(RoomID),
+    MirrorPlayers(RoomID, usize),
+    /// Sent after the listing's current rooms, as `ROOM_ADDED` messages.
+    RoomsSubscribed,
+    RoomsUnsubscribed,
+    /// A room in the listing, with its number of players, its data and its
+    /// name, if it has one.
+    RoomAdded(RoomID, usize, Arc<str>, Option<Arc<str>>),
+    RoomUpdated(RoomID, usize, Arc<str>, Option<Arc<str>>),
+    RoomRemoved(RoomID),
+    ListRooms(Vec<(RoomID, Arc<str>, Option<Arc<str>>)>),
+    ListMembers(RoomID, Named, Vec<Named>),
+    ListJoinRequests(RoomID, Vec<UserID>),
+    ListSpectators(RoomID, Vec<UserID>),
+    RoomInfo(RoomID, UserID, usize, Option<usize>, JoinPolicy, Arc<str>, Option<Arc<str>>),
+    /// Each member's estimated latency in milliseconds, if known.
+    RoomPings(RoomID, Vec<(UserID, Option<u32>)>),
     RoomCreated(RoomID),
+    Queued(usize),
+    MatchFound(RoomID, UserID),
     RoomJoined(RoomID),
+    RoomSpectating(RoomID),
+    SpectatorJoined(RoomID, UserID),
     RoomClosed(RoomID),
     ChangedOwner(RoomID, UserID),
     RoomRejected(RoomID, String),
-    JoinRequested(RoomID, UserID, String),
-    PlayerLeft(RoomID, UserID),
-    ReceivedFrom(RoomID, UserID, String),
+    /// The user's name comes last, so that clients which don't expect it
+    /// can ignore it.
+    JoinRequested(RoomID, Named, String),
+    JoinRequestSent(RoomID),
+    MemberJoined(RoomID, UserID, String),
+    PlayerDisconnected(RoomID, UserID),
+    PlayerReconnected(RoomID, UserID),
+    /// The player didn't resume their session in time; `PLAYER_LEFT`
+    /// follows.
+    PlayerTimedOut(RoomID, UserID),
+    PlayerLeft(RoomID, Named),
+    /// A vote to remove a member, with how many players have voted for it
+    /// and how many votes are needed.
+    KickVote(RoomID, UserID, usize, usize),
+    /// Enough players voted to remove this member from the room.
+    VotedOut(RoomID, UserID),
+    /// The room's owner invited this user, who can now join it directly.
+    Invited(RoomID, Named),
+    FriendAdded(String),
+    FriendRemoved(String),
+    /// Every friend the user has listed, in order of account name.
+    Friends(Vec<(String, FriendStatus)>),
+    /// A friend has signed in, with their user ID and account.
+    FriendOnline(UserID, String),
+    FriendOffline(String),
+    /// A friend has created or joined a game.
+    FriendJoinedRoom(RoomID, UserID, String),
+    ReceivedFrom(RoomID, UserID, Arc<str>),
     ReceivedBroadcast(RoomID, Arc<str>),
-    ReceivedIndividual(RoomID, String),
+    ReceivedIndividual(RoomID, Arc<str>),
+    /// Binary payloads, in base64, as sent with `SEND_BINARY`.
+    ReceivedBinaryFrom(RoomID, UserID, Arc<str>),
+    ReceivedBinaryBroadcast(RoomID, Arc<str>),
+    Chat(RoomID, UserID, Arc<str>),
+    /// A message to this user could not be delivered.
+    Undelivered(UserID),
+    Whisper(RoomID, UserID, Arc<str>),
+    /// A WebRTC signaling message, and the player who sent it.
+    Signal(Signal, RoomID, UserID, Arc<str>),
+    /// Whose turn it is, and how many turns have started.
+    Turn(RoomID, u64, UserID),
+    NoTurnOrder(RoomID),
+    /// A payload sent in a turn-based game, with the turn it was sent in.
+    ReceivedOnTurn(RoomID, u64, UserID, Arc<str>),
+    /// One of the owner's earlier broadcasts, sent to a user who has just
+    /// joined or started spectating.
+    Replayed(RoomID, Arc<str>),
+    AdminOk,
+    ConfigReloaded,
+    /// The current time, in seconds since the Unix epoch.
+    Clock(u64),
+    /// One line of the message of the day, sent after `WELCOME`.
+    Motd(Arc<str>),
+    /// A notice from the administrators.
+    Announcement(Arc<str>),
+    /// How many users an announcement was sent to.
+    Announced(usize),
+    Timeline(RoomID, Vec<TimelineEntry>),
+    /// The UDP port, and a token to send to it from the endpoint to register.
+    UdpToken(u16, String),
+    Warning(Warning),
     Error(Error),
+    /// An error in reply to a request, written with the request's keyword
+    /// first, as in `ERROR|JOIN_GAME|9|No such game`.
+    Failed(&'static str, Error),
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) enum Error {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
     ServerFull,
     InvalidRequest,
     AlreadyInARoom,
@@ -96,6 +332,44 @@ pub(crate) enum Error {
     NoSuchUser,
     NoSuchRoom,
     NoSuchJoinRequest,
+    RoomFull,
+    UpgradeRequired(Option<Arc<str>>),
+    NoOpenRooms,
+    ServerDraining,
+    AlreadyQueued,
+    NotQueued,
+    InvalidSchema,
+    InvalidPayload,
+    IsSpectator,
+    InvalidToken,
+    ClockNotSimulated,
+    RateLimited,
+    RequestTooLong,
+    NotAdmin,
+    Kicked,
+    ReloadFailed(String),
+    IncorrectPassword,
+    InvalidName,
+    AuthRequired,
+    UdpUnavailable,
+    TooManyRooms,
+    NotYourTurn,
+    NotTurnBased,
+    InvalidTurnOrder,
+    AcksNotEnabled,
+    InvalidTags,
+    NoAccount,
+    TooManyFriends,
+    RoomDataTooLong,
+    /// The server's rules for game data, or the embedding application,
+    /// refused it.
+    InvalidRoomData,
+    PayloadTooLarge,
+    /// The owner rejected this user's last join request too recently for
+    /// them to ask again.
+    RejoinCooldown,
+    TooManyRequests,
+    RoomNameTaken,
 }
 
 impl From<Error> for Message {
@@ -114,57 +388,428 @@ impl From<Message> for Response {
     }
 }
 
-impl std::fmt::Display for Message {
+/// One field of a message, as a codec writes it. Numbers are kept apart
+/// from text, for encodings which tell them apart.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Field<'a> {
+    Int(u64),
+    Str(Cow<'a, str>),
+    /// Written in base64 by encodings which only have text.
+    Bytes(&'a [u8]),
+}
+
+impl From<u32> for Field<'_> {
+    fn from(n: u32) -> Self {
+        Field::Int(n.into())
+    }
+}
+impl From<u64> for Field<'_> {
+    fn from(n: u64) -> Self {
+        Field::Int(n)
+    }
+}
+impl From<usize> for Field<'_> {
+    fn from(n: usize) -> Self {
+        Field::Int(n as u64)
+    }
+}
+impl <'a> From<&'a str> for Field<'a> {
+    fn from(s: &'a str) -> Self {
+        Field::Str(Cow::Borrowed(s))
+    }
+}
+impl From<String> for Field<'_> {
+    fn from(s: String) -> Self {
+        Field::Str(Cow::Owned(s))
+    }
+}
+
+impl std::fmt::Display for Field<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Message::Welcome(user_id) => {
-                write!(f, "WELCOME|{user_id}")
-            },
-            Message::Pong(sequence_number) => {
-                write!(f, "PONG|{sequence_number}")
+            Field::Int(n) => write!(f, "{n}"),
+            Field::Str(s) => f.write_str(s),
+            Field::Bytes(bytes) => f.write_str(&base64::engine::general_purpose::STANDARD.encode(bytes)),
+        }
+    }
+}
+
+impl Message {
+    /// The keyword which this message is sent with.
+    pub(crate) fn keyword(&self) -> &'static str {
+        match self {
+            Message::Welcome(..) => "WELCOME",
+            Message::Waiting(..) => "WAITING",
+            Message::Resumed(..) => "RESUMED",
+            Message::ResumeReplayed => "RESUME_REPLAYED",
+            Message::HelloOk => "HELLO_OK",
+            Message::Compression(..) => "COMPRESSION",
+            Message::Compressed(..) => "COMPRESSED",
+            Message::AcksEnabled => "ACKS_ENABLED",
+            Message::Sequenced(..) => "SEQUENCED",
+            Message::Reply(_, msg) => msg.keyword(),
+            Message::Pong(..) => "PONG",
+            Message::Ping(..) => "PING",
+            Message::Stats(..) => "STATS",
+            Message::MirrorRoomOpened(..) => "GAME_OPENED",
+            Message::MirrorRoomClosed(..) => "GAME_CLOSED",
+            Message::MirrorPlayers(..) => "GAME_PLAYERS",
+            Message::RoomsSubscribed => "GAMES_SUBSCRIBED",
+            Message::RoomsUnsubscribed => "GAMES_UNSUBSCRIBED",
+            Message::RoomAdded(..) => "ROOM_ADDED",
+            Message::RoomUpdated(..) => "ROOM_UPDATED",
+            Message::RoomRemoved(..) => "ROOM_REMOVED",
+            Message::ServerRestarting(..) => "SERVER_RESTARTING",
+            Message::ListRooms(rooms) if rooms.is_empty() => "NO_OPEN_GAMES",
+            Message::ListRooms(..) => "OPEN_GAMES",
+            Message::ListMembers(..) => "MEMBERS",
+            Message::RoomPings(..) => "ROOM_PINGS",
+            Message::ListJoinRequests(..) => "JOIN_REQUESTS",
+            Message::ListSpectators(..) => "SPECTATORS",
+            Message::RoomInfo(..) => "GAME_INFO",
+            Message::RoomCreated(..) => "CREATED_GAME",
+            Message::Queued(..) => "QUEUED",
+            Message::MatchFound(..) => "MATCH_FOUND",
+            Message::ChangedOwner(..) => "CHANGED_OWNER",
+            Message::RoomJoined(..) => "JOINED",
+            Message::RoomSpectating(..) => "SPECTATING",
+            Message::SpectatorJoined(..) => "SPECTATOR_JOINED",
+            Message::RoomClosed(..) => "GAME_OVER",
+            Message::RoomRejected(..) => "REJECTED",
+            Message::JoinRequested(..) => "PLAYER_JOINED",
+            Message::JoinRequestSent(..) => "JOIN_REQUESTED",
+            Message::MemberJoined(..) => "MEMBER_JOINED",
+            Message::PlayerDisconnected(..) => "PLAYER_DISCONNECTED",
+            Message::PlayerReconnected(..) => "PLAYER_RECONNECTED",
+            Message::PlayerTimedOut(..) => "PLAYER_TIMED_OUT",
+            Message::PlayerLeft(..) => "PLAYER_LEFT",
+            Message::KickVote(..) => "KICK_VOTE",
+            Message::VotedOut(..) => "VOTED_OUT",
+            Message::Invited(..) => "INVITED",
+            Message::FriendAdded(..) => "FRIEND_ADDED",
+            Message::FriendRemoved(..) => "FRIEND_REMOVED",
+            Message::Friends(..) => "FRIENDS",
+            Message::FriendOnline(..) => "FRIEND_ONLINE",
+            Message::FriendOffline(..) => "FRIEND_OFFLINE",
+            Message::FriendJoinedRoom(..) => "FRIEND_JOINED_GAME",
+            Message::ReceivedFrom(..) |
+            Message::ReceivedBroadcast(..) |
+            Message::ReceivedIndividual(..) => "RECEIVED",
+            Message::ReceivedBinaryFrom(..) |
+            Message::ReceivedBinaryBroadcast(..) => "RECEIVED_BINARY",
+            Message::Chat(..) => "CHAT",
+            Message::Undelivered(..) => "UNDELIVERED",
+            Message::Whisper(..) => "WHISPER",
+            Message::Signal(signal, ..) => signal.keyword(),
+            Message::Turn(..) => "TURN",
+            Message::NoTurnOrder(..) => "NO_TURN_ORDER",
+            Message::ReceivedOnTurn(..) => "RECEIVED_ON_TURN",
+            Message::Replayed(..) => "REPLAYED",
+            Message::AdminOk => "ADMIN_OK",
+            Message::ConfigReloaded => "CONFIG_RELOADED",
+            Message::Clock(..) => "CLOCK",
+            Message::Motd(..) => "MOTD",
+            Message::Announcement(..) => "ANNOUNCEMENT",
+            Message::Announced(..) => "ANNOUNCED",
+            Message::Timeline(..) => "TIMELINE",
+            Message::UdpToken(..) => "UDP_TOKEN",
+            Message::Warning(..) => "WARNING",
+            Message::Error(..) |
+            Message::Failed(..) => "ERROR",
+        }
+    }
+    
+    /// The fields which follow the keyword. Lists are flattened into the
+    /// fields, and a user's name comes last if they have one, so that
+    /// clients which don't expect it can ignore it.
+    pub(crate) fn fields(&self) -> Vec<Field<'_>> {
+        match self {
+            Message::ResumeReplayed |
+            Message::HelloOk |
+            Message::AcksEnabled |
+            Message::RoomsSubscribed |
+            Message::RoomsUnsubscribed |
+            Message::AdminOk |
+            Message::ConfigReloaded => Vec::new(),
+            
+            &Message::Waiting(n) |
+            &Message::Queued(n) |
+            &Message::Announced(n) => vec![n.into()],
+            
+            &Message::Pong(n) |
+            &Message::Ping(n) => vec![n.into()],
+            
+            &Message::ServerRestarting(secs) |
+            &Message::Clock(secs) => vec![secs.into()],
+            
+            &Message::MirrorRoomClosed(id) |
+            &Message::RoomRemoved(id) |
+            &Message::RoomCreated(id) |
+            &Message::RoomJoined(id) |
+            &Message::RoomSpectating(id) |
+            &Message::RoomClosed(id) |
+            &Message::JoinRequestSent(id) |
+            &Message::NoTurnOrder(id) |
+            &Message::Undelivered(id) => vec![id.into()],
+            
+            &Message::MatchFound(room_id, user_id) |
+            &Message::ChangedOwner(room_id, user_id) |
+            &Message::SpectatorJoined(room_id, user_id) |
+            &Message::PlayerDisconnected(room_id, user_id) |
+            &Message::PlayerReconnected(room_id, user_id) |
+            &Message::PlayerTimedOut(room_id, user_id) |
+            &Message::VotedOut(room_id, user_id) => vec![room_id.into(), user_id.into()],
+            
+            &Message::KickVote(room_id, user_id, votes, needed) => vec![room_id.into(), user_id.into(), votes.into(), needed.into()],
+            
+            &Message::MirrorRoomOpened(room_id, players) |
+            &Message::MirrorPlayers(room_id, players) => vec![room_id.into(), players.into()],
+            
+            // the data runs to the end of the line, so the name can't follow it
+            // in the text encodings without confusing older clients
+            Message::RoomAdded(room_id, players, data, _) |
+            Message::RoomUpdated(room_id, players, data, _) => vec![(*room_id).into(), (*players).into(), Field::from(&**data)],
+            
+            Message::Resumed(user_id, token) => vec![(*user_id).into(), token.as_str().into()],
+            
+            Message::Welcome(user_id, token, info) => {
+                let (per_second, burst) = info.rate_limit
+                    .map_or((0.0, 0.0), |limit| (limit.per_second, limit.burst));
+                vec![
+                    (*user_id).into(),
+                    token.as_str().into(),
+                    info.version.as_str().into(),
+                    info.protocol_version.into(),
+                    info.max_request_length.into(),
+                    // as with a room's capacity, 0 means there is no limit
+                    info.max_room_members.unwrap_or(0).into(),
+                    per_second.to_string().into(),
+                    burst.to_string().into(),
+                ]
             },
-            Message::ListRooms(rooms) => if rooms.is_empty() {
-                write!(f, "NO_OPEN_GAMES")
-            } else {
-                write!(f, "OPEN_GAMES")?;
-                for (room_id, data) in rooms {
-                    write!(f, "|{room_id}|{data}")?;
+            
+            &Message::Stats(users, rooms, uptime, traffic) => {
+                let mut fields = vec![users.into(), rooms.into(), uptime.into()];
+                if let Some(traffic) = traffic {
+                    fields.extend([traffic.messages_in.into(), traffic.bytes_in.into(), traffic.messages_out.into(), traffic.bytes_out.into()]);
                 }
-                Ok(())
-            },
-            Message::RoomCreated(room_id) => {
-                write!(f, "CREATED_GAME|{room_id}")
-            },
-            Message::ChangedOwner(room_id, user_id) => {
-                write!(f, "CHANGED_OWNER|{room_id}|{user_id}")
-            },
-            Message::RoomJoined(room_id) => {
-                write!(f, "JOINED|{room_id}")
-            },
-            Message::RoomClosed(room_id) => {
-                write!(f, "GAME_OVER|{room_id}")
-            },
-            Message::RoomRejected(room_id, reason) => {
-                write!(f, "REJECTED|{room_id}|{reason}")
-            },
-            Message::JoinRequested(room_id, user_id, msg) => {
-                write!(f, "PLAYER_JOINED|{room_id}|{user_id}|{msg}")
-            },
-            Message::PlayerLeft(room_id, user_id) => {
-                write!(f, "PLAYER_LEFT|{room_id}|{user_id}")
-            },
-            Message::ReceivedBroadcast(room_id, payload) => {
-                write!(f, "RECEIVED|{room_id}|{payload}")
+                fields
             },
-            Message::ReceivedIndividual(room_id, payload) => {
-                write!(f, "RECEIVED|{room_id}|{payload}")
+            
+            Message::ListRooms(rooms) => rooms.iter()
+                .flat_map(|(room_id, data, _)| [(*room_id).into(), Field::from(&**data)])
+                .collect(),
+            
+            Message::ListMembers(room_id, owner, members) => [(*room_id).into(), owner.to_string().into()].into_iter()
+                .chain(members.iter().map(|member| member.to_string().into()))
+                .collect(),
+            
+            Message::RoomPings(room_id, pings) => std::iter::once((*room_id).into())
+                .chain(pings.iter().map(|(user_id, latency)| match latency {
+                    Some(ms) => format!("{user_id},{ms}").into(),
+                    None => format!("{user_id},?").into(),
+                }))
+                .collect(),
+            
+            Message::ListJoinRequests(room_id, user_ids) |
+            Message::ListSpectators(room_id, user_ids) => std::iter::once(room_id)
+                .chain(user_ids)
+                .map(|&id| id.into())
+                .collect(),
+            
+            Message::RoomInfo(room_id, owner_id, member_count, capacity, join_policy, data, _) => vec![
+                (*room_id).into(),
+                (*owner_id).into(),
+                (*member_count).into(),
+                // a capacity of 0 means there is no limit
+                capacity.unwrap_or(0).into(),
+                join_policy.to_string().into(),
+                Field::from(&**data),
+            ],
+            
+            &Message::Turn(room_id, turn, user_id) => vec![room_id.into(), turn.into(), user_id.into()],
+            
+            Message::ReceivedOnTurn(room_id, turn, user_id, payload) => vec![
+                (*room_id).into(),
+                (*turn).into(),
+                (*user_id).into(),
+                Field::from(&**payload),
+            ],
+            
+            Message::RoomRejected(room_id, text) => vec![(*room_id).into(), text.as_str().into()],
+            
+            Message::FriendAdded(account) |
+            Message::FriendRemoved(account) |
+            Message::FriendOffline(account) => vec![account.as_str().into()],
+            Message::Motd(text) |
+            Message::Announcement(text) => vec![Field::from(&**text)],
+            
+            Message::Friends(friends) => friends.iter()
+                .map(|(account, status)| match status {
+                    FriendStatus::Pending => format!("{account},pending").into(),
+                    FriendStatus::Offline => format!("{account},offline").into(),
+                    FriendStatus::Online(user_id) => format!("{account},online,{user_id}").into(),
+                })
+                .collect(),
+            
+            // the account comes last, as the authenticator chose it
+            Message::FriendOnline(user_id, account) => vec![(*user_id).into(), account.as_str().into()],
+            Message::FriendJoinedRoom(room_id, user_id, account) => vec![(*room_id).into(), (*user_id).into(), account.as_str().into()],
+            
+            Message::MemberJoined(room_id, user_id, text) => vec![(*room_id).into(), (*user_id).into(), text.as_str().into()],
+            
+            Message::JoinRequested(room_id, Named(user_id, name), msg) => {
+                let mut fields = vec![(*room_id).into(), (*user_id).into(), msg.as_str().into()];
+                fields.extend(name.as_deref().map(Field::from));
+                fields
             },
-            Message::ReceivedFrom(room_id, user_id, payload) => {
-                write!(f, "RECEIVED|{room_id}|{user_id}|{payload}")
+            Message::PlayerLeft(room_id, Named(user_id, name)) |
+            Message::Invited(room_id, Named(user_id, name)) => {
+                let mut fields = vec![(*room_id).into(), (*user_id).into()];
+                fields.extend(name.as_deref().map(Field::from));
+                fields
             },
-            Message::Error(e) => {
-                write!(f, "ERROR|{e}")
+            
+            Message::ReceivedBroadcast(room_id, payload) |
+            Message::ReceivedIndividual(room_id, payload) |
+            Message::ReceivedBinaryBroadcast(room_id, payload) |
+            Message::Replayed(room_id, payload) => vec![(*room_id).into(), Field::from(&**payload)],
+            
+            Message::ReceivedFrom(room_id, user_id, text) |
+            Message::ReceivedBinaryFrom(room_id, user_id, text) |
+            Message::Chat(room_id, user_id, text) |
+            Message::Whisper(room_id, user_id, text) |
+            Message::Signal(_, room_id, user_id, text) => vec![(*room_id).into(), (*user_id).into(), Field::from(&**text)],
+            
+            Message::Timeline(room_id, entries) => std::iter::once((*room_id).into())
+                .chain(entries.iter().map(|entry| entry.to_string().into()))
+                .collect(),
+            
+            Message::Warning(warning @ Warning::RoomNearlyFull(room_id, members, capacity)) => vec![
+                warning.name().into(),
+                (*room_id).into(),
+                (*members).into(),
+                (*capacity).into(),
+            ],
+            Message::Warning(warning @ (
+                Warning::NearlyRateLimited(count, limit) |
+                Warning::PayloadNearlyTooLarge(count, limit) |
+                Warning::QueueNearlyFull(count, limit)
+            )) => vec![warning.name().into(), (*count).into(), (*limit).into()],
+            
+            Message::Compression(c) => vec![c.map_or("none".into(), |c| c.to_string()).into()],
+            Message::Compressed(c, bytes) => vec![c.to_string().into(), Field::Bytes(bytes)],
+            Message::Reply(_, msg) => msg.fields(),
+            Message::Sequenced(n, msg) => {
+                let mut fields = vec![(*n).into(), msg.keyword().into()];
+                fields.extend(msg.fields());
+                fields
             },
+            Message::UdpToken(port, token) => vec![u32::from(*port).into(), token.as_str().into()],
+            
+            Message::Error(e) => vec![e.code().into(), e.to_string().into()],
+            Message::Failed(request, e) => vec![(*request).into(), e.code().into(), e.to_string().into()],
+        }
+    }
+    
+    /// The payload this message relays from another user, if any.
+    pub(crate) fn payload(&self) -> Option<&str> {
+        match self {
+            Message::ReceivedFrom(_, _, payload) |
+            Message::ReceivedBroadcast(_, payload) |
+            Message::ReceivedIndividual(_, payload) |
+            Message::ReceivedBinaryFrom(_, _, payload) |
+            Message::ReceivedBinaryBroadcast(_, payload) |
+            Message::ReceivedOnTurn(_, _, _, payload) |
+            Message::Replayed(_, payload) => Some(payload),
+            Message::Sequenced(_, msg) |
+            Message::Reply(_, msg) => msg.payload(),
+            _ => None,
+        }
+    }
+    
+    /// Says which request an error is for; other messages are unchanged.
+    pub(crate) fn failing(self, request: &'static str) -> Message {
+        match self {
+            Message::Error(e) => Message::Failed(request, e),
+            msg => msg,
+        }
+    }
+    
+    /// Marks this message as the reply to a request, if the client gave the
+    /// request an ID.
+    pub(crate) fn replying_to(self, request_id: Option<RequestID>) -> Message {
+        match request_id {
+            Some(request_id) => Message::Reply(request_id, Box::new(self)),
+            None => self,
+        }
+    }
+}
+
+/// Writes a message in the original `|`-separated format.
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Message::Reply(request_id, _) = self {
+            write!(f, "#{request_id}|")?;
+        }
+        f.write_str(self.keyword())?;
+        for field in self.fields() {
+            write!(f, "|{field}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    /// A number which identifies the kind of error, so that clients needn't
+    /// match on the description. Codes are never changed or reused.
+    pub(crate) fn code(&self) -> u32 {
+        match self {
+            Error::ServerFull => 1,
+            Error::InvalidRequest => 2,
+            Error::AlreadyInARoom => 3,
+            Error::AlreadyRequestedJoin => 4,
+            Error::NotRoomOwner => 5,
+            Error::IsRoomOwner => 6,
+            Error::NotInThatRoom => 7,
+            Error::NoSuchUser => 8,
+            Error::NoSuchRoom => 9,
+            Error::NoSuchJoinRequest => 10,
+            Error::RoomFull => 11,
+            Error::UpgradeRequired(..) => 12,
+            Error::NoOpenRooms => 13,
+            Error::ServerDraining => 14,
+            Error::AlreadyQueued => 15,
+            Error::NotQueued => 16,
+            Error::InvalidSchema => 17,
+            Error::InvalidPayload => 18,
+            Error::IsSpectator => 19,
+            Error::InvalidToken => 20,
+            // 21 was for a stale resume counter, which is no longer sent
+            Error::ClockNotSimulated => 22,
+            Error::RateLimited => 23,
+            Error::RequestTooLong => 24,
+            Error::NotAdmin => 25,
+            Error::Kicked => 26,
+            Error::ReloadFailed(..) => 27,
+            Error::IncorrectPassword => 28,
+            Error::InvalidName => 29,
+            Error::AuthRequired => 30,
+            Error::UdpUnavailable => 31,
+            Error::TooManyRooms => 32,
+            Error::NotYourTurn => 33,
+            Error::NotTurnBased => 34,
+            Error::InvalidTurnOrder => 35,
+            Error::AcksNotEnabled => 36,
+            Error::InvalidTags => 37,
+            Error::NoAccount => 38,
+            Error::TooManyFriends => 39,
+            Error::RoomDataTooLong => 40,
+            Error::InvalidRoomData => 41,
+            Error::PayloadTooLarge => 42,
+            Error::RejoinCooldown => 43,
+            Error::TooManyRequests => 44,
+            Error::RoomNameTaken => 45,
         }
     }
 }
@@ -182,6 +827,123 @@ impl std::fmt::Display for Error {
             Error::NoSuchUser => f.write_str("No such user"),
             Error::NoSuchRoom => f.write_str("No such game"),
             Error::NoSuchJoinRequest => f.write_str("No such join request"),
+            Error::RoomFull => f.write_str("Game is full"),
+            Error::NoOpenRooms => f.write_str("No open games"),
+            Error::ServerDraining => f.write_str("Server is restarting soon"),
+            Error::AlreadyQueued => f.write_str("Already in the matchmaking queue"),
+            Error::NotQueued => f.write_str("Not in the matchmaking queue"),
+            Error::InvalidSchema => f.write_str("Invalid schema"),
+            Error::InvalidPayload => f.write_str("Message does not match the game's schema"),
+            Error::IsSpectator => f.write_str("Spectators cannot send messages"),
+            Error::InvalidToken => f.write_str("Invalid or expired token"),
+            Error::ClockNotSimulated => f.write_str("This server's clock cannot be changed"),
+            Error::RateLimited => f.write_str("Too many requests"),
+            Error::RequestTooLong => f.write_str("Request too long"),
+            Error::NotAdmin => f.write_str("You are not an administrator"),
+            Error::Kicked => f.write_str("You were removed by an administrator"),
+            Error::ReloadFailed(e) => write!(f, "Failed to reload config: {e}"),
+            Error::IncorrectPassword => f.write_str("Incorrect password"),
+            Error::InvalidName => f.write_str("Invalid name"),
+            Error::AuthRequired => f.write_str("Authentication required"),
+            Error::UdpUnavailable => f.write_str("UDP relay is not enabled"),
+            Error::TooManyRooms => f.write_str("You are in too many games"),
+            Error::NotYourTurn => f.write_str("It is not your turn"),
+            Error::NotTurnBased => f.write_str("This game is not turn-based"),
+            Error::InvalidTurnOrder => f.write_str("Invalid turn order"),
+            Error::AcksNotEnabled => f.write_str("Acknowledgements are not enabled"),
+            Error::InvalidTags => f.write_str("Invalid tags"),
+            Error::NoAccount => f.write_str("Not signed in to an account"),
+            Error::TooManyFriends => f.write_str("Too many friends"),
+            Error::RoomDataTooLong => f.write_str("Game data is too long"),
+            Error::InvalidRoomData => f.write_str("Game data is not allowed"),
+            Error::PayloadTooLarge => f.write_str("Message is too large"),
+            Error::RejoinCooldown => f.write_str("Join request was rejected recently, try again later"),
+            Error::TooManyRequests => f.write_str("Too many players are waiting to join this game"),
+            Error::RoomNameTaken => f.write_str("Another game already has that name"),
+            Error::UpgradeRequired(None) => f.write_str("Client upgrade required"),
+            Error::UpgradeRequired(Some(hint)) => write!(f, "Client upgrade required, download from {hint}"),
+        }
+    }
+}
+
+impl std::fmt::Display for JoinPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinPolicy::AskOwner => f.write_str("ASK"),
+            JoinPolicy::Open => f.write_str("OPEN"),
+        }
+    }
+}
+
+impl std::fmt::Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Presence::Owner => f.write_str("OWNER"),
+            Presence::All => f.write_str("ALL"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn builder() {
+        let mut room = Room::new(1, 1, "hello".into());
+        room.members.extend([2, 3]);
+        room.spectators.insert(4);
+        
+        let response = Response::to_all(room.everyone())
+            .except(2)
+            .msg(Message::RoomClosed(1))
+            .and_to(2)
+            .msg(Message::PlayerLeft(1, 2.into()))
+            .returning(Message::Pong(1));
+        
+        let expected = Response {
+            returns: Some(Message::Pong(1)),
+            sends: vec![
+                (1, Message::RoomClosed(1)),
+                (3, Message::RoomClosed(1)),
+                (4, Message::RoomClosed(1)),
+                (2, Message::PlayerLeft(1, 2.into())),
+            ],
+        };
+        assert_eq!(expected, response);
+    }
+    
+    #[test]
+    fn broadcast() {
+        let mut room = Room::new(1, 1, "hello".into());
+        room.members.insert(2);
+        room.spectators.insert(3);
+        
+        let expected = Response::sends_all([
+            (1, Message::ChangedOwner(1, 2)),
+            (2, Message::ChangedOwner(1, 2)),
+            (3, Message::ChangedOwner(1, 2)),
+        ]);
+        assert_eq!(expected, Response::empty().broadcast(&room, Message::ChangedOwner(1, 2)));
+    }
+    
+    #[test]
+    fn display_names() {
+        let alice = Named(2, Some("alice".into()));
+        let members = Message::ListMembers(1, 1.into(), vec![alice.clone(), 3.into()]);
+        assert_eq!("MEMBERS|1|1|2,alice|3", members.to_string());
+        assert_eq!("PLAYER_JOINED|1|2|hi|alice", Message::JoinRequested(1, alice.clone(), "hi".into()).to_string());
+        assert_eq!("PLAYER_LEFT|1|2|alice", Message::PlayerLeft(1, alice).to_string());
+        assert_eq!("PLAYER_LEFT|1|3", Message::PlayerLeft(1, 3.into()).to_string());
+    }
+    
+    #[test]
+    fn failing() {
+        let failed = Response::error(Error::NoSuchRoom).failing("JOIN_GAME").replying_to(Some(7));
+        assert_eq!("#7|ERROR|JOIN_GAME|9|No such game", failed.returns.unwrap().to_string());
+        
+        // only errors are changed
+        let ok = Response::returns(Message::RoomJoined(1)).failing("JOIN_GAME");
+        assert_eq!(Some(Message::RoomJoined(1)), ok.returns);
+    }
+}
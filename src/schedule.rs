@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The exit status used when the server shuts down for a scheduled restart,
+/// so that a supervisor can tell it apart from a crash.
+pub(crate) const RESTART_EXIT_CODE: i32 = 75;
+
+/// A daily restart at a fixed time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RestartSchedule {
+    /// Seconds after midnight UTC.
+    time_of_day: u64,
+    /// How long to wait for games to finish before restarting anyway.
+    pub(crate) drain_timeout: Duration,
+}
+
+impl RestartSchedule {
+    pub(crate) fn daily_at(hour: u64, minute: u64, drain_timeout: Duration) -> Option<RestartSchedule> {
+        (hour < 24 && minute < 60).then_some(RestartSchedule {
+            time_of_day: hour * 60 * 60 + minute * 60,
+            drain_timeout,
+        })
+    }
+    
+    /// Parses a time of day in the form `HH:MM`.
+    pub(crate) fn parse(s: &str, drain_timeout: Duration) -> Option<RestartSchedule> {
+        let (hour, minute) = s.split_once(':')?;
+        RestartSchedule::daily_at(hour.parse().ok()?, minute.parse().ok()?, drain_timeout)
+    }
+    
+    /// How long from `now` until the next scheduled restart.
+    pub(crate) fn until_next(&self, now: SystemTime) -> Duration {
+        let now = now.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let wait = (self.time_of_day + SECONDS_PER_DAY - now % SECONDS_PER_DAY) % SECONDS_PER_DAY;
+        Duration::from_secs(wait)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    const TIMEOUT: Duration = Duration::from_secs(600);
+    
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+    
+    #[test]
+    fn parse() {
+        assert_eq!(RestartSchedule::daily_at(4, 0, TIMEOUT), RestartSchedule::parse("04:00", TIMEOUT));
+        assert_eq!(None, RestartSchedule::parse("24:00", TIMEOUT));
+        assert_eq!(None, RestartSchedule::parse("4", TIMEOUT));
+    }
+    
+    #[test]
+    fn until_next() {
+        let schedule = RestartSchedule::daily_at(4, 0, TIMEOUT).unwrap();
+        let day = SECONDS_PER_DAY;
+        assert_eq!(Duration::from_secs(4 * 3600), schedule.until_next(at(10 * day)));
+        assert_eq!(Duration::from_secs(3600), schedule.until_next(at(10 * day + 3 * 3600)));
+        assert_eq!(Duration::from_secs(day - 60), schedule.until_next(at(10 * day + 4 * 3600 + 60)));
+    }
+}
@@ -1,32 +1,924 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::collections::btree_map::Entry;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::models::{UserID, RoomID, User, Room, UserState};
-use crate::request::Request;
-use crate::response::{Error, Message, Response, Result};
+use crate::access::AccessControl;
+use crate::auth::{Authenticator, NoAuth};
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{Codec, PipeCodec};
+use crate::compression::Compression;
+use crate::hooks::{Hooks, NoHooks};
+use crate::dispatch::UndeliveredPolicy;
+use crate::friends::{FriendStatus, Friends};
+use crate::ids::{self, IdGenerator, Sequential};
+use crate::limits::{self, Backoff, RateLimit, Warning};
+use crate::matchmaking::{Enqueued, Matchmaker};
+use crate::mirror::{Listing, Lobby};
+use crate::models::{self, UserID, RoomID, User, Room, Membership, JoinPolicy, Pattern, Presence, Signal};
+use crate::request::{Audience, Request, RoomOrder, RoomQuery};
+use crate::response::{Error, Message, Named, Response, Result, ServerInfo};
+use crate::schedule::RestartSchedule;
+use crate::snapshot::{self, Snapshot};
+use crate::timeline::{RoomEvent, RoomStore, Timelines};
+use crate::turns::Turns;
+use crate::version::{self, VersionPolicy};
 
-fn next_id<T>(last_id: u32, map: &HashMap<u32, T>) -> u32 {
-    let mut id = last_id;
-    loop {
-        id = id.wrapping_add(1);
-        if !map.contains_key(&id) { return id; }
+/// How many IDs to try before giving up on finding a free one.
+const MAX_ID_ATTEMPTS: usize = 1000;
+
+fn next_id<T>(ids: &mut dyn IdGenerator, map: &HashMap<u32, T>) -> Result<u32> {
+    std::iter::repeat_with(|| ids.generate())
+        .take(MAX_ID_ATTEMPTS)
+        .find(|id| *id != 0 && !map.contains_key(id))
+        .ok_or(Error::ServerFull)
+}
+
+/// Either files a join request or joins the room immediately, depending on
+/// the room's join policy; invited users always join immediately.
+fn join(user: &mut User, room: &mut Room, msg: String) -> Result {
+    let policy = if room.invited.contains(&user.id) {
+        JoinPolicy::Open
+    } else {
+        room.join_policy
+    };
+    match policy {
+        JoinPolicy::AskOwner => {
+            if room.has_too_many_join_requests() {
+                return Err(Error::TooManyRequests);
+            }
+            user.try_join_room(room)?;
+            Ok(Response::to(room.owner_id).msg(Message::JoinRequested(room.id, user.named(), msg)))
+        },
+        JoinPolicy::Open => {
+            if room.is_full() {
+                return Err(Error::RoomFull);
+            }
+            user.try_join_room(room)?;
+            room.accept_join_request(user)?;
+            let response = Response::to(room.owner_id)
+                .msg(Message::MemberJoined(room.id, user.id, msg))
+                .returning(Message::RoomJoined(room.id));
+            let response = with_history(room, user.id, response);
+            Ok(with_capacity_warning(room, response))
+        },
+    }
+}
+
+/// Sends the owner's recent broadcasts to a user who has just joined or
+/// started spectating, so they don't have to wait for the owner to resend
+/// the game's state.
+fn with_history(room: &Room, user_id: UserID, response: Response) -> Response {
+    room.history.iter().fold(response, |response, payload| {
+        response.and_to(user_id)
+            .msg(Message::Replayed(room.id, payload.clone()))
+    })
+}
+
+/// The timeline event for a user who has just asked to join a room, which
+/// depends on whether they had to ask or joined immediately.
+fn join_event(user: &User, room_id: RoomID) -> RoomEvent {
+    if user.membership(room_id) == Some(Membership::Member) {
+        RoomEvent::Joined(user.id)
+    } else {
+        RoomEvent::JoinRequested(user.id)
+    }
+}
+
+/// Checks that a group found by the matchmaking queue fits in a room; the
+/// owner doesn't count towards the room's capacity.
+pub(crate) fn check_match_size(match_size: usize, max_room_members: Option<usize>) -> std::result::Result<(), String> {
+    match max_room_members {
+        Some(max) if match_size > max.saturating_add(1) => {
+            Err(format!("A match of {match_size} players doesn't fit in a game of at most {max} players and its owner"))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Warns the owner when their room is nearly full, so they can stop
+/// accepting join requests before they start failing.
+fn with_capacity_warning(room: &Room, response: Response) -> Response {
+    match room.capacity {
+        Some(capacity) if room.is_nearly_full() => {
+            let warning = Warning::RoomNearlyFull(room.id, room.members.len(), capacity);
+            response.and_to(room.owner_id)
+                .msg(Message::Warning(warning))
+        },
+        _ => response,
+    }
+}
+
+/// Tells whoever cares that a user's connection has dropped, come back or
+/// timed out: the owner for anyone else in a room, unless the room's
+/// presence setting says everyone, or everyone else if it is the owner.
+fn connection_notice(room: &Room, user_id: UserID, msg: fn(RoomID, UserID) -> Message) -> Response {
+    let recipients = if user_id == room.owner_id {
+        Response::to_all(room.audience())
+    } else if room.presence == Presence::All {
+        Response::to_all(std::iter::once(room.owner_id).chain(room.audience()).filter(|&u_id| u_id != user_id))
+    } else {
+        Response::to(room.owner_id)
+    };
+    recipients.msg(msg(room.id, user_id))
+}
+
+/// Notifies the owner, all remaining members and spectators that a member
+/// has left.
+fn player_left(room: &mut Room, user: Named) -> Response {
+    let user_id = user.0;
+    Response::empty()
+        .broadcast(room, Message::PlayerLeft(room.id, user))
+        .and(leave_turns(room, user_id))
+}
+
+/// Takes a player who has left out of the room's turn order, telling
+/// everyone if it was their turn, or if nobody is left to take turns.
+fn leave_turns(room: &mut Room, user_id: UserID) -> Response {
+    let Some(turns) = &mut room.turns else {
+        return Response::empty();
+    };
+    if !turns.remove(user_id) {
+        return Response::empty();
+    }
+    let msg = if turns.is_empty() {
+        room.turns = None;
+        Message::NoTurnOrder(room.id)
+    } else {
+        Message::Turn(room.id, turns.turn(), turns.current())
+    };
+    Response::empty().broadcast(room, msg)
+}
+
+/// Produces fresh settings for a running server, from which the ones which
+/// can safely change are applied.
+pub(crate) type Reloader = Arc<dyn Fn() -> std::result::Result<ServerBuilder, String> + Send + Sync>;
+
+pub struct ServerBuilder {
+    name: Option<Arc<str>>,
+    max_connections: usize,
+    max_room_members: Option<usize>,
+    max_join_requests: Option<usize>,
+    max_room_data_length: usize,
+    room_data_pattern: Option<Pattern>,
+    version_policy: VersionPolicy,
+    restart_schedule: Option<RestartSchedule>,
+    match_size: usize,
+    admin_password: Option<String>,
+    authenticator: Arc<dyn Authenticator>,
+    codec: Arc<dyn Codec>,
+    udp_port: Option<u16>,
+    disconnect_grace: Duration,
+    room_idle_timeout: Duration,
+    rejoin_cooldown: Duration,
+    vote_kick_threshold: f64,
+    waiting_room_capacity: usize,
+    waiting_timeout: Duration,
+    rate_limit: Option<RateLimit>,
+    max_request_length: usize,
+    max_payload_length: usize,
+    max_queued_messages: usize,
+    compress_threshold: usize,
+    write_timeout: Duration,
+    undelivered_policy: UndeliveredPolicy,
+    motd: Vec<Arc<str>>,
+    access_control: AccessControl,
+    state_file: Option<PathBuf>,
+    snapshot_interval: Duration,
+    recording: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    reloader: Option<Reloader>,
+    handle_signals: bool,
+    room_store: Box<dyn RoomStore>,
+    hooks: Box<dyn Hooks>,
+    user_ids: Box<dyn IdGenerator>,
+    room_ids: Box<dyn IdGenerator>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            name: None,
+            max_connections: 256,
+            max_room_members: None,
+            max_join_requests: None,
+            max_room_data_length: 1024,
+            room_data_pattern: None,
+            version_policy: VersionPolicy::default(),
+            restart_schedule: None,
+            match_size: 2,
+            admin_password: None,
+            authenticator: Arc::new(NoAuth),
+            codec: Arc::new(PipeCodec),
+            udp_port: None,
+            disconnect_grace: Duration::ZERO,
+            room_idle_timeout: Duration::ZERO,
+            rejoin_cooldown: Duration::ZERO,
+            vote_kick_threshold: 0.5,
+            waiting_room_capacity: 0,
+            waiting_timeout: Duration::ZERO,
+            rate_limit: None,
+            max_request_length: usize::MAX,
+            max_payload_length: usize::MAX,
+            max_queued_messages: 256,
+            compress_threshold: 1024,
+            write_timeout: Duration::ZERO,
+            undelivered_policy: UndeliveredPolicy::Log,
+            motd: Vec::new(),
+            access_control: AccessControl::default(),
+            state_file: None,
+            snapshot_interval: Duration::ZERO,
+            recording: None,
+            clock: Arc::new(SystemClock),
+            reloader: None,
+            handle_signals: false,
+            room_store: Box::<Timelines>::default(),
+            hooks: Box::new(NoHooks),
+            user_ids: Box::<Sequential>::default(),
+            room_ids: Box::<Sequential>::default(),
+        }
+    }
+    
+    /// Names this server, to distinguish it from other virtual server
+    /// instances in the same process.
+    pub fn name(mut self, name: &str) -> ServerBuilder {
+        self.name = Some(Arc::from(name));
+        self
+    }
+    
+    pub fn max_connections(mut self, max_connections: usize) -> ServerBuilder {
+        self.max_connections = max_connections;
+        self
+    }
+    
+    pub(crate) fn max_room_members(mut self, max_room_members: Option<usize>) -> ServerBuilder {
+        self.max_room_members = max_room_members;
+        self
+    }
+    
+    /// Limits how many users can be waiting for the owner to accept them
+    /// into each room.
+    pub(crate) fn max_join_requests(mut self, max_join_requests: Option<usize>) -> ServerBuilder {
+        self.max_join_requests = max_join_requests;
+        self
+    }
+    
+    /// Limits the data which games are created with, in bytes.
+    pub(crate) fn max_room_data_length(mut self, max_room_data_length: usize) -> ServerBuilder {
+        self.max_room_data_length = max_room_data_length;
+        self
+    }
+    
+    /// If set, games can only be created with data matching this pattern.
+    pub(crate) fn room_data_pattern(mut self, pattern: Option<Pattern>) -> ServerBuilder {
+        self.room_data_pattern = pattern;
+        self
+    }
+    
+    pub(crate) fn version_policy(mut self, version_policy: VersionPolicy) -> ServerBuilder {
+        self.version_policy = version_policy;
+        self
+    }
+    
+    pub(crate) fn restart_schedule(mut self, restart_schedule: Option<RestartSchedule>) -> ServerBuilder {
+        self.restart_schedule = restart_schedule;
+        self
+    }
+    
+    /// How many players are grouped together by the matchmaking queue.
+    pub(crate) fn match_size(mut self, match_size: usize) -> ServerBuilder {
+        self.match_size = match_size;
+        self
+    }
+    
+    /// Relays datagrams between game owners and members on this UDP port,
+    /// at each of the hosts the server listens on.
+    pub(crate) fn udp_port(mut self, udp_port: Option<u16>) -> ServerBuilder {
+        self.udp_port = udp_port;
+        self
+    }
+    
+    /// Users who log in with this password become administrators.
+    pub fn admin_password(mut self, admin_password: Option<String>) -> ServerBuilder {
+        self.admin_password = admin_password;
+        self
+    }
+    
+    /// Decides which connections may use the server; by default, all of
+    /// them.
+    pub(crate) fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> ServerBuilder {
+        self.authenticator = authenticator;
+        self
+    }
+    
+    /// How requests and messages are written on the wire; by default, the
+    /// pipe format.
+    pub(crate) fn codec(mut self, codec: Arc<dyn Codec>) -> ServerBuilder {
+        self.codec = codec;
+        self
+    }
+    
+    /// How long to keep a disconnected user's place, in case they resume.
+    pub(crate) fn disconnect_grace(mut self, disconnect_grace: Duration) -> ServerBuilder {
+        self.disconnect_grace = disconnect_grace;
+        self
+    }
+    
+    /// Rooms where nobody has done anything for this long are closed, unless
+    /// it is zero.
+    pub(crate) fn room_idle_timeout(mut self, room_idle_timeout: Duration) -> ServerBuilder {
+        self.room_idle_timeout = room_idle_timeout;
+        self
+    }
+    
+    /// How long a user whose join request was rejected must wait before
+    /// asking to join the same room again, unless its owner says otherwise.
+    pub(crate) fn rejoin_cooldown(mut self, rejoin_cooldown: Duration) -> ServerBuilder {
+        self.rejoin_cooldown = rejoin_cooldown;
+        self
+    }
+    
+    /// A member is removed once more than this fraction of the room's other
+    /// players have voted to kick them, so at least 1 means never.
+    pub(crate) fn vote_kick_threshold(mut self, vote_kick_threshold: f64) -> ServerBuilder {
+        self.vote_kick_threshold = vote_kick_threshold;
+        self
+    }
+    
+    /// When the server is full, up to this many connections may wait for a
+    /// free slot, for at most the given timeout.
+    pub(crate) fn waiting_room(mut self, capacity: usize, timeout: Duration) -> ServerBuilder {
+        self.waiting_room_capacity = capacity;
+        self.waiting_timeout = timeout;
+        self
+    }
+    
+    /// Where to save users and rooms every `interval`, so that they can be
+    /// restored after a restart.
+    pub(crate) fn state_file(mut self, path: Option<PathBuf>, interval: Duration) -> ServerBuilder {
+        self.state_file = path;
+        self.snapshot_interval = interval;
+        self
+    }
+    
+    /// Where to record every request and message, so that the session can be
+    /// replayed.
+    pub(crate) fn recording(mut self, path: Option<PathBuf>) -> ServerBuilder {
+        self.recording = path;
+        self
+    }
+    
+    pub(crate) fn rate_limit(mut self, rate_limit: Option<RateLimit>) -> ServerBuilder {
+        self.rate_limit = rate_limit;
+        self
+    }
+    
+    /// The maximum length of a request in bytes, not counting the newline.
+    pub(crate) fn max_request_length(mut self, max_request_length: usize) -> ServerBuilder {
+        self.max_request_length = max_request_length;
+        self
+    }
+    
+    /// The maximum length in bytes of a payload or join message to relay,
+    /// which is less than a request's so that one request can't be sent on
+    /// to a whole room at full size.
+    pub(crate) fn max_payload_length(mut self, max_payload_length: usize) -> ServerBuilder {
+        self.max_payload_length = max_payload_length;
+        self
+    }
+    
+    /// How many messages may wait to be sent to a client before it is
+    /// disconnected for falling behind.
+    pub(crate) fn max_queued_messages(mut self, max_queued_messages: usize) -> ServerBuilder {
+        self.max_queued_messages = max_queued_messages;
+        self
+    }
+    
+    /// How long a relayed payload must be, in bytes, before it is compressed
+    /// for clients which asked for compression.
+    pub(crate) fn compress_threshold(mut self, compress_threshold: usize) -> ServerBuilder {
+        self.compress_threshold = compress_threshold;
+        self
+    }
+    
+    /// How long a client may take to accept a message before it is
+    /// disconnected, or zero to wait indefinitely.
+    pub(crate) fn write_timeout(mut self, write_timeout: Duration) -> ServerBuilder {
+        self.write_timeout = write_timeout;
+        self
+    }
+    
+    pub(crate) fn undelivered_policy(mut self, policy: UndeliveredPolicy) -> ServerBuilder {
+        self.undelivered_policy = policy;
+        self
+    }
+    
+    pub(crate) fn motd(mut self, motd: Vec<Arc<str>>) -> ServerBuilder {
+        self.motd = motd;
+        self
+    }
+    
+    pub(crate) fn access_control(mut self, access_control: AccessControl) -> ServerBuilder {
+        self.access_control = access_control;
+        self
+    }
+    
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> ServerBuilder {
+        self.clock = clock;
+        self
+    }
+    
+    pub(crate) fn reloader(mut self, reloader: Reloader) -> ServerBuilder {
+        self.reloader = Some(reloader);
+        self
+    }
+    
+    /// Reloads the config when the process receives SIGHUP, and stops the
+    /// server gracefully when it receives SIGINT or SIGTERM; if either of
+    /// those is received again, the process stops at once. This is for
+    /// applications which run nothing but the server.
+    pub fn handle_signals(mut self) -> ServerBuilder {
+        self.handle_signals = true;
+        self
+    }
+    
+    /// Where to keep rooms and their timelines, instead of in memory.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn room_store(mut self, store: impl RoomStore + 'static) -> ServerBuilder {
+        self.room_store = Box::new(store);
+        self
+    }
+    
+    /// Callbacks for an application embedding the server; the server itself
+    /// doesn't need any.
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> ServerBuilder {
+        self.hooks = Box::new(hooks);
+        self
+    }
+    
+    pub fn user_ids(mut self, ids: impl IdGenerator + 'static) -> ServerBuilder {
+        self.user_ids = Box::new(ids);
+        self
+    }
+    
+    pub fn room_ids(mut self, ids: impl IdGenerator + 'static) -> ServerBuilder {
+        self.room_ids = Box::new(ids);
+        self
+    }
+    
+    pub fn build(self) -> Server {
+        Server {
+            name: self.name,
+            max_connections: self.max_connections,
+            max_room_members: self.max_room_members,
+            max_join_requests: self.max_join_requests,
+            max_room_data_length: self.max_room_data_length,
+            room_data_pattern: self.room_data_pattern,
+            version_policy: self.version_policy,
+            restart_schedule: self.restart_schedule,
+            draining: false,
+            next_ping: 0,
+            // a match which couldn't fit in a room is made smaller
+            matchmaker: Matchmaker::new(self.max_room_members.map_or(self.match_size, |max| self.match_size.min(max.saturating_add(1)))),
+            admin_password: self.admin_password,
+            admin_failures: Backoff::new(),
+            authenticator: self.authenticator,
+            codec: self.codec,
+            udp_port: self.udp_port,
+            disconnect_grace: self.disconnect_grace,
+            room_idle_timeout: self.room_idle_timeout,
+            rejoin_cooldown: self.rejoin_cooldown,
+            vote_kick_threshold: self.vote_kick_threshold,
+            waiting_room_capacity: self.waiting_room_capacity,
+            waiting_timeout: self.waiting_timeout,
+            rate_limit: self.rate_limit,
+            max_request_length: self.max_request_length,
+            max_payload_length: self.max_payload_length,
+            max_queued_messages: self.max_queued_messages,
+            compress_threshold: self.compress_threshold,
+            write_timeout: self.write_timeout,
+            undelivered_policy: self.undelivered_policy,
+            motd: self.motd,
+            access_control: self.access_control,
+            state_file: self.state_file,
+            snapshot_interval: self.snapshot_interval,
+            recording: self.recording,
+            started: self.clock.now(),
+            clock: self.clock,
+            reloader: self.reloader,
+            handle_signals: self.handle_signals,
+            room_store: self.room_store,
+            hooks: self.hooks,
+            user_ids: self.user_ids,
+            users: HashMap::new(),
+            room_ids: self.room_ids,
+            rooms: HashMap::new(),
+            friends: Friends::new(),
+            accounts: HashMap::new(),
+        }
     }
 }
 
-#[derive(Default)]
-pub(crate) struct Server {
+pub struct Server {
+    name: Option<Arc<str>>,
     max_connections: usize,
-    last_user_id: UserID,
+    max_room_members: Option<usize>,
+    max_join_requests: Option<usize>,
+    max_room_data_length: usize,
+    room_data_pattern: Option<Pattern>,
+    version_policy: VersionPolicy,
+    restart_schedule: Option<RestartSchedule>,
+    /// Whether the server is about to restart, so no new games may be created.
+    draining: bool,
+    /// The sequence number of the next round of pings.
+    next_ping: u32,
+    matchmaker: Matchmaker,
+    admin_password: Option<String>,
+    /// Failed attempts to log in as an administrator, from any connection,
+    /// since a guesser can always connect again.
+    admin_failures: Backoff<()>,
+    authenticator: Arc<dyn Authenticator>,
+    codec: Arc<dyn Codec>,
+    udp_port: Option<u16>,
+    disconnect_grace: Duration,
+    room_idle_timeout: Duration,
+    rejoin_cooldown: Duration,
+    vote_kick_threshold: f64,
+    waiting_room_capacity: usize,
+    waiting_timeout: Duration,
+    rate_limit: Option<RateLimit>,
+    max_request_length: usize,
+    max_payload_length: usize,
+    max_queued_messages: usize,
+    compress_threshold: usize,
+    write_timeout: Duration,
+    undelivered_policy: UndeliveredPolicy,
+    /// The message of the day, sent line by line after `WELCOME`.
+    motd: Vec<Arc<str>>,
+    access_control: AccessControl,
+    state_file: Option<PathBuf>,
+    snapshot_interval: Duration,
+    recording: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    started: SystemTime,
+    reloader: Option<Reloader>,
+    handle_signals: bool,
+    room_store: Box<dyn RoomStore>,
+    hooks: Box<dyn Hooks>,
+    user_ids: Box<dyn IdGenerator>,
     users: HashMap<UserID, User>,
-    last_room_id: RoomID,
+    room_ids: Box<dyn IdGenerator>,
     rooms: HashMap<RoomID, Room>,
+    friends: Friends,
+    /// Which user each signed-in account is online as; if an account is
+    /// signed in more than once, friends only see one of its users.
+    accounts: HashMap<String, UserID>,
 }
 
 impl Server {
+    #[cfg(test)]
     pub(crate) fn new(max_connections: usize) -> Server {
-        Server {
-            max_connections,
-            ..Default::default()
+        ServerBuilder::new()
+            .max_connections(max_connections)
+            .build()
+    }
+    
+    pub(crate) fn name(&self) -> Option<&Arc<str>> {
+        self.name.as_ref()
+    }
+    
+    pub(crate) fn restart_schedule(&self) -> Option<RestartSchedule> {
+        self.restart_schedule
+    }
+    
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+    
+    pub(crate) fn disconnect_grace(&self) -> Duration {
+        self.disconnect_grace
+    }
+    
+    pub(crate) fn waiting_room_capacity(&self) -> usize {
+        self.waiting_room_capacity
+    }
+    
+    pub(crate) fn waiting_timeout(&self) -> Duration {
+        self.waiting_timeout
+    }
+    
+    pub(crate) fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+    
+    pub(crate) fn max_request_length(&self) -> usize {
+        self.max_request_length
+    }
+    
+    pub(crate) fn max_payload_length(&self) -> usize {
+        self.max_payload_length
+    }
+    
+    /// What clients are told about the server when they connect.
+    pub(crate) fn info(&self) -> ServerInfo {
+        ServerInfo {
+            version: version::SERVER_VERSION.to_string(),
+            protocol_version: version::PROTOCOL_VERSION,
+            max_request_length: self.max_request_length,
+            max_room_members: self.max_room_members,
+            rate_limit: self.rate_limit,
+        }
+    }
+    
+    pub(crate) fn max_queued_messages(&self) -> usize {
+        self.max_queued_messages
+    }
+    
+    pub(crate) fn compress_threshold(&self) -> usize {
+        self.compress_threshold
+    }
+    
+    pub(crate) fn write_timeout(&self) -> Duration {
+        self.write_timeout
+    }
+    
+    pub(crate) fn undelivered_policy(&self) -> UndeliveredPolicy {
+        self.undelivered_policy
+    }
+    
+    /// What each client is told after `WELCOME`, if anything.
+    pub(crate) fn motd(&self) -> impl Iterator<Item = Message> + '_ {
+        self.motd.iter().map(|line| Message::Motd(line.clone()))
+    }
+    
+    pub(crate) fn access_control(&self) -> &AccessControl {
+        &self.access_control
+    }
+    
+    pub(crate) fn handles_signals(&self) -> bool {
+        self.handle_signals
+    }
+    
+    pub(crate) fn state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+    
+    pub(crate) fn snapshot_interval(&self) -> Duration {
+        self.snapshot_interval
+    }
+    
+    pub(crate) fn recording(&self) -> Option<&Path> {
+        self.recording.as_deref()
+    }
+    
+    pub(crate) fn has_user(&self, user_id: UserID) -> bool {
+        self.users.contains_key(&user_id)
+    }
+    
+    pub(crate) fn udp_port(&self) -> Option<u16> {
+        self.udp_port
+    }
+    
+    pub(crate) fn admin_password(&self) -> Option<&str> {
+        self.admin_password.as_deref()
+    }
+    
+    pub(crate) fn authenticator(&self) -> &Arc<dyn Authenticator> {
+        &self.authenticator
+    }
+    
+    pub(crate) fn codec(&self) -> &Arc<dyn Codec> {
+        &self.codec
+    }
+    
+    /// All users, in order of ID.
+    pub(crate) fn users(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by_key(|user| user.id);
+        users
+    }
+    
+    /// All rooms, in order of ID.
+    pub(crate) fn rooms(&self) -> Vec<&Room> {
+        let mut rooms: Vec<&Room> = self.rooms.values().collect();
+        rooms.sort_by_key(|room| room.id);
+        rooms
+    }
+    
+    pub(crate) fn room(&self, room_id: RoomID) -> Option<&Room> {
+        self.rooms.get(&room_id)
+    }
+    
+    /// Users whose connections have dropped, but who may still resume.
+    pub(crate) fn disconnected_users(&self) -> Vec<UserID> {
+        self.users.values()
+            .filter(|user| !user.connected)
+            .map(|user| user.id)
+            .collect()
+    }
+    
+    pub(crate) fn snapshot(&self) -> String {
+        snapshot::format(self.users().into_iter(), self.rooms().into_iter(), &self.friends)
+    }
+    
+    /// Adds the users, rooms and friend lists from a snapshot, before anyone
+    /// has connected.
+    /// Every user is restored as disconnected, so they must resume their
+    /// session within the grace period. Users' memberships are taken from
+    /// the rooms which list them; users the snapshot doesn't have, and rooms
+    /// whose owner it doesn't have, are dropped. Matchmaking queues are not
+    /// restored.
+    pub(crate) fn restore(&mut self, snapshot: Snapshot) {
+        for mut user in snapshot.users {
+            user.connected = false;
+            user.queued = false;
+            user.rooms.clear();
+            if let Some(account) = &user.account {
+                self.accounts.entry(account.clone()).or_insert(user.id);
+            }
+            self.users.insert(user.id, user);
+        }
+        self.friends = snapshot.friends;
+        
+        let users = &mut self.users;
+        for mut room in snapshot.rooms {
+            let room_id = room.id;
+            // each user can only be listed once in each room
+            let mut claim = |user_id, membership| match users.get_mut(&user_id).map(|u| u.rooms.entry(room_id)) {
+                Some(Entry::Vacant(entry)) => {
+                    entry.insert(membership);
+                    true
+                },
+                _ => false,
+            };
+            if self.rooms.contains_key(&room_id) || !claim(room.owner_id, Membership::Owner) {
+                continue;
+            }
+            room.members.retain(|&u_id| claim(u_id, Membership::Member));
+            room.join_requests.retain(|&u_id| claim(u_id, Membership::RequestedJoin));
+            room.spectators.retain(|&u_id| claim(u_id, Membership::Spectating));
+            if let Some(turns) = &room.turns {
+                let order: Vec<_> = turns.upcoming()
+                    .filter(|u_id| *u_id == room.owner_id || room.members.contains(u_id))
+                    .collect();
+                room.turns = (!order.is_empty()).then(|| Turns::resume(order, turns.turn()));
+            }
+            self.rooms.insert(room_id, room);
+        }
+    }
+    
+    /// Re-reads the settings which can change while the server is running.
+    /// Existing games keep their capacity, and existing connections keep
+    /// their rate limit and maximum request length.
+    pub(crate) fn reload(&mut self) -> Result<()> {
+        let reloader = self.reloader.as_ref()
+            .ok_or_else(|| Error::ReloadFailed("no config to reload".into()))?;
+        let new = reloader().map_err(Error::ReloadFailed)?;
+        check_match_size(self.matchmaker.group_size(), new.max_room_members)
+            .map_err(Error::ReloadFailed)?;
+        
+        self.max_connections = new.max_connections;
+        self.max_room_members = new.max_room_members;
+        self.max_join_requests = new.max_join_requests;
+        self.max_room_data_length = new.max_room_data_length;
+        self.room_data_pattern = new.room_data_pattern;
+        self.disconnect_grace = new.disconnect_grace;
+        self.room_idle_timeout = new.room_idle_timeout;
+        self.rejoin_cooldown = new.rejoin_cooldown;
+        self.vote_kick_threshold = new.vote_kick_threshold;
+        self.waiting_room_capacity = new.waiting_room_capacity;
+        self.waiting_timeout = new.waiting_timeout;
+        self.rate_limit = new.rate_limit;
+        self.max_request_length = new.max_request_length;
+        self.max_payload_length = new.max_payload_length;
+        self.max_queued_messages = new.max_queued_messages;
+        self.compress_threshold = new.compress_threshold;
+        self.write_timeout = new.write_timeout;
+        self.undelivered_policy = new.undelivered_policy;
+        self.version_policy = new.version_policy;
+        self.motd = new.motd;
+        Ok(())
+    }
+    
+    fn reload_config(&mut self, user_id: UserID) -> Result {
+        self.expect_admin(user_id)?;
+        self.reload()?;
+        Ok(Message::ConfigReloaded.into())
+    }
+    
+    /// Closes a room on an administrator's behalf, so the owner is told too.
+    pub(crate) fn force_close_room(&mut self, room_id: RoomID) -> Result {
+        let owner_id = self.get_room(room_id)?.owner_id;
+        let response = self.close_room(room_id)?;
+        Ok(response.and_to(owner_id).msg(Message::RoomClosed(room_id)))
+    }
+    
+    /// Pings every connected user, so that their latency can be measured
+    /// when they answer. A ping which hasn't been answered by the next round
+    /// is forgotten.
+    pub(crate) fn ping_users(&mut self) -> Response {
+        let now = self.clock.now();
+        let sequence_number = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        let mut pinged = Vec::new();
+        for user in self.users.values_mut().filter(|user| user.connected) {
+            user.ping = Some((sequence_number, now));
+            pinged.push(user.id);
+        }
+        Response::to_all(pinged).msg(Message::Ping(sequence_number))
+    }
+    
+    /// Closes every room where nobody has done anything for longer than the
+    /// idle timeout, e.g. because the owner's client has hung. Returns which
+    /// rooms were closed.
+    pub(crate) fn close_idle_rooms(&mut self) -> (Vec<RoomID>, Response) {
+        let mut response = Response::empty();
+        if self.room_idle_timeout.is_zero() {
+            return (Vec::new(), response);
+        }
+        
+        let now = self.clock.now();
+        let mut idle = Vec::new();
+        for room in self.rooms.values_mut() {
+            let last_active = *room.last_active.get_or_insert(now);
+            if now.duration_since(last_active).is_ok_and(|d| d >= self.room_idle_timeout) {
+                idle.push(room.id);
+            }
+        }
+        for &room_id in &idle {
+            if let Ok(r) = self.force_close_room(room_id) {
+                response.sends.extend(r.sends);
+            }
+        }
+        (idle, response)
+    }
+    
+    /// Stops new games from being created, and warns every user that the
+    /// server will restart within the given number of seconds.
+    pub(crate) fn start_draining(&mut self, deadline_secs: u64) -> Response {
+        self.draining = true;
+        Response::to_all(self.users.keys().copied())
+            .msg(Message::ServerRestarting(deadline_secs))
+    }
+    
+    /// The open rooms and how many players are in each, for observers.
+    pub(crate) fn lobby(&self) -> Lobby {
+        self.rooms.values()
+            .map(|room| (room.id, room.owner_and_members().count()))
+            .collect()
+    }
+    
+    /// The open rooms, as clients which subscribe to the listing see them.
+    pub(crate) fn listing(&self) -> Listing {
+        self.rooms.values()
+            .map(|room| (room.id, (room.owner_and_members().count(), room.data.clone(), room.name.as_deref().map(Into::into))))
+            .collect()
+    }
+    
+    /// A short description of the server's state, for logging.
+    pub(crate) fn summary(&self) -> String {
+        format!("{} users connected, {} games open", self.users.len(), self.rooms.len())
+    }
+    
+    /// Whether the server is draining and all games have finished.
+    pub(crate) fn is_drained(&self) -> bool {
+        self.draining && self.rooms.is_empty()
+    }
+    
+    fn record(&mut self, room_id: RoomID, event: RoomEvent) {
+        let time = self.clock.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.room_store.record(room_id, time, event);
+        match event {
+            RoomEvent::Created(owner_id) => self.hooks.room_created(room_id, owner_id),
+            RoomEvent::Joined(user_id) => self.hooks.user_joined(room_id, user_id),
+            RoomEvent::Left(user_id) |
+            RoomEvent::Kicked(user_id) => self.hooks.user_left(room_id, user_id),
+            RoomEvent::Closed => self.hooks.room_closed(room_id),
+            _ => {},
+        }
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.last_active = Some(self.clock.now());
+            self.room_store.save_room(room);
+        }
+    }
+    
+    fn expect_admin(&self, user_id: UserID) -> Result<()> {
+        let user = self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?;
+        if user.is_admin {
+            Ok(())
+        } else {
+            Err(Error::NotAdmin)
         }
     }
     
@@ -41,6 +933,13 @@ impl Server {
             .ok_or(Error::NoSuchUser)
     }
     
+    /// A user with their display name, or just their ID if they have none or
+    /// have gone.
+    fn named(&self, user_id: UserID) -> Named {
+        self.users.get(&user_id)
+            .map_or(user_id.into(), User::named)
+    }
+    
     fn get_room(&self, room_id: RoomID) -> Result<&Room> {
         self.rooms.get(&room_id)
             .ok_or(Error::NoSuchRoom)
@@ -63,19 +962,46 @@ impl Server {
         let room = self.rooms.remove(&room_id)
             .ok_or(Error::NoSuchRoom)?;
         let all_users = room.members.into_iter()
-            .chain(room.join_requests);
+            .chain(room.join_requests)
+            .chain(room.spectators);
         
         if let Ok(owner) = self.get_user_mut(room.owner_id) {
-            owner.state = UserState::Nowhere;
+            owner.rooms.remove(&room_id);
         }
         
-        let mut messages = Vec::new();
+        let mut recipients = Vec::new();
         for u_id in all_users {
             let u = self.get_user_mut(u_id)?;
-            u.state = UserState::Nowhere;
-            messages.push((u_id, Message::RoomClosed(room_id)));
+            u.rooms.remove(&room_id);
+            recipients.push(u_id);
+        }
+        self.record(room_id, RoomEvent::Closed);
+        Ok(Response::to_all(recipients).msg(Message::RoomClosed(room_id)))
+    }
+    
+    /// Hands a room over to its longest-standing member after the owner has
+    /// gone, or closes it if there are no members left.
+    fn migrate_owner(&mut self, room_id: RoomID, old_owner: Named) -> Result {
+        let old_owner_id = old_owner.0;
+        let Some(&new_owner_id) = self.get_room(room_id)?.members.first() else {
+            return self.close_room(room_id);
+        };
+        let (new_owner, room) = self.get_user_room_mut(new_owner_id, room_id)?;
+        room.promote_member(new_owner)?;
+        
+        let mut response = Response::empty()
+            .broadcast(room, Message::PlayerLeft(room_id, old_owner))
+            .broadcast(room, Message::ChangedOwner(room_id, new_owner_id))
+            .and(leave_turns(room, old_owner_id));
+        if !room.join_requests.is_empty() {
+            // nobody has told the new owner about these yet
+            let requests = Message::ListJoinRequests(room_id, room.join_requests.iter().copied().collect());
+            response = response.and_to(new_owner_id).msg(requests);
         }
-        Ok(Response::sends_all(messages))
+        
+        self.record(room_id, RoomEvent::Left(old_owner_id));
+        self.record(room_id, RoomEvent::OwnerChanged(new_owner_id));
+        Ok(response)
     }
     
     pub(crate) fn add_user(&mut self) -> Option<UserID> {
@@ -83,185 +1009,1139 @@ impl Server {
             return None;
         }
         
-        let user_id = next_id(self.last_user_id, &self.users);
+        let user_id = next_id(self.user_ids.as_mut(), &self.users).ok()?;
         let user = User::new(user_id);
         self.users.insert(user_id, user);
-        self.last_user_id = user_id;
         Some(user_id)
     }
     
-    pub(crate) fn remove_user(&mut self, user_id: UserID) -> Result {
-        let mut user = self.users.remove(&user_id)
-            .ok_or(Error::NoSuchUser)?;
-        
-        match user.state {
-            UserState::RoomOwner(room_id) => {
-                self.close_room(room_id)
-            },
-            UserState::InRoom(room_id) => {
-                let room = self.get_room_mut(room_id)?;
-                room.remove_user(user_id)?;
-                let msg = Message::PlayerLeft(room_id, user_id);
-                Ok(Response::sends(room.owner_id, msg))
-            },
-            UserState::RequestedJoin(room_id) => {
-                let room = self.get_room_mut(room_id)?;
-                room.cancel_join_request(&mut user)?;
-                let msg = Message::PlayerLeft(room_id, user_id);
-                Ok(Response::sends(room.owner_id, msg))
-            },
-            UserState::Nowhere => Ok(Response::empty(),)
+    /// Records which account a user signed in with, telling their friends
+    /// that they are online.
+    pub(crate) fn set_account(&mut self, user_id: UserID, account: String) -> Result {
+        self.get_user_mut(user_id)?.account = Some(account.clone());
+        if self.accounts.contains_key(&account) {
+            return Ok(Response::empty());
         }
+        self.accounts.insert(account.clone(), user_id);
+        Ok(self.tell_friends(&account, Message::FriendOnline(user_id, account.clone())))
     }
     
-    fn list_rooms(&self) -> Response {
-        let rooms = self.rooms
-            .values()
-            .map(|room| (room.id, room.data.clone()))
+    /// Sends a message to each of an account's friends who are online. Only
+    /// mutual friends are told anything.
+    fn tell_friends(&self, account: &str, msg: Message) -> Response {
+        let online: Vec<UserID> = self.friends.mutual(account)
+            .filter_map(|friend| self.accounts.get(friend).copied())
             .collect();
-        Message::ListRooms(rooms).into()
+        Response::to_all(online).msg(msg)
     }
     
-    fn create_room(&mut self, user_id: UserID, data: String) -> Result {
-        let room_id = next_id(self.last_room_id, &self.rooms);
-        let room = self.get_user_mut(user_id)?
-            .try_create_room(room_id, data)?;
-        self.rooms.insert(room_id, room);
-        self.last_room_id = room_id;
-        Ok(Message::RoomCreated(room_id).into())
+    /// Stops a removed user's account from being online as them, passing it
+    /// to another of the account's users if there is one.
+    fn release_account(&mut self, user_id: UserID, account: Option<String>) -> Response {
+        let Some(account) = account else {
+            return Response::empty();
+        };
+        if self.accounts.get(&account) != Some(&user_id) {
+            return Response::empty();
+        }
+        let other = self.users.values()
+            .filter(|user| user.account.as_ref() == Some(&account))
+            .map(|user| user.id)
+            .min();
+        if let Some(other_id) = other {
+            self.accounts.insert(account, other_id);
+            Response::empty()
+        } else {
+            self.accounts.remove(&account);
+            self.tell_friends(&account, Message::FriendOffline(account.clone()))
+        }
     }
     
-    fn set_owner(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID) -> Result {
-        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
-        room.expect_owner(user_id)?;
-        
-        // build response before changing members, so that the right members get the message
-        let response: Vec<_> = room.members.iter()
-            .copied()
-            .map(|u_id| (u_id, Message::ChangedOwner(room_id, other_id)))
-            .collect();
-        
-        room.set_owner(other)?;
-        let user = self.get_user_mut(user_id).unwrap();
-        user.state = UserState::InRoom(room_id);
-        Ok(Response::sends_all(response))
+    /// Tells a user's friends that they have joined a room as its owner or
+    /// a member.
+    fn friend_joined(&self, user_id: UserID, room_id: RoomID) -> Response {
+        let Some(account) = self.users.get(&user_id).and_then(|user| user.account.as_ref()) else {
+            return Response::empty();
+        };
+        self.tell_friends(account, Message::FriendJoinedRoom(room_id, user_id, account.clone()))
     }
     
-    fn ask_join(&mut self, user_id: UserID, room_id: RoomID, msg: String) -> Result {
-        let (user, room) = self.get_user_room_mut(user_id, room_id)?;
-        user.try_join_room(room)?;
-        Ok(Response::sends(room.owner_id, Message::JoinRequested(room_id, user.id, msg)))
+    pub(crate) fn session_id(&self, user_id: UserID) -> Option<&str> {
+        self.users.get(&user_id)
+            .map(|user| user.session_id.as_str())
     }
     
-    fn accept_join(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID) -> Result {
-        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
-        room.expect_owner(user_id)?;
-        room.accept_join_request(other)?;
-        
-        Ok(Response::sends(other_id, Message::RoomJoined(room_id)))
+    pub(crate) fn resume_token(&self, user_id: UserID) -> Option<&str> {
+        self.users.get(&user_id)
+            .map(|user| user.resume_token.as_str())
     }
     
-    fn reject_join(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID, reason: String) -> Result {
-        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
-        room.expect_owner(user_id)?;
-        room.cancel_join_request(other)?;
-        Ok(Response::sends(other_id, Message::RoomRejected(room_id, reason)))
+    /// Marks a user's connection as dropped, without removing them yet, so
+    /// that they can resume their session.
+    pub(crate) fn disconnect_user(&mut self, user_id: UserID) -> Result {
+        let user = self.get_user_mut(user_id)?;
+        user.connected = false;
+        let room_ids: Vec<RoomID> = user.rooms.keys().copied().collect();
+        self.connection_notices(user_id, room_ids, Message::PlayerDisconnected)
     }
     
-    fn leave_room(&mut self, user_id: UserID, room_id: RoomID) -> Result {
+    /// Tells each of these rooms that a user's connection has dropped, come
+    /// back or timed out.
+    fn connection_notices(&self, user_id: UserID, room_ids: Vec<RoomID>, notice: fn(RoomID, UserID) -> Message) -> Result {
+        let mut response = Response::empty();
+        for room_id in room_ids {
+            let room = self.get_room(room_id)?;
+            response = response.and(connection_notice(room, user_id, notice));
+        }
+        Ok(response)
+    }
+    
+    pub(crate) fn remove_user(&mut self, user_id: UserID) -> Result {
+        let mut user = self.users.remove(&user_id)
+            .ok_or(Error::NoSuchUser)?;
+        if user.queued {
+            self.matchmaker.remove(user_id);
+        }
+        // the user's ID may be given to someone else later
+        for room in self.rooms.values_mut() {
+            room.invited.swap_remove(&user_id);
+            room.rejected.remove(&user_id);
+        }
+        
+        let mut response = self.release_account(user_id, user.account.take());
+        for (room_id, membership) in std::mem::take(&mut user.rooms) {
+            let r = match membership {
+                Membership::Owner => {
+                    self.migrate_owner(room_id, user.named())?
+                },
+                Membership::Member => {
+                    let room = self.get_room_mut(room_id)?;
+                    room.remove_user(user_id)?;
+                    let r = player_left(room, user.named());
+                    self.record(room_id, RoomEvent::Left(user_id));
+                    r
+                },
+                Membership::RequestedJoin |
+                Membership::Spectating => {
+                    let room = self.get_room_mut(room_id)?;
+                    room.join_requests.swap_remove(&user_id);
+                    room.spectators.swap_remove(&user_id);
+                    let r = Response::to(room.owner_id)
+                        .msg(Message::PlayerLeft(room_id, user.named()));
+                    self.record(room_id, RoomEvent::Left(user_id));
+                    r
+                },
+            };
+            response = response.and(r);
+        }
+        Ok(response)
+    }
+    
+    /// Removes a user who didn't resume their session in time, first telling
+    /// their rooms that they timed out.
+    pub(crate) fn time_out_user(&mut self, user_id: UserID) -> Result {
+        let user = self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?;
+        let room_ids: Vec<RoomID> = user.rooms.keys().copied().collect();
+        let response = self.connection_notices(user_id, room_ids, Message::PlayerTimedOut)?;
+        Ok(response.and(self.remove_user(user_id)?))
+    }
+    
+    fn hello(&mut self, user_id: UserID, version: String) -> Result {
+        self.version_policy.check(&version)?;
+        self.get_user_mut(user_id)?.client_version = Some(version);
+        Ok(Message::HelloOk.into())
+    }
+    
+    /// Checks that the user has said `HELLO` with an acceptable client
+    /// version, if the server requires it.
+    fn expect_version_ok(&self, user_id: UserID, request: &Request) -> Result<()> {
+        let exempt = matches!(request, Request::Hello(_) | Request::Compress(_) | Request::Resume(..) | Request::Ping(..) | Request::Pong(_) | Request::Stats | Request::Quit);
+        if exempt || !self.version_policy.is_enforced() {
+            return Ok(());
+        }
+        
+        let user = self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?;
+        if user.client_version.is_some() {
+            Ok(())
+        } else {
+            Err(self.version_policy.upgrade_required())
+        }
+    }
+    
+    fn list_rooms(&self, query: &RoomQuery) -> Response {
+        let mut rooms: Vec<&Room> = self.rooms
+            .values()
+            .filter(|room| room.has_tags(&query.tags))
+            .filter(|room| query.search.as_ref().is_none_or(|text| room.listed_name().contains(text.as_str())))
+            .collect();
+        // ties are broken by ID, so that the order doesn't change between listings
+        match query.sort {
+            Some(RoomOrder::Newest) => rooms.sort_by_key(|room| Reverse((room.created_at, room.id))),
+            Some(RoomOrder::FewestPlayers) => rooms.sort_by_key(|room| (room.members.len(), room.id)),
+            Some(RoomOrder::Name) => rooms.sort_by(|a, b| a.listed_name().cmp(b.listed_name()).then(a.id.cmp(&b.id))),
+            None => {},
+        }
+        let rooms = rooms.into_iter()
+            .map(|room| (room.id, room.data.clone(), room.name.as_deref().map(Into::into)))
+            .collect();
+        Message::ListRooms(rooms).into()
+    }
+    
+    fn stats(&self) -> Response {
+        let users = self.users.values()
+            .filter(|user| user.connected)
+            .count();
+        let uptime = self.clock.now()
+            .duration_since(self.started)
+            .unwrap_or_default()
+            .as_secs();
+        Message::Stats(users, self.rooms.len(), uptime, None).into()
+    }
+    
+    fn list_members(&self, user_id: UserID, room_id: RoomID) -> Result {
+        let room = self.get_room(room_id)?;
+        let members = room.members.iter()
+            .map(|&member_id| self.named(member_id))
+            .collect();
+        let mut response = Response::returns(Message::ListMembers(room_id, self.named(room.owner_id), members));
+        
+        if user_id == room.owner_id {
+            // only the owner gets to see pending join requests
+            let requests = Message::ListJoinRequests(room_id, room.join_requests.iter().copied().collect());
+            response = response.and_to(user_id).msg(requests);
+        } else if !room.members.contains(&user_id) && !room.spectators.contains(&user_id) {
+            return Err(Error::NotInThatRoom);
+        }
+        if !room.spectators.is_empty() {
+            let spectators = Message::ListSpectators(room_id, room.spectators.iter().copied().collect());
+            response = response.and_to(user_id).msg(spectators);
+        }
+        Ok(response)
+    }
+    
+    fn room_info(&self, room_id: RoomID) -> Result {
+        let room = self.get_room(room_id)?;
+        Ok(Message::RoomInfo(
+            room_id,
+            room.owner_id,
+            room.members.len(),
+            room.capacity,
+            room.join_policy,
+            room.data.clone(),
+            room.name.as_deref().map(Into::into),
+        ).into())
+    }
+    
+    /// Moves this connection to the session which the token belongs to. The
+    /// connection's own user is discarded, so it must not be doing anything
+    /// yet. Each token can only be used once, so that a captured handshake
+    /// can't be replayed.
+    fn resume(&mut self, user_id: UserID, token: &str) -> Result {
+        let replayed = self.users.values()
+            .find(|u| u.previous_resume_token.as_deref().is_some_and(|previous| ids::secrets_match(token, previous)))
+            .map(|u| u.id);
+        if let Some(old_id) = replayed {
+            return Ok(Response::to(old_id)
+                .msg(Message::ResumeReplayed)
+                .returning(Message::Error(Error::InvalidToken)));
+        }
+        
+        let old = self.users.values()
+            .find(|u| u.id != user_id && ids::secrets_match(token, &u.resume_token))
+            .ok_or(Error::InvalidToken)?;
+        let old_id = old.id;
+        self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?
+            .expect_nowhere()?;
+        
+        let account = self.users.remove(&user_id)
+            .and_then(|user| user.account);
+        let released = self.release_account(user_id, account);
+        let user = self.get_user_mut(old_id)?;
+        // tokens are single-use, in case the old one was intercepted
+        let new_token = ids::resume_token(&user.session_id);
+        let old_token = std::mem::replace(&mut user.resume_token, new_token);
+        user.previous_resume_token = Some(old_token);
+        let resumed = Message::Resumed(old_id, user.resume_token.clone());
+        let was_connected = std::mem::replace(&mut user.connected, true);
+        
+        let room_ids: Vec<RoomID> = if was_connected {
+            Vec::new()
+        } else {
+            user.rooms.keys().copied().collect()
+        };
+        let response = self.connection_notices(old_id, room_ids, Message::PlayerReconnected)?;
+        Ok(response.and(released).returning(resumed))
+    }
+    
+    /// Measures the user's latency from when they were sent the ping they
+    /// are answering. An answer to any other ping is ignored.
+    fn pong(&mut self, user_id: UserID, sequence_number: u32) -> Result {
+        let now = self.clock.now();
+        let user = self.get_user_mut(user_id)?;
+        if let Some((_, sent)) = user.ping.filter(|&(expected, _)| expected == sequence_number) {
+            user.ping = None;
+            let millis = now.duration_since(sent).unwrap_or_default().as_millis();
+            user.record_latency(millis.try_into().unwrap_or(u32::MAX));
+        }
+        Ok(Response::empty())
+    }
+    
+    fn room_pings(&self, user_id: UserID, room_id: RoomID) -> Result {
+        let room = self.get_room(room_id)?;
+        room.expect_owner(user_id)?;
+        let pings = room.members.iter()
+            .map(|&u_id| (u_id, self.users.get(&u_id).and_then(|u| u.latency_ms)))
+            .collect();
+        Ok(Message::RoomPings(room_id, pings).into())
+    }
+    
+    fn create_room(&mut self, user_id: UserID, data: String, tags: Vec<String>) -> Result {
+        self.create_named_room(user_id, None, data, tags)
+    }
+    
+    fn create_named_room(&mut self, user_id: UserID, name: Option<String>, data: String, tags: Vec<String>) -> Result {
+        if self.draining {
+            return Err(Error::ServerDraining);
+        }
+        
+        // check first, so that a failed request doesn't use up an ID
+        self.get_user_mut(user_id)?.expect_room_slot()?;
+        if let Some(name) = &name {
+            models::expect_valid_room_name(name)?;
+            if self.room_named(name).is_ok() {
+                return Err(Error::RoomNameTaken);
+            }
+        }
+        models::expect_valid_tags(&tags)?;
+        self.expect_valid_room_data(user_id, &data)?;
+        let room_id = next_id(self.room_ids.as_mut(), &self.rooms)?;
+        let mut room = self.get_user_mut(user_id)?
+            .try_create_room(room_id, data)?;
+        room.capacity = self.max_room_members;
+        room.max_join_requests = self.max_join_requests;
+        room.name = name;
+        room.tags = tags;
+        room.created_at = Some(self.clock.now());
+        self.rooms.insert(room_id, room);
+        self.record(room_id, RoomEvent::Created(user_id));
+        Ok(Response::returns(Message::RoomCreated(room_id))
+            .and(self.friend_joined(user_id, room_id)))
+    }
+    
+    /// Finds the room with this name, ignoring case.
+    fn room_named(&self, name: &str) -> Result<RoomID> {
+        let name = name.to_lowercase();
+        self.rooms.values()
+            .find(|room| room.name.as_ref().is_some_and(|n| n.to_lowercase() == name))
+            .map(|room| room.id)
+            .ok_or(Error::NoSuchRoom)
+    }
+    
+    /// Asks to join a room by name. The user isn't told the room's ID until
+    /// they join, so it is sent back along with the request.
+    fn join_named_room(&mut self, user_id: UserID, name: &str, msg: String, password: Option<&str>) -> Result {
+        let room_id = self.room_named(name)?;
+        self.get_room(room_id)?.expect_password(user_id, password)?;
+        let response = self.ask_join(user_id, room_id, msg)?;
+        Ok(if response.returns.is_none() {
+            response.returning(Message::JoinRequestSent(room_id))
+        } else {
+            response
+        })
+    }
+    
+    /// Checks a new game's data against the server's limit and pattern,
+    /// and then asks the embedding application.
+    fn expect_valid_room_data(&self, user_id: UserID, data: &str) -> Result<()> {
+        if data.len() > self.max_room_data_length {
+            return Err(Error::RoomDataTooLong);
+        }
+        let matches = self.room_data_pattern.as_ref()
+            .is_none_or(|pattern| pattern.is_match(data));
+        if matches && self.hooks.accept_room_data(user_id, data) {
+            Ok(())
+        } else {
+            Err(Error::InvalidRoomData)
+        }
+    }
+    
+    fn queue(&mut self, user_id: UserID, criteria: String) -> Result {
+        if self.draining {
+            return Err(Error::ServerDraining);
+        }
+        let user = self.get_user_mut(user_id)?;
+        if user.queued {
+            return Err(Error::AlreadyQueued);
+        }
+        user.expect_room_slot()?;
+        user.queued = true;
+        
+        match self.matchmaker.enqueue(user_id, &criteria) {
+            Enqueued::Waiting(others) => Ok(Message::Queued(others).into()),
+            Enqueued::Matched(group) => self.create_matched_room(group, criteria),
+        }
+    }
+    
+    fn unqueue(&mut self, user_id: UserID) -> Result {
+        let user = self.get_user_mut(user_id)?;
+        if !user.queued {
+            return Err(Error::NotQueued);
+        }
+        user.queued = false;
+        self.matchmaker.remove(user_id);
+        Ok(Response::empty())
+    }
+    
+    /// Creates a game for a group found by the matchmaker. The user who has
+    /// waited longest becomes the owner, and the rest become members.
+    fn create_matched_room(&mut self, group: Vec<UserID>, criteria: String) -> Result<Response> {
+        let room_id = match next_id(self.room_ids.as_mut(), &self.rooms) {
+            Ok(room_id) => room_id,
+            Err(e) => {
+                for &u_id in &group {
+                    if let Ok(user) = self.get_user_mut(u_id) { user.queued = false; }
+                }
+                return Err(e);
+            },
+        };
+        let owner_id = group[0];
+        let mut room = Room::new(room_id, owner_id, criteria);
+        room.capacity = self.max_room_members;
+        room.max_join_requests = self.max_join_requests;
+        
+        for &u_id in &group {
+            let Ok(user) = self.get_user_mut(u_id) else { continue; };
+            user.queued = false;
+            if u_id == owner_id {
+                user.rooms.insert(room_id, Membership::Owner);
+            } else {
+                user.rooms.insert(room_id, Membership::Member);
+                room.members.insert(u_id);
+            }
+        }
+        self.rooms.insert(room_id, room);
+        
+        self.record(room_id, RoomEvent::Created(owner_id));
+        for &u_id in &group[1..] {
+            self.record(room_id, RoomEvent::Joined(u_id));
+        }
+        
+        let notices = group.iter()
+            .fold(Response::empty(), |r, &u_id| r.and(self.friend_joined(u_id, room_id)));
+        Ok(Response::to_all(group)
+            .msg(Message::MatchFound(room_id, owner_id))
+            .and(notices))
+    }
+    
+    fn set_owner(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID) -> Result {
+        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
+        room.expect_owner(user_id)?;
+        
+        // build response before changing members, so that the right members get the message
+        let response = Response::to_all(room.audience())
+            .msg(Message::ChangedOwner(room_id, other_id));
+        
+        room.set_owner(other)?;
+        let user = self.get_user_mut(user_id).unwrap();
+        user.rooms.insert(room_id, Membership::Member);
+        self.record(room_id, RoomEvent::OwnerChanged(other_id));
+        Ok(response)
+    }
+    
+    fn set_join_policy(&mut self, user_id: UserID, room_id: RoomID, policy: JoinPolicy) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        room.join_policy = policy;
+        Ok(Response::empty())
+    }
+    
+    fn set_presence(&mut self, user_id: UserID, room_id: RoomID, presence: Presence) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        room.presence = presence;
+        Ok(Response::empty())
+    }
+    
+    fn set_schema(&mut self, user_id: UserID, room_id: RoomID, pattern: &str) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        room.set_schema(pattern)?;
+        Ok(Response::empty())
+    }
+    
+    fn set_name(&mut self, user_id: UserID, name: String) -> Result<()> {
+        self.get_user_mut(user_id)?.set_name(name)
+    }
+    
+    fn set_password(&mut self, user_id: UserID, room_id: RoomID, password: String) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        room.set_password(password);
+        Ok(Response::empty())
+    }
+    
+    fn set_rejoin_cooldown(&mut self, user_id: UserID, room_id: RoomID, secs: u64) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        room.rejoin_cooldown = Some(Duration::from_secs(secs));
+        Ok(Response::empty())
+    }
+    
+    fn ask_join(&mut self, user_id: UserID, room_id: RoomID, msg: String) -> Result {
+        let now = self.clock.now();
+        let cooldown = self.rejoin_cooldown;
+        let (user, room) = self.get_user_room_mut(user_id, room_id)?;
+        room.expect_cooled_down(user_id, now, cooldown)?;
+        let response = join(user, room, msg)?;
+        let event = join_event(user, room_id);
+        let joined = matches!(event, RoomEvent::Joined(_));
+        self.record(room_id, event);
+        Ok(if joined { response.and(self.friend_joined(user_id, room_id)) } else { response })
+    }
+    
+    fn spectate(&mut self, user_id: UserID, room_id: RoomID) -> Result {
+        let (user, room) = self.get_user_room_mut(user_id, room_id)?;
+        user.try_spectate_room(room)?;
+        let response = Response::to(room.owner_id)
+            .msg(Message::SpectatorJoined(room_id, user_id))
+            .returning(Message::RoomSpectating(room_id));
+        let response = with_history(room, user_id, response);
+        self.record(room_id, RoomEvent::Spectating(user_id));
+        Ok(response)
+    }
+    
+    fn join_any(&mut self, user_id: UserID, filter: &str, msg: String) -> Result {
+        let user = self.users.get_mut(&user_id)
+            .ok_or(Error::NoSuchUser)?;
+        user.expect_room_slot()?;
+        let now = self.clock.now();
+        
+        // prefer the oldest matching game, so that it fills up first
+        let room = self.rooms.values_mut()
+            .filter(|room| !user.rooms.contains_key(&room.id))
+            .filter(|room| room.expect_cooled_down(user_id, now, self.rejoin_cooldown).is_ok())
+            .filter(|room| !room.is_full() && !room.has_too_many_join_requests())
+            .filter(|room| room.password.is_none() && room.data.contains(filter))
+            .min_by_key(|room| room.id)
+            .ok_or(Error::NoOpenRooms)?;
+        
+        let mut response = join(user, room, msg)?;
+        let room_id = room.id;
+        if response.returns.is_none() {
+            response = response.returning(Message::JoinRequestSent(room_id));
+        }
+        let event = join_event(user, room_id);
+        let joined = matches!(event, RoomEvent::Joined(_));
+        self.record(room_id, event);
+        Ok(if joined { response.and(self.friend_joined(user_id, room_id)) } else { response })
+    }
+    
+    fn accept_join(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID) -> Result {
+        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
+        room.expect_owner(user_id)?;
+        room.accept_join_request(other)?;
+        
+        let response = Response::to(other_id)
+            .msg(Message::RoomJoined(room_id));
+        let response = with_history(room, other_id, response);
+        let response = with_capacity_warning(room, response);
+        self.record(room_id, RoomEvent::Joined(other_id));
+        Ok(response.and(self.friend_joined(other_id, room_id)))
+    }
+    
+    /// Lets another user join the room without asking, or giving the
+    /// password, and tells them so.
+    fn invite(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID) -> Result {
+        let owner = self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?
+            .named();
+        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
+        room.expect_owner(user_id)?;
+        if other.membership(room_id).is_some() {
+            return Err(Error::AlreadyInARoom);
+        }
+        room.invited.insert(other_id);
+        Ok(Response::to(other_id).msg(Message::Invited(room_id, owner)))
+    }
+    
+    /// The account the user signed in with, which their friends know them
+    /// by.
+    fn account(&self, user_id: UserID) -> Result<String> {
+        self.users.get(&user_id)
+            .ok_or(Error::NoSuchUser)?
+            .account
+            .clone()
+            .ok_or(Error::NoAccount)
+    }
+    
+    /// Lists a friend, and if they have already listed this user back and
+    /// are online, tells each that the other is online.
+    fn add_friend(&mut self, user_id: UserID, friend: String) -> Result {
+        let account = self.account(user_id)?;
+        let added = self.friends.add(&account, &friend)?;
+        let response = Response::returns(Message::FriendAdded(friend.clone()));
+        let friend_id = self.accounts.get(&friend).copied();
+        match friend_id {
+            Some(friend_id) if added && self.friends.are_mutual(&account, &friend) => {
+                let own_id = self.accounts.get(&account).copied().unwrap_or(user_id);
+                Ok(response.and_to(friend_id)
+                    .msg(Message::FriendOnline(own_id, account))
+                    .and_to(user_id)
+                    .msg(Message::FriendOnline(friend_id, friend)))
+            },
+            _ => Ok(response),
+        }
+    }
+    
+    fn remove_friend(&mut self, user_id: UserID, friend: String) -> Result {
+        let account = self.account(user_id)?;
+        if !self.friends.remove(&account, &friend) {
+            return Err(Error::NoSuchUser);
+        }
+        Ok(Message::FriendRemoved(friend).into())
+    }
+    
+    fn list_friends(&self, user_id: UserID) -> Result {
+        let account = self.account(user_id)?;
+        let friends = self.friends.list(&account)
+            .map(|friend| {
+                let status = if !self.friends.are_mutual(&account, friend) {
+                    FriendStatus::Pending
+                } else if let Some(&friend_id) = self.accounts.get(friend) {
+                    FriendStatus::Online(friend_id)
+                } else {
+                    FriendStatus::Offline
+                };
+                (friend.to_string(), status)
+            })
+            .collect();
+        Ok(Message::Friends(friends).into())
+    }
+    
+    fn reject_join(&mut self, user_id: UserID, room_id: RoomID, other_id: UserID, reason: String) -> Result {
+        let now = self.clock.now();
+        let cooldown = self.rejoin_cooldown;
+        let (other, room) = self.get_user_room_mut(other_id, room_id)?;
+        room.expect_owner(user_id)?;
+        room.cancel_join_request(other)?;
+        room.reject(other_id, now, cooldown);
+        self.record(room_id, RoomEvent::Rejected(other_id));
+        Ok(Response::to(other_id).msg(Message::RoomRejected(room_id, reason)))
+    }
+    
+    fn leave_room(&mut self, user_id: UserID, room_id: RoomID) -> Result {
         let (user, room) = self.get_user_room_mut(user_id, room_id)?;
         
-        if room.owner_id == user.id {
-            self.close_room(room_id)
+        let response = if room.owner_id == user.id {
+            return self.close_room(room_id);
+        } else if user.membership(room_id) == Some(Membership::Member) {
+            user.leave_room(room)?;
+            player_left(room, user.named())
         } else {
+            // join requests and spectators are only of interest to the owner
             user.leave_room(room)?;
-            Ok(Response::sends(room.owner_id, Message::PlayerLeft(room_id, user.id)))
+            Response::to(room.owner_id).msg(Message::PlayerLeft(room_id, user.named()))
+        };
+        self.record(room_id, RoomEvent::Left(user_id));
+        Ok(response)
+    }
+    
+    /// Counts a player's vote to remove another member, and removes them once
+    /// enough of the other players agree. Like a rejected user, they must
+    /// then wait before asking to join again.
+    fn vote_kick(&mut self, user_id: UserID, room_id: RoomID, target_id: UserID) -> Result {
+        let now = self.clock.now();
+        let cooldown = self.rejoin_cooldown;
+        let threshold = self.vote_kick_threshold;
+        let (target, room) = self.get_user_room_mut(target_id, room_id)?;
+        if room.spectators.contains(&user_id) {
+            return Err(Error::IsSpectator);
+        } else if user_id != room.owner_id && !room.members.contains(&user_id) {
+            return Err(Error::NotInThatRoom);
+        } else if user_id == target_id || !room.members.contains(&target_id) {
+            return Err(Error::NoSuchUser);
+        }
+        
+        // everyone but the target can vote, including the owner
+        let votes = room.vote_kick(user_id, target_id);
+        let needed = (room.members.len() as f64 * threshold).floor() as usize + 1;
+        if votes < needed {
+            return Ok(Response::empty().broadcast(room, Message::KickVote(room_id, target_id, votes, needed)));
+        }
+        
+        target.leave_room(room)?;
+        room.reject(target_id, now, cooldown);
+        let response = Response::to(target_id)
+            .msg(Message::VotedOut(room_id, target_id))
+            .broadcast(room, Message::VotedOut(room_id, target_id))
+            .and(leave_turns(room, target_id));
+        self.record(room_id, RoomEvent::Kicked(target_id));
+        Ok(response)
+    }
+    
+    /// Ends the game for everyone in it, leaving the owner free to create
+    /// another.
+    fn close_own_room(&mut self, user_id: UserID, room_id: RoomID) -> Result {
+        self.get_room(room_id)?.expect_owner(user_id)?;
+        Ok(self.close_room(room_id)?.returning(Message::RoomClosed(room_id)))
+    }
+    
+    fn admin_login(&mut self, user_id: UserID, password: &str) -> Result {
+        let now = self.clock.now();
+        if !self.admin_failures.permits(&(), now) {
+            return Err(Error::RateLimited);
+        }
+        let correct = self.admin_password.as_deref()
+            .is_some_and(|secret| ids::secrets_match(password, secret));
+        self.admin_failures.record((), correct, now);
+        if !correct {
+            return Err(Error::IncorrectPassword);
+        }
+        self.get_user_mut(user_id)?.is_admin = true;
+        Ok(Message::AdminOk.into())
+    }
+    
+    fn advance_clock(&mut self, user_id: UserID, secs: u64) -> Result {
+        self.expect_admin(user_id)?;
+        if !self.clock.advance(Duration::from_secs(secs)) {
+            return Err(Error::ClockNotSimulated);
+        }
+        let now = self.clock.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Message::Clock(now).into())
+    }
+    
+    /// Sends a notice to every connected user, or only those in games, and
+    /// tells the administrator how many it was sent to.
+    fn announce(&self, user_id: UserID, audience: Audience, text: Arc<str>) -> Result {
+        self.expect_admin(user_id)?;
+        let recipients: Vec<UserID> = self.users()
+            .into_iter()
+            .filter(|user| user.connected)
+            .filter(|user| audience == Audience::Everyone || user.rooms.values().any(|&m| m != Membership::RequestedJoin))
+            .map(|user| user.id)
+            .collect();
+        let n = recipients.len();
+        Ok(Response::to_all(recipients)
+            .msg(Message::Announcement(text))
+            .returning(Message::Announced(n)))
+    }
+    
+    fn get_timeline(&self, user_id: UserID, room_id: RoomID) -> Result {
+        self.expect_admin(user_id)?;
+        let timeline = self.room_store.timeline(room_id)
+            .ok_or(Error::NoSuchRoom)?;
+        Ok(Message::Timeline(room_id, timeline).into())
+    }
+    
+    /// Relays a payload from the owner to the audience, or from a member to
+    /// the owner. The owner's payloads are kept to replay to later joiners.
+    fn send(&mut self, from_user_id: UserID, room_id: RoomID, payload: Arc<str>) -> Result {
+        let room = self.get_room(room_id)?;
+        let response = if let Some(turns) = &room.turns {
+            self.send_on_turn(room, turns, from_user_id, payload.clone())?
+        } else {
+            self.send_freely(room, from_user_id, payload.clone())?
+        };
+        
+        let room = self.get_room_mut(room_id)?;
+        if from_user_id == room.owner_id {
+            room.remember(payload);
+        }
+        Ok(response)
+    }
+    
+    /// Relays a payload in a game which isn't turn-based.
+    fn send_freely(&self, room: &Room, from_user_id: UserID, payload: Arc<str>) -> Result {
+        let room_id = room.id;
+        Ok(if from_user_id == room.owner_id {
+            self.hooks.message_relayed(room_id, from_user_id, &payload);
+            Response::to_all(room.audience())
+                .msg(Message::ReceivedBroadcast(room_id, payload))
+        } else if room.spectators.contains(&from_user_id) {
+            return Err(Error::IsSpectator);
+        } else {
+            room.expect_valid_payload(&payload)?;
+            self.hooks.message_relayed(room_id, from_user_id, &payload);
+            Response::to(room.owner_id)
+                .msg(Message::ReceivedFrom(room_id, from_user_id, payload))
+        })
+    }
+    
+    /// Like `send`, but only the player whose turn it is may send, and the
+    /// payload is relayed with the turn it was sent in.
+    fn send_on_turn(&self, room: &Room, turns: &Turns, from_user_id: UserID, payload: Arc<str>) -> Result {
+        if room.spectators.contains(&from_user_id) {
+            return Err(Error::IsSpectator);
+        }
+        room.expect_turn(from_user_id)?;
+        let recipients = if from_user_id == room.owner_id {
+            Response::to_all(room.audience())
+        } else {
+            room.expect_valid_payload(&payload)?;
+            Response::to(room.owner_id)
+        };
+        self.hooks.message_relayed(room.id, from_user_id, &payload);
+        Ok(recipients.msg(Message::ReceivedOnTurn(room.id, turns.turn(), from_user_id, payload)))
+    }
+    
+    /// Sets the order in which players take turns, starting with the first
+    /// player's turn, or stops enforcing turns if the order is empty.
+    fn set_turn_order(&mut self, user_id: UserID, room_id: RoomID, order: Vec<UserID>) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        room.expect_owner(user_id)?;
+        let is_player = |u_id: &UserID| *u_id == room.owner_id || room.members.contains(u_id);
+        let is_repeated = order.iter().enumerate().any(|(i, u_id)| order[..i].contains(u_id));
+        if is_repeated || !order.iter().all(is_player) {
+            return Err(Error::InvalidTurnOrder);
+        }
+        
+        let msg = if order.is_empty() {
+            room.turns = None;
+            Message::NoTurnOrder(room_id)
+        } else {
+            let turns = Turns::new(order);
+            let msg = Message::Turn(room_id, turns.turn(), turns.current());
+            room.turns = Some(turns);
+            msg
+        };
+        Ok(Response::empty().broadcast(room, msg))
+    }
+    
+    /// Ends the current player's turn. The owner can end anyone's turn, e.g.
+    /// if they are taking too long.
+    fn end_turn(&mut self, user_id: UserID, room_id: RoomID) -> Result {
+        let room = self.get_room_mut(room_id)?;
+        let owner_id = room.owner_id;
+        let turns = room.turns.as_mut()
+            .ok_or(Error::NotTurnBased)?;
+        if user_id != owner_id && user_id != turns.current() {
+            return Err(Error::NotYourTurn);
         }
+        turns.advance();
+        let msg = Message::Turn(room_id, turns.turn(), turns.current());
+        Ok(Response::empty().broadcast(room, msg))
     }
     
-    fn send(&self, from_user_id: UserID, room_id: RoomID, payload: String) -> Result {
+    /// Like `send`, but the payload is binary data in base64. A game's schema
+    /// only describes text payloads, so members of a game with one can't
+    /// send binary payloads at all.
+    fn send_binary(&self, from_user_id: UserID, room_id: RoomID, payload: Arc<str>) -> Result {
         let room = self.get_room(room_id)?;
+        if !room.spectators.contains(&from_user_id) {
+            room.expect_turn(from_user_id)?;
+        }
         
         Ok(if from_user_id == room.owner_id {
-            let payload: Arc<str> = Arc::from(payload);
-            room.members.iter()
-                .copied()
-                .map(|u_id| (u_id, Message::ReceivedBroadcast(room_id, payload.clone())))
-                .collect()
+            self.hooks.message_relayed(room_id, from_user_id, &payload);
+            Response::to_all(room.audience())
+                .msg(Message::ReceivedBinaryBroadcast(room_id, payload))
+        } else if room.spectators.contains(&from_user_id) {
+            return Err(Error::IsSpectator);
+        } else if room.schema.is_some() {
+            return Err(Error::InvalidPayload);
         } else {
-            let message = Message::ReceivedFrom(room_id, from_user_id, payload);
-            Response::sends(room.owner_id, message)
+            self.hooks.message_relayed(room_id, from_user_id, &payload);
+            Response::to(room.owner_id)
+                .msg(Message::ReceivedBinaryFrom(room_id, from_user_id, payload))
         })
     }
     
-    fn send_to(&self, from_user_id: UserID, room_id: RoomID, to_user_id: UserID, payload: String) -> Result {
+    /// Hands out a token for the user to send from the UDP endpoint they want
+    /// datagrams relayed to.
+    fn register_udp(&self) -> Result {
+        let port = self.udp_port.ok_or(Error::UdpUnavailable)?;
+        Ok(Message::UdpToken(port, ids::secret_token()).into())
+    }
+    
+    /// Who a datagram from this user is relayed to: the room's audience if
+    /// they own it, or else its owner, as with `send`.
+    pub(crate) fn datagram_recipients(&self, from_user_id: UserID, room_id: RoomID) -> Result<Vec<UserID>> {
+        let room = self.get_room(room_id)?;
+        if from_user_id == room.owner_id {
+            Ok(room.audience().collect())
+        } else if room.spectators.contains(&from_user_id) {
+            Err(Error::IsSpectator)
+        } else if room.members.contains(&from_user_id) {
+            Ok(vec![room.owner_id])
+        } else {
+            Err(Error::NotInThatRoom)
+        }
+    }
+    
+    fn chat(&self, from_user_id: UserID, room_id: RoomID, text: Arc<str>) -> Result {
+        let room = self.get_room(room_id)?;
+        if room.spectators.contains(&from_user_id) {
+            return Err(Error::IsSpectator);
+        } else if from_user_id != room.owner_id && !room.members.contains(&from_user_id) {
+            return Err(Error::NotInThatRoom);
+        }
+        
+        Ok(Response::to_all(room.everyone())
+            .except(from_user_id)
+            .msg(Message::Chat(room_id, from_user_id, text)))
+    }
+    
+    fn whisper(&self, from_user_id: UserID, room_id: RoomID, to_user_id: UserID, text: Arc<str>) -> Result {
+        let room = self.get_room(room_id)?;
+        let is_player = |user_id| user_id == room.owner_id || room.members.contains(&user_id);
+        if !is_player(from_user_id) {
+            return Err(Error::NotInThatRoom);
+        } else if to_user_id == from_user_id || !is_player(to_user_id) {
+            return Err(Error::NoSuchUser);
+        }
+        
+        Ok(Response::to(to_user_id).msg(Message::Whisper(room_id, from_user_id, text)))
+    }
+    
+    /// Relays a WebRTC signaling message between two players in a room;
+    /// spectators can't connect to players directly.
+    fn signal(&self, from_user_id: UserID, room_id: RoomID, to_user_id: UserID, signal: Signal, data: Arc<str>) -> Result {
+        let room = self.get_room(room_id)?;
+        let is_player = |user_id| user_id == room.owner_id || room.members.contains(&user_id);
+        if room.spectators.contains(&from_user_id) {
+            return Err(Error::IsSpectator);
+        } else if !is_player(from_user_id) {
+            return Err(Error::NotInThatRoom);
+        } else if to_user_id == from_user_id || !is_player(to_user_id) {
+            return Err(Error::NoSuchUser);
+        }
+        
+        Ok(Response::to(to_user_id).msg(Message::Signal(signal, room_id, from_user_id, data)))
+    }
+    
+    fn send_to(&self, from_user_id: UserID, room_id: RoomID, to_user_id: UserID, payload: Arc<str>) -> Result {
         let room = self.get_room(room_id)?;
         room.expect_owner(from_user_id)?;
         room.expect_member(to_user_id)?;
         
-        let message = Message::ReceivedIndividual(room_id, payload);
-        Ok(Response::sends(to_user_id, message))
+        self.hooks.message_relayed(room_id, from_user_id, &payload);
+        Ok(Response::to(to_user_id).msg(Message::ReceivedIndividual(room_id, payload)))
     }
     
-    fn echo_from(&self, user_id: UserID, room_id: RoomID, from_user_id: UserID, payload: String) -> Result {
+    fn echo_from(&self, user_id: UserID, room_id: RoomID, from_user_id: UserID, payload: Arc<str>) -> Result {
         let room = self.get_room(room_id)?;
         room.expect_owner(user_id)?;
         
         // allow echoing messages from a user who has already left
         //room.expect_member(from_user_id)?;
         
-        let payload: Arc<str> = Arc::from(payload);
-        Ok(room.members.iter()
-            .copied()
-            .filter(|&u_id| u_id != from_user_id)
-            .map(|u_id| (u_id, Message::ReceivedBroadcast(room_id, payload.clone())))
-            .collect())
+        self.hooks.message_relayed(room_id, user_id, &payload);
+        Ok(Response::to_all(room.audience())
+            .except(from_user_id)
+            .msg(Message::ReceivedBroadcast(room_id, payload)))
     }
     
     pub(crate) fn handle_request(&mut self, user_id: UserID, request: Request) -> Response {
-        match request {
-            Request::ListRooms => {
-                self.list_rooms()
+        if let Err(e) = self.expect_version_ok(user_id, &request) {
+            return e.into();
+        }
+        let payload_length = request.payload().map_or(0, str::len);
+        if payload_length > self.max_payload_length {
+            return Error::PayloadTooLarge.into();
+        }
+        
+        // anything someone does in their own room shows it isn't abandoned
+        if let Some(room_id) = request.room_id() {
+            let now = self.clock.now();
+            let in_room = self.users.get(&user_id)
+                .is_some_and(|user| user.rooms.contains_key(&room_id));
+            if let Some(room) = self.rooms.get_mut(&room_id).filter(|_| in_room) {
+                room.last_active = Some(now);
+            }
+        }
+        
+        let response = match request {
+            Request::Hello(version) => {
+                self.hello(user_id, version).into()
             },
-            Request::Ping(sequence_number) => {
-                Response::returns(Message::Pong(sequence_number))
+            Request::Compress(algorithms) => {
+                Message::Compression(Compression::negotiate(&algorithms)).into()
             },
-            Request::CreateRoom(data) => {
-                self.create_room(user_id, data).into()
+            Request::ListRooms(query) => {
+                self.list_rooms(&query)
             },
-            Request::SetOwner(room_id, other_id) => {
-                self.set_owner(user_id, room_id, other_id).into()
+            Request::Stats => {
+                self.stats()
             },
-            Request::AskJoinRoom(room_id, msg) => {
-                self.ask_join(user_id, room_id, msg).into()
+            Request::ListMembers(room_id) => {
+                self.list_members(user_id, room_id).into()
             },
-            Request::AcceptJoinRoom(room_id, other_id) => {
-                self.accept_join(user_id, room_id, other_id).into()
+            Request::GetRoomInfo(room_id) => {
+                self.room_info(room_id).into()
             },
-            Request::RejectJoinRoom(room_id, other_id, reason) => {
-                self.reject_join(user_id, room_id, other_id, reason).into()
+            // the client's own measurement is ignored, since it could say anything
+            Request::Ping(sequence_number, _) => {
+                Message::Pong(sequence_number).into()
             },
-            Request::LeaveRoom(room_id) => {
-                self.leave_room(user_id, room_id).into()
+            Request::Pong(sequence_number) => {
+                self.pong(user_id, sequence_number).into()
             },
-            Request::Send(room_id, payload) => {
-                self.send(user_id, room_id, payload).into()
+            Request::Resume(token) => {
+                self.resume(user_id, &token).into()
             },
-            Request::SendTo(room_id, other_id, payload) => {
-                self.send_to(user_id, room_id, other_id, payload).into()
+            Request::RoomPings(room_id) => {
+                self.room_pings(user_id, room_id).into()
+            },
+            Request::CreateRoom(data, tags) => {
+                self.create_room(user_id, data, tags).into()
+            },
+            Request::CreateNamedRoom(name, data, tags) => {
+                self.create_named_room(user_id, Some(name), data, tags).into()
+            },
+            Request::SetOwner(room_id, other_id) => {
+                self.set_owner(user_id, room_id, other_id).into()
+            },
+            Request::SetJoinPolicy(room_id, policy) => {
+                self.set_join_policy(user_id, room_id, policy).into()
+            },
+            Request::SetPresence(room_id, presence) => {
+                self.set_presence(user_id, room_id, presence).into()
+            },
+            Request::SetSchema(room_id, pattern) => {
+                self.set_schema(user_id, room_id, &pattern).into()
+            },
+            Request::SetName(name) => {
+                self.set_name(user_id, name).into()
+            },
+            Request::SetPassword(room_id, password) => {
+                self.set_password(user_id, room_id, password).into()
+            },
+            Request::SetRejoinCooldown(room_id, secs) => {
+                self.set_rejoin_cooldown(user_id, room_id, secs).into()
+            },
+            Request::AskJoinRoom(room_id, msg, password) => {
+                self.get_room(room_id)
+                    .and_then(|room| room.expect_password(user_id, password.as_deref()))
+                    .and_then(|()| self.ask_join(user_id, room_id, msg))
+                    .into()
+            },
+            Request::Spectate(room_id, password) => {
+                self.get_room(room_id)
+                    .and_then(|room| room.expect_password(user_id, password.as_deref()))
+                    .and_then(|()| self.spectate(user_id, room_id))
+                    .into()
+            },
+            Request::JoinAnyRoom(filter, msg) => {
+                self.join_any(user_id, &filter, msg).into()
+            },
+            Request::JoinNamedRoom(name, msg, password) => {
+                self.join_named_room(user_id, &name, msg, password.as_deref()).into()
+            },
+            Request::AcceptJoinRoom(room_id, other_id) => {
+                self.accept_join(user_id, room_id, other_id).into()
+            },
+            Request::Invite(room_id, other_id) => {
+                self.invite(user_id, room_id, other_id).into()
+            },
+            Request::RejectJoinRoom(room_id, other_id, reason) => {
+                self.reject_join(user_id, room_id, other_id, reason).into()
+            },
+            Request::Queue(criteria) => {
+                self.queue(user_id, criteria).into()
+            },
+            Request::Unqueue => {
+                self.unqueue(user_id).into()
+            },
+            Request::LeaveRoom(room_id) => {
+                self.leave_room(user_id, room_id).into()
+            },
+            Request::CloseRoom(room_id) => {
+                self.close_own_room(user_id, room_id).into()
+            },
+            Request::VoteKick(room_id, other_id) => {
+                self.vote_kick(user_id, room_id, other_id).into()
+            },
+            Request::Send(room_id, payload) => {
+                self.send(user_id, room_id, payload).into()
+            },
+            Request::SendBinary(room_id, payload) => {
+                self.send_binary(user_id, room_id, payload).into()
+            },
+            Request::Chat(room_id, text) => {
+                self.chat(user_id, room_id, text).into()
+            },
+            Request::Whisper(room_id, other_id, text) => {
+                self.whisper(user_id, room_id, other_id, text).into()
+            },
+            Request::Signal(signal, room_id, other_id, data) => {
+                self.signal(user_id, room_id, other_id, signal, data).into()
+            },
+            Request::SetTurnOrder(room_id, order) => {
+                self.set_turn_order(user_id, room_id, order).into()
+            },
+            Request::EndTurn(room_id) => {
+                self.end_turn(user_id, room_id).into()
+            },
+            Request::SendTo(room_id, other_id, payload) => {
+                self.send_to(user_id, room_id, other_id, payload).into()
             },
             Request::EchoFrom(room_id, other_id, payload) => {
                 self.echo_from(user_id, room_id, other_id, payload).into()
             },
+            Request::AdminLogin(password) => {
+                self.admin_login(user_id, &password).into()
+            },
+            Request::ReloadConfig => {
+                self.reload_config(user_id).into()
+            },
+            Request::AdvanceClock(secs) => {
+                self.advance_clock(user_id, secs).into()
+            },
+            Request::GetTimeline(room_id) => {
+                self.get_timeline(user_id, room_id).into()
+            },
+            Request::Announce(audience, text) => {
+                self.announce(user_id, audience, text).into()
+            },
+            Request::RegisterUdp => {
+                self.register_udp().into()
+            },
+            Request::EnableAcks => {
+                Message::AcksEnabled.into()
+            },
+            // the dispatcher keeps track of subscribers, and sends them the
+            // changes to the listing
+            Request::SubscribeRooms => {
+                Message::RoomsSubscribed.into()
+            },
+            Request::UnsubscribeRooms => {
+                Message::RoomsUnsubscribed.into()
+            },
+            Request::AddFriend(friend) => {
+                self.add_friend(user_id, friend).into()
+            },
+            Request::RemoveFriend(friend) => {
+                self.remove_friend(user_id, friend).into()
+            },
+            Request::ListFriends => {
+                self.list_friends(user_id).into()
+            },
+            // acknowledgements are handled by the dispatcher, which keeps
+            // the messages being acknowledged
+            Request::Ack(_) |
             Request::Quit => {
                 Response::empty()
             },
+        };
+        
+        if payload_length > 0 && limits::is_near_limit(payload_length, self.max_payload_length) {
+            let warning = Warning::PayloadNearlyTooLarge(payload_length, self.max_payload_length);
+            response.and_to(user_id).msg(Message::Warning(warning))
+        } else {
+            response
         }
     }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use crate::clock::SimulatedClock;
+    use crate::models::MAX_HISTORY_LENGTH;
     use super::*;
     
     fn ok(t: Message) -> Result {
@@ -269,205 +2149,1405 @@ mod test {
     }
     
     impl Server {
-        fn assert_state(&self, user_id: UserID, expected: UserState) {
+        /// Checks which rooms a user takes part in, and how.
+        fn assert_rooms(&self, user_id: UserID, expected: &[(RoomID, Membership)]) {
             let user = self.get_user(user_id).unwrap();
-            assert_eq!(user.state, expected);
+            let rooms: Vec<_> = user.rooms.iter().map(|(&room_id, &m)| (room_id, m)).collect();
+            assert_eq!(expected, rooms);
         }
     }
     
     #[test]
-    fn add_user() {
+    fn add_user() {
+        let mut server = Server::new(4);
+        assert_eq!(Some(1), server.add_user());
+        server.assert_rooms(1, &[]);
+    }
+    
+    #[test]
+    fn ping() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        
+        let request = Request::Ping(23, None);
+        let expected = Response::returns(Message::Pong(23));
+        assert_eq!(expected, server.handle_request(1, request));
+    }
+    
+    #[test]
+    fn stats() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.disconnect_user(3).unwrap();
+        clock.advance(Duration::from_secs(90));
+        
+        let expected = Response::returns(Message::Stats(2, 1, 90, None));
+        assert_eq!(expected, server.handle_request(2, Request::Stats));
+    }
+    
+    /// Writes down every hook call, so tests can check them.
+    struct RecordingHooks(Arc<std::sync::Mutex<Vec<String>>>);
+    
+    impl Hooks for RecordingHooks {
+        fn room_created(&self, room_id: RoomID, owner_id: UserID) {
+            self.0.lock().unwrap().push(format!("created {room_id} by {owner_id}"));
+        }
+        
+        fn room_closed(&self, room_id: RoomID) {
+            self.0.lock().unwrap().push(format!("closed {room_id}"));
+        }
+        
+        fn user_joined(&self, room_id: RoomID, user_id: UserID) {
+            self.0.lock().unwrap().push(format!("{user_id} joined {room_id}"));
+        }
+        
+        fn user_left(&self, room_id: RoomID, user_id: UserID) {
+            self.0.lock().unwrap().push(format!("{user_id} left {room_id}"));
+        }
+        
+        fn message_relayed(&self, room_id: RoomID, from_user_id: UserID, payload: &str) {
+            self.0.lock().unwrap().push(format!("{from_user_id} sent {payload} in {room_id}"));
+        }
+    }
+    
+    #[test]
+    fn hooks() {
+        let calls = Arc::default();
+        let mut server = ServerBuilder::new()
+            .hooks(RecordingHooks(Arc::clone(&calls)))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.handle_request(1, Request::CreateRoom("hello".into(), Vec::new()));
+        server.handle_request(1, Request::SetJoinPolicy(1, JoinPolicy::Open));
+        server.handle_request(2, Request::AskJoinRoom(1, "hi".into(), None));
+        server.handle_request(2, Request::Send(1, "move".into()));
+        server.handle_request(2, Request::Send(2, "lost".into()));
+        server.handle_request(2, Request::LeaveRoom(1));
+        server.handle_request(1, Request::LeaveRoom(1));
+        
+        let expected = ["created 1 by 1", "2 joined 1", "2 sent move in 1", "2 left 1", "closed 1"];
+        assert_eq!(expected.map(String::from).to_vec(), *calls.lock().unwrap());
+    }
+    
+    #[test]
+    fn payload_too_large() {
+        let mut server = ServerBuilder::new()
+            .max_payload_length(4)
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        
+        let too_large = Response::returns(Message::Error(Error::PayloadTooLarge));
+        assert_eq!(too_large, server.handle_request(2, Request::AskJoinRoom(1, "hello".into(), None)));
+        server.handle_request(2, Request::AskJoinRoom(1, "hi".into(), None));
+        server.handle_request(1, Request::AcceptJoinRoom(1, 2));
+        assert_eq!(too_large, server.handle_request(2, Request::Send(1, "12345".into())));
+        assert_eq!(too_large, server.handle_request(1, Request::SendTo(1, 2, "12345".into())));
+        
+        let expected = Response::sends_all([
+            (1, Message::ReceivedFrom(1, 2, "1234".into())),
+            (2, Message::Warning(Warning::PayloadNearlyTooLarge(4, 4))),
+        ]);
+        assert_eq!(expected, server.handle_request(2, Request::Send(1, "1234".into())));
+        
+        let expected = Response::sends(1, Message::ReceivedFrom(1, 2, "12".into()));
+        assert_eq!(expected, server.handle_request(2, Request::Send(1, "12".into())));
+    }
+    
+    /// Refuses games whose data mentions spam.
+    struct NoSpam;
+    
+    impl Hooks for NoSpam {
+        fn accept_room_data(&self, _owner_id: UserID, data: &str) -> bool {
+            !data.contains("spam")
+        }
+    }
+    
+    #[test]
+    fn room_data_rules() {
+        let mut server = ServerBuilder::new()
+            .max_room_data_length(16)
+            .room_data_pattern(Some("name=.*".parse().unwrap()))
+            .hooks(NoSpam)
+            .build();
+        server.add_user().unwrap();
+        
+        let mut create = |data: &str| server.create_room(1, data.into(), Vec::new());
+        assert_eq!(Err(Error::RoomDataTooLong), create(&"name=x".repeat(3)));
+        assert_eq!(Err(Error::InvalidRoomData), create("hello"));
+        assert_eq!(Err(Error::InvalidRoomData), create("name=spam"));
+        assert_eq!(ok(Message::RoomCreated(1)), create("name=eggs"));
+    }
+    
+    #[test]
+    fn close_idle_rooms() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .clock(clock.clone())
+            .room_idle_timeout(Duration::from_secs(600))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.create_room(3, "hello".into(), Vec::new()).unwrap();
+        
+        clock.advance(Duration::from_secs(500));
+        server.handle_request(2, Request::Chat(1, "still here".into()));
+        assert_eq!(Vec::<RoomID>::new(), server.close_idle_rooms().0);
+        
+        clock.advance(Duration::from_secs(100));
+        let (closed, response) = server.close_idle_rooms();
+        assert_eq!(vec![2], closed);
+        assert_eq!(vec![(3, Message::RoomClosed(2))], response.sends);
+        server.assert_rooms(3, &[]);
+        assert!(server.room(1).is_some());
+    }
+    
+    #[test]
+    fn version_gating() {
+        let mut server = ServerBuilder::new()
+            .version_policy(VersionPolicy {
+                min_version: Some("1.2".parse().unwrap()),
+                blocked: Vec::new(),
+                upgrade_hint: None,
+            })
+            .build();
+        server.add_user().unwrap();
+        
+        let upgrade_required = Response::error(Error::UpgradeRequired(None));
+        assert_eq!(upgrade_required, server.handle_request(1, Request::ListRooms(RoomQuery::default())));
+        // compression is negotiated with or without a client version
+        let compression = Response::returns(Message::Compression(Some(Compression::Zstd)));
+        assert_eq!(compression, server.handle_request(1, Request::Compress("zstd".into())));
+        assert_eq!(upgrade_required, server.handle_request(1, Request::Hello("1.1".into())));
+        assert_eq!(upgrade_required, server.handle_request(1, Request::ListRooms(RoomQuery::default())));
+        
+        assert_eq!(Response::returns(Message::HelloOk), server.handle_request(1, Request::Hello("1.2".into())));
+        assert_eq!(Response::returns(Message::ListRooms(Vec::new())), server.handle_request(1, Request::ListRooms(RoomQuery::default())));
+    }
+    
+    #[test]
+    fn remove_user() {
+        let mut server = Server::new(4);
+        assert_eq!(Some(1), server.add_user());
+        assert_eq!(Ok(Response::empty()), server.remove_user(1));
+        assert_eq!(Error::NoSuchUser, server.get_user(1).unwrap_err());
+    }
+    
+    #[test]
+    fn custom_ids() {
+        let mut next_user_id = 100;
+        let mut server = ServerBuilder::new()
+            .user_ids(move || { next_user_id += 1; next_user_id })
+            .room_ids(|| 7)
+            .build();
+        
+        assert_eq!(Some(101), server.add_user());
+        assert_eq!(Some(102), server.add_user());
+        assert_eq!(ok(Message::RoomCreated(7)), server.create_room(101, "hello".into(), Vec::new()));
+        // a generator which runs out of free IDs doesn't hang the server
+        assert_eq!(Err(Error::ServerFull), server.create_room(102, "hello".into(), Vec::new()));
+    }
+    
+    #[test]
+    fn max_connections() {
+        let mut server = Server::new(4);
+        assert_eq!(Some(1), server.add_user());
+        assert_eq!(Some(2), server.add_user());
+        assert_eq!(Some(3), server.add_user());
+        assert_eq!(Some(4), server.add_user());
+        assert_eq!(None, server.add_user());
+    }
+    
+    #[test]
+    fn create_room() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        assert_eq!(ok(Message::RoomCreated(1)), server.create_room(1, "hello".into(), Vec::new()));
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+    }
+    
+    #[test]
+    fn draining() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        
+        let expected = Response::sends_all([
+            (1, Message::ServerRestarting(60)),
+            (2, Message::ServerRestarting(60)),
+        ]);
+        assert_eq!(expected, server.start_draining(60).canonical());
+        assert_eq!(Err(Error::ServerDraining), server.create_room(2, "world".into(), Vec::new()));
+        assert!(!server.is_drained());
+        
+        server.leave_room(1, 1).unwrap();
+        assert!(server.is_drained());
+    }
+    
+    #[test]
+    fn list_rooms() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        
+        assert_eq!(ok(Message::RoomCreated(1)), server.create_room(2, "hello".into(), Vec::new()));
+        server.assert_rooms(2, &[(1, Membership::Owner)]);
+        assert_eq!(ok(Message::RoomCreated(2)), server.create_room(1, "world".into(), Vec::new()));
+        server.assert_rooms(1, &[(2, Membership::Owner)]);
+        
+        let expected: Response = Message::ListRooms(vec![
+            (1, "hello".into(), None),
+            (2, "world".into(), None),
+        ]).into();
+        assert_eq!(expected, server.list_rooms(&RoomQuery::default()).canonical());
+    }
+    
+    #[test]
+    fn list_rooms_by_tag() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        
+        server.create_room(1, "a".into(), tags(&["coop", "eu"])).unwrap();
+        server.create_room(1, "b".into(), tags(&["coop"])).unwrap();
+        server.create_room(1, "c".into(), Vec::new()).unwrap();
+        let listed = |server: &Server, filter: &[&str]| {
+            server.list_rooms(&RoomQuery {tags: tags(filter), ..RoomQuery::default()}).canonical()
+        };
+        assert_eq!(Response::returns(Message::ListRooms(vec![(1, "a".into(), None), (2, "b".into(), None)])), listed(&server, &["coop"]));
+        assert_eq!(Response::returns(Message::ListRooms(vec![(1, "a".into(), None)])), listed(&server, &["eu", "coop"]));
+        assert_eq!(Response::returns(Message::ListRooms(Vec::new())), listed(&server, &["pvp"]));
+        
+        // a failed request doesn't use up an ID
+        assert_eq!(Err(Error::InvalidTags), server.create_room(1, "d".into(), tags(&["co,op"])));
+        assert_eq!(Err(Error::InvalidTags), server.create_room(1, "d".into(), tags(&["pvp"; 9])));
+        assert_eq!(ok(Message::RoomCreated(4)), server.create_room(1, "d".into(), tags(&["pvp"; 8])));
+    }
+    
+    #[test]
+    fn list_rooms_sorted() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        for data in ["b:dragons", "c:knights", "a:dragons"] {
+            server.create_room(1, data.into(), Vec::new()).unwrap();
+            clock.advance(Duration::from_secs(1));
+        }
+        server.rooms.get_mut(&1).unwrap().members.extend([2, 3]);
+        server.rooms.get_mut(&2).unwrap().members.extend([2]);
+        
+        let listed = |server: &Server, search: Option<&str>, sort| {
+            let query = RoomQuery {tags: Vec::new(), search: search.map(Into::into), sort: Some(sort)};
+            match server.list_rooms(&query).returns {
+                Some(Message::ListRooms(rooms)) => rooms.into_iter().map(|(room_id, ..)| room_id).collect::<Vec<_>>(),
+                r => panic!("unexpected {r:?}"),
+            }
+        };
+        assert_eq!(vec![3, 2, 1], listed(&server, None, RoomOrder::Newest));
+        assert_eq!(vec![3, 2, 1], listed(&server, None, RoomOrder::FewestPlayers));
+        assert_eq!(vec![3, 1, 2], listed(&server, None, RoomOrder::Name));
+        assert_eq!(vec![3, 1], listed(&server, Some("dragons"), RoomOrder::Name));
+        
+        // a room with a name is listed by it instead of its data
+        server.rooms.get_mut(&2).unwrap().name = Some("Aardvarks".into());
+        assert_eq!(vec![2, 3, 1], listed(&server, None, RoomOrder::Name));
+        assert_eq!(vec![2], listed(&server, Some("Aard"), RoomOrder::Name));
+        assert_eq!(Vec::<RoomID>::new(), listed(&server, Some("knights"), RoomOrder::Name));
+    }
+    
+    #[test]
+    fn list_members() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.ask_join(4, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        server.set_name(3, "carol".into()).unwrap();
+        
+        let members = vec![2.into(), Named(3, Some("carol".into()))];
+        let expected = Response {
+            returns: Some(Message::ListMembers(1, 1.into(), members.clone())),
+            sends: vec![(1, Message::ListJoinRequests(1, vec![4]))],
+        };
+        assert_eq!(Ok(expected), server.list_members(1, 1).map(Response::canonical));
+        
+        let expected = Message::ListMembers(1, 1.into(), members).into();
+        assert_eq!(Ok(expected), server.list_members(2, 1).map(Response::canonical));
+        
+        assert_eq!(Err(Error::NotInThatRoom), server.list_members(4, 1));
+    }
+    
+    #[test]
+    fn resume() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        let token = server.resume_token(2).unwrap().to_string();
+        assert_eq!(Err(Error::InvalidToken), server.resume(3, "wrong"));
+        
+        let Ok(Response {returns: Some(Message::Resumed(2, new_token)), ..}) = server.resume(3, &token) else {
+            panic!("expected to resume user 2");
+        };
+        assert_ne!(token, new_token);
+        // the session keeps its ID across resumes
+        assert_eq!(server.session_id(2), ids::token_session(&new_token));
+        assert_eq!(ids::token_session(&token), ids::token_session(&new_token));
+        assert!(server.get_user(3).is_err());
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        
+        // the old token cannot be used again, and the user is told about it
+        server.add_user().unwrap();
+        let expected = Response::sends(2, Message::ResumeReplayed)
+            .returning(Message::Error(Error::InvalidToken));
+        assert_eq!(Ok(expected), server.resume(4, &token));
+    }
+    
+    #[test]
+    fn disconnect_and_resume() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        let expected = Response::sends(1, Message::PlayerDisconnected(1, 2));
+        assert_eq!(Ok(expected), server.disconnect_user(2));
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        
+        let token = server.resume_token(2).unwrap().to_string();
+        let response = server.resume(3, &token).unwrap();
+        assert!(matches!(response.returns, Some(Message::Resumed(2, _))));
+        assert_eq!(vec![(1, Message::PlayerReconnected(1, 2))], response.sends);
+    }
+    
+    #[test]
+    fn presence_for_everyone() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.set_join_policy(1, 1, JoinPolicy::Open).unwrap();
+        server.ask_join(2, 1, "hi".into()).unwrap();
+        server.ask_join(3, 1, "hi".into()).unwrap();
+        assert_eq!(Err(Error::NotRoomOwner), server.set_presence(2, 1, Presence::All));
+        server.set_presence(1, 1, Presence::All).unwrap();
+        
+        let response = server.disconnect_user(2).unwrap();
+        assert_eq!(vec![(1, Message::PlayerDisconnected(1, 2)), (3, Message::PlayerDisconnected(1, 2))], response.sends);
+        
+        // the players are told it timed out before that it left
+        let response = server.time_out_user(2).unwrap();
+        assert_eq!(vec![
+            (1, Message::PlayerTimedOut(1, 2)),
+            (3, Message::PlayerTimedOut(1, 2)),
+        ], response.sends[..2]);
+        assert!(matches!(response.sends[2], (_, Message::PlayerLeft(1, Named(2, None)))));
+        assert!(!server.has_user(2));
+    }
+    
+    #[test]
+    fn snapshot_and_restore() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.queue(3, "any".into()).unwrap();
+        let token = server.resume_token(2).unwrap().to_string();
+        
+        let mut restored = Server::new(4);
+        restored.restore(snapshot::parse(&server.snapshot()).unwrap());
+        let mut disconnected = restored.disconnected_users();
+        disconnected.sort();
+        assert_eq!(vec![1, 2, 3], disconnected);
+        restored.assert_rooms(2, &[(1, Membership::Member)]);
+        restored.assert_rooms(3, &[]);
+        
+        assert_eq!(Some(4), restored.add_user());
+        let response = restored.resume(4, &token).unwrap();
+        assert!(matches!(response.returns, Some(Message::Resumed(2, _))));
+    }
+    
+    #[test]
+    fn multiple_rooms() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.create_room(2, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(1, 2, "please".into()).unwrap();
+        assert_eq!(Err(Error::AlreadyRequestedJoin), server.ask_join(1, 2, "please".into()));
+        server.accept_join(2, 2, 1).unwrap();
+        server.assert_rooms(1, &[(1, Membership::Owner), (2, Membership::Member)]);
+        assert_eq!(Err(Error::AlreadyInARoom), server.spectate(1, 1));
+        
+        let expected = Response::sends(2, Message::PlayerDisconnected(2, 1));
+        assert_eq!(Ok(expected), server.disconnect_user(1));
+        let expected = Response::sends(2, Message::PlayerLeft(2, 1.into()));
+        assert_eq!(Ok(expected), server.remove_user(1));
+        assert!(server.room(1).is_none());
+        server.assert_rooms(2, &[(2, Membership::Owner)]);
+        
+        for _ in 1..16 {
+            server.create_room(2, "hello".into(), Vec::new()).unwrap();
+        }
+        assert_eq!(Err(Error::TooManyRooms), server.create_room(2, "hello".into(), Vec::new()));
+    }
+    
+    #[test]
+    fn restore_inconsistent_snapshot() {
+        let users = vec![User::new(1), User::new(2), User::new(3)];
+        let mut room = Room::new(1, 1, "hello".into());
+        room.members = indexmap::IndexSet::from([3, 4]);
+        room.spectators = indexmap::IndexSet::from([3]);
+        let ownerless = Room::new(2, 5, "hello".into());
+        let duplicate = Room::new(1, 2, "hello".into());
+        
+        let mut server = Server::new(4);
+        server.restore(Snapshot {users, rooms: vec![room, ownerless, duplicate], friends: Friends::new()});
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+        server.assert_rooms(2, &[]);
+        server.assert_rooms(3, &[(1, Membership::Member)]);
+        let room = server.room(1).unwrap();
+        assert_eq!(indexmap::IndexSet::from([3]), room.members);
+        assert!(room.spectators.is_empty());
+        assert!(server.room(2).is_none());
+    }
+    
+    #[test]
+    fn owner_disconnect() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.spectate(3, 1).unwrap();
+        
+        let expected = Response::sends_all([
+            (2, Message::PlayerDisconnected(1, 1)),
+            (3, Message::PlayerDisconnected(1, 1)),
+        ]);
+        assert_eq!(Ok(expected), server.disconnect_user(1).map(Response::canonical));
+    }
+    
+    #[test]
+    fn room_pings() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        
+        // what the client says its latency is makes no difference
+        server.handle_request(2, Request::Ping(1, Some(5)));
+        
+        let expected = Response::to_all([1, 2, 3]).msg(Message::Ping(0));
+        assert_eq!(expected, server.ping_users().canonical());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(Ok(Response::empty()), server.pong(2, 0));
+        
+        // only an answer to the latest ping counts, and only once
+        server.ping_users();
+        clock.advance(Duration::from_millis(200));
+        server.pong(2, 0).unwrap();
+        server.pong(2, 1).unwrap();
+        server.pong(2, 1).unwrap();
+        
+        let expected = Message::RoomPings(1, vec![(2, Some(125)), (3, None)]);
+        assert_eq!(ok(expected), server.room_pings(1, 1).map(Response::canonical));
+        assert_eq!(Err(Error::NotRoomOwner), server.room_pings(2, 1));
+    }
+    
+    #[test]
+    fn lobby() {
+        let mut server = Server::new(3);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.create_room(2, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        
+        assert_eq!(Lobby::from([(1, 2), (2, 1)]), server.lobby());
+        assert_eq!(Listing::from([(1, (2, "hello".into(), None)), (2, (1, "hello".into(), None))]), server.listing());
+    }
+    
+    #[test]
+    fn room_info() {
+        let mut server = ServerBuilder::new()
+            .max_room_members(Some(3))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        let expected = Message::RoomInfo(1, 1, 1, Some(3), JoinPolicy::AskOwner, "hello".into(), None);
+        assert_eq!(ok(expected), server.room_info(1));
+        assert_eq!(Err(Error::NoSuchRoom), server.room_info(2));
+    }
+    
+    #[test]
+    fn room_full() {
+        let mut server = ServerBuilder::new()
+            .max_room_members(Some(1))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        
+        let expected = Response::sends_all([
+            (1, Message::Warning(Warning::RoomNearlyFull(1, 1, 1))),
+            (2, Message::RoomJoined(1)),
+        ]);
+        assert_eq!(Ok(expected), server.accept_join(1, 1, 2).map(Response::canonical));
+        assert_eq!(Err(Error::RoomFull), server.accept_join(1, 1, 3));
+        server.assert_rooms(3, &[(1, Membership::RequestedJoin)]);
+    }
+    
+    #[test]
+    fn ask_join() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        
+        let expected = Response::sends(1, Message::JoinRequested(1, 2.into(), "please".into()));
+        assert_eq!(Ok(expected), server.ask_join(2, 1, "please".into()));
+        server.assert_rooms(2, &[(1, Membership::RequestedJoin)]);
+    }
+    
+    #[test]
+    fn set_name() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        
+        assert_eq!(Ok(()), server.set_name(2, "bob".into()));
+        assert_eq!(Err(Error::InvalidName), server.set_name(2, "bob,alice".into()));
+        assert_eq!(Err(Error::InvalidName), server.set_name(2, "b".repeat(33)));
+        
+        let bob = Named(2, Some("bob".into()));
+        let expected = Response::sends(1, Message::JoinRequested(1, bob.clone(), "please".into()));
+        assert_eq!(Ok(expected), server.ask_join(2, 1, "please".into()));
+        let expected = Response::sends(1, Message::PlayerLeft(1, bob));
+        assert_eq!(Ok(expected), server.leave_room(2, 1));
+        
+        // an empty name clears it
+        server.set_name(2, String::new()).unwrap();
+        assert_eq!(None, server.get_user(2).unwrap().name);
+    }
+    
+    #[test]
+    fn open_join() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.set_join_policy(1, 1, JoinPolicy::Open).unwrap();
+        
+        let expected = Response {
+            returns: Some(Message::RoomJoined(1)),
+            sends: vec![(1, Message::MemberJoined(1, 2, "hi".into()))],
+        };
+        assert_eq!(Ok(expected), server.ask_join(2, 1, "hi".into()));
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+    }
+    
+    #[test]
+    fn invite() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.set_name(1, "alice".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.handle_request(1, Request::SetPassword(1, "hunter2".into()));
+        
+        assert_eq!(Err(Error::NotRoomOwner), server.invite(3, 1, 2));
+        assert_eq!(Err(Error::AlreadyInARoom), server.invite(1, 1, 1));
+        let expected = Response::sends(2, Message::Invited(1, Named(1, Some("alice".into()))));
+        assert_eq!(Ok(expected), server.invite(1, 1, 2));
+        
+        // an invited user joins without asking, or giving the password
+        let response = server.handle_request(2, Request::AskJoinRoom(1, "hi".into(), None));
+        assert_eq!(Some(Message::RoomJoined(1)), response.returns);
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        
+        // the invitation is used up
+        server.leave_room(2, 1).unwrap();
+        let response = server.handle_request(2, Request::AskJoinRoom(1, "hi".into(), None));
+        assert_eq!(Some(Message::Error(Error::IncorrectPassword)), response.returns);
+        
+        // and forgotten if the user leaves the server
+        server.invite(1, 1, 3).unwrap();
+        server.remove_user(3).unwrap();
+        assert!(server.rooms[&1].invited.is_empty());
+    }
+    
+    #[test]
+    fn friends() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        assert_eq!(Err(Error::NoAccount), server.add_friend(1, "bob".into()));
+        assert_eq!(Ok(Response::empty()), server.set_account(1, "alice".into()));
+        server.set_account(2, "bob".into()).unwrap();
+        
+        // nobody is told anything until the friendship is mutual
+        assert_eq!(ok(Message::FriendAdded("bob".into())), server.add_friend(1, "bob".into()));
+        assert_eq!(ok(Message::Friends(vec![("bob".into(), FriendStatus::Pending)])), server.list_friends(1));
+        let expected = Response::returns(Message::FriendAdded("alice".into()))
+            .and_to(1)
+            .msg(Message::FriendOnline(2, "bob".into()))
+            .and_to(2)
+            .msg(Message::FriendOnline(1, "alice".into()));
+        assert_eq!(Ok(expected), server.add_friend(2, "alice".into()));
+        assert_eq!(ok(Message::Friends(vec![("bob".into(), FriendStatus::Online(2))])), server.list_friends(1));
+        
+        let expected = Response::returns(Message::RoomCreated(1))
+            .and_to(1)
+            .msg(Message::FriendJoinedRoom(1, 2, "bob".into()));
+        assert_eq!(Ok(expected), server.create_room(2, "hello".into(), Vec::new()));
+        
+        // a second connection to the same account keeps it online
+        server.set_account(3, "bob".into()).unwrap();
+        let response = server.remove_user(2).unwrap();
+        assert!(!response.sends.iter().any(|(_, msg)| matches!(msg, Message::FriendOffline(_))));
+        let expected = Response::sends(1, Message::FriendOffline("bob".into()));
+        assert_eq!(Ok(expected), server.remove_user(3));
+        assert_eq!(ok(Message::Friends(vec![("bob".into(), FriendStatus::Offline)])), server.list_friends(1));
+        
+        assert_eq!(ok(Message::FriendRemoved("bob".into())), server.remove_friend(1, "bob".into()));
+        assert_eq!(Err(Error::NoSuchUser), server.remove_friend(1, "bob".into()));
+    }
+    
+    #[test]
+    fn room_password() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.set_password(1, 1, "secret".into()).unwrap();
+        
+        let incorrect = Response::returns(Message::Error(Error::IncorrectPassword));
+        assert_eq!(incorrect, server.handle_request(2, Request::AskJoinRoom(1, "please".into(), None)));
+        assert_eq!(Err(Error::NoOpenRooms), server.join_any(2, "", "please".into()));
+        server.handle_request(2, Request::AskJoinRoom(1, "please".into(), Some("secret".into())));
+        server.assert_rooms(2, &[(1, Membership::RequestedJoin)]);
+        server.accept_join(1, 1, 2).unwrap();
+        
+        // rotating the password doesn't affect existing members
+        assert_eq!(Err(Error::NotRoomOwner), server.set_password(2, 1, "mine".into()));
+        server.set_password(1, 1, "secret2".into()).unwrap();
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        assert_eq!(incorrect, server.handle_request(3, Request::Spectate(1, Some("secret".into()))));
+        server.handle_request(3, Request::Spectate(1, Some("secret2".into())));
+        server.assert_rooms(3, &[(1, Membership::Spectating)]);
+        
+        // revoking it lets anyone join
+        server.set_password(1, 1, "".into()).unwrap();
+        server.handle_request(4, Request::AskJoinRoom(1, "please".into(), None));
+        server.assert_rooms(4, &[(1, Membership::RequestedJoin)]);
+    }
+    
+    #[test]
+    fn join_any() {
+        let mut server = ServerBuilder::new()
+            .max_room_members(Some(1))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "coop".into(), Vec::new()).unwrap();
+        server.create_room(2, "versus".into(), Vec::new()).unwrap();
+        
+        let expected = Response {
+            returns: Some(Message::JoinRequestSent(2)),
+            sends: vec![(2, Message::JoinRequested(2, 3.into(), "hi".into()))],
+        };
+        assert_eq!(Ok(expected), server.join_any(3, "vers", "hi".into()));
+        server.assert_rooms(3, &[(2, Membership::RequestedJoin)]);
+        server.accept_join(2, 2, 3).unwrap();
+        
+        // the only matching game is now full
+        assert_eq!(Err(Error::NoOpenRooms), server.join_any(4, "vers", "hi".into()));
+    }
+    
+    #[test]
+    fn matchmaking() {
+        let mut server = ServerBuilder::new()
+            .match_size(3)
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        
+        assert_eq!(ok(Message::Queued(0)), server.queue(1, "coop".into()));
+        assert_eq!(ok(Message::Queued(0)), server.queue(2, "versus".into()));
+        assert_eq!(ok(Message::Queued(1)), server.queue(3, "coop".into()));
+        assert!(server.get_user(1).unwrap().queued);
+        assert_eq!(Err(Error::AlreadyQueued), server.queue(1, "coop".into()));
+        
+        let expected = Response::sends_all([
+            (1, Message::MatchFound(1, 1)),
+            (3, Message::MatchFound(1, 1)),
+            (4, Message::MatchFound(1, 1)),
+        ]);
+        assert_eq!(Ok(expected), server.queue(4, "coop".into()));
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+        server.assert_rooms(3, &[(1, Membership::Member)]);
+        server.assert_rooms(4, &[(1, Membership::Member)]);
+        
+        assert_eq!(Ok(Response::empty()), server.unqueue(2));
+        server.assert_rooms(2, &[]);
+        assert_eq!(Err(Error::NotQueued), server.unqueue(2));
+    }
+    
+    #[test]
+    fn match_size_fits_room() {
+        assert!(check_match_size(3, None).is_ok());
+        assert!(check_match_size(3, Some(2)).is_ok());
+        assert!(check_match_size(3, Some(1)).is_err());
+        
+        let mut server = ServerBuilder::new()
+            .match_size(3)
+            .max_room_members(Some(1))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        
+        assert_eq!(ok(Message::Queued(0)), server.queue(1, "coop".into()));
+        let expected = Response::sends_all([
+            (1, Message::MatchFound(1, 1)),
+            (2, Message::MatchFound(1, 1)),
+        ]);
+        assert_eq!(Ok(expected), server.queue(2, "coop".into()));
+    }
+    
+    #[test]
+    fn accept_join() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        
+        server.assert_rooms(2, &[(1, Membership::RequestedJoin)]);
+        
+        let expected = Response::sends(2, Message::RoomJoined(1));
+        assert_eq!(Ok(expected), server.accept_join(1, 1, 2));
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+    }
+    
+    #[test]
+    fn reject_join() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        
+        server.assert_rooms(2, &[(1, Membership::RequestedJoin)]);
+        
+        let expected = Response::sends(2, Message::RoomRejected(1, "no".into()));
+        assert_eq!(Ok(expected), server.reject_join(1, 1, 2, "no".into()));
+        server.assert_rooms(2, &[]);
+    }
+    
+    #[test]
+    fn max_join_requests() {
+        let mut server = ServerBuilder::new()
+            .max_join_requests(Some(1))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        
+        assert_eq!(Err(Error::TooManyRequests), server.ask_join(3, 1, "please".into()).map(|_| ()));
+        assert_eq!(Err(Error::NoOpenRooms), server.join_any(3, "", "please".into()).map(|_| ()));
+        server.assert_rooms(3, &[]);
+        
+        // invited users don't wait for the owner, so aren't limited
+        server.invite(1, 1, 3).unwrap();
+        server.ask_join(3, 1, "thanks".into()).unwrap();
+        server.assert_rooms(3, &[(1, Membership::Member)]);
+    }
+    
+    #[test]
+    fn rejoin_cooldown() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .rejoin_cooldown(Duration::from_secs(60))
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.reject_join(1, 1, 2, "no".into()).unwrap();
+        
+        assert_eq!(Err(Error::RejoinCooldown), server.ask_join(2, 1, "please".into()).map(|_| ()));
+        assert_eq!(Err(Error::NoOpenRooms), server.join_any(2, "", "please".into()).map(|_| ()));
+        assert_eq!(Err(Error::NotRoomOwner), server.set_rejoin_cooldown(3, 1, 0).map(|_| ()));
+        
+        // the owner can choose a shorter cooldown than the server's
+        server.set_rejoin_cooldown(1, 1, 10).unwrap();
+        clock.advance(Duration::from_secs(10));
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.reject_join(1, 1, 2, "still no".into()).unwrap();
+        assert_eq!(Err(Error::RejoinCooldown), server.ask_join(2, 1, "please".into()).map(|_| ()));
+        
+        // invited users needn't wait
+        server.invite(1, 1, 2).unwrap();
+        server.ask_join(2, 1, "thanks".into()).unwrap();
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+    }
+    
+    #[test]
+    fn leave_room() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        
+        let expected = Response::sends(1, Message::PlayerLeft(1, 2.into()));
+        assert_eq!(Ok(expected), server.leave_room(2, 1));
+        server.assert_rooms(2, &[]);
+    }
+    
+    #[test]
+    fn leave_room_notifies_members() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        
+        let expected = Response::sends_all([
+            (1, Message::PlayerLeft(1, 2.into())),
+            (3, Message::PlayerLeft(1, 2.into())),
+        ]);
+        assert_eq!(Ok(expected), server.leave_room(2, 1).map(Response::canonical));
+        server.assert_rooms(2, &[]);
+    }
+    
+    #[test]
+    fn set_owner() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        server.assert_rooms(3, &[(1, Membership::Member)]);
+        
+        let expected = Response::sends_all([
+            (2, Message::ChangedOwner(1, 2)),
+            (3, Message::ChangedOwner(1, 2)),
+        ]);
+        assert_eq!(Ok(expected), server.set_owner(1, 1, 2).map(Response::canonical));
+        server.assert_rooms(1, &[(1, Membership::Member)]);
+        server.assert_rooms(2, &[(1, Membership::Owner)]);
+        server.assert_rooms(3, &[(1, Membership::Member)]);
+    }
+    
+    #[test]
+    fn close_room() {
         let mut server = Server::new(4);
-        assert_eq!(Some(1), server.add_user());
-        server.assert_state(1, UserState::Nowhere);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        
+        let expected = Response::sends(2, Message::RoomClosed(1));
+        assert_eq!(Ok(expected), server.leave_room(1, 1));
+        server.assert_rooms(1, &[]);
+        server.assert_rooms(2, &[]);
     }
     
     #[test]
-    fn ping() {
+    fn owner_send() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
         
-        let request = Request::Ping(23);
-        let expected = Response::returns(Message::Pong(23));
-        assert_eq!(expected, server.handle_request(1, request));
+        let expected = Response::sends_all([
+            (2, Message::ReceivedBroadcast(1, "whee".into())),
+            (3, Message::ReceivedBroadcast(1, "whee".into())),
+        ]);
+        assert_eq!(Ok(expected), server.send(1, 1, "whee".into()));
     }
     
     #[test]
-    fn remove_user() {
-        let mut server = Server::new(4);
-        assert_eq!(Some(1), server.add_user());
-        assert_eq!(Ok(Response::empty()), server.remove_user(1));
-        assert_eq!(Error::NoSuchUser, server.get_user(1).unwrap_err());
+    fn spectate() {
+        let mut server = ServerBuilder::new()
+            .max_room_members(Some(1))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        
+        // spectators don't count towards capacity
+        let expected = Response {
+            returns: Some(Message::RoomSpectating(1)),
+            sends: vec![(1, Message::SpectatorJoined(1, 3))],
+        };
+        assert_eq!(Ok(expected), server.spectate(3, 1));
+        server.assert_rooms(3, &[(1, Membership::Spectating)]);
+        
+        let expected = Response::sends_all([
+            (2, Message::ReceivedBroadcast(1, "whee".into())),
+            (3, Message::ReceivedBroadcast(1, "whee".into())),
+        ]);
+        assert_eq!(Ok(expected), server.send(1, 1, "whee".into()).map(Response::canonical));
+        assert_eq!(Err(Error::IsSpectator), server.send(3, 1, "whee".into()));
+        
+        let expected = Response {
+            returns: Some(Message::ListMembers(1, 1.into(), vec![2.into()])),
+            sends: vec![(3, Message::ListSpectators(1, vec![3]))],
+        };
+        assert_eq!(Ok(expected), server.list_members(3, 1));
+        
+        let expected = Response::sends(1, Message::PlayerLeft(1, 3.into()));
+        assert_eq!(Ok(expected), server.leave_room(3, 1));
+        server.assert_rooms(3, &[]);
     }
     
     #[test]
-    fn max_connections() {
+    fn named_rooms() {
         let mut server = Server::new(4);
-        assert_eq!(Some(1), server.add_user());
-        assert_eq!(Some(2), server.add_user());
-        assert_eq!(Some(3), server.add_user());
-        assert_eq!(Some(4), server.add_user());
-        assert_eq!(None, server.add_user());
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        let create = |server: &mut Server, user_id, name: &str| {
+            server.create_named_room(user_id, Some(name.into()), "hello".into(), Vec::new())
+        };
+        assert_eq!(ok(Message::RoomCreated(1)), create(&mut server, 1, "Friday Night Draft"));
+        assert_eq!(Err(Error::RoomNameTaken), create(&mut server, 2, "friday night DRAFT"));
+        assert_eq!(Err(Error::InvalidName), create(&mut server, 2, " Friday Night Draft"));
+        assert_eq!(Err(Error::InvalidName), create(&mut server, 2, ""));
+        
+        let expected = Response::sends(1, Message::JoinRequested(1, 2.into(), "please".into()))
+            .returning(Message::JoinRequestSent(1));
+        assert_eq!(Ok(expected), server.join_named_room(2, "Friday Night Draft", "please".into(), None));
+        assert_eq!(Err(Error::NoSuchRoom), server.join_named_room(3, "Saturday", "please".into(), None));
+        
+        // the name is free again once the game is over
+        server.close_own_room(1, 1).unwrap();
+        assert!(create(&mut server, 2, "Friday Night Draft").is_ok());
     }
     
     #[test]
-    fn create_room() {
+    fn close_own_room() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
-        assert_eq!(ok(Message::RoomCreated(1)), server.create_room(1, "hello".into()));
-        server.assert_state(1, UserState::RoomOwner(1));
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.spectate(3, 1).unwrap();
+        
+        assert_eq!(Err(Error::NotRoomOwner), server.close_own_room(2, 1));
+        
+        let expected = Response::sends_all([
+            (2, Message::RoomClosed(1)),
+            (3, Message::RoomClosed(1)),
+        ]).returning(Message::RoomClosed(1));
+        assert_eq!(Ok(expected), server.close_own_room(1, 1).map(Response::canonical));
+        server.assert_rooms(1, &[]);
+        server.assert_rooms(2, &[]);
+        server.assert_rooms(3, &[]);
+        assert_eq!(Err(Error::NoSuchRoom), server.close_own_room(1, 1));
+        
+        // the owner is still connected, and can start another game
+        assert!(server.create_room(1, "again".into(), Vec::new()).is_ok());
     }
     
     #[test]
-    fn list_rooms() {
-        let mut server = Server::new(4);
-        server.add_user().unwrap();
-        server.add_user().unwrap();
+    fn vote_kick() {
+        let mut server = Server::new(5);
+        for _ in 0..5 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        for u_id in 2..=4 {
+            server.ask_join(u_id, 1, "please".into()).unwrap();
+            server.accept_join(1, 1, u_id).unwrap();
+        }
+        server.spectate(5, 1).unwrap();
         
-        assert_eq!(ok(Message::RoomCreated(1)), server.create_room(2, "hello".into()));
-        server.assert_state(2, UserState::RoomOwner(1));
-        assert_eq!(ok(Message::RoomCreated(2)), server.create_room(1, "world".into()));
-        server.assert_state(1, UserState::RoomOwner(2));
+        assert_eq!(Err(Error::IsSpectator), server.vote_kick(5, 1, 4));
+        assert_eq!(Err(Error::NoSuchUser), server.vote_kick(2, 1, 2));
+        assert_eq!(Err(Error::NoSuchUser), server.vote_kick(2, 1, 1));
         
-        let expected: Response = Message::ListRooms(vec![
-            (1, "hello".into()),
-            (2, "world".into()),
-        ]).into();
-        assert_eq!(expected, server.list_rooms().canonical());
+        // more than half of the other three players must vote
+        let Ok(response) = server.vote_kick(2, 1, 4) else {
+            panic!("expected the vote to count");
+        };
+        assert!(response.sends.iter().all(|(_, msg)| *msg == Message::KickVote(1, 4, 1, 2)));
+        server.vote_kick(2, 1, 4).unwrap();
+        server.assert_rooms(4, &[(1, Membership::Member)]);
+        
+        let response = server.vote_kick(1, 1, 4).unwrap();
+        assert!(response.sends.contains(&(4, Message::VotedOut(1, 4))));
+        assert!(response.sends.contains(&(2, Message::VotedOut(1, 4))));
+        server.assert_rooms(4, &[]);
+        assert_eq!(Err(Error::NoSuchUser), server.vote_kick(3, 1, 4));
+        
+        let last = server.room_store.timeline(1).unwrap().pop().unwrap();
+        assert_eq!(RoomEvent::Kicked(4), last.event);
     }
     
     #[test]
-    fn ask_join() {
+    fn close_room_with_spectator() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.spectate(2, 1).unwrap();
         
-        let expected = Response::sends(1, Message::JoinRequested(1, 2, "please".into()));
-        assert_eq!(Ok(expected), server.ask_join(2, 1, "please".into()));
-        server.assert_state(2, UserState::RequestedJoin(1));
+        let expected = Response::sends(2, Message::RoomClosed(1));
+        assert_eq!(Ok(expected), server.leave_room(1, 1));
+        server.assert_rooms(2, &[]);
     }
     
     #[test]
-    fn accept_join() {
+    fn member_send() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
         
-        server.assert_state(2, UserState::RequestedJoin(1));
-        
-        let expected = Response::sends(2, Message::RoomJoined(1));
-        assert_eq!(Ok(expected), server.accept_join(1, 1, 2));
-        server.assert_state(2, UserState::InRoom(1));
+        let expected = Response::sends(1, Message::ReceivedFrom(1, 2, "whee".into()));
+        assert_eq!(Ok(expected), server.send(2, 1, "whee".into()));
     }
     
     #[test]
-    fn reject_join() {
+    fn member_send_schema() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
         
-        server.assert_state(2, UserState::RequestedJoin(1));
+        assert_eq!(Err(Error::NotRoomOwner), server.set_schema(2, 1, "MOVE"));
+        assert_eq!(Err(Error::InvalidSchema), server.set_schema(1, 1, "(MOVE"));
+        assert_eq!(Err(Error::InvalidSchema), server.set_schema(1, 1, "MOVE)|(.*"));
+        assert_eq!(Ok(Response::empty()), server.set_schema(1, 1, "MOVE:[0-9]+|PASS"));
         
-        let expected = Response::sends(2, Message::RoomRejected(1, "no".into()));
-        assert_eq!(Ok(expected), server.reject_join(1, 1, 2, "no".into()));
-        server.assert_state(2, UserState::Nowhere);
+        let expected = Response::sends(1, Message::ReceivedFrom(1, 2, "MOVE:12".into()));
+        assert_eq!(Ok(expected), server.send(2, 1, "MOVE:12".into()));
+        assert_eq!(Err(Error::InvalidPayload), server.send(2, 1, "MOVE:12 lol".into()));
+        
+        // the owner is not restricted
+        assert!(server.send(1, 1, "anything".into()).is_ok());
+        
+        server.set_schema(1, 1, "").unwrap();
+        assert!(server.send(2, 1, "anything".into()).is_ok());
     }
     
     #[test]
-    fn leave_room() {
+    fn send_binary() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
         
-        server.assert_state(2, UserState::InRoom(1));
+        let expected = Response::sends(2, Message::ReceivedBinaryBroadcast(1, "AP8B".into()));
+        assert_eq!(Ok(expected), server.send_binary(1, 1, "AP8B".into()));
+        let expected = Response::sends(1, Message::ReceivedBinaryFrom(1, 2, "AP8B".into()));
+        assert_eq!(Ok(expected), server.send_binary(2, 1, "AP8B".into()));
         
-        let expected = Response::sends(1, Message::PlayerLeft(1, 2));
-        assert_eq!(Ok(expected), server.leave_room(2, 1));
-        server.assert_state(2, UserState::Nowhere);
+        // a schema can't say which binary payloads are valid
+        server.set_schema(1, 1, "MOVE:[0-9]+").unwrap();
+        assert_eq!(Err(Error::InvalidPayload), server.send_binary(2, 1, "AP8B".into()));
+        assert!(server.send_binary(1, 1, "AP8B".into()).is_ok());
     }
     
     #[test]
-    fn set_owner() {
+    fn datagram_recipients() {
+        let mut server = ServerBuilder::new()
+            .udp_port(Some(9000))
+            .build();
+        for _ in 0..4 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.spectate(3, 1).unwrap();
+        
+        let Ok(Response {returns: Some(Message::UdpToken(9000, _)), ..}) = server.register_udp() else {
+            panic!("expected a UDP token");
+        };
+        let mut audience = server.datagram_recipients(1, 1).unwrap();
+        audience.sort();
+        assert_eq!(vec![2, 3], audience);
+        assert_eq!(Ok(vec![1]), server.datagram_recipients(2, 1));
+        assert_eq!(Err(Error::IsSpectator), server.datagram_recipients(3, 1));
+        assert_eq!(Err(Error::NotInThatRoom), server.datagram_recipients(4, 1));
+        
+        assert_eq!(Err(Error::UdpUnavailable), Server::new(4).register_udp());
+    }
+    
+    #[test]
+    fn chat() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.ask_join(3, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
         server.accept_join(1, 1, 3).unwrap();
-        
-        server.assert_state(1, UserState::RoomOwner(1));
-        server.assert_state(2, UserState::InRoom(1));
-        server.assert_state(3, UserState::InRoom(1));
+        server.spectate(4, 1).unwrap();
         
         let expected = Response::sends_all([
-            (2, Message::ChangedOwner(1, 2)),
-            (3, Message::ChangedOwner(1, 2)),
+            (1, Message::Chat(1, 2, "gg".into())),
+            (3, Message::Chat(1, 2, "gg".into())),
+            (4, Message::Chat(1, 2, "gg".into())),
         ]);
-        assert_eq!(Ok(expected), server.set_owner(1, 1, 2).map(Response::canonical));
-        server.assert_state(1, UserState::InRoom(1));
-        server.assert_state(2, UserState::RoomOwner(1));
-        server.assert_state(3, UserState::InRoom(1));
+        assert_eq!(Ok(expected), server.chat(2, 1, "gg".into()).map(Response::canonical));
+        assert_eq!(Err(Error::IsSpectator), server.chat(4, 1, "gg".into()));
     }
     
     #[test]
-    fn close_room() {
+    fn whisper() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        server.spectate(4, 1).unwrap();
         
-        server.assert_state(1, UserState::RoomOwner(1));
-        server.assert_state(2, UserState::InRoom(1));
+        let expected = Response::sends(3, Message::Whisper(1, 2, "psst".into()));
+        assert_eq!(Ok(expected), server.whisper(2, 1, 3, "psst".into()));
         
-        let expected = Response::sends(2, Message::RoomClosed(1));
-        assert_eq!(Ok(expected), server.leave_room(1, 1));
-        server.assert_state(1, UserState::Nowhere);
-        server.assert_state(2, UserState::Nowhere);
+        // the owner is a player too
+        let expected = Response::sends(1, Message::Whisper(1, 2, "psst".into()));
+        assert_eq!(Ok(expected), server.whisper(2, 1, 1, "psst".into()));
+        let expected = Response::sends(2, Message::Whisper(1, 1, "psst".into()));
+        assert_eq!(Ok(expected), server.whisper(1, 1, 2, "psst".into()));
+        
+        assert_eq!(Err(Error::NoSuchUser), server.whisper(2, 1, 2, "psst".into()));
+        assert_eq!(Err(Error::NoSuchUser), server.whisper(2, 1, 4, "psst".into()));
+        assert_eq!(Err(Error::NotInThatRoom), server.whisper(4, 1, 2, "psst".into()));
     }
     
     #[test]
-    fn owner_send() {
+    fn signal() {
         let mut server = Server::new(4);
-        server.add_user().unwrap();
-        server.add_user().unwrap();
-        server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        for _ in 0..4 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
-        server.ask_join(3, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
-        server.accept_join(1, 1, 3).unwrap();
+        server.spectate(3, 1).unwrap();
         
-        let expected = Response::sends_all([
-            (2, Message::ReceivedBroadcast(1, "whee".into())),
-            (3, Message::ReceivedBroadcast(1, "whee".into())),
-        ]);
-        assert_eq!(Ok(expected), server.send(1, 1, "whee".into()));
+        let expected = Response::sends(2, Message::Signal(Signal::Offer, 1, 1, "sdp".into()));
+        assert_eq!(Ok(expected), server.signal(1, 1, 2, Signal::Offer, "sdp".into()));
+        let expected = Response::sends(1, Message::Signal(Signal::Answer, 1, 2, "sdp".into()));
+        assert_eq!(Ok(expected), server.signal(2, 1, 1, Signal::Answer, "sdp".into()));
+        assert_eq!(Err(Error::NoSuchUser), server.signal(2, 1, 2, Signal::IceCandidate, "c".into()));
+        assert_eq!(Err(Error::NoSuchUser), server.signal(2, 1, 3, Signal::IceCandidate, "c".into()));
+        assert_eq!(Err(Error::IsSpectator), server.signal(3, 1, 1, Signal::IceCandidate, "c".into()));
+        assert_eq!(Err(Error::NotInThatRoom), server.signal(4, 1, 1, Signal::IceCandidate, "c".into()));
     }
     
     #[test]
-    fn member_send() {
+    fn history_replay() {
         let mut server = Server::new(4);
-        server.add_user().unwrap();
-        server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        for _ in 0..3 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
+        for i in 0..MAX_HISTORY_LENGTH + 1 {
+            server.send(1, 1, i.to_string().into()).unwrap();
+        }
+        // only the owner's broadcasts are kept
+        server.send(2, 1, "move".into()).unwrap();
         
-        let expected = Response::sends(1, Message::ReceivedFrom(1, 2, "whee".into()));
-        assert_eq!(Ok(expected), server.send(2, 1, "whee".into()));
+        let history = server.room(1).unwrap().history.clone();
+        assert_eq!(MAX_HISTORY_LENGTH, history.len());
+        assert_eq!("1", &*history[0]);
+        
+        let response = server.spectate(3, 1).unwrap();
+        assert_eq!(Some(Message::RoomSpectating(1)), response.returns);
+        let replayed: Vec<_> = history.into_iter()
+            .map(|payload| (3, Message::Replayed(1, payload)))
+            .collect();
+        assert_eq!(replayed, response.sends[1..]);
+    }
+    
+    #[test]
+    fn turn_order() {
+        let mut server = Server::new(4);
+        for _ in 0..3 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.spectate(3, 1).unwrap();
+        
+        assert_eq!(Err(Error::NotTurnBased), server.end_turn(1, 1));
+        assert_eq!(Err(Error::NotRoomOwner), server.set_turn_order(2, 1, vec![2, 1]));
+        assert_eq!(Err(Error::InvalidTurnOrder), server.set_turn_order(1, 1, vec![2, 3]));
+        assert_eq!(Err(Error::InvalidTurnOrder), server.set_turn_order(1, 1, vec![2, 2]));
+        
+        let turn = Message::Turn(1, 1, 2);
+        let expected = Response::sends_all([(1, turn.clone()), (2, turn.clone()), (3, turn)]);
+        assert_eq!(Ok(expected), server.set_turn_order(1, 1, vec![2, 1]));
+        
+        // only the player whose turn it is can send
+        assert_eq!(Err(Error::NotYourTurn), server.send(1, 1, "x".into()));
+        assert_eq!(Err(Error::IsSpectator), server.send(3, 1, "x".into()));
+        let expected = Response::sends(1, Message::ReceivedOnTurn(1, 1, 2, "x".into()));
+        assert_eq!(Ok(expected), server.send(2, 1, "x".into()));
+        
+        assert_eq!(Err(Error::NotYourTurn), server.end_turn(3, 1));
+        server.end_turn(2, 1).unwrap();
+        let expected = Response::sends_all([
+            (2, Message::ReceivedOnTurn(1, 2, 1, "y".into())),
+            (3, Message::ReceivedOnTurn(1, 2, 1, "y".into())),
+        ]);
+        assert_eq!(Ok(expected), server.send(1, 1, "y".into()));
+        
+        // the owner can end anyone's turn
+        server.end_turn(1, 1).unwrap();
+        server.end_turn(1, 1).unwrap();
+        assert_eq!(Err(Error::NotYourTurn), server.send(2, 1, "z".into()));
+        
+        let expected = Response::sends_all([(1, Message::NoTurnOrder(1)), (2, Message::NoTurnOrder(1)), (3, Message::NoTurnOrder(1))]);
+        assert_eq!(Ok(expected), server.set_turn_order(1, 1, Vec::new()));
+        assert_eq!(Ok(Response::sends(1, Message::ReceivedFrom(1, 2, "z".into()))), server.send(2, 1, "z".into()));
+    }
+    
+    #[test]
+    fn turn_passes_when_player_leaves() {
+        let mut server = Server::new(4);
+        for _ in 0..3 {
+            server.add_user().unwrap();
+        }
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        for user_id in [2, 3] {
+            server.ask_join(user_id, 1, "please".into()).unwrap();
+            server.accept_join(1, 1, user_id).unwrap();
+        }
+        server.set_turn_order(1, 1, vec![2, 3]).unwrap();
+        
+        let expected = Response::sends_all([
+            (1, Message::PlayerLeft(1, 2.into())),
+            (3, Message::PlayerLeft(1, 2.into())),
+            (1, Message::Turn(1, 2, 3)),
+            (3, Message::Turn(1, 2, 3)),
+        ]);
+        assert_eq!(Ok(expected), server.leave_room(2, 1));
+        
+        let expected = Response::sends_all([
+            (1, Message::PlayerLeft(1, 3.into())),
+            (1, Message::NoTurnOrder(1)),
+        ]);
+        assert_eq!(Ok(expected), server.leave_room(3, 1));
+        assert!(server.room(1).unwrap().turns.is_none());
     }
     
     #[test]
@@ -476,7 +3556,7 @@ mod test {
         server.add_user().unwrap();
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.ask_join(3, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
@@ -492,7 +3572,7 @@ mod test {
         server.add_user().unwrap();
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.ask_join(3, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
@@ -502,32 +3582,341 @@ mod test {
         assert_eq!(Ok(expected), server.echo_from(1, 1, 2, "whee".into()));
     }
     
+    #[test]
+    fn admin_login_backoff() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .admin_password(Some("hunter2".into()))
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        
+        // a failure from one connection holds up attempts from every other
+        assert_eq!(Err(Error::IncorrectPassword), server.admin_login(1, "hunter3"));
+        assert_eq!(Err(Error::RateLimited), server.admin_login(2, "hunter2"));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Err(Error::IncorrectPassword), server.admin_login(2, "hunter"));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Err(Error::RateLimited), server.admin_login(2, "hunter2"));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(ok(Message::AdminOk), server.admin_login(2, "hunter2"));
+        assert!(!server.get_user(1).unwrap().is_admin);
+        
+        let mut server = Server::new(1);
+        server.add_user().unwrap();
+        assert_eq!(Err(Error::IncorrectPassword), server.admin_login(1, ""));
+    }
+    
+    #[test]
+    fn timeline() {
+        let mut server = ServerBuilder::new()
+            .admin_password(Some("hunter2".into()))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.set_owner(1, 1, 2).unwrap();
+        server.leave_room(1, 1).unwrap();
+        server.leave_room(2, 1).unwrap();
+        
+        assert_eq!(Err(Error::NotAdmin), server.get_timeline(3, 1));
+        assert_eq!(ok(Message::AdminOk), server.admin_login(3, "hunter2"));
+        
+        let Ok(Response {returns: Some(Message::Timeline(1, timeline)), ..}) = server.get_timeline(3, 1) else {
+            panic!("expected a timeline");
+        };
+        let events: Vec<_> = timeline.into_iter()
+            .map(|entry| entry.event)
+            .collect();
+        assert_eq!(vec![
+            RoomEvent::Created(1),
+            RoomEvent::JoinRequested(2),
+            RoomEvent::Joined(2),
+            RoomEvent::OwnerChanged(2),
+            RoomEvent::Left(1),
+            RoomEvent::Closed,
+        ], events);
+    }
+    
+    #[test]
+    fn announce() {
+        let mut server = ServerBuilder::new()
+            .admin_password(Some("hunter2".into()))
+            .build();
+        for _ in 0..4 {
+            server.add_user().unwrap();
+        }
+        server.create_room(2, "hello".into(), Vec::new()).unwrap();
+        server.disconnect_user(4).unwrap();
+        
+        assert_eq!(Err(Error::NotAdmin), server.announce(1, Audience::Everyone, "hi".into()));
+        server.admin_login(1, "hunter2").unwrap();
+        
+        let expected = Response::to_all([1, 2, 3])
+            .msg(Message::Announcement("hi".into()))
+            .returning(Message::Announced(3));
+        assert_eq!(Ok(expected), server.announce(1, Audience::Everyone, "hi".into()));
+        
+        // a join request doesn't count as being in a game
+        server.handle_request(3, Request::AskJoinRoom(1, "hi".into(), None));
+        let expected = Response::sends(2, Message::Announcement("hi".into()))
+            .returning(Message::Announced(1));
+        assert_eq!(Ok(expected), server.announce(1, Audience::InRooms, "hi".into()));
+    }
+    
+    #[test]
+    fn reload_config() {
+        let mut server = ServerBuilder::new()
+            .max_connections(2)
+            .admin_password(Some("hunter2".into()))
+            .reloader(Arc::new(|| Ok(ServerBuilder::new().max_connections(3))))
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        assert_eq!(None, server.add_user());
+        
+        assert_eq!(Err(Error::NotAdmin), server.reload_config(1));
+        server.admin_login(1, "hunter2").unwrap();
+        assert_eq!(ok(Message::ConfigReloaded), server.reload_config(1));
+        assert_eq!(Some(3), server.add_user());
+        
+        let mut server = Server::new(2);
+        assert!(matches!(server.reload(), Err(Error::ReloadFailed(_))));
+        
+        // a match of three players needs room for two besides the owner
+        let mut server = ServerBuilder::new()
+            .match_size(3)
+            .reloader(Arc::new(|| Ok(ServerBuilder::new().max_room_members(Some(1)))))
+            .build();
+        assert!(matches!(server.reload(), Err(Error::ReloadFailed(_))));
+        assert_eq!(None, server.max_room_members);
+    }
+    
+    #[test]
+    fn advance_clock() {
+        let clock = Arc::new(SimulatedClock::new(UNIX_EPOCH));
+        let mut server = ServerBuilder::new()
+            .admin_password(Some("hunter2".into()))
+            .clock(clock.clone())
+            .build();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        
+        assert_eq!(Err(Error::NotAdmin), server.advance_clock(1, 60));
+        server.admin_login(1, "hunter2").unwrap();
+        assert_eq!(ok(Message::Clock(60)), server.advance_clock(1, 60));
+        
+        server.create_room(2, "hello".into(), Vec::new()).unwrap();
+        let Ok(Response {returns: Some(Message::Timeline(1, timeline)), ..}) = server.get_timeline(1, 1) else {
+            panic!("expected a timeline");
+        };
+        assert_eq!(60, timeline[0].time);
+        
+        let mut real = ServerBuilder::new()
+            .admin_password(Some("hunter2".into()))
+            .build();
+        real.add_user().unwrap();
+        real.admin_login(1, "hunter2").unwrap();
+        assert_eq!(Err(Error::ClockNotSimulated), real.advance_clock(1, 60));
+    }
+    
     #[test]
     fn owner_quit_during_game() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         
-        server.assert_state(1, UserState::RoomOwner(1));
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
         
         assert_eq!(Ok(Response::empty()), server.remove_user(1));
         assert_eq!(Error::NoSuchUser, server.get_user(1).unwrap_err());
     }
     
+    #[test]
+    fn owner_quit_migrates_host() {
+        let mut server = Server::new(5);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.spectate(4, 1).unwrap();
+        server.ask_join(5, 1, "please".into()).unwrap();
+        
+        let expected = Response::sends_all([
+            (2, Message::PlayerLeft(1, 1.into())),
+            (2, Message::ChangedOwner(1, 3)),
+            (3, Message::PlayerLeft(1, 1.into())),
+            (3, Message::ChangedOwner(1, 3)),
+            (3, Message::ListJoinRequests(1, vec![5])),
+            (4, Message::PlayerLeft(1, 1.into())),
+            (4, Message::ChangedOwner(1, 3)),
+        ]);
+        assert_eq!(Ok(expected), server.remove_user(1).map(Response::canonical));
+        server.assert_rooms(3, &[(1, Membership::Owner)]);
+        server.assert_rooms(2, &[(1, Membership::Member)]);
+        server.assert_rooms(5, &[(1, Membership::RequestedJoin)]);
+    }
+    
     #[test]
     fn member_quit_during_game() {
         let mut server = Server::new(4);
         server.add_user().unwrap();
         server.add_user().unwrap();
-        server.create_room(1, "hello".into()).unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
         server.ask_join(2, 1, "please".into()).unwrap();
         server.accept_join(1, 1, 2).unwrap();
         
-        server.assert_state(1, UserState::RoomOwner(1));
-        server.assert_state(2, UserState::InRoom(1));
+        server.assert_rooms(1, &[(1, Membership::Owner)]);
+        server.assert_rooms(2, &[(1, Membership::Member)]);
         
-        let expected = Response::sends(1, Message::PlayerLeft(1, 2));
+        let expected = Response::sends(1, Message::PlayerLeft(1, 2.into()));
         assert_eq!(Ok(expected), server.remove_user(2));
         assert_eq!(Error::NoSuchUser, server.get_user(2).unwrap_err());
     }
+    
+    #[test]
+    fn member_quit_notifies_members() {
+        let mut server = Server::new(4);
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.add_user().unwrap();
+        server.create_room(1, "hello".into(), Vec::new()).unwrap();
+        server.ask_join(2, 1, "please".into()).unwrap();
+        server.ask_join(3, 1, "please".into()).unwrap();
+        server.accept_join(1, 1, 2).unwrap();
+        server.accept_join(1, 1, 3).unwrap();
+        
+        let expected = Response::sends_all([
+            (1, Message::PlayerLeft(1, 3.into())),
+            (2, Message::PlayerLeft(1, 3.into())),
+        ]);
+        assert_eq!(Ok(expected), server.remove_user(3).map(Response::canonical));
+    }
+    
+    /// Checks what the server's state must always satisfy, whatever requests
+    /// it has handled.
+    pub(crate) fn check_invariants(server: &Server) {
+        for (&room_id, room) in &server.rooms {
+            assert_eq!(room_id, room.id);
+            let owner = server.users.get(&room.owner_id).expect("room has no owner");
+            assert_eq!(Some(Membership::Owner), owner.membership(room_id));
+            
+            let lists = [
+                (&room.members, Membership::Member),
+                (&room.join_requests, Membership::RequestedJoin),
+                (&room.spectators, Membership::Spectating),
+            ];
+            for (user_ids, membership) in lists {
+                for user_id in user_ids {
+                    let user = server.users.get(user_id).expect("dangling user in room");
+                    assert_eq!(Some(membership), user.membership(room_id), "user {user_id} in room {room_id}");
+                }
+            }
+            let mut everyone: Vec<UserID> = room.everyone().collect();
+            everyone.extend(&room.join_requests);
+            let count = everyone.len();
+            everyone.sort();
+            everyone.dedup();
+            assert_eq!(count, everyone.len(), "user listed twice in room {room_id}");
+            if let Some(capacity) = room.capacity {
+                assert!(room.members.len() <= capacity);
+            }
+        }
+        
+        let mut queued = server.matchmaker.queued_users();
+        for (&user_id, user) in &server.users {
+            assert_eq!(user_id, user.id);
+            for (room_id, membership) in &user.rooms {
+                let room = server.rooms.get(room_id).expect("user in a room which doesn't exist");
+                let listed = match membership {
+                    Membership::Owner => room.owner_id == user_id,
+                    Membership::Member => room.members.contains(&user_id),
+                    Membership::RequestedJoin => room.join_requests.contains(&user_id),
+                    Membership::Spectating => room.spectators.contains(&user_id),
+                };
+                assert!(listed, "user {user_id} not listed in room {room_id}");
+            }
+            assert_eq!(user.queued, queued.contains(&user_id), "user {user_id} queued wrongly");
+        }
+        let count = queued.len();
+        queued.sort();
+        queued.dedup();
+        assert_eq!(count, queued.len(), "user queued twice");
+    }
+    
+    /// A request which is likely to refer to users and rooms which exist,
+    /// but often isn't allowed.
+    fn random_request(rng: &mut ids::Random) -> Request {
+        let mut pick = |n: u32| rng.generate() % n;
+        let (room_id, user_id) = (pick(4) + 1, pick(6) + 1);
+        match pick(16) {
+            0 => Request::CreateRoom(["a", "b"][pick(2) as usize].into(), Vec::new()),
+            1 => Request::AskJoinRoom(room_id, "hi".into(), None),
+            2 => Request::AcceptJoinRoom(room_id, user_id),
+            3 => Request::RejectJoinRoom(room_id, user_id, "no".into()),
+            4 => Request::LeaveRoom(room_id),
+            5 => Request::SetOwner(room_id, user_id),
+            6 => Request::SetJoinPolicy(room_id, [JoinPolicy::AskOwner, JoinPolicy::Open][pick(2) as usize]),
+            7 => Request::Spectate(room_id, None),
+            8 => Request::Queue(["a", "b"][pick(2) as usize].into()),
+            9 => Request::Unqueue,
+            10 => Request::JoinAnyRoom("".into(), "hi".into()),
+            11 => Request::Send(room_id, "move".into()),
+            12 => Request::SendTo(room_id, user_id, "move".into()),
+            13 => Request::CloseRoom(room_id),
+            14 => Request::VoteKick(room_id, user_id),
+            _ => Request::Chat(room_id, "gg".into()),
+        }
+    }
+    
+    #[test]
+    fn random_requests_keep_invariants() {
+        let mut rng = ids::Random::seeded(ids::test_seed());
+        for _ in 0..200 {
+            let mut server = ServerBuilder::new()
+                .max_room_members(Some(2))
+                .match_size(2)
+                .build();
+            for _ in 0..6 {
+                server.add_user().unwrap();
+            }
+            let mut log = Vec::new();
+            for _ in 0..100 {
+                let user_id = rng.generate() % 6 + 1;
+                let response = match rng.generate() % 20 {
+                    // users sometimes leave, and someone else connects
+                    0 if server.users.contains_key(&user_id) => {
+                        log.push(format!("{user_id} disconnects"));
+                        server.remove_user(user_id).unwrap()
+                    },
+                    1 => {
+                        log.push("connect".to_string());
+                        server.add_user();
+                        Response::empty()
+                    },
+                    _ => {
+                        let request = random_request(&mut rng);
+                        log.push(format!("{user_id}: {request}"));
+                        server.handle_request(user_id, request)
+                    },
+                };
+                // a response can't be addressed to a user who doesn't exist
+                for (recipient, msg) in &response.sends {
+                    assert!(server.users.contains_key(recipient), "{msg} sent to {recipient} after {log:?}");
+                }
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| check_invariants(&server)));
+                assert!(result.is_ok(), "invariant broken after {log:?}");
+            }
+        }
+    }
 }
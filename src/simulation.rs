@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use async_std::task;
+use futures::StreamExt;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::client;
+use crate::clock::{Clock, SimulatedClock};
+use crate::dispatch::{Dispatcher, Event};
+use crate::ids::{IdGenerator, Random};
+use crate::models::{Membership, RoomID, UserID};
+use crate::request::Request;
+use crate::response::Message;
+use crate::server::ServerBuilder;
+use crate::server::test::check_invariants;
+use crate::transport::Conn;
+use crate::transport::test::Pipe;
+
+/// Sent to each client after every step, so that once it arrives, the
+/// simulation knows that the client has been sent everything before it.
+const SETTLED: u32 = u32::MAX;
+
+/// One thing a virtual client does.
+pub(crate) enum Step {
+    Request(Request),
+    /// Waits this long, in virtual time, before the next step.
+    Wait(Duration),
+    /// The client's connection drops; the user is removed once the grace
+    /// period has passed.
+    Disconnect,
+}
+
+struct VirtualClient {
+    user_id: UserID,
+    steps: VecDeque<Step>,
+    ready_at: SystemTime,
+    /// What the client sends to the server, until its connection closes.
+    input: Option<UnboundedSender<Vec<u8>>>,
+    output: UnboundedReceiver<Vec<u8>>,
+    /// The start of a line which hasn't been written in full yet.
+    partial: Vec<u8>,
+    inbox: Vec<Message>,
+}
+
+impl VirtualClient {
+    /// Reads the next whole line the server has written to this client.
+    async fn read_line(&mut self) -> String {
+        loop {
+            if let Some(i) = self.partial.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=i).collect();
+                return String::from_utf8_lossy(&line[..i]).into_owned();
+            }
+            let bytes = self.output.next().await.expect("connection closed");
+            self.partial.extend(bytes);
+        }
+    }
+}
+
+/// Runs scripted clients against a dispatcher, over in-memory connections,
+/// with virtual time and a seeded choice of which client acts next, so that
+/// a race which a seed finds can be replayed exactly. The dispatcher handles
+/// one event at a time, and the server's invariants are checked after every
+/// step.
+pub(crate) struct Simulation {
+    dispatcher: Dispatcher,
+    clock: Arc<SimulatedClock>,
+    rng: Random,
+    clients: Vec<VirtualClient>,
+    /// Users whose connections have dropped, and when they are removed.
+    dropped: Vec<(UserID, SystemTime)>,
+    /// What each client sent and received, in order.
+    pub(crate) trace: Vec<String>,
+}
+
+impl Simulation {
+    pub(crate) fn new(seed: u64, builder: ServerBuilder) -> Simulation {
+        let clock = Arc::new(SimulatedClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        Simulation {
+            dispatcher: Dispatcher::new(builder.clock(clock.clone()).build()),
+            clock,
+            rng: Random::seeded(seed),
+            clients: Vec::new(),
+            dropped: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+    
+    /// Connects a client which will follow this script.
+    pub(crate) fn client(&mut self, steps: impl IntoIterator<Item = Step>) -> UserID {
+        let (pipe, input, output) = Pipe::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000 + self.clients.len() as u16));
+        self.handle(Event::Connected(Conn::new(pipe), addr));
+        
+        let mut client = VirtualClient {
+            user_id: 0,
+            steps: steps.into_iter().collect(),
+            ready_at: self.clock.now(),
+            input: Some(input),
+            output,
+            partial: Vec::new(),
+            inbox: Vec::new(),
+        };
+        let welcome = task::block_on(client.read_line());
+        let Some(Message::Welcome(user_id, ..)) = client::parse_message(&welcome, |_| false) else {
+            panic!("server is full");
+        };
+        client.user_id = user_id;
+        self.clients.push(client);
+        user_id
+    }
+    
+    /// Everything the server has sent this user.
+    pub(crate) fn inbox(&self, user_id: UserID) -> &[Message] {
+        let client = self.clients.iter()
+            .find(|client| client.user_id == user_id)
+            .expect("no such client");
+        &client.inbox
+    }
+    
+    /// The rooms a user takes part in, and how, unless they have been removed.
+    pub(crate) fn rooms(&self, user_id: UserID) -> Option<Vec<(RoomID, Membership)>> {
+        self.dispatcher.server().users()
+            .into_iter()
+            .find(|user| user.id == user_id)
+            .map(|user| user.rooms.iter().map(|(&room_id, &m)| (room_id, m)).collect())
+    }
+    
+    /// Runs every script to the end, and waits for dropped users to be
+    /// removed.
+    pub(crate) fn run(&mut self) {
+        loop {
+            let now = self.clock.now();
+            let ready: Vec<usize> = (0..self.clients.len())
+                .filter(|&i| !self.clients[i].steps.is_empty() && self.clients[i].ready_at <= now)
+                .collect();
+            if ready.is_empty() && !self.advance() {
+                return;
+            } else if !ready.is_empty() {
+                let i = ready[self.rng.generate() as usize % ready.len()];
+                self.step(i);
+            }
+            self.settle();
+            check_invariants(self.dispatcher.server());
+        }
+    }
+    
+    fn step(&mut self, i: usize) {
+        let client = &mut self.clients[i];
+        let user_id = client.user_id;
+        match client.steps.pop_front().unwrap() {
+            Step::Request(request) => {
+                self.trace.push(format!("{user_id} sent {request}"));
+                let quit = request.is_quit();
+                self.send(i, format!("{request}\n"));
+                self.handle_next();
+                if quit {
+                    // the connection closes after quitting
+                    self.clients[i].steps.clear();
+                    self.clients[i].input = None;
+                    self.handle_next();
+                }
+            },
+            Step::Wait(duration) => {
+                client.ready_at = self.clock.now() + duration;
+            },
+            Step::Disconnect => {
+                client.steps.clear();
+                client.input = None;
+                self.trace.push(format!("{user_id} disconnected"));
+                self.handle_next();
+                let grace = self.dispatcher.server().disconnect_grace();
+                if !grace.is_zero() {
+                    self.dropped.push((user_id, self.clock.now() + grace));
+                }
+            },
+        }
+    }
+    
+    fn send(&mut self, i: usize, line: String) {
+        let input = self.clients[i].input.as_ref().expect("client has disconnected");
+        input.unbounded_send(line.into_bytes()).unwrap();
+    }
+    
+    /// Handles the next event from a connection, waiting for it to arrive.
+    fn handle_next(&mut self) {
+        let event = task::block_on(self.dispatcher.next_event()).expect("dispatcher closed");
+        self.handle(event);
+    }
+    
+    fn handle(&mut self, event: Event) {
+        task::block_on(self.dispatcher.handle(event)).unwrap();
+    }
+    
+    /// Waits until everything sent to each connected client has been written,
+    /// and reads it, one client at a time.
+    fn settle(&mut self) {
+        for i in 0..self.clients.len() {
+            let user_id = self.clients[i].user_id;
+            if self.clients[i].input.is_none() || !task::block_on(self.dispatcher.send(user_id, Message::Pong(SETTLED))) {
+                continue;
+            }
+            loop {
+                let line = task::block_on(self.clients[i].read_line());
+                let msg = client::parse_message(&line, |_| false).expect("invalid message");
+                if msg == Message::Pong(SETTLED) {
+                    break;
+                }
+                self.trace.push(format!("{user_id} got {line}"));
+                self.clients[i].inbox.push(msg);
+            }
+        }
+    }
+    
+    /// Moves virtual time on to when the next thing happens, returning false
+    /// if nothing else will.
+    fn advance(&mut self) -> bool {
+        let next = self.clients.iter()
+            .filter(|client| !client.steps.is_empty())
+            .map(|client| client.ready_at)
+            .chain(self.dropped.iter().map(|&(_, deadline)| deadline))
+            .min();
+        let Some(next) = next else {
+            return false;
+        };
+        if let Ok(by) = next.duration_since(self.clock.now()) {
+            self.clock.advance(by);
+        }
+        
+        let now = self.clock.now();
+        let (due, waiting): (Vec<_>, _) = std::mem::take(&mut self.dropped)
+            .into_iter()
+            .partition(|&(_, deadline)| deadline <= now);
+        self.dropped = waiting;
+        // the grace periods' timers may fire in any order
+        let mut expired: Vec<Event> = (0..due.len())
+            .map(|_| task::block_on(self.dispatcher.next_event()).expect("dispatcher closed"))
+            .collect();
+        expired.sort_by_key(|event| match event {
+            Event::GraceExpired(user_id, _) => *user_id,
+            _ => panic!("unexpected event"),
+        });
+        for event in expired {
+            if let Event::GraceExpired(user_id, _) = event {
+                self.trace.push(format!("{user_id} removed"));
+            }
+            self.handle(event);
+        }
+        self.handle(Event::SweepRooms);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::JoinPolicy;
+    use super::*;
+    
+    /// The owner quits at the same moment as someone asks to join; whichever
+    /// comes first, the joiner must end up out of the room and told so.
+    fn owner_quits_while_join_pending(seed: u64) -> Simulation {
+        let mut sim = Simulation::new(seed, ServerBuilder::new());
+        sim.client([
+            Step::Request(Request::CreateRoom("x".into(), Vec::new())),
+            Step::Wait(Duration::from_secs(1)),
+            Step::Request(Request::Quit),
+        ]);
+        let joiner = sim.client([
+            Step::Wait(Duration::from_secs(1)),
+            Step::Request(Request::AskJoinRoom(1, "hi".into(), None)),
+        ]);
+        sim.run();
+        
+        assert_eq!(Some(Vec::new()), sim.rooms(joiner));
+        let told = sim.inbox(joiner).iter().any(|msg| {
+            matches!(msg, Message::RoomClosed(1) | Message::Error(_) | Message::Failed(..))
+        });
+        assert!(told, "joiner wasn't told with seed {seed}: {:?}", sim.trace);
+        sim
+    }
+    
+    #[test]
+    fn join_pending_race() {
+        // both orders must turn up among the seeds
+        let mut orders = std::collections::HashSet::new();
+        for seed in 0..32 {
+            let sim = owner_quits_while_join_pending(seed);
+            orders.insert(sim.trace[2].clone());
+        }
+        assert_eq!(2, orders.len());
+    }
+    
+    #[test]
+    fn same_seed_same_trace() {
+        let script = || [
+            Step::Request(Request::CreateRoom("x".into(), Vec::new())),
+            Step::Request(Request::SetJoinPolicy(1, JoinPolicy::Open)),
+            Step::Wait(Duration::from_secs(5)),
+            Step::Request(Request::Send(1, "move".into())),
+            Step::Disconnect,
+        ];
+        let joiner = || [
+            Step::Request(Request::AskJoinRoom(1, "hi".into(), None)),
+            Step::Request(Request::Send(1, "hello".into())),
+            Step::Wait(Duration::from_secs(3)),
+            Step::Request(Request::LeaveRoom(1)),
+        ];
+        let run = |seed| {
+            let mut sim = Simulation::new(seed, ServerBuilder::new().disconnect_grace(Duration::from_secs(30)));
+            sim.client(script());
+            sim.client(joiner());
+            sim.client(joiner());
+            sim.run();
+            sim.trace
+        };
+        assert_eq!(run(7), run(7));
+        assert!((0..16).any(|seed| run(seed) != run(7)));
+    }
+}
@@ -0,0 +1,356 @@
+use std::time::{Duration, UNIX_EPOCH};
+use indexmap::IndexSet;
+
+use crate::friends::Friends;
+use crate::models::{JoinPolicy, Presence, Room, RoomID, User, UserID};
+use crate::turns::Turns;
+
+/// Users, rooms and friend lists saved to disk, so that open games survive
+/// a restart.
+pub(crate) struct Snapshot {
+    pub(crate) users: Vec<User>,
+    pub(crate) rooms: Vec<Room>,
+    pub(crate) friends: Friends,
+}
+
+/// Formats users and rooms as TOML, with one `[[users]]` or `[[rooms]]`
+/// table for each, and friend lists in a `[friends]` table by account.
+pub(crate) fn format<'a>(users: impl Iterator<Item = &'a User>, rooms: impl Iterator<Item = &'a Room>, friends: &Friends) -> String {
+    let mut table = toml::Table::new();
+    table.insert("users".into(), users.map(user_table).collect::<Vec<_>>().into());
+    table.insert("rooms".into(), rooms.map(room_table).collect::<Vec<_>>().into());
+    let lists: toml::Table = friends.lists()
+        .map(|(account, list)| (account.to_string(), list.iter().cloned().collect::<Vec<_>>().into()))
+        .collect();
+    table.insert("friends".into(), lists.into());
+    table.to_string()
+}
+
+fn ids(ids: &IndexSet<UserID>) -> Vec<i64> {
+    ids.iter().map(|&id| id.into()).collect()
+}
+
+fn user_table(user: &User) -> toml::Table {
+    let mut table = toml::Table::new();
+    table.insert("id".into(), i64::from(user.id).into());
+    if let Some(name) = &user.name {
+        table.insert("name".into(), name.as_str().into());
+    }
+    if let Some(version) = &user.client_version {
+        table.insert("client_version".into(), version.as_str().into());
+    }
+    if let Some(account) = &user.account {
+        table.insert("account".into(), account.as_str().into());
+    }
+    table.insert("admin".into(), user.is_admin.into());
+    if let Some(latency_ms) = user.latency_ms {
+        table.insert("latency_ms".into(), i64::from(latency_ms).into());
+    }
+    table.insert("session".into(), user.session_id.as_str().into());
+    table.insert("resume_token".into(), user.resume_token.as_str().into());
+    if let Some(token) = &user.previous_resume_token {
+        table.insert("previous_resume_token".into(), token.as_str().into());
+    }
+    table
+}
+
+fn room_table(room: &Room) -> toml::Table {
+    let mut table = toml::Table::new();
+    table.insert("id".into(), i64::from(room.id).into());
+    table.insert("owner".into(), i64::from(room.owner_id).into());
+    table.insert("data".into(), room.data.as_ref().into());
+    table.insert("members".into(), ids(&room.members).into());
+    table.insert("join_requests".into(), ids(&room.join_requests).into());
+    table.insert("spectators".into(), ids(&room.spectators).into());
+    if !room.invited.is_empty() {
+        table.insert("invited".into(), ids(&room.invited).into());
+    }
+    if let Some(capacity) = room.capacity {
+        table.insert("capacity".into(), (capacity as i64).into());
+    }
+    if let Some(max) = room.max_join_requests {
+        table.insert("max_join_requests".into(), (max as i64).into());
+    }
+    table.insert("join_policy".into(), room.join_policy.to_string().into());
+    table.insert("presence".into(), room.presence.to_string().into());
+    if let Some(pattern) = room.schema_pattern() {
+        table.insert("schema".into(), pattern.into());
+    }
+    if let Some(password) = &room.password {
+        table.insert("password".into(), password.as_str().into());
+    }
+    if let Some(cooldown) = room.rejoin_cooldown {
+        table.insert("rejoin_cooldown".into(), (cooldown.as_secs() as i64).into());
+    }
+    if let Some(turns) = &room.turns {
+        // saved from the current player, who is first when restored
+        let order: Vec<i64> = turns.upcoming().map(i64::from).collect();
+        table.insert("turn_order".into(), order.into());
+        table.insert("turn".into(), (turns.turn() as i64).into());
+    }
+    if let Some(name) = &room.name {
+        table.insert("name".into(), name.as_str().into());
+    }
+    table.insert("tags".into(), room.tags.clone().into());
+    if let Some(created_at) = room.created_at {
+        let millis = created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        table.insert("created_at".into(), (millis as i64).into());
+    }
+    let history: Vec<&str> = room.history.iter().map(|payload| &**payload).collect();
+    table.insert("history".into(), history.into());
+    table
+}
+
+/// Parses a saved snapshot. Which rooms users are in is only saved in the
+/// rooms, so the caller must give users their memberships, and decide
+/// whether they are still connected.
+pub(crate) fn parse(text: &str) -> Result<Snapshot, String> {
+    let table: toml::Table = text.parse()
+        .map_err(|e: toml::de::Error| e.message().to_string())?;
+    let fields = Fields(&table, "snapshot");
+    Ok(Snapshot {
+        users: fields.tables("users")?.into_iter().map(parse_user).collect::<Result<_, _>>()?,
+        rooms: fields.tables("rooms")?.into_iter().map(parse_room).collect::<Result<_, _>>()?,
+        friends: parse_friends(fields)?,
+    })
+}
+
+/// Friend lists are only checked for being lists of names, as they were
+/// checked when the friends were added.
+fn parse_friends(fields: Fields) -> Result<Friends, String> {
+    let mut friends = Friends::new();
+    let Some(value) = fields.0.get("friends") else {
+        return Ok(friends);
+    };
+    let lists = value.as_table()
+        .ok_or_else(|| fields.invalid("friends"))?;
+    for account in lists.keys() {
+        let list = Fields(lists, "friends").optional_strings(account)?;
+        friends.set_list(account.clone(), list.into_iter().collect());
+    }
+    Ok(friends)
+}
+
+fn parse_user(fields: Fields) -> Result<User, String> {
+    let mut user = User::new(fields.id("id")?);
+    user.name = fields.optional_string("name")?;
+    user.client_version = fields.optional_string("client_version")?;
+    user.account = fields.optional_string("account")?;
+    user.is_admin = fields.bool("admin")?;
+    user.latency_ms = fields.optional_id("latency_ms")?;
+    // older state files don't have this, so the session gets a new ID
+    if let Some(session_id) = fields.optional_string("session")? {
+        user.session_id = session_id;
+    }
+    user.resume_token = fields.string("resume_token")?;
+    user.previous_resume_token = fields.optional_string("previous_resume_token")?;
+    Ok(user)
+}
+
+fn parse_room(fields: Fields) -> Result<Room, String> {
+    let mut room = Room::new(fields.id("id")?, fields.id("owner")?, fields.string("data")?);
+    room.members = fields.ids("members")?;
+    room.join_requests = fields.ids("join_requests")?;
+    room.spectators = fields.ids("spectators")?;
+    room.invited = fields.optional_ids("invited")?;
+    room.capacity = fields.optional_id("capacity")?.map(|n| n as usize);
+    room.max_join_requests = fields.optional_count("max_join_requests")?.map(|n| n as usize);
+    room.join_policy = match fields.string("join_policy")?.as_str() {
+        "ASK" => JoinPolicy::AskOwner,
+        "OPEN" => JoinPolicy::Open,
+        _ => return Err(fields.invalid("join_policy")),
+    };
+    // older state files don't have this
+    room.presence = match fields.optional_string("presence")?.as_deref() {
+        None | Some("OWNER") => Presence::Owner,
+        Some("ALL") => Presence::All,
+        Some(_) => return Err(fields.invalid("presence")),
+    };
+    if let Some(pattern) = fields.optional_string("schema")? {
+        room.set_schema(&pattern)
+            .map_err(|_| fields.invalid("schema"))?;
+    }
+    room.password = fields.optional_string("password")?;
+    room.name = fields.optional_string("name")?;
+    room.rejoin_cooldown = fields.optional_count("rejoin_cooldown")?.map(Duration::from_secs);
+    if let Some(turn) = fields.optional_count("turn")? {
+        let order = fields.ids("turn_order")?;
+        if order.is_empty() {
+            return Err(fields.invalid("turn_order"));
+        }
+        room.turns = Some(Turns::resume(order.into_iter().collect(), turn));
+    }
+    // snapshots from older versions have no tags, creation time or history
+    room.tags = fields.optional_strings("tags")?;
+    room.created_at = fields.optional_count("created_at")?
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+    for payload in fields.optional_strings("history")? {
+        room.remember(payload.into());
+    }
+    Ok(room)
+}
+
+/// The fields of one table in a snapshot, named by `.1` in error messages.
+#[derive(Clone, Copy)]
+struct Fields<'a>(&'a toml::Table, &'static str);
+
+impl <'a> Fields<'a> {
+    fn invalid(self, key: &str) -> String {
+        format!("invalid '{key}' in {}", self.1)
+    }
+    
+    fn tables(self, key: &'static str) -> Result<Vec<Fields<'a>>, String> {
+        let Some(value) = self.0.get(key) else {
+            return Ok(Vec::new());
+        };
+        value.as_array()
+            .ok_or_else(|| self.invalid(key))?
+            .iter()
+            .map(|value| value.as_table().map(|table| Fields(table, key)).ok_or_else(|| self.invalid(key)))
+            .collect()
+    }
+    
+    fn optional_id(self, key: &str) -> Result<Option<RoomID>, String> {
+        self.0.get(key)
+            .map(|value| value.as_integer()
+                .and_then(|n| n.try_into().ok())
+                .ok_or_else(|| self.invalid(key)))
+            .transpose()
+    }
+    
+    fn optional_count(self, key: &str) -> Result<Option<u64>, String> {
+        self.0.get(key)
+            .map(|value| value.as_integer()
+                .and_then(|n| n.try_into().ok())
+                .ok_or_else(|| self.invalid(key)))
+            .transpose()
+    }
+    
+    fn id(self, key: &str) -> Result<RoomID, String> {
+        self.optional_id(key)?.ok_or_else(|| self.invalid(key))
+    }
+    
+    fn ids(self, key: &str) -> Result<IndexSet<UserID>, String> {
+        self.0.get(key)
+            .and_then(toml::Value::as_array)
+            .ok_or_else(|| self.invalid(key))?
+            .iter()
+            .map(|value| value.as_integer()
+                .and_then(|n| n.try_into().ok())
+                .ok_or_else(|| self.invalid(key)))
+            .collect()
+    }
+    
+    fn optional_ids(self, key: &str) -> Result<IndexSet<UserID>, String> {
+        match self.0.get(key) {
+            Some(_) => self.ids(key),
+            None => Ok(IndexSet::new()),
+        }
+    }
+    
+    fn optional_string(self, key: &str) -> Result<Option<String>, String> {
+        self.0.get(key)
+            .map(|value| value.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| self.invalid(key)))
+            .transpose()
+    }
+    
+    fn optional_strings(self, key: &str) -> Result<Vec<String>, String> {
+        let Some(value) = self.0.get(key) else {
+            return Ok(Vec::new());
+        };
+        value.as_array()
+            .ok_or_else(|| self.invalid(key))?
+            .iter()
+            .map(|value| value.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| self.invalid(key)))
+            .collect()
+    }
+    
+    fn string(self, key: &str) -> Result<String, String> {
+        self.optional_string(key)?.ok_or_else(|| self.invalid(key))
+    }
+    
+    fn bool(self, key: &str) -> Result<bool, String> {
+        self.0.get(key)
+            .and_then(toml::Value::as_bool)
+            .ok_or_else(|| self.invalid(key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn round_trip() {
+        let mut owner = User::new(1);
+        owner.client_version = Some("1.2".into());
+        owner.name = Some("alice".into());
+        owner.account = Some("alice@example".into());
+        let mut member = User::new(2);
+        member.latency_ms = Some(40);
+        let mut room = Room::new(5, 1, "level=3".into());
+        room.members.insert(2);
+        room.capacity = Some(4);
+        room.max_join_requests = Some(8);
+        room.join_policy = JoinPolicy::Open;
+        room.presence = Presence::All;
+        room.invited.insert(3);
+        room.set_schema("[a-z]+").unwrap();
+        room.set_password("hunter2".into());
+        room.rejoin_cooldown = Some(Duration::from_secs(30));
+        let mut turns = Turns::new(vec![1, 2]);
+        turns.advance();
+        room.turns = Some(turns);
+        room.remember("a|b".into());
+        room.tags = vec!["coop".into()];
+        room.name = Some("Friday Night Draft".into());
+        room.created_at = Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+        
+        let mut friends = Friends::new();
+        friends.add("alice@example", "bob@example").unwrap();
+        friends.add("alice@example", "carol").unwrap();
+        
+        let text = format([&owner, &member].into_iter(), [&room].into_iter(), &friends);
+        let snapshot = parse(&text).unwrap();
+        assert_eq!(format!("{:?}", [owner, member]), format!("{:?}", snapshot.users));
+        assert_eq!(vec!["bob@example", "carol"], snapshot.friends.list("alice@example").collect::<Vec<_>>());
+        
+        let restored = &snapshot.rooms[0];
+        assert_eq!(IndexSet::from([2]), restored.members);
+        assert_eq!(Some(4), restored.capacity);
+        assert_eq!(Some(8), restored.max_join_requests);
+        assert_eq!(JoinPolicy::Open, restored.join_policy);
+        assert_eq!(Presence::All, restored.presence);
+        assert_eq!(IndexSet::from([3]), restored.invited);
+        assert_eq!(Some("[a-z]+"), restored.schema_pattern());
+        assert_eq!(Some("hunter2"), restored.password.as_deref());
+        assert_eq!(Some(Duration::from_secs(30)), restored.rejoin_cooldown);
+        assert_eq!("level=3", &*restored.data);
+        assert_eq!(Some(Turns::resume(vec![2, 1], 2)), restored.turns);
+        assert_eq!(vec!["coop"], restored.tags);
+        assert_eq!(Some("Friday Night Draft"), restored.name.as_deref());
+        assert_eq!(room.created_at, restored.created_at);
+        assert_eq!(vec!["a|b"], restored.history.iter().map(|p| &**p).collect::<Vec<_>>());
+    }
+    
+    #[test]
+    fn invalid_snapshot() {
+        assert_eq!(0, parse("").unwrap().users.len());
+        assert_eq!(
+            Some("invalid 'alice' in friends".to_string()),
+            parse("[friends]\nalice = \"bob\"\n").err(),
+        );
+        assert_eq!(
+            Some("invalid 'admin' in users".to_string()),
+            parse("[[users]]\nid = 1\nadmin = \"no\"\nresume_token = \"x\"\n").err(),
+        );
+        assert_eq!(
+            Some("invalid 'id' in rooms".to_string()),
+            parse("[[rooms]]\nid = -1\n").err(),
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::path::Path;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::warn;
+
+use crate::models::{Room, RoomID, UserID};
+use crate::timeline::{RoomEvent, RoomStore, TimelineEntry, MAX_EVENTS_PER_ROOM};
+
+/// Room IDs are reused, so each room gets its own `key`; a room's current
+/// row is the newest one with its ID which hasn't closed.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        key INTEGER PRIMARY KEY,
+        room_id INTEGER NOT NULL,
+        owner_id INTEGER NOT NULL,
+        data TEXT NOT NULL DEFAULT '',
+        members TEXT NOT NULL DEFAULT '',
+        capacity INTEGER,
+        join_policy TEXT NOT NULL DEFAULT 'ASK',
+        created_at INTEGER NOT NULL,
+        closed_at INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS rooms_by_id ON rooms (room_id);
+    CREATE TABLE IF NOT EXISTS room_events (
+        room_key INTEGER NOT NULL REFERENCES rooms (key),
+        time INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        user_id INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS room_events_by_room ON room_events (room_key);
+";
+
+/// Keeps every room and its full timeline in an SQLite database, so that
+/// they outlast the server and can be queried by other tools.
+pub(crate) struct SqliteStore {
+    db: Connection,
+}
+
+impl SqliteStore {
+    pub(crate) fn open(path: &Path) -> rusqlite::Result<SqliteStore> {
+        let db = Connection::open(path)?;
+        db.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {db})
+    }
+    
+    fn open_room_key(&self, room_id: RoomID) -> rusqlite::Result<Option<i64>> {
+        self.db.query_row(
+            "SELECT key FROM rooms WHERE room_id = ? AND closed_at IS NULL ORDER BY key DESC LIMIT 1",
+            [room_id],
+            |row| row.get(0),
+        ).optional()
+    }
+    
+    fn try_record(&mut self, room_id: RoomID, time: u64, event: RoomEvent) -> rusqlite::Result<()> {
+        if let RoomEvent::Created(owner_id) = event {
+            self.db.execute(
+                "INSERT INTO rooms (room_id, owner_id, created_at) VALUES (?, ?, ?)",
+                params![room_id, owner_id, time],
+            )?;
+        }
+        // rooms created before the store was attached have no row
+        let Some(key) = self.open_room_key(room_id)? else {
+            return Ok(());
+        };
+        
+        self.db.execute(
+            "INSERT INTO room_events (room_key, time, event, user_id) VALUES (?, ?, ?, ?)",
+            params![key, time, event.name(), event.user_id()],
+        )?;
+        if let RoomEvent::Closed = event {
+            self.db.execute("UPDATE rooms SET closed_at = ? WHERE key = ?", params![time, key])?;
+        }
+        Ok(())
+    }
+    
+    fn try_save_room(&mut self, room: &Room) -> rusqlite::Result<()> {
+        let Some(key) = self.open_room_key(room.id)? else {
+            return Ok(());
+        };
+        let members: Vec<String> = room.members.iter().map(UserID::to_string).collect();
+        self.db.execute(
+            "UPDATE rooms SET owner_id = ?, data = ?, members = ?, capacity = ?, join_policy = ? WHERE key = ?",
+            params![
+                room.owner_id,
+                &*room.data,
+                members.join(","),
+                room.capacity,
+                room.join_policy.to_string(),
+                key,
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn try_timeline(&self, room_id: RoomID) -> rusqlite::Result<Option<Vec<TimelineEntry>>> {
+        let key: Option<i64> = self.db.query_row(
+            "SELECT key FROM rooms WHERE room_id = ? ORDER BY key DESC LIMIT 1",
+            [room_id],
+            |row| row.get(0),
+        ).optional()?;
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        
+        let mut query = self.db.prepare(
+            "SELECT time, event, user_id FROM room_events WHERE room_key = ? ORDER BY rowid DESC LIMIT ?",
+        )?;
+        let rows = query.query_map(params![key, MAX_EVENTS_PER_ROOM], |row| {
+            let (time, name, user_id): (u64, String, _) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            Ok(RoomEvent::parse(&name, user_id).map(|event| TimelineEntry {time, event}))
+        })?;
+        
+        let mut entries = Vec::new();
+        for entry in rows {
+            entries.extend(entry?);
+        }
+        entries.reverse();
+        Ok(Some(entries))
+    }
+}
+
+/// A failing store shouldn't take the server down with it, so errors are
+/// only logged.
+fn log_error<T>(result: rusqlite::Result<T>) -> Option<T> {
+    result.map_err(|e| warn!(error = %e, "Room store error")).ok()
+}
+
+impl RoomStore for SqliteStore {
+    fn record(&mut self, room_id: RoomID, time: u64, event: RoomEvent) {
+        log_error(self.try_record(room_id, time, event));
+    }
+    
+    fn save_room(&mut self, room: &Room) {
+        log_error(self.try_save_room(room));
+    }
+    
+    fn timeline(&self, room_id: RoomID) -> Option<Vec<TimelineEntry>> {
+        log_error(self.try_timeline(room_id)).flatten()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn record_and_query() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        store.record(1, 10, RoomEvent::Created(1));
+        let mut room = Room::new(1, 1, "hello".into());
+        room.members.insert(2);
+        store.save_room(&room);
+        store.record(1, 11, RoomEvent::Joined(2));
+        store.record(1, 12, RoomEvent::Closed);
+        
+        let expected = vec![
+            TimelineEntry {time: 10, event: RoomEvent::Created(1)},
+            TimelineEntry {time: 11, event: RoomEvent::Joined(2)},
+            TimelineEntry {time: 12, event: RoomEvent::Closed},
+        ];
+        assert_eq!(Some(expected), store.timeline(1));
+        assert_eq!(None, store.timeline(2));
+        
+        let (data, members, closed_at): (String, String, Option<u64>) = store.db.query_row(
+            "SELECT data, members, closed_at FROM rooms WHERE room_id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert_eq!(("hello".to_string(), "2".to_string(), Some(12)), (data, members, closed_at));
+    }
+    
+    #[test]
+    fn reused_id() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        store.record(1, 0, RoomEvent::Created(1));
+        store.record(1, 1, RoomEvent::Closed);
+        store.record(1, 2, RoomEvent::Created(2));
+        
+        let expected = vec![TimelineEntry {time: 2, event: RoomEvent::Created(2)}];
+        assert_eq!(Some(expected), store.timeline(1));
+        
+        let rooms: i64 = store.db.query_row("SELECT COUNT(*) FROM rooms", [], |row| row.get(0)).unwrap();
+        assert_eq!(2, rooms);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::models::RoomID;
+use crate::request::Request;
+use crate::response::Message;
+
+/// How many messages and bytes a connection has received and sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounts {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// Traffic counters which the connection updates as it goes, shared with
+/// the dispatcher so that they can be read while it is still open. A
+/// connection's traffic is also added to the server's total, if it has one.
+#[derive(Default)]
+pub(crate) struct Traffic {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    total: Option<Arc<Traffic>>,
+}
+
+impl Traffic {
+    pub(crate) fn counted_in(total: &Arc<Traffic>) -> Traffic {
+        Traffic {total: Some(Arc::clone(total)), ..Traffic::default()}
+    }
+    
+    pub(crate) fn record_in(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_in(bytes);
+        }
+    }
+    
+    pub(crate) fn record_out(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_out(bytes);
+        }
+    }
+    
+    pub(crate) fn counts(&self) -> TrafficCounts {
+        TrafficCounts {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A summary of what a single connection did, logged when it disconnects.
+pub(crate) struct ConnectionStats {
+    connected_at: Instant,
+    requests: BTreeMap<&'static str, u32>,
+    invalid_requests: u32,
+    errors: u32,
+    traffic: Arc<Traffic>,
+    rooms: BTreeSet<RoomID>,
+    /// The most messages seen waiting in the connection's queue at once.
+    peak_queue: usize,
+}
+
+impl ConnectionStats {
+    pub(crate) fn new(traffic: Arc<Traffic>) -> ConnectionStats {
+        ConnectionStats {
+            connected_at: Instant::now(),
+            requests: BTreeMap::new(),
+            invalid_requests: 0,
+            errors: 0,
+            traffic,
+            rooms: BTreeSet::new(),
+            peak_queue: 0,
+        }
+    }
+    
+    /// Counts a request, which was `bytes` long not counting any line ending.
+    pub(crate) fn record_request(&mut self, bytes: usize, request: Option<&Request>) {
+        self.traffic.record_in(bytes);
+        
+        let Some(request) = request else {
+            self.invalid_requests += 1;
+            return;
+        };
+        *self.requests.entry(request.name()).or_default() += 1;
+        if let Some(room_id) = request.room_id() {
+            self.rooms.insert(room_id);
+        }
+    }
+    
+    pub(crate) fn record_message(&mut self, msg: &Message, bytes: usize) {
+        self.traffic.record_out(bytes);
+        match msg {
+            Message::Error(_) |
+            Message::Failed(..) => {
+                self.errors += 1;
+            },
+            Message::RoomCreated(room_id) |
+            Message::RoomJoined(room_id) => {
+                self.rooms.insert(*room_id);
+            },
+            _ => {},
+        }
+    }
+    
+    pub(crate) fn record_queue_depth(&mut self, depth: usize) {
+        self.peak_queue = self.peak_queue.max(depth);
+    }
+}
+
+impl std::fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let duration = self.connected_at.elapsed().as_secs_f64();
+        let total: u32 = self.requests.values().sum();
+        write!(f, "connected for {duration:.1}s, {total} requests (")?;
+        for (i, (name, count)) in self.requests.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{sep}{name} x{count}")?;
+        }
+        let traffic = self.traffic.counts();
+        write!(f, "), {} invalid requests, {} errors, {} bytes in, {} messages out, {} bytes out, peak queue {}, games: ", self.invalid_requests, self.errors, traffic.bytes_in, traffic.messages_out, traffic.bytes_out, self.peak_queue)?;
+        if self.rooms.is_empty() {
+            write!(f, "none")
+        } else {
+            for (i, room_id) in self.rooms.iter().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                write!(f, "{sep}#{room_id}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::response::Error;
+    
+    #[test]
+    fn counts() {
+        let total = Arc::default();
+        let mut stats = ConnectionStats::new(Arc::new(Traffic::counted_in(&total)));
+        stats.record_request(6, Some(&Request::Ping(1, None)));
+        stats.record_request(6, Some(&Request::Ping(2, None)));
+        stats.record_request(9, Some(&Request::Send(4, "hi".into())));
+        stats.record_request(4, None);
+        stats.record_message(&Message::Error(Error::NoSuchRoom), 20);
+        stats.record_message(&Message::RoomJoined(3), 9);
+        stats.record_queue_depth(4);
+        stats.record_queue_depth(2);
+        
+        assert_eq!(2, stats.requests["PING"]);
+        assert_eq!(1, stats.requests["SEND"]);
+        assert_eq!(1, stats.invalid_requests);
+        assert_eq!(1, stats.errors);
+        let traffic = stats.traffic.counts();
+        assert_eq!(4, traffic.messages_in);
+        assert_eq!(6 + 6 + 9 + 4, traffic.bytes_in);
+        assert_eq!(2, traffic.messages_out);
+        assert_eq!(29, traffic.bytes_out);
+        assert_eq!(traffic, total.counts());
+        assert_eq!(4, stats.peak_queue);
+        assert_eq!(vec![3, 4], stats.rooms.iter().copied().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::models::{Room, RoomID, UserID};
+
+/// The maximum number of events kept, or reported, for each room.
+pub(crate) const MAX_EVENTS_PER_ROOM: usize = 64;
+
+/// How many closed rooms to keep timelines for.
+const MAX_CLOSED_ROOMS: usize = 32;
+
+/// A structural change to a room, recorded so that administrators can find
+/// out what happened in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomEvent {
+    Created(UserID),
+    JoinRequested(UserID),
+    Joined(UserID),
+    Rejected(UserID),
+    Spectating(UserID),
+    Left(UserID),
+    /// The user was voted out of the room.
+    Kicked(UserID),
+    OwnerChanged(UserID),
+    Closed,
+}
+
+impl RoomEvent {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RoomEvent::Created(_) => "CREATED",
+            RoomEvent::JoinRequested(_) => "JOIN_REQUESTED",
+            RoomEvent::Joined(_) => "JOINED",
+            RoomEvent::Rejected(_) => "REJECTED",
+            RoomEvent::Spectating(_) => "SPECTATING",
+            RoomEvent::Left(_) => "LEFT",
+            RoomEvent::Kicked(_) => "KICKED",
+            RoomEvent::OwnerChanged(_) => "OWNER_CHANGED",
+            RoomEvent::Closed => "CLOSED",
+        }
+    }
+    
+    /// The event with this name and user, as written by `RoomEvent::name` and
+    /// `RoomEvent::user_id`.
+    pub(crate) fn parse(name: &str, user_id: Option<UserID>) -> Option<RoomEvent> {
+        let event = match (name, user_id) {
+            ("CLOSED", None) => RoomEvent::Closed,
+            ("CREATED", Some(user_id)) => RoomEvent::Created(user_id),
+            ("JOIN_REQUESTED", Some(user_id)) => RoomEvent::JoinRequested(user_id),
+            ("JOINED", Some(user_id)) => RoomEvent::Joined(user_id),
+            ("REJECTED", Some(user_id)) => RoomEvent::Rejected(user_id),
+            ("SPECTATING", Some(user_id)) => RoomEvent::Spectating(user_id),
+            ("LEFT", Some(user_id)) => RoomEvent::Left(user_id),
+            ("KICKED", Some(user_id)) => RoomEvent::Kicked(user_id),
+            ("OWNER_CHANGED", Some(user_id)) => RoomEvent::OwnerChanged(user_id),
+            _ => return None,
+        };
+        Some(event)
+    }
+    
+    /// The user the event is about, if any.
+    pub(crate) fn user_id(self) -> Option<UserID> {
+        match self {
+            RoomEvent::Created(user_id) |
+            RoomEvent::JoinRequested(user_id) |
+            RoomEvent::Joined(user_id) |
+            RoomEvent::Rejected(user_id) |
+            RoomEvent::Spectating(user_id) |
+            RoomEvent::Left(user_id) |
+            RoomEvent::Kicked(user_id) |
+            RoomEvent::OwnerChanged(user_id) => Some(user_id),
+            RoomEvent::Closed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub event: RoomEvent,
+}
+
+/// Where rooms and their timelines are kept. The default store is in
+/// memory and forgets closed rooms quickly; others may keep them for good.
+pub(crate) trait RoomStore: Send {
+    fn record(&mut self, room_id: RoomID, time: u64, event: RoomEvent);
+    
+    /// Saves the room's current details, after an event has been recorded
+    /// for it.
+    fn save_room(&mut self, _room: &Room) {}
+    
+    /// The most recent events for the room with this ID, oldest first.
+    fn timeline(&self, room_id: RoomID) -> Option<Vec<TimelineEntry>>;
+}
+
+/// Bounded in-memory timelines of recent events for each room, including
+/// rooms which have recently closed.
+#[derive(Default)]
+pub(crate) struct Timelines {
+    rooms: HashMap<RoomID, VecDeque<TimelineEntry>>,
+    closed: VecDeque<RoomID>,
+}
+
+impl RoomStore for Timelines {
+    fn record(&mut self, room_id: RoomID, time: u64, event: RoomEvent) {
+        if let RoomEvent::Created(_) = event {
+            // the ID might have been used by an older room
+            self.rooms.remove(&room_id);
+            self.closed.retain(|&r_id| r_id != room_id);
+        }
+        
+        let timeline = self.rooms.entry(room_id).or_default();
+        if timeline.len() >= MAX_EVENTS_PER_ROOM {
+            timeline.pop_front();
+        }
+        timeline.push_back(TimelineEntry {time, event});
+        
+        if let RoomEvent::Closed = event {
+            self.closed.push_back(room_id);
+            if self.closed.len() > MAX_CLOSED_ROOMS {
+                let oldest = self.closed.pop_front().unwrap();
+                self.rooms.remove(&oldest);
+            }
+        }
+    }
+    
+    fn timeline(&self, room_id: RoomID) -> Option<Vec<TimelineEntry>> {
+        self.rooms.get(&room_id)
+            .map(|timeline| timeline.iter().cloned().collect())
+    }
+}
+
+impl std::fmt::Display for TimelineEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.time, self.event.name())?;
+        match self.event.user_id() {
+            Some(user_id) => write!(f, ",{user_id}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn bounded_events() {
+        let mut timelines = Timelines::default();
+        timelines.record(1, 0, RoomEvent::Created(1));
+        for i in 0..MAX_EVENTS_PER_ROOM as u64 {
+            timelines.record(1, i, RoomEvent::Joined(2));
+        }
+        
+        let timeline = timelines.timeline(1).unwrap();
+        assert_eq!(MAX_EVENTS_PER_ROOM, timeline.len());
+        assert_eq!(RoomEvent::Joined(2), timeline[0].event);
+    }
+    
+    #[test]
+    fn bounded_closed_rooms() {
+        let mut timelines = Timelines::default();
+        for room_id in 0..=MAX_CLOSED_ROOMS as RoomID {
+            timelines.record(room_id, 0, RoomEvent::Created(1));
+            timelines.record(room_id, 1, RoomEvent::Closed);
+        }
+        
+        assert_eq!(None, timelines.timeline(0));
+        assert_eq!(2, timelines.timeline(1).unwrap().len());
+    }
+    
+    #[test]
+    fn reused_id() {
+        let mut timelines = Timelines::default();
+        timelines.record(1, 0, RoomEvent::Created(1));
+        timelines.record(1, 1, RoomEvent::Closed);
+        timelines.record(1, 2, RoomEvent::Created(2));
+        
+        let expected = vec![TimelineEntry {time: 2, event: RoomEvent::Created(2)}];
+        assert_eq!(Some(expected), timelines.timeline(1));
+    }
+}
@@ -0,0 +1,162 @@
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+
+pub type Reader = Box<dyn Read + Send + Unpin>;
+pub type Writer = Box<dyn Write + Send + Unpin>;
+
+/// Something clients can connect over. Requests are read while messages are
+/// being written, so a transport must be split into separate halves.
+pub trait Transport: Send + 'static {
+    fn split(self) -> (Reader, Writer);
+}
+
+impl Transport for TcpStream {
+    fn split(self) -> (Reader, Writer) {
+        // both halves are handles to the same socket
+        (Box::new(self.clone()), Box::new(self))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for async_std::os::unix::net::UnixStream {
+    fn split(self) -> (Reader, Writer) {
+        (Box::new(self.clone()), Box::new(self))
+    }
+}
+
+/// A client's connection, whichever transport it came over.
+pub(crate) struct Conn {
+    pub(crate) reader: Reader,
+    pub(crate) writer: Writer,
+}
+
+impl Conn {
+    pub(crate) fn new(transport: impl Transport) -> Conn {
+        let (reader, writer) = transport.split();
+        Conn {reader, writer}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use async_std::io;
+    use futures::channel::mpsc;
+    use futures::{StreamExt, TryStreamExt};
+    use super::*;
+    
+    /// A transport which reads from a fixed buffer, and keeps whatever is
+    /// written to it, for testing.
+    pub(crate) struct Memory {
+        input: Vec<u8>,
+        pub(crate) output: Arc<Mutex<Vec<u8>>>,
+    }
+    
+    impl Memory {
+        pub(crate) fn new(input: &str) -> Memory {
+            Memory::from_bytes(input.as_bytes().to_vec())
+        }
+        
+        pub(crate) fn from_bytes(input: Vec<u8>) -> Memory {
+            Memory {input, output: Arc::default()}
+        }
+    }
+    
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+    
+    impl Write for SharedBuffer {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+    
+    impl Transport for Memory {
+        fn split(self) -> (Reader, Writer) {
+            (Box::new(io::Cursor::new(self.input)), Box::new(SharedBuffer(self.output)))
+        }
+    }
+    
+    /// A transport whose other end is held by the test: what is sent on
+    /// `input` is read by the server, which closes when `input` is dropped,
+    /// and what the server writes arrives on `output`.
+    pub(crate) struct Pipe {
+        input: mpsc::UnboundedReceiver<Vec<u8>>,
+        output: mpsc::UnboundedSender<Vec<u8>>,
+    }
+    
+    impl Pipe {
+        pub(crate) fn new() -> (Pipe, mpsc::UnboundedSender<Vec<u8>>, mpsc::UnboundedReceiver<Vec<u8>>) {
+            let (input, reader) = mpsc::unbounded();
+            let (writer, output) = mpsc::unbounded();
+            (Pipe {input: reader, output: writer}, input, output)
+        }
+    }
+    
+    struct PipeWriter(mpsc::UnboundedSender<Vec<u8>>);
+    
+    impl Write for PipeWriter {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            match self.0.unbounded_send(buf.to_vec()) {
+                Ok(()) => Poll::Ready(Ok(buf.len())),
+                Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+        
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.close_channel();
+            Poll::Ready(Ok(()))
+        }
+    }
+    
+    impl Transport for Pipe {
+        fn split(self) -> (Reader, Writer) {
+            let reader = self.input.map(Ok).into_async_read();
+            (Box::new(reader), Box::new(PipeWriter(self.output)))
+        }
+    }
+    
+    /// A transport to a client which never sends anything, nor reads what
+    /// it is sent.
+    pub(crate) struct Stalled;
+    
+    impl Read for Stalled {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+    
+    impl Write for Stalled {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+        
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+        
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+    
+    impl Transport for Stalled {
+        fn split(self) -> (Reader, Writer) {
+            (Box::new(Stalled), Box::new(Stalled))
+        }
+    }
+}
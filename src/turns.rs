@@ -0,0 +1,106 @@
+use crate::models::UserID;
+
+/// The order in which a room's players take turns, for games in which the
+/// server only relays payloads from the player whose turn it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Turns {
+    order: Vec<UserID>,
+    /// Where the current player is in the order.
+    index: usize,
+    /// How many turns have started, including the current one.
+    turn: u64,
+}
+
+impl Turns {
+    /// Starts the first turn, which is the first player's. The order must
+    /// not be empty.
+    pub(crate) fn new(order: Vec<UserID>) -> Turns {
+        Turns::resume(order, 1)
+    }
+    
+    /// Continues from a given turn, which is the first player's.
+    pub(crate) fn resume(order: Vec<UserID>, turn: u64) -> Turns {
+        assert!(!order.is_empty(), "a turn order needs at least one player");
+        Turns {order, index: 0, turn}
+    }
+    
+    pub(crate) fn turn(&self) -> u64 {
+        self.turn
+    }
+    
+    /// The player whose turn it is.
+    pub(crate) fn current(&self) -> UserID {
+        self.order[self.index]
+    }
+    
+    /// Every player, starting with the one whose turn it is.
+    pub(crate) fn upcoming(&self) -> impl Iterator<Item = UserID> + '_ {
+        self.order[self.index..].iter()
+            .chain(&self.order[..self.index])
+            .copied()
+    }
+    
+    /// Starts the next player's turn.
+    pub(crate) fn advance(&mut self) {
+        self.index = (self.index + 1) % self.order.len();
+        self.turn += 1;
+    }
+    
+    /// Takes a player out of the order, returning whether it was their
+    /// turn; if so, the next player's turn starts, unless nobody is left.
+    pub(crate) fn remove(&mut self, user_id: UserID) -> bool {
+        let Some(index) = self.order.iter().position(|&u_id| u_id == user_id) else {
+            return false;
+        };
+        self.order.remove(index);
+        if index < self.index {
+            self.index -= 1;
+            false
+        } else if index == self.index {
+            if self.index == self.order.len() {
+                self.index = 0;
+            }
+            self.turn += 1;
+            true
+        } else {
+            false
+        }
+    }
+    
+    pub(crate) fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn advance() {
+        let mut turns = Turns::new(vec![3, 1, 2]);
+        assert_eq!((1, 3), (turns.turn(), turns.current()));
+        turns.advance();
+        turns.advance();
+        assert_eq!((3, 2), (turns.turn(), turns.current()));
+        turns.advance();
+        assert_eq!((4, 3), (turns.turn(), turns.current()));
+        assert_eq!(vec![3, 1, 2], turns.upcoming().collect::<Vec<_>>());
+    }
+    
+    #[test]
+    fn remove() {
+        let mut turns = Turns::new(vec![1, 2, 3]);
+        turns.advance();
+        assert!(!turns.remove(1));
+        assert_eq!((2, 2), (turns.turn(), turns.current()));
+        assert!(!turns.remove(4));
+        
+        // the last player's turn passes back to the first
+        turns.advance();
+        assert!(turns.remove(3));
+        assert_eq!((4, 2), (turns.turn(), turns.current()));
+        assert!(turns.remove(2));
+        assert!(turns.is_empty());
+    }
+}
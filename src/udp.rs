@@ -0,0 +1,208 @@
+//! A relay for unreliable datagrams between a game's owner and its members,
+//! for game traffic which is better dropped than delivered late. The lobby
+//! is still only reachable over TCP.
+//!
+//! A user asks for a token with `REGISTER_UDP`, and then sends the token
+//! alone in a datagram from the endpoint they want to use; the server
+//! answers `UDP_REGISTERED`. After that, a datagram `room_id|payload` from
+//! that endpoint is relayed as `room_id|user_id|payload` to the room's
+//! members if the sender owns it, or to its owner if not. The payload may
+//! be any bytes.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_std::io;
+use async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use tracing::{debug, info};
+
+use crate::access::AccessControl;
+use crate::dispatch::{canonical_addr, Event, Sender};
+use crate::err;
+use crate::models::{RoomID, UserID};
+
+/// Longer datagrams are dropped, so that relayed datagrams stay within a
+/// typical path MTU without being fragmented.
+pub(crate) const MAX_DATAGRAM_LENGTH: usize = 1200;
+
+/// Sent to an endpoint which has registered.
+pub(crate) const REGISTERED: &[u8] = b"UDP_REGISTERED";
+
+/// Where each user's datagrams come from and are sent to.
+pub(crate) struct UdpRelay {
+    /// Tokens which have been given out but not yet used.
+    tokens: HashMap<String, UserID>,
+    /// The token each user was last given, while it is unused.
+    pending: HashMap<UserID, String>,
+    endpoints: HashMap<UserID, Endpoint>,
+    users: HashMap<SocketAddr, UserID>,
+}
+
+struct Endpoint {
+    /// The socket the endpoint registered through, which its datagrams are
+    /// sent from.
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+}
+
+impl UdpRelay {
+    pub(crate) fn new() -> UdpRelay {
+        UdpRelay {
+            tokens: HashMap::new(),
+            pending: HashMap::new(),
+            endpoints: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+    
+    /// Gives a user a token, replacing any they haven't used yet.
+    pub(crate) fn add_token(&mut self, token: String, user_id: UserID) {
+        if let Some(old) = self.pending.insert(user_id, token.clone()) {
+            self.tokens.remove(&old);
+        }
+        self.tokens.insert(token, user_id);
+    }
+    
+    /// Registers the endpoint a token was sent from, replacing the user's
+    /// previous endpoint. Each token can only be used once.
+    pub(crate) fn register(&mut self, socket: Arc<UdpSocket>, addr: SocketAddr, token: &[u8]) -> Option<UserID> {
+        let token = std::str::from_utf8(token).ok()?;
+        let user_id = self.tokens.remove(token)?;
+        self.pending.remove(&user_id);
+        if let Some(old) = self.endpoints.remove(&user_id) {
+            self.users.remove(&old.addr);
+        }
+        if let Some(old_id) = self.users.insert(addr, user_id) {
+            self.endpoints.remove(&old_id);
+        }
+        self.endpoints.insert(user_id, Endpoint {socket, addr});
+        Some(user_id)
+    }
+    
+    pub(crate) fn user_at(&self, addr: SocketAddr) -> Option<UserID> {
+        self.users.get(&addr).copied()
+    }
+    
+    /// Forgets a user's endpoint and any tokens they haven't used.
+    pub(crate) fn remove_user(&mut self, user_id: UserID) {
+        if let Some(old) = self.endpoints.remove(&user_id) {
+            self.users.remove(&old.addr);
+        }
+        if let Some(token) = self.pending.remove(&user_id) {
+            self.tokens.remove(&token);
+        }
+    }
+    
+    /// Sends a datagram to a user, if they have registered an endpoint.
+    /// Datagrams may be lost anyway, so failures are only logged.
+    pub(crate) async fn send(&self, user_id: UserID, datagram: &[u8]) {
+        let Some(endpoint) = self.endpoints.get(&user_id) else { return; };
+        if let Err(e) = endpoint.socket.send_to(datagram, endpoint.addr).await {
+            debug!(user_id, addr = %endpoint.addr, error = %e, "Failed to send datagram");
+        }
+    }
+}
+
+/// Splits a datagram to relay into its room ID and payload.
+pub(crate) fn parse(datagram: &[u8]) -> Option<(RoomID, &[u8])> {
+    let i = datagram.iter().position(|&b| b == b'|')?;
+    let room_id = std::str::from_utf8(&datagram[..i]).ok()?
+        .parse().ok()?;
+    Some((room_id, &datagram[i + 1..]))
+}
+
+/// A relayed datagram, which says who it is from.
+pub(crate) fn relayed(room_id: RoomID, from_user_id: UserID, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = format!("{room_id}|{from_user_id}|").into_bytes();
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Receives datagrams on an address, and passes them to the dispatcher.
+/// When the dispatcher is busy, datagrams are dropped rather than queued,
+/// since a late datagram is no better than a lost one.
+pub(crate) async fn serve(addr: String, mut access: AccessControl, mut dispatcher: Sender<Event>) -> err::Result {
+    let socket = Arc::new(bind(&addr).await?);
+    info!(%addr, "Relaying datagrams");
+    
+    // one byte extra, to tell when a datagram was too long
+    let mut buf = [0; MAX_DATAGRAM_LENGTH + 1];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok((n, addr)) => (n, canonical_addr(addr)),
+            Err(e) => {
+                debug!(error = %e, "Failed to receive datagram");
+                continue;
+            },
+        };
+        if n > MAX_DATAGRAM_LENGTH || !access.permits(addr.ip()) {
+            continue;
+        }
+        match dispatcher.try_send(Event::Datagram(socket.clone(), addr, buf[..n].to_vec())) {
+            Err(e) if e.is_disconnected() => return Ok(()),
+            _ => {},
+        }
+    }
+}
+
+/// Binds a UDP socket; as with TCP listeners, an IPv6 wildcard address also
+/// receives from IPv4 clients.
+async fn bind(addr: &str) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    
+    let mut error = None;
+    for addr in addr.to_socket_addrs().await? {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() && addr.ip().is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+        match socket.bind(&addr.into()) {
+            Ok(()) => {
+                socket.set_nonblocking(true)?;
+                return Ok(std::net::UdpSocket::from(socket).into());
+            },
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to listen on")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn parse_datagram() {
+        assert_eq!(Some((3, &b"x|y"[..])), parse(b"3|x|y"));
+        assert_eq!(Some((3, &b""[..])), parse(b"3|"));
+        assert_eq!(None, parse(b"3"));
+        assert_eq!(None, parse(b"room|x"));
+        assert_eq!(b"3|1|x|y".to_vec(), relayed(3, 1, b"x|y"));
+    }
+    
+    #[test]
+    fn register() {
+        let socket = Arc::new(async_std::task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap());
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        let mut relay = UdpRelay::new();
+        relay.add_token("xyz".to_string(), 1);
+        relay.add_token("abc".to_string(), 1);
+        
+        // only the latest token is any use
+        assert_eq!(None, relay.register(socket.clone(), a, b"xyz"));
+        assert_eq!(Some(1), relay.register(socket.clone(), a, b"abc"));
+        assert_eq!(None, relay.register(socket.clone(), a, b"abc"));
+        assert_eq!(Some(1), relay.user_at(a));
+        
+        // a new endpoint replaces the old one
+        relay.add_token("def".to_string(), 1);
+        assert_eq!(Some(1), relay.register(socket.clone(), b, b"def"));
+        assert_eq!(None, relay.user_at(a));
+        assert_eq!(Some(1), relay.user_at(b));
+        
+        relay.add_token("ghi".to_string(), 1);
+        relay.remove_user(1);
+        assert_eq!(None, relay.user_at(b));
+        assert_eq!(None, relay.register(socket, b, b"ghi"));
+    }
+}
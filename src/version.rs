@@ -0,0 +1,117 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::response::{Error, Result};
+
+/// The version of this server, as in `--version`.
+pub(crate) const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Increased when the protocol changes in a way which clients may need to
+/// know about; sent to clients in `WELCOME`.
+pub(crate) const PROTOCOL_VERSION: u32 = 3;
+
+/// A dotted client version number, such as `1.4.2`. Missing components are
+/// treated as zero, so `1.4` and `1.4.0` are equal.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientVersion(Vec<u32>);
+
+impl std::str::FromStr for ClientVersion {
+    type Err = ();
+    
+    fn from_str(s: &str) -> std::result::Result<ClientVersion, ()> {
+        s.split('.')
+            .map(|part| part.parse().map_err(|_| ()))
+            .collect::<std::result::Result<_, _>>()
+            .map(ClientVersion)
+    }
+}
+
+impl Ord for ClientVersion {
+    fn cmp(&self, other: &ClientVersion) -> Ordering {
+        let n = self.0.len().max(other.0.len());
+        let component = |v: &ClientVersion, i: usize| v.0.get(i).copied().unwrap_or(0);
+        (0..n)
+            .map(|i| component(self, i).cmp(&component(other, i)))
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+impl PartialOrd for ClientVersion {
+    fn partial_cmp(&self, other: &ClientVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for ClientVersion {
+    fn eq(&self, other: &ClientVersion) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+impl Eq for ClientVersion {}
+
+/// Which client versions are allowed to use the server. Clients announce
+/// their version with a `HELLO` request.
+#[derive(Default)]
+pub(crate) struct VersionPolicy {
+    pub(crate) min_version: Option<ClientVersion>,
+    pub(crate) blocked: Vec<ClientVersion>,
+    /// Where to download a newer client from, sent to rejected clients.
+    pub(crate) upgrade_hint: Option<Arc<str>>,
+}
+
+impl VersionPolicy {
+    /// Whether clients must say `HELLO` before making other requests.
+    pub(crate) fn is_enforced(&self) -> bool {
+        self.min_version.is_some() || !self.blocked.is_empty()
+    }
+    
+    pub(crate) fn check(&self, version: &str) -> Result<()> {
+        let ok = version.parse::<ClientVersion>()
+            .is_ok_and(|v| {
+                self.min_version.as_ref().is_none_or(|min| v >= *min)
+                    && !self.blocked.contains(&v)
+            });
+        
+        if ok {
+            Ok(())
+        } else {
+            Err(self.upgrade_required())
+        }
+    }
+    
+    pub(crate) fn upgrade_required(&self) -> Error {
+        Error::UpgradeRequired(self.upgrade_hint.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    fn v(s: &str) -> ClientVersion {
+        s.parse().unwrap()
+    }
+    
+    #[test]
+    fn compare() {
+        assert!(v("1.2") < v("1.10"));
+        assert!(v("2") > v("1.99.99"));
+        assert_eq!(v("1.4"), v("1.4.0"));
+        assert!("1.x".parse::<ClientVersion>().is_err());
+    }
+    
+    #[test]
+    fn check() {
+        let policy = VersionPolicy {
+            min_version: Some(v("1.2")),
+            blocked: vec![v("1.3.1")],
+            upgrade_hint: Some("example.com".into()),
+        };
+        let err = Err(Error::UpgradeRequired(Some("example.com".into())));
+        
+        assert_eq!(Ok(()), policy.check("1.2"));
+        assert_eq!(Ok(()), policy.check("1.3.2"));
+        assert_eq!(err, policy.check("1.1.9"));
+        assert_eq!(err, policy.check("1.3.1"));
+        assert_eq!(err, policy.check("banana"));
+    }
+}